@@ -5,7 +5,7 @@ use std::path::Path;
 use anyhow::Result;
 use shush_rs::SecretString;
 
-use rencfs::crypto::Cipher;
+use rencfs::crypto::{Cipher, KdfParams};
 use rencfs::encryptedfs::write_all_string_to_fs;
 use rencfs::encryptedfs::{CreateFileAttr, EncryptedFs, FileType, PasswordProvider};
 
@@ -27,6 +27,13 @@ async fn main() -> Result<()> {
     let data_dir = Path::new("/tmp/rencfs_data_test").to_path_buf();
     let _ = fs::remove_dir_all(data_dir.to_str().unwrap());
     let cipher = Cipher::ChaCha20Poly1305;
+    EncryptedFs::init(
+        &data_dir,
+        PasswordProviderImpl {}.get_password().unwrap(),
+        cipher,
+        KdfParams::default(),
+    )
+    .await?;
     let fs = EncryptedFs::new(
         data_dir.clone(),
         Box::new(PasswordProviderImpl {}),
@@ -2,7 +2,7 @@ use core::str::FromStr;
 
 use anyhow::Result;
 use rencfs::{
-    crypto::Cipher,
+    crypto::{Cipher, KdfParams},
     encryptedfs::{
         write_all_string_to_fs, CreateFileAttr, EncryptedFs, FileType, PasswordProvider,
     },
@@ -31,6 +31,13 @@ async fn main() -> Result<()> {
     clean_up_directory(&data_dir)?;
 
     let cipher = Cipher::ChaCha20Poly1305;
+    EncryptedFs::init(
+        &data_dir,
+        PasswordProviderImpl.get_password().unwrap(),
+        cipher,
+        KdfParams::default(),
+    )
+    .await?;
     let fs = EncryptedFs::new(
         data_dir.clone(),
         Box::new(PasswordProviderImpl),
@@ -61,6 +68,19 @@ async fn main() -> Result<()> {
     fs.remove_file(ROOT_INODE, &file_name).await?;
     assert!(!fs.exists_by_name(ROOT_INODE, &file_name)?);
 
+    // or by paths, like with std::fs
+    let path_fs = fs.path();
+    path_fs.create_dir_all("/dir1/dir2").await?;
+    path_fs
+        .write_all("/dir1/dir2/file2", data.as_bytes())
+        .await?;
+    assert_eq!(
+        data.as_bytes(),
+        path_fs.read_to_end("/dir1/dir2/file2").await?
+    );
+    path_fs.remove_dir_all("/dir1").await?;
+    assert!(!path_fs.exists("/dir1").await?);
+
     clean_up_directory(&data_dir)?;
 
     Ok(())
@@ -7,8 +7,8 @@ use anyhow::Result;
 use shush_rs::SecretString;
 use tracing::info;
 
-use rencfs::crypto::Cipher;
-use rencfs::encryptedfs::PasswordProvider;
+use rencfs::crypto::{Cipher, KdfParams};
+use rencfs::encryptedfs::{EncryptedFs, PasswordProvider};
 use rencfs::mount::create_mount_point;
 use rencfs::mount::MountPoint;
 
@@ -30,6 +30,15 @@ async fn main() -> Result<()> {
             Some(SecretString::from_str("a").unwrap())
         }
     }
+    if !EncryptedFs::is_initialized(Path::new(&data_path)) {
+        EncryptedFs::init(
+            Path::new(&data_path),
+            PasswordProviderImpl {}.get_password().unwrap(),
+            Cipher::ChaCha20Poly1305,
+            KdfParams::default(),
+        )
+        .await?;
+    }
     let mount_point = create_mount_point(
         Path::new(&mount_path),
         Path::new(&data_path),
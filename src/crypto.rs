@@ -4,6 +4,7 @@ use std::io::{Read, Seek, Write};
 use std::num::ParseIntError;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use argon2::Argon2;
 use base64::alphabet::STANDARD;
@@ -22,12 +23,15 @@ use thiserror::Error;
 use tracing::{debug, error, instrument};
 use write::CryptoInnerWriter;
 
+use crate::crypto::block_store::{BlockStorage, BlockStore};
 use crate::crypto::read::{CryptoRead, CryptoReadSeek, RingCryptoRead};
 use crate::crypto::write::{CryptoWrite, CryptoWriteSeek, RingCryptoWrite};
 use crate::encryptedfs::FsResult;
 use crate::{fs_util, stream_util};
 
+pub mod block_store;
 pub mod buf_mut;
+pub mod header;
 pub mod read;
 pub mod write;
 
@@ -52,6 +56,16 @@ impl Cipher {
         }
     }
 
+    /// Length (in bytes) of the authentication tag appended to each encrypted block.
+    #[must_use]
+    #[allow(clippy::use_self)]
+    pub fn tag_len(&self) -> usize {
+        match self {
+            Cipher::ChaCha20Poly1305 => CHACHA20_POLY1305.tag_len(),
+            Cipher::Aes256Gcm => AES_256_GCM.tag_len(),
+        }
+    }
+
     /// Max length (in bytes) of the plaintext that can be encrypted before becoming unsafe.
     #[must_use]
     #[allow(clippy::use_self)]
@@ -175,6 +189,16 @@ fn create_ring_read_seek<R: Read + Seek + Send + Sync>(
     RingCryptoRead::new_seek(reader, algorithm, key)
 }
 
+/// Creates a store to read and write blocks of encrypted content in place.
+#[allow(clippy::missing_errors_doc)]
+pub fn create_block_store<F: BlockStorage>(
+    file: F,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> io::Result<BlockStore<F>> {
+    BlockStore::new(file, cipher, key)
+}
+
 /// Creates an encrypted reader
 pub fn create_read<R: Read + Send + Sync>(
     reader: R,
@@ -193,10 +217,54 @@ pub fn create_read_seek<R: Read + Seek + Send + Sync>(
     create_ring_read_seek(reader, cipher, key)
 }
 
+/// What older versions of the format wrote, it's accepted only until the data dir is upgraded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Legacy {
+    /// Only what we write now
+    None,
+    /// Content without the header, written before format version 4, zeroed blocks are not holes
+    WithoutHeader,
+    /// Zeroed blocks in content with the header without the map of the holes, only format
+    /// version 4 wrote the holes like that
+    ZeroedHoles,
+}
+
+/// Like [`create_read`], with what older versions wrote as `legacy` accepted too.
+pub fn create_read_compat<R: Read + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    legacy: Legacy,
+) -> impl CryptoRead<R> {
+    create_ring_read(reader, cipher, key).allow_legacy(legacy)
+}
+
+/// Like [`create_read_seek`], with what older versions wrote as `legacy` accepted too.
+pub fn create_read_seek_compat<R: Read + Seek + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    legacy: Legacy,
+) -> impl CryptoReadSeek<R> {
+    create_ring_read_seek(reader, cipher, key).allow_legacy(legacy)
+}
+
+/// Like [`create_block_store`], with what older versions wrote as `legacy` accepted too.
+#[allow(clippy::missing_errors_doc)]
+pub fn create_block_store_compat<F: BlockStorage>(
+    file: F,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    legacy: Legacy,
+) -> io::Result<BlockStore<F>> {
+    BlockStore::new_legacy(file, cipher, key, legacy)
+}
+
 #[allow(clippy::missing_errors_doc)]
 pub fn encrypt(s: &SecretString, cipher: Cipher, key: &SecretVec<u8>) -> Result<String> {
     let mut cursor = io::Cursor::new(vec![]);
-    let mut writer = create_write(cursor, cipher, key);
+    // no header, it would make the names too long
+    let mut writer = create_ring_write(cursor, cipher, key).without_header();
     writer.write_all(s.expose_secret().as_bytes())?;
     cursor = writer.finish()?;
     let v = cursor.into_inner();
@@ -209,7 +277,7 @@ pub fn decrypt(s: &str, cipher: Cipher, key: &SecretVec<u8>) -> Result<SecretStr
     let vec = BASE64.decode(s)?;
     let cursor = io::Cursor::new(vec);
 
-    let mut reader = create_read(cursor, cipher, key);
+    let mut reader = create_ring_read(cursor, cipher, key).without_header();
     let mut decrypted = String::new();
     reader.read_to_string(&mut decrypted)?;
     Ok(SecretString::new(Box::new(decrypted)))
@@ -221,13 +289,106 @@ pub fn decrypt_file_name(name: &str, cipher: Cipher, key: &SecretVec<u8>) -> Res
     decrypt(&name, cipher, key)
 }
 
-#[instrument(skip(password, salt))]
+/// Parameters of Argon2id used to derive the key from the password.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    /// Memory size in KiB
+    pub m_cost: u32,
+    /// Number of iterations
+    pub t_cost: u32,
+    /// Degree of parallelism
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            m_cost: argon2::Params::DEFAULT_M_COST,
+            t_cost: argon2::Params::DEFAULT_T_COST,
+            p_cost: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KdfParams {
+    /// We don't go above this when calibrating, 1 GiB.
+    pub const MAX_CALIBRATED_M_COST: u32 = 1024 * 1024;
+    /// Above these we don't derive, the params come from files and archives that could be
+    /// changed to make us use all the memory or spin for a very long time.
+    pub const MAX_M_COST: u32 = 4 * 1024 * 1024;
+    pub const MAX_T_COST: u32 = 100;
+    pub const MAX_P_COST: u32 = 64;
+
+    /// Pick parameters so deriving the key takes about `target` on this machine.
+    ///
+    /// Memory is doubled first, starting from the default, as that's what makes attacks with GPUs
+    /// costly, then iterations are added to fill the remaining time.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn calibrate(target: Duration) -> Result<Self> {
+        let mut params = Self {
+            t_cost: 1,
+            ..Self::default()
+        };
+        let mut elapsed = params.time_derive()?;
+        while elapsed * 2 <= target && params.m_cost * 2 <= Self::MAX_CALIBRATED_M_COST {
+            params.m_cost *= 2;
+            elapsed = params.time_derive()?;
+        }
+        let t_cost = target.as_nanos() / elapsed.as_nanos().max(1);
+        params.t_cost = t_cost.clamp(1, u128::from(Self::MAX_T_COST)) as u32;
+        debug!(?params, ?elapsed, "calibrated kdf");
+        Ok(params)
+    }
+
+    /// Fails if any of the params is above its maximum.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn check(&self) -> Result<()> {
+        if self.m_cost > Self::MAX_M_COST
+            || self.t_cost > Self::MAX_T_COST
+            || self.p_cost > Self::MAX_P_COST
+        {
+            return Err(Error::GenericString(format!(
+                "kdf params above the maximum of {} KiB memory, {} iterations, {} parallelism",
+                Self::MAX_M_COST,
+                Self::MAX_T_COST,
+                Self::MAX_P_COST
+            )));
+        }
+        Ok(())
+    }
+
+    fn time_derive(&self) -> Result<Duration> {
+        let password = SecretString::from_str("calibrate").expect("cannot create password");
+        let mut salt = vec![0; 16];
+        create_rng().fill_bytes(&mut salt);
+        let start = Instant::now();
+        derive_key_with_params(&password, Cipher::ChaCha20Poly1305, &salt, self)?;
+        Ok(start.elapsed())
+    }
+}
+
+/// Derive the key from the password with the default [`KdfParams`].
 #[allow(clippy::missing_errors_doc)]
 pub fn derive_key(password: &SecretString, cipher: Cipher, salt: &[u8]) -> Result<SecretVec<u8>> {
+    derive_key_with_params(password, cipher, salt, &KdfParams::default())
+}
+
+#[instrument(skip(password, salt))]
+#[allow(clippy::missing_errors_doc)]
+pub fn derive_key_with_params(
+    password: &SecretString,
+    cipher: Cipher,
+    salt: &[u8],
+    params: &KdfParams,
+) -> Result<SecretVec<u8>> {
+    params.check()?;
     let mut dk = vec![];
     let key_len = cipher.key_len();
     dk.resize(key_len, 0);
-    Argon2::default()
+    let params = argon2::Params::new(params.m_cost, params.t_cost, params.p_cost, None)
+        .map_err(|err| Error::GenericString(err.to_string()))?;
+    Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
         .hash_password_into(password.expose_secret().as_bytes(), salt, &mut dk)
         .map_err(|err| Error::GenericString(err.to_string()))?;
     Ok(SecretVec::new(Box::new(dk)))
@@ -255,9 +416,54 @@ pub fn encrypt_file_name(
     }
 }
 
-#[allow(clippy::missing_errors_doc)]
+/// Context used to derive the key for [`hash_file_name`] from the master key.
+pub const FILE_NAME_HASH_KEY_CONTEXT: &str = "rencfs 2026-10-18 file name hash key";
+
+/// Derive a subkey for a specific purpose, identified by `context`, from the master key.
 #[must_use]
-pub fn hash_file_name(name: &SecretString) -> String {
+pub fn derive_subkey(key: &SecretVec<u8>, context: &str) -> SecretVec<u8> {
+    let mut subkey = vec![0; blake3::KEY_LEN];
+    blake3::derive_key(context, &key.expose_secret(), &mut subkey);
+    SecretVec::new(Box::new(subkey))
+}
+
+/// Context used to hash the content of keyfiles in [`keyfile_key_material`].
+pub const KEYFILE_CONTEXT: &str = "rencfs 2026-10-18 keyfile";
+
+/// Key material from a keyfile, the key is derived from it like from a password.
+///
+/// It's a hash of the content so keyfiles can be binary and of any size.
+#[must_use]
+pub fn keyfile_key_material(content: &[u8]) -> SecretString {
+    let mut hash = [0; blake3::OUT_LEN];
+    blake3::derive_key(KEYFILE_CONTEXT, content, &mut hash);
+    SecretString::new(Box::new(hex::encode(hash)))
+}
+
+/// Hash of the file name keyed with `hash_key`, which should be derived with [`derive_subkey`]
+/// and [`FILE_NAME_HASH_KEY_CONTEXT`].
+///
+/// Without the key the hash cannot be checked against guessed names.
+#[allow(clippy::missing_panics_doc)]
+#[must_use]
+pub fn hash_file_name(name: &SecretString, hash_key: &SecretVec<u8>) -> String {
+    if *name.expose_secret() == "$." || *name.expose_secret() == "$.." {
+        name.expose_secret().clone()
+    } else if *name.expose_secret() == "." || *name.expose_secret() == ".." {
+        format!("${}", name.expose_secret())
+    } else {
+        let hash_key: &[u8; blake3::KEY_LEN] = &hash_key
+            .expose_secret()
+            .as_slice()
+            .try_into()
+            .expect("invalid hash key length");
+        hex::encode(blake3::keyed_hash(hash_key, name.expose_secret().as_bytes()).as_bytes())
+    }
+}
+
+/// Unkeyed hash of the file name, used by data dirs created by older versions.
+#[must_use]
+pub fn legacy_hash_file_name(name: &SecretString) -> String {
     if *name.expose_secret() == "$." || *name.expose_secret() == "$.." {
         name.expose_secret().clone()
     } else if *name.expose_secret() == "." || *name.expose_secret() == ".." {
@@ -519,6 +725,59 @@ mod tests {
         assert_eq!(derived_keys.len(), salts.len());
     }
 
+    #[test]
+    fn test_derive_key_with_params() {
+        let password = SecretString::from_str("password").unwrap();
+        let salt = b"random_salt";
+
+        let default_key = derive_key(&password, Cipher::ChaCha20Poly1305, salt).unwrap();
+        let same_key = derive_key_with_params(
+            &password,
+            Cipher::ChaCha20Poly1305,
+            salt,
+            &KdfParams::default(),
+        )
+        .unwrap();
+        assert_eq!(default_key.expose_secret(), same_key.expose_secret());
+
+        let params = KdfParams {
+            t_cost: KdfParams::default().t_cost + 1,
+            ..KdfParams::default()
+        };
+        let other_key =
+            derive_key_with_params(&password, Cipher::ChaCha20Poly1305, salt, &params).unwrap();
+        assert_ne!(default_key.expose_secret(), other_key.expose_secret());
+
+        let invalid = KdfParams {
+            t_cost: 0,
+            ..KdfParams::default()
+        };
+        assert!(
+            derive_key_with_params(&password, Cipher::ChaCha20Poly1305, salt, &invalid).is_err()
+        );
+        let excessive = KdfParams {
+            m_cost: KdfParams::MAX_M_COST + 1,
+            ..KdfParams::default()
+        };
+        assert!(excessive.check().is_err());
+        assert!(
+            derive_key_with_params(&password, Cipher::ChaCha20Poly1305, salt, &excessive).is_err()
+        );
+    }
+
+    #[test]
+    fn test_kdf_calibrate() {
+        let params = KdfParams::calibrate(std::time::Duration::from_millis(100)).unwrap();
+        assert!(params.t_cost >= 1);
+        assert!(params.m_cost >= KdfParams::default().m_cost);
+        assert!(params.m_cost <= KdfParams::MAX_CALIBRATED_M_COST);
+        assert_eq!(params.p_cost, KdfParams::default().p_cost);
+
+        let password = SecretString::from_str("password").unwrap();
+        derive_key_with_params(&password, Cipher::ChaCha20Poly1305, b"random_salt", &params)
+            .unwrap();
+    }
+
     #[test]
     fn test_encrypt_decrypt() {
         for &cipher in &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
@@ -549,37 +808,92 @@ mod tests {
 
     #[test]
     fn test_hash_file_name_special_cases() {
+        let hash_key = derive_subkey(&SecretVec::from(vec![0; 32]), FILE_NAME_HASH_KEY_CONTEXT);
+
         let expected = "$.".to_owned();
         let name = SecretString::new(Box::new(expected.clone()));
-        let result = hash_file_name(&name);
+        let result = hash_file_name(&name, &hash_key);
         assert_eq!(result, expected);
 
         let expected = "$..".to_owned();
         let name = SecretString::new(Box::new(expected.clone()));
-        let result = hash_file_name(&name);
+        let result = hash_file_name(&name, &hash_key);
         assert_eq!(result, expected);
 
         let input = ".".to_owned();
         let expected = "$.".to_owned();
         let name = SecretString::new(Box::new(input));
-        let result = hash_file_name(&name);
+        let result = hash_file_name(&name, &hash_key);
         assert_eq!(result, expected);
 
         let input = "..".to_owned();
         let expected = "$..".to_owned();
         let name = SecretString::new(Box::new(input));
-        let result = hash_file_name(&name);
+        let result = hash_file_name(&name, &hash_key);
         assert_eq!(result, expected);
     }
 
     #[test]
     fn test_hash_file_name_regular_case() {
+        let hash_key = derive_subkey(&SecretVec::from(vec![0; 32]), FILE_NAME_HASH_KEY_CONTEXT);
         let name = SecretString::new(Box::new("filename.txt".to_owned()));
-        let result = hash_file_name(&name);
+        let result = hash_file_name(&name, &hash_key);
+        assert_eq!(64, result.len());
+        assert_eq!(result, hash_file_name(&name, &hash_key));
+        // not the plain hash of the name
+        assert_ne!(result, hex::encode(hash_secret_string(&name)));
+        assert_ne!(result, legacy_hash_file_name(&name));
+
+        // depends on the key
+        let hash_key2 = derive_subkey(&SecretVec::from(vec![1; 32]), FILE_NAME_HASH_KEY_CONTEXT);
+        assert_ne!(result, hash_file_name(&name, &hash_key2));
+    }
+
+    #[test]
+    fn test_legacy_hash_file_name() {
+        let name = SecretString::new(Box::new("filename.txt".to_owned()));
+        let result = legacy_hash_file_name(&name);
         let expected_hash = hex::encode(hash_secret_string(&name));
         assert_eq!(result, expected_hash);
     }
 
+    #[test]
+    fn test_keyfile_key_material() {
+        let material = keyfile_key_material(b"\x00\xffbinary keyfile");
+        assert_eq!(material.expose_secret().len(), blake3::OUT_LEN * 2);
+        assert_eq!(
+            material.expose_secret(),
+            keyfile_key_material(b"\x00\xffbinary keyfile").expose_secret()
+        );
+        assert_ne!(
+            material.expose_secret(),
+            keyfile_key_material(b"\x00\xffbinary keyfilf").expose_secret()
+        );
+    }
+
+    #[test]
+    fn test_derive_subkey() {
+        let key = SecretVec::from(vec![0; 32]);
+        let subkey = derive_subkey(&key, FILE_NAME_HASH_KEY_CONTEXT);
+        assert_eq!(32, subkey.expose_secret().len());
+        assert_eq!(
+            subkey.expose_secret().as_slice(),
+            derive_subkey(&key, FILE_NAME_HASH_KEY_CONTEXT)
+                .expose_secret()
+                .as_slice()
+        );
+        assert_ne!(
+            key.expose_secret().as_slice(),
+            subkey.expose_secret().as_slice()
+        );
+        assert_ne!(
+            subkey.expose_secret().as_slice(),
+            derive_subkey(&key, "other context")
+                .expose_secret()
+                .as_slice()
+        );
+    }
+
     #[test]
     fn test_hash_secret_string() {
         let secret = SecretString::new(Box::new("hash this secret".to_owned()));
@@ -0,0 +1,1062 @@
+//! Random access to encrypted content, one block at a time.
+//!
+//! The layout is the same [`RingCryptoWrite`](crate::crypto::write::RingCryptoWrite) writes, the
+//! header and then the blocks, each of them nonce, ciphertext and tag. Each block is encrypted on
+//! its own, so we can change it in place without touching the others, the cost of a write is
+//! bounded by the block size.
+//!
+//! Blocks which were never written are holes, their ciphertext is only zeros, and they read as
+//! zeros. We don't write them when extending the file, so on storage with sparse files they don't
+//! take any space. They are recorded in the encrypted map in the header, a block which is zeroed
+//! but not in the map fails to decrypt like any other changed block. Content with the header
+//! without the map, or without a header, cannot have holes, there we write encrypted zeros. Format
+//! version 4 wrote holes there too, we accept them only from data dirs in that format, until they
+//! are upgraded.
+
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+use rand_chacha::rand_core::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN};
+use shush_rs::{ExposeSecret, SecretVec};
+
+use crate::crypto::header::{self, FileId, Holes, HEADER_LEN, HOLES_HEADER_LEN};
+use crate::crypto::write::BLOCK_SIZE;
+use crate::crypto::{self, Cipher, Legacy};
+use crate::{fs_util, stream_util};
+
+/// Block kept in memory, so sequential writes don't encrypt the same block multiple times.
+struct CachedBlock {
+    index: u64,
+    data: Vec<u8>,
+    dirty: bool,
+}
+
+/// Reads and writes the plaintext at any offset, with read-modify-write of the blocks it touches.
+///
+/// The last block written is kept in memory until another block is accessed, [`BlockStore::flush`]
+/// or [`BlockStore::finish`] is called.
+pub struct BlockStore<F: BlockStorage> {
+    file: F,
+    key: LessSafeKey,
+    rng: Box<dyn RngCore + Send + Sync>,
+    tag_len: usize,
+    file_id: Option<FileId>,
+    header_len: u64,
+    // new content, the header is written with the first block
+    header_pending: bool,
+    // `None` if the content doesn't have the map in the header
+    holes: Option<Holes>,
+    // zeroed blocks are holes, for content of format version 4 without the map
+    legacy_holes: bool,
+    cache: Option<CachedBlock>,
+}
+
+impl<F: BlockStorage> BlockStore<F> {
+    /// Content without the header is an error, see [`BlockStore::new_legacy`].
+    #[allow(clippy::missing_errors_doc)]
+    pub fn new(file: F, cipher: Cipher, key: &SecretVec<u8>) -> io::Result<Self> {
+        Self::open(file, cipher, key, Legacy::None)
+    }
+
+    /// Like [`BlockStore::new`], but what older versions wrote as `legacy` is accepted, we keep it
+    /// like that.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn new_legacy(
+        file: F,
+        cipher: Cipher,
+        key: &SecretVec<u8>,
+        legacy: Legacy,
+    ) -> io::Result<Self> {
+        Self::open(file, cipher, key, legacy)
+    }
+
+    fn open(mut file: F, cipher: Cipher, key: &SecretVec<u8>, legacy: Legacy) -> io::Result<Self> {
+        let algorithm = match cipher {
+            Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+            Cipher::Aes256Gcm => &AES_256_GCM,
+        };
+        let key = LessSafeKey::new(
+            UnboundKey::new(algorithm, &key.expose_secret()).expect("unbound key"),
+        );
+        let (file_id, holes, header_pending) = if file.seek(SeekFrom::End(0))? == 0 {
+            (Some(header::new_file_id()), Some(Holes::default()), true)
+        } else {
+            file.seek(SeekFrom::Start(0))?;
+            let mut buf = vec![0; HOLES_HEADER_LEN];
+            let len = stream_util::read(&mut file, &mut buf)?;
+            let file_id = header::decode(&buf[..len]);
+            let holes = match &file_id {
+                Some(file_id) if header::has_holes(&buf) => {
+                    Some(Holes::decode(&buf[..len], file_id, &key)?)
+                }
+                Some(_) => None,
+                None if legacy == Legacy::WithoutHeader => None,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "content without header",
+                    ))
+                }
+            };
+            (file_id, holes, false)
+        };
+        let legacy_holes = legacy == Legacy::ZeroedHoles && holes.is_none();
+        Ok(Self {
+            file,
+            key,
+            rng: Box::new(crypto::create_rng()),
+            tag_len: algorithm.tag_len(),
+            header_len: match (&file_id, &holes) {
+                (Some(_), Some(_)) => HOLES_HEADER_LEN as u64,
+                (Some(_), None) => HEADER_LEN as u64,
+                (None, _) => 0,
+            },
+            file_id,
+            header_pending,
+            holes,
+            legacy_holes,
+            cache: None,
+        })
+    }
+
+    const fn ciphertext_block_size(&self) -> u64 {
+        (NONCE_LEN + BLOCK_SIZE + self.tag_len) as u64
+    }
+
+    const fn block_offset(&self, index: u64) -> u64 {
+        self.header_len + index * self.ciphertext_block_size()
+    }
+
+    /// Plaintext length of what is in the file, without the cached block.
+    fn stored_len(&mut self) -> io::Result<u64> {
+        let ciphertext_len = self
+            .file
+            .seek(SeekFrom::End(0))?
+            .saturating_sub(self.header_len);
+        let blocks = ciphertext_len / self.ciphertext_block_size();
+        let last = ciphertext_len % self.ciphertext_block_size();
+        Ok(blocks * BLOCK_SIZE as u64 + last.saturating_sub((NONCE_LEN + self.tag_len) as u64))
+    }
+
+    /// Plaintext length, including what was written but not flushed yet.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn len(&mut self) -> io::Result<u64> {
+        let stored_len = self.stored_len()?;
+        Ok(match &self.cache {
+            Some(block) if block.dirty => {
+                stored_len.max(block.index * BLOCK_SIZE as u64 + block.data.len() as u64)
+            }
+            _ => stored_len,
+        })
+    }
+
+    #[allow(clippy::missing_errors_doc)]
+    pub fn is_empty(&mut self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// If there is data which was not written to the file yet.
+    #[must_use]
+    pub fn is_dirty(&self) -> bool {
+        self.cache.as_ref().is_some_and(|block| block.dirty)
+    }
+
+    /// Decrypt the block from the file, it's empty if we don't have it.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn read_block(&mut self, index: u64) -> io::Result<Vec<u8>> {
+        #[allow(clippy::cast_possible_truncation)]
+        let mut buf = vec![0; self.ciphertext_block_size() as usize];
+        self.file.seek(SeekFrom::Start(self.block_offset(index)))?;
+        let len = stream_util::read(&mut self.file, &mut buf)?;
+        if len == 0 {
+            return Ok(vec![]);
+        }
+        // zeroed blocks which are not in the map fail to decrypt
+        if self.in_holes(index) && is_hole(&buf[..len]) {
+            return Ok(vec![0; len.saturating_sub(NONCE_LEN + self.tag_len)]);
+        }
+        if len <= NONCE_LEN + self.tag_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "block too short",
+            ));
+        }
+        let nonce = Nonce::try_assume_unique_for_key(&buf[..NONCE_LEN])
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid nonce"))?;
+        let aad = Aad::from(header::block_aad(self.file_id.as_ref(), index));
+        let plaintext_len = self
+            .key
+            .open_in_place(nonce, aad, &mut buf[NONCE_LEN..len])
+            .map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("error opening block {index}: {err}"),
+                )
+            })?
+            .len();
+        buf.copy_within(NONCE_LEN..NONCE_LEN + plaintext_len, 0);
+        buf.truncate(plaintext_len);
+        Ok(buf)
+    }
+
+    /// Encrypt the block with a new nonce and write it in place.
+    ///
+    /// Only the last block can be shorter than [`BLOCK_SIZE`].
+    #[allow(clippy::missing_errors_doc)]
+    pub fn write_block(&mut self, index: u64, data: &[u8]) -> io::Result<()> {
+        // the blocks we skip are holes
+        let blocks = self.stored_len()?.div_ceil(BLOCK_SIZE as u64);
+        if index > blocks {
+            self.add_holes(blocks, index, index * BLOCK_SIZE as u64)?;
+        }
+        self.seal_block(index, data)?;
+        // after the block, if we crash before the map is written it still has it, that only
+        // lets the block be zeroed as before
+        let evicted = match self.holes.as_mut() {
+            Some(holes) if holes.contains(index) => holes.remove(index),
+            _ => return Ok(()),
+        };
+        if let Some((start, end)) = evicted {
+            let size = self.stored_len()?;
+            self.write_zero_blocks(start, end, size)?;
+        }
+        self.write_holes()
+    }
+
+    fn seal_block(&mut self, index: u64, data: &[u8]) -> io::Result<()> {
+        if data.len() > BLOCK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "data is bigger than the block size",
+            ));
+        }
+        self.write_header()?;
+        let mut nonce = [0; NONCE_LEN];
+        self.rng.fill_bytes(&mut nonce);
+        let mut block = Vec::with_capacity(NONCE_LEN + data.len() + self.tag_len);
+        block.extend_from_slice(&nonce);
+        block.extend_from_slice(data);
+        let aad = Aad::from(header::block_aad(self.file_id.as_ref(), index));
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(nonce),
+                aad,
+                &mut block[NONCE_LEN..],
+            )
+            .map_err(|err| io::Error::other(format!("error sealing block {index}: {err}")))?;
+        block.extend_from_slice(tag.as_ref());
+        self.file.seek(SeekFrom::Start(self.block_offset(index)))?;
+        self.file.write_all(&block)?;
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if self.header_pending {
+            self.write_holes()?;
+        }
+        Ok(())
+    }
+
+    /// Write the header with the map of the holes, new content always has it.
+    fn write_holes(&mut self) -> io::Result<()> {
+        let Some(holes) = &self.holes else {
+            return Ok(());
+        };
+        let header =
+            holes.encode_header(self.file_id.as_ref().unwrap(), &self.key, &mut *self.rng)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.header_pending = false;
+        Ok(())
+    }
+
+    fn in_holes(&self, index: u64) -> bool {
+        self.holes
+            .as_ref()
+            .map_or(self.legacy_holes, |holes| holes.contains(index))
+    }
+
+    /// If the content has the map of the holes, it's authenticated when we open it, so the key is
+    /// the one it's encrypted with.
+    #[must_use]
+    pub const fn has_holes_map(&self) -> bool {
+        self.holes.is_some()
+    }
+
+    /// Where the zeroed blocks which read as holes of format version 4 are in the storage, their
+    /// offset and length.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn legacy_hole_ranges(&mut self) -> io::Result<Vec<(u64, u64)>> {
+        let mut ranges = vec![];
+        if !self.legacy_holes {
+            return Ok(ranges);
+        }
+        let file_len = self.file.seek(SeekFrom::End(0))?;
+        let blocks = self.stored_len()?.div_ceil(BLOCK_SIZE as u64);
+        for index in 0..blocks {
+            if self.is_hole(index)? {
+                let offset = self.block_offset(index);
+                let end = (offset + self.ciphertext_block_size()).min(file_len);
+                ranges.push((offset, end - offset));
+            }
+        }
+        Ok(ranges)
+    }
+
+    /// Copy the blocks with data to `dst`, the holes stay holes.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn copy_to<G: BlockStorage>(&mut self, dst: &mut BlockStore<G>) -> io::Result<()> {
+        let mut offset = 0;
+        while let Some(data_offset) = self.seek_data(offset)? {
+            let index = data_offset / BLOCK_SIZE as u64;
+            dst.write_block(index, &self.read_block(index)?)?;
+            offset = (index + 1) * BLOCK_SIZE as u64;
+        }
+        dst.set_len(self.len()?)
+    }
+
+    /// Record the blocks as holes, if we cannot they are written as encrypted zeros instead.
+    ///
+    /// Returns the blocks which are not holes, in that case, they are written already.
+    fn add_holes(&mut self, start: u64, end: u64, size: u64) -> io::Result<Option<(u64, u64)>> {
+        let evicted = match self.holes.as_mut() {
+            Some(holes) => holes.insert(start, end),
+            None => Some((start, end)),
+        };
+        if let Some((start, end)) = evicted {
+            self.write_zero_blocks(start, end, size)?;
+        }
+        // after the blocks we removed from the map are written
+        self.write_holes()?;
+        Ok(evicted)
+    }
+
+    /// Write the blocks in the range as encrypted zeros, the content has `size` after.
+    #[allow(clippy::cast_possible_truncation)]
+    fn write_zero_blocks(&mut self, start: u64, end: u64, size: u64) -> io::Result<()> {
+        for index in start..end {
+            let len = (size.saturating_sub(index * BLOCK_SIZE as u64)).min(BLOCK_SIZE as u64);
+            if len > 0 {
+                self.seal_block(index, &vec![0; len as usize])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// If the block was not written, it reads as zeros.
+    ///
+    /// Only the blocks in the map of the holes can be, the nonce of a written block is random, so
+    /// we check only that, it's cheap to do it for each block when looking for holes.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn is_hole(&mut self, index: u64) -> io::Result<bool> {
+        if self
+            .cache
+            .as_ref()
+            .is_some_and(|block| block.index == index && block.dirty)
+            || !self.in_holes(index)
+        {
+            return Ok(false);
+        }
+        let mut nonce = [0; NONCE_LEN];
+        self.file.seek(SeekFrom::Start(self.block_offset(index)))?;
+        let len = stream_util::read(&mut self.file, &mut nonce)?;
+        Ok(len > 0 && is_hole(&nonce[..len]))
+    }
+
+    /// Start of the next data from `offset`, like `lseek(2)` with `SEEK_DATA`.
+    ///
+    /// Returns `None` if `offset` is after the end or there are only holes until the end.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn seek_data(&mut self, offset: u64) -> io::Result<Option<u64>> {
+        self.flush()?;
+        let len = self.len()?;
+        if offset >= len {
+            return Ok(None);
+        }
+        let blocks = len.div_ceil(BLOCK_SIZE as u64);
+        let mut index = offset / BLOCK_SIZE as u64;
+        while index < blocks {
+            let Some(pos) = self.file.next_data(self.block_offset(index))? else {
+                return Ok(None);
+            };
+            // skip the blocks in the hole of the storage, it might start or end inside a block
+            index = index.max(pos.saturating_sub(self.header_len) / self.ciphertext_block_size());
+            if index < blocks && !self.is_hole(index)? {
+                return Ok(Some(offset.max(index * BLOCK_SIZE as u64)));
+            }
+            index += 1;
+        }
+        Ok(None)
+    }
+
+    /// Start of the next hole from `offset`, like `lseek(2)` with `SEEK_HOLE`, the end counts as
+    /// a hole.
+    ///
+    /// Returns `None` if `offset` is after the end.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn seek_hole(&mut self, offset: u64) -> io::Result<Option<u64>> {
+        self.flush()?;
+        let len = self.len()?;
+        if offset >= len {
+            return Ok(None);
+        }
+        let blocks = len.div_ceil(BLOCK_SIZE as u64);
+        let mut index = offset / BLOCK_SIZE as u64;
+        // the holes of the storage don't help, its data could have zeroed blocks if it doesn't
+        // support sparse files
+        while index < blocks {
+            if self.is_hole(index)? {
+                return Ok(Some(offset.max(index * BLOCK_SIZE as u64)));
+            }
+            index += 1;
+        }
+        Ok(Some(len))
+    }
+
+    /// Make the block the cached one, writing the previous one if it was changed.
+    fn load(&mut self, index: u64, read: bool) -> io::Result<&mut CachedBlock> {
+        if self.cache.as_ref().is_none_or(|block| block.index != index) {
+            self.flush()?;
+            let data = if read {
+                self.read_block(index)?
+            } else {
+                vec![]
+            };
+            self.cache = Some(CachedBlock {
+                index,
+                data,
+                dirty: false,
+            });
+        }
+        Ok(self.cache.as_mut().unwrap())
+    }
+
+    /// Read from `offset` until the end of `buf` or of the content.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() {
+            let pos = offset + read as u64;
+            let in_block = (pos % BLOCK_SIZE as u64) as usize;
+            let block = self.load(pos / BLOCK_SIZE as u64, true)?;
+            if in_block >= block.data.len() {
+                break;
+            }
+            let len = (block.data.len() - in_block).min(buf.len() - read);
+            buf[read..read + len].copy_from_slice(&block.data[in_block..in_block + len]);
+            read += len;
+        }
+        Ok(read)
+    }
+
+    /// Write all of `buf` at `offset`, if it's after the end we fill with zeros until it.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        if offset > self.len()? {
+            self.set_len(offset)?;
+        }
+        let mut written = 0;
+        while written < buf.len() {
+            let pos = offset + written as u64;
+            let in_block = (pos % BLOCK_SIZE as u64) as usize;
+            let len = (BLOCK_SIZE - in_block).min(buf.len() - written);
+            // we don't need the old data if we replace the whole block
+            let block = self.load(pos / BLOCK_SIZE as u64, len < BLOCK_SIZE)?;
+            if block.data.len() < in_block + len {
+                block.data.resize(in_block + len, 0);
+            }
+            block.data[in_block..in_block + len].copy_from_slice(&buf[written..written + len]);
+            block.dirty = true;
+            written += len;
+        }
+        Ok(())
+    }
+
+    /// Truncate or extend with zeros, only the new last block is re-encrypted when truncating.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn set_len(&mut self, size: u64) -> io::Result<()> {
+        self.flush()?;
+        self.cache = None;
+        let len = self.stored_len()?;
+        match size.cmp(&len) {
+            Ordering::Less => {
+                if let Some(holes) = self.holes.as_mut() {
+                    // before the blocks are removed, so an extension cannot have them as holes
+                    holes.truncate(size.div_ceil(BLOCK_SIZE as u64));
+                    self.write_holes()?;
+                }
+                let index = size / BLOCK_SIZE as u64;
+                let rem = (size % BLOCK_SIZE as u64) as usize;
+                let file_len = if rem == 0 {
+                    self.block_offset(index)
+                } else {
+                    // a hole stays a hole, it's shorter after we truncate the file
+                    if !self.is_hole(index)? {
+                        let mut data = self.read_block(index)?;
+                        data.truncate(rem);
+                        self.write_block(index, &data)?;
+                    }
+                    self.block_offset(index) + (NONCE_LEN + rem + self.tag_len) as u64
+                };
+                self.file.flush()?;
+                self.file.set_len(file_len)?;
+            }
+            Ordering::Greater => {
+                let index = len / BLOCK_SIZE as u64;
+                let rem = (len % BLOCK_SIZE as u64) as usize;
+                if rem > 0 && !self.is_hole(index)? {
+                    // the last block becomes full or ends at the new size, the ones after are holes
+                    let mut data = self.read_block(index)?;
+                    let end = ((index + 1) * BLOCK_SIZE as u64).min(size);
+                    data.resize((end - index * BLOCK_SIZE as u64) as usize, 0);
+                    self.write_block(index, &data)?;
+                }
+                self.write_header()?;
+                // the new blocks are holes, recorded before they exist
+                self.add_holes(
+                    len.div_ceil(BLOCK_SIZE as u64),
+                    size.div_ceil(BLOCK_SIZE as u64),
+                    size,
+                )?;
+                let rem = (size % BLOCK_SIZE as u64) as usize;
+                let file_len = self.block_offset(size / BLOCK_SIZE as u64)
+                    + if rem == 0 {
+                        0
+                    } else {
+                        (NONCE_LEN + rem + self.tag_len) as u64
+                    };
+                self.file.flush()?;
+                if file_len > self.file.seek(SeekFrom::End(0))? {
+                    self.file.set_len(file_len)?;
+                }
+            }
+            Ordering::Equal => {}
+        }
+        Ok(())
+    }
+
+    /// Make the range a hole, like `fallocate(2)` with `FALLOC_FL_PUNCH_HOLE`, the length doesn't
+    /// change.
+    ///
+    /// The blocks it covers only partially are zeroed in place.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.flush()?;
+        self.cache = None;
+        let size = self.stored_len()?;
+        let end = offset.saturating_add(len).min(size);
+        if offset >= end {
+            return Ok(());
+        }
+        // consecutive blocks which become holes, we zero them at once
+        let mut holes: Option<(u64, u64)> = None;
+        for index in offset / BLOCK_SIZE as u64..=(end - 1) / BLOCK_SIZE as u64 {
+            let block_start = index * BLOCK_SIZE as u64;
+            let block_end = (block_start + BLOCK_SIZE as u64).min(size);
+            let start = offset.max(block_start);
+            let stop = end.min(block_end);
+            if start == block_start && stop == block_end {
+                holes = Some((holes.map_or(index, |(first, _)| first), index + 1));
+            } else if !self.is_hole(index)? {
+                let mut data = self.read_block(index)?;
+                data[(start - block_start) as usize..(stop - block_start) as usize].fill(0);
+                self.write_block(index, &data)?;
+            }
+        }
+        if let Some((first, last)) = holes {
+            // recorded before they are zeroed, if they don't fit they are written as zeros
+            if self
+                .add_holes(first, last, size)?
+                .is_some_and(|(start, end)| start <= first && last <= end)
+            {
+                return Ok(());
+            }
+            let start = self.block_offset(first);
+            // the last block might be shorter
+            let end = self
+                .block_offset(last)
+                .min(self.file.seek(SeekFrom::End(0))?);
+            self.file.zero_range(start, end - start)?;
+        }
+        Ok(())
+    }
+
+    /// Reserve space for the blocks in the range, like `fallocate(2)`, the length doesn't change.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn allocate(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let start = self.block_offset(offset / BLOCK_SIZE as u64);
+        let end = self.block_offset(offset.saturating_add(len).div_ceil(BLOCK_SIZE as u64));
+        self.file.allocate(start, end - start)
+    }
+
+    /// Write the cached block if it was changed.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(mut block) = self.cache.take() {
+            if block.dirty {
+                self.write_block(block.index, &block.data)?;
+                block.dirty = false;
+            }
+            self.cache = Some(block);
+        }
+        self.file.flush()
+    }
+
+    #[must_use]
+    pub const fn get_ref(&self) -> &F {
+        &self.file
+    }
+
+    /// Flush and return the file.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn finish(mut self) -> io::Result<F> {
+        self.flush()?;
+        Ok(self.file)
+    }
+}
+
+/// Storage for a [`BlockStore`], besides [`Read`], [`Write`] and [`Seek`] it needs to be truncated.
+pub trait BlockStorage: Read + Write + Seek {
+    /// When extending it, the new part should be a hole if the storage supports that.
+    #[allow(clippy::missing_errors_doc)]
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Start of the next data from `pos`, `None` if there is only a hole until the end.
+    ///
+    /// Implement it if the storage knows where the holes are, so we don't check each block.
+    #[allow(clippy::missing_errors_doc)]
+    fn next_data(&mut self, pos: u64) -> io::Result<Option<u64>> {
+        Ok(Some(pos))
+    }
+
+    /// Reserve space for the range without changing the length, what was not written reads as
+    /// zeros.
+    ///
+    /// It does nothing by default, for storage which cannot do that.
+    #[allow(clippy::missing_errors_doc)]
+    fn allocate(&mut self, _offset: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Make the range zeros, the storage can deallocate it.
+    #[allow(clippy::missing_errors_doc)]
+    fn zero_range(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        write_zeros(self, offset, len)
+    }
+}
+
+impl BlockStorage for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn next_data(&mut self, pos: u64) -> io::Result<Option<u64>> {
+        fs_util::seek_data(self, pos)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn allocate(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        match fs_util::fallocate(self, libc::FALLOC_FL_KEEP_SIZE, offset, len) {
+            // reserving is only an optimization, the blocks are allocated when written
+            Err(err) if err.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+            res => res,
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn zero_range(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        match fs_util::fallocate(
+            self,
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset,
+            len,
+        ) {
+            Err(err) if err.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                write_zeros(self, offset, len)
+            }
+            res => res,
+        }
+    }
+}
+
+impl BlockStorage for Cursor<Vec<u8>> {
+    #[allow(clippy::cast_possible_truncation)]
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+fn write_zeros<F: BlockStorage + ?Sized>(file: &mut F, offset: u64, len: u64) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    stream_util::fill_zeros(&mut &mut *file, len)
+}
+
+fn is_hole(ciphertext: &[u8]) -> bool {
+    ciphertext.iter().all(|b| *b == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Write};
+
+    use rand::RngCore;
+    use shush_rs::SecretVec;
+
+    use super::*;
+    use crate::crypto::write::CryptoWrite;
+
+    fn key(cipher: Cipher) -> SecretVec<u8> {
+        let mut key = vec![0; cipher.key_len()];
+        rand::thread_rng().fill_bytes(&mut key);
+        SecretVec::from(key)
+    }
+
+    fn decrypt_all(content: &[u8], cipher: Cipher, key: &SecretVec<u8>) -> Vec<u8> {
+        let mut reader = crypto::create_read(content, cipher, key);
+        let mut plaintext = vec![];
+        reader.read_to_end(&mut plaintext).unwrap();
+        plaintext
+    }
+
+    #[test]
+    fn test_write_at() {
+        let cipher = Cipher::ChaCha20Poly1305;
+        let key = key(cipher);
+        let mut store = BlockStore::new(Cursor::new(vec![]), cipher, &key).unwrap();
+        let mut expected = vec![0_u8; 350];
+        rand::thread_rng().fill_bytes(&mut expected);
+        store.write_at(0, &expected).unwrap();
+        assert_eq!(store.len().unwrap(), 350);
+        // over two blocks
+        store.write_at(90, &[1; 20]).unwrap();
+        expected[90..110].copy_from_slice(&[1; 20]);
+        // after the end
+        store.write_at(400, b"end").unwrap();
+        expected.resize(400, 0);
+        expected.extend_from_slice(b"end");
+        assert!(store.is_dirty());
+        assert_eq!(store.len().unwrap(), 403);
+
+        let mut buf = vec![0; 500];
+        let len = store.read_at(0, &mut buf).unwrap();
+        assert_eq!(&buf[..len], &expected[..]);
+        let len = store.read_at(395, &mut buf[..4]).unwrap();
+        assert_eq!(&buf[..len], &expected[395..399]);
+
+        let content = store.finish().unwrap().into_inner();
+        assert_eq!(decrypt_all(&content, cipher, &key), expected);
+    }
+
+    #[test]
+    fn test_write_in_place() {
+        let cipher = Cipher::Aes256Gcm;
+        let key = key(cipher);
+        let mut plaintext = vec![0_u8; 450];
+        rand::thread_rng().fill_bytes(&mut plaintext);
+        let mut writer = crypto::create_write(Cursor::new(vec![]), cipher, &key);
+        writer.write_all(&plaintext).unwrap();
+        let before = writer.finish().unwrap().into_inner();
+
+        let mut store = BlockStore::new(Cursor::new(before.clone()), cipher, &key).unwrap();
+        store.write_at(210, b"middle").unwrap();
+        plaintext[210..216].copy_from_slice(b"middle");
+        let after = store.finish().unwrap().into_inner();
+
+        // only the third block changed
+        let block_len = NONCE_LEN + BLOCK_SIZE + cipher.tag_len();
+        let changed = HEADER_LEN + 2 * block_len..HEADER_LEN + 3 * block_len;
+        assert_eq!(before.len(), after.len());
+        assert_eq!(before[..changed.start], after[..changed.start]);
+        assert_eq!(before[changed.end..], after[changed.end..]);
+        assert_ne!(before[changed.clone()], after[changed]);
+        assert_eq!(decrypt_all(&after, cipher, &key), plaintext);
+    }
+
+    #[test]
+    fn test_set_len() {
+        let cipher = Cipher::ChaCha20Poly1305;
+        let key = key(cipher);
+        let mut store = BlockStore::new(Cursor::new(vec![]), cipher, &key).unwrap();
+        store.write_at(0, &[42; 250]).unwrap();
+
+        store.set_len(120).unwrap();
+        assert_eq!(store.len().unwrap(), 120);
+        store.set_len(300).unwrap();
+        assert_eq!(store.len().unwrap(), 300);
+        let mut expected = vec![42; 120];
+        expected.resize(300, 0);
+        let mut buf = vec![0; 300];
+        assert_eq!(store.read_at(0, &mut buf).unwrap(), 300);
+        assert_eq!(buf, expected);
+
+        store.set_len(200).unwrap();
+        let content = store.finish().unwrap().into_inner();
+        assert_eq!(decrypt_all(&content, cipher, &key), &expected[..200]);
+
+        let mut store = BlockStore::new(Cursor::new(content), cipher, &key).unwrap();
+        store.set_len(0).unwrap();
+        assert!(store.is_empty().unwrap());
+        store.write_at(0, b"again").unwrap();
+        let content = store.finish().unwrap().into_inner();
+        assert_eq!(decrypt_all(&content, cipher, &key), b"again");
+    }
+
+    #[test]
+    fn test_holes() {
+        let cipher = Cipher::ChaCha20Poly1305;
+        let key = key(cipher);
+        let mut store = BlockStore::new(Cursor::new(vec![]), cipher, &key).unwrap();
+        store.set_len(1000).unwrap();
+        store.write_at(300, &[1; 100]).unwrap();
+        store.write_at(990, &[2; 10]).unwrap();
+        assert!(store.is_hole(0).unwrap());
+        assert!(!store.is_hole(3).unwrap());
+        assert!(store.is_hole(8).unwrap());
+        // not written yet
+        assert!(!store.is_hole(9).unwrap());
+
+        assert_eq!(store.seek_data(0).unwrap(), Some(300));
+        assert_eq!(store.seek_data(350).unwrap(), Some(350));
+        assert_eq!(store.seek_hole(0).unwrap(), Some(0));
+        assert_eq!(store.seek_hole(300).unwrap(), Some(400));
+        assert_eq!(store.seek_data(400).unwrap(), Some(900));
+        assert_eq!(store.seek_hole(995).unwrap(), Some(1000));
+        assert_eq!(store.seek_data(1000).unwrap(), None);
+        assert_eq!(store.seek_hole(1000).unwrap(), None);
+
+        // extend with a hole and truncate in a hole
+        store.set_len(1550).unwrap();
+        assert!(store.is_hole(15).unwrap());
+        assert_eq!(store.seek_data(1000).unwrap(), None);
+        store.set_len(1420).unwrap();
+        assert!(store.is_hole(14).unwrap());
+
+        let mut expected = vec![0; 1420];
+        expected[300..400].copy_from_slice(&[1; 100]);
+        expected[990..1000].copy_from_slice(&[2; 10]);
+        let mut buf = vec![0; 1500];
+        assert_eq!(store.read_at(0, &mut buf).unwrap(), 1420);
+        assert_eq!(&buf[..1420], &expected[..]);
+        let content = store.finish().unwrap().into_inner();
+        assert_eq!(decrypt_all(&content, cipher, &key), expected);
+    }
+
+    #[test]
+    fn test_punch_hole() {
+        let cipher = Cipher::ChaCha20Poly1305;
+        let key = key(cipher);
+        let mut store = BlockStore::new(Cursor::new(vec![]), cipher, &key).unwrap();
+        store.write_at(0, &[1; 550]).unwrap();
+        // partial blocks at both ends
+        store.punch_hole(150, 300).unwrap();
+        assert_eq!(store.len().unwrap(), 550);
+        assert!(!store.is_hole(1).unwrap());
+        assert!(store.is_hole(2).unwrap());
+        assert!(store.is_hole(3).unwrap());
+        assert!(!store.is_hole(4).unwrap());
+        assert_eq!(store.seek_hole(0).unwrap(), Some(200));
+        assert_eq!(store.seek_data(200).unwrap(), Some(400));
+        // after the end it's clipped
+        store.punch_hole(500, 1000).unwrap();
+        assert_eq!(store.len().unwrap(), 550);
+        store.allocate(0, 1000).unwrap();
+        assert_eq!(store.len().unwrap(), 550);
+
+        let mut expected = vec![1; 550];
+        expected[150..450].fill(0);
+        expected[500..].fill(0);
+        let mut buf = vec![0; 600];
+        assert_eq!(store.read_at(0, &mut buf).unwrap(), 550);
+        assert_eq!(&buf[..550], &expected[..]);
+        let content = store.finish().unwrap().into_inner();
+        assert_eq!(decrypt_all(&content, cipher, &key), expected);
+    }
+
+    #[test]
+    fn test_zeroed_block_is_not_a_hole() {
+        let cipher = Cipher::ChaCha20Poly1305;
+        let key = key(cipher);
+        let mut store = BlockStore::new(Cursor::new(vec![]), cipher, &key).unwrap();
+        store.write_at(0, &[1; 350]).unwrap();
+        store.set_len(1000).unwrap();
+        let mut content = store.finish().unwrap().into_inner();
+        assert!(header::has_holes(&content));
+
+        // the map is kept
+        let mut store = BlockStore::new(Cursor::new(content.clone()), cipher, &key).unwrap();
+        assert!(!store.is_hole(1).unwrap());
+        assert!(store.is_hole(5).unwrap());
+        assert_eq!(store.read_block(5).unwrap(), vec![0; BLOCK_SIZE]);
+
+        let block_len = NONCE_LEN + BLOCK_SIZE + cipher.tag_len();
+        let block = HOLES_HEADER_LEN + block_len..HOLES_HEADER_LEN + 2 * block_len;
+        content[block].fill(0);
+        let mut store = BlockStore::new(Cursor::new(content.clone()), cipher, &key).unwrap();
+        assert!(!store.is_hole(1).unwrap());
+        assert!(store.read_block(1).is_err());
+        assert!(crypto::create_read(&content[..], cipher, &key)
+            .read_to_end(&mut vec![])
+            .is_err());
+        // format version 4 had no map, its holes are accepted only for content without it
+        assert!(
+            BlockStore::new_legacy(Cursor::new(content), cipher, &key, Legacy::ZeroedHoles)
+                .unwrap()
+                .read_block(1)
+                .is_err()
+        );
+
+        // another key cannot open the map
+        let mut store = BlockStore::new(Cursor::new(vec![]), cipher, &key).unwrap();
+        store.set_len(500).unwrap();
+        let content = store.finish().unwrap().into_inner();
+        assert!(BlockStore::new(Cursor::new(content), cipher, &self::key(cipher)).is_err());
+    }
+
+    #[test]
+    fn test_more_holes_than_the_map() {
+        let cipher = Cipher::Aes256Gcm;
+        let key = key(cipher);
+        let blocks = 2 * (header::MAX_HOLES as u64 + 4);
+        let size = blocks * BLOCK_SIZE as u64;
+        let mut store = BlockStore::new(Cursor::new(vec![]), cipher, &key).unwrap();
+        store.set_len(size).unwrap();
+        let mut expected = vec![0; size as usize];
+        // a hole between each two blocks with data, the smallest ranges are written as zeros
+        for index in (1..blocks).step_by(2) {
+            let offset = index * BLOCK_SIZE as u64;
+            store.write_at(offset, &[3; 10]).unwrap();
+            expected[offset as usize..offset as usize + 10].fill(3);
+        }
+        store.flush().unwrap();
+        let holes = (0..blocks)
+            .filter(|index| store.is_hole(*index).unwrap())
+            .count();
+        assert!(0 < holes && holes <= header::MAX_HOLES);
+
+        let mut buf = vec![0; size as usize];
+        assert_eq!(store.read_at(0, &mut buf).unwrap(), size as usize);
+        assert_eq!(buf, expected);
+        let content = store.finish().unwrap().into_inner();
+        assert_eq!(decrypt_all(&content, cipher, &key), expected);
+
+        // the same when copied, with the blocks we skip as holes
+        let mut src = BlockStore::new(Cursor::new(content), cipher, &key).unwrap();
+        let mut dst = BlockStore::new(Cursor::new(vec![]), cipher, &key).unwrap();
+        src.copy_to(&mut dst).unwrap();
+        assert_eq!(
+            (0..blocks)
+                .filter(|index| dst.is_hole(*index).unwrap())
+                .count(),
+            holes
+        );
+        let content = dst.finish().unwrap().into_inner();
+        assert_eq!(decrypt_all(&content, cipher, &key), expected);
+    }
+
+    #[test]
+    fn test_legacy_zeroed_blocks() {
+        let cipher = Cipher::ChaCha20Poly1305;
+        let key = key(cipher);
+        // format version 4 wrote the holes as zeros, in content with the header without the map
+        let mut writer = crypto::create_write(Cursor::new(vec![]), cipher, &key);
+        writer.write_all(&[5; 250]).unwrap();
+        let mut content = writer.finish().unwrap().into_inner();
+        let block_len = NONCE_LEN + BLOCK_SIZE + cipher.tag_len();
+        content[HEADER_LEN..HEADER_LEN + block_len].fill(0);
+
+        let mut expected = vec![5; 250];
+        expected[..BLOCK_SIZE].fill(0);
+        assert!(BlockStore::new(Cursor::new(content.clone()), cipher, &key)
+            .unwrap()
+            .read_block(0)
+            .is_err());
+        // not by the versions before it
+        assert!(BlockStore::new_legacy(
+            Cursor::new(content.clone()),
+            cipher,
+            &key,
+            Legacy::WithoutHeader
+        )
+        .unwrap()
+        .read_block(0)
+        .is_err());
+        assert!(crypto::create_ring_read(&content[..], cipher, &key)
+            .allow_legacy(Legacy::WithoutHeader)
+            .read_to_end(&mut vec![])
+            .is_err());
+        let mut reader =
+            crypto::create_ring_read(&content[..], cipher, &key).allow_legacy(Legacy::ZeroedHoles);
+        let mut plaintext = vec![];
+        reader.read_to_end(&mut plaintext).unwrap();
+        assert_eq!(plaintext, expected);
+
+        let mut src =
+            BlockStore::new_legacy(Cursor::new(content), cipher, &key, Legacy::ZeroedHoles)
+                .unwrap();
+        assert!(src.is_hole(0).unwrap());
+        let block_len = block_len as u64;
+        assert_eq!(
+            src.legacy_hole_ranges().unwrap(),
+            vec![(HEADER_LEN as u64, block_len)]
+        );
+        let mut dst = BlockStore::new(Cursor::new(vec![]), cipher, &key).unwrap();
+        src.copy_to(&mut dst).unwrap();
+        assert!(dst.is_hole(0).unwrap());
+        let content = dst.finish().unwrap().into_inner();
+        assert!(header::has_holes(&content));
+        assert_eq!(decrypt_all(&content, cipher, &key), expected);
+    }
+
+    #[test]
+    fn test_legacy_without_header() {
+        let cipher = Cipher::ChaCha20Poly1305;
+        let key = key(cipher);
+        // like older versions wrote it
+        let mut writer =
+            crypto::create_ring_write(Cursor::new(vec![]), cipher, &key).without_header();
+        writer.write_all(&[7; 150]).unwrap();
+        let content = writer.finish().unwrap().into_inner();
+
+        assert!(BlockStore::new(Cursor::new(content.clone()), cipher, &key).is_err());
+        // zeroed blocks are not holes there
+        let mut zeroed = content.clone();
+        zeroed[..NONCE_LEN + BLOCK_SIZE + cipher.tag_len()].fill(0);
+        assert!(BlockStore::new_legacy(
+            Cursor::new(zeroed.clone()),
+            cipher,
+            &key,
+            Legacy::WithoutHeader
+        )
+        .unwrap()
+        .read_block(0)
+        .is_err());
+        assert!(crypto::create_ring_read(&zeroed[..], cipher, &key)
+            .allow_legacy(Legacy::WithoutHeader)
+            .read_to_end(&mut vec![])
+            .is_err());
+        let mut store =
+            BlockStore::new_legacy(Cursor::new(content), cipher, &key, Legacy::WithoutHeader)
+                .unwrap();
+        store.write_at(140, &[8; 20]).unwrap();
+        let content = store.finish().unwrap().into_inner();
+        assert!(header::decode(&content).is_none());
+        let mut reader = crypto::create_ring_read(&content[..], cipher, &key)
+            .allow_legacy(Legacy::WithoutHeader);
+        let mut plaintext = vec![];
+        reader.read_to_end(&mut plaintext).unwrap();
+        assert_eq!(&plaintext[..140], &[7; 140]);
+        assert_eq!(&plaintext[140..], &[8; 20]);
+    }
+}
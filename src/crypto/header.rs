@@ -0,0 +1,208 @@
+//! Header written at the start of encrypted files.
+//!
+//! It holds a random id of the file which is mixed in the AAD of each block, so a block
+//! cannot be moved from one file to another. Files written by older versions don't have it,
+//! in that case the AAD is only the block index. Readers accept them only if asked to, for data
+//! dirs which were not upgraded yet.
+//!
+//! Content written in place by the [`BlockStore`](crate::crypto::block_store::BlockStore) has the
+//! map of its holes after the id, encrypted with the same key, so a block cannot be zeroed to
+//! read as a hole.
+
+use std::io;
+
+use rand_chacha::rand_core::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, NONCE_LEN};
+
+use crate::crypto;
+
+/// Last byte is the version of the format.
+pub(crate) const MAGIC: &[u8; 8] = b"rencfs\x00\x01";
+/// Like [`MAGIC`], the header is followed by the map of the holes.
+pub(crate) const MAGIC_HOLES: &[u8; 8] = b"rencfs\x00\x02";
+pub(crate) const FILE_ID_LEN: usize = 16;
+/// In bytes.
+pub const HEADER_LEN: usize = MAGIC.len() + FILE_ID_LEN;
+/// Most ranges the map of the holes can have, when there are more we write the smallest one as
+/// encrypted zeros.
+pub(crate) const MAX_HOLES: usize = 32;
+// the number of ranges and the ranges, it has a fixed size so the blocks don't move
+const HOLES_LEN: usize = 8 + MAX_HOLES * 16;
+// both ciphers have the same tag length
+const TAG_LEN: usize = 16;
+/// In bytes, with the map of the holes.
+pub const HOLES_HEADER_LEN: usize = HEADER_LEN + NONCE_LEN + HOLES_LEN + TAG_LEN;
+
+pub(crate) type FileId = [u8; FILE_ID_LEN];
+
+pub(crate) fn new_file_id() -> FileId {
+    let mut file_id = [0; FILE_ID_LEN];
+    crypto::create_rng().fill_bytes(&mut file_id);
+    file_id
+}
+
+pub(crate) fn encode(file_id: &FileId) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[..MAGIC.len()].copy_from_slice(MAGIC);
+    header[MAGIC.len()..].copy_from_slice(file_id);
+    header
+}
+
+/// Returns the file id if `buf` starts with a header.
+pub(crate) fn decode(buf: &[u8]) -> Option<FileId> {
+    if buf.len() < HEADER_LEN || (&buf[..MAGIC.len()] != MAGIC && !has_holes(buf)) {
+        return None;
+    }
+    let mut file_id = [0; FILE_ID_LEN];
+    file_id.copy_from_slice(&buf[MAGIC.len()..HEADER_LEN]);
+    Some(file_id)
+}
+
+/// If the header is followed by the map of the holes, it's [`HOLES_HEADER_LEN`] long then.
+pub(crate) fn has_holes(buf: &[u8]) -> bool {
+    buf.len() >= MAGIC_HOLES.len() && &buf[..MAGIC_HOLES.len()] == MAGIC_HOLES
+}
+
+/// AAD for a block, files without header use only the block index.
+pub(crate) fn block_aad(file_id: Option<&FileId>, block_index: u64) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(FILE_ID_LEN + 8);
+    if let Some(file_id) = file_id {
+        aad.extend_from_slice(file_id);
+    }
+    aad.extend_from_slice(&block_index.to_le_bytes());
+    aad
+}
+
+fn holes_aad(file_id: &FileId) -> Vec<u8> {
+    let mut aad = b"holes".to_vec();
+    aad.extend_from_slice(file_id);
+    aad
+}
+
+/// Blocks which were never written, as sorted ranges of block indexes, the end is excluded.
+///
+/// Only the blocks in the map can read as zeros without being decrypted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Holes(Vec<(u64, u64)>);
+
+impl Holes {
+    pub(crate) fn contains(&self, index: u64) -> bool {
+        self.0
+            .iter()
+            .any(|(start, end)| *start <= index && index < *end)
+    }
+
+    /// Add the range, merged with the ones it touches.
+    ///
+    /// If there are too many ranges the smallest one is removed and returned, it might be the one
+    /// we add, its blocks need to be written as encrypted zeros.
+    pub(crate) fn insert(&mut self, start: u64, end: u64) -> Option<(u64, u64)> {
+        if start >= end {
+            return None;
+        }
+        let (mut start, mut end) = (start, end);
+        self.0.retain(|(s, e)| {
+            if *s <= end && start <= *e {
+                start = start.min(*s);
+                end = end.max(*e);
+                false
+            } else {
+                true
+            }
+        });
+        let pos = self.0.partition_point(|(s, _)| *s < start);
+        self.0.insert(pos, (start, end));
+        self.evict()
+    }
+
+    /// Remove the block, if it splits a range and there are too many ranges then, the smaller part
+    /// is removed and returned, its blocks need to be written as encrypted zeros.
+    pub(crate) fn remove(&mut self, index: u64) -> Option<(u64, u64)> {
+        let pos = self
+            .0
+            .iter()
+            .position(|(start, end)| *start <= index && index < *end)?;
+        let (start, end) = self.0.remove(pos);
+        let mut pos = pos;
+        for range in [(start, index), (index + 1, end)] {
+            if range.0 < range.1 {
+                self.0.insert(pos, range);
+                pos += 1;
+            }
+        }
+        self.evict()
+    }
+
+    /// Remove the blocks from `blocks` on.
+    pub(crate) fn truncate(&mut self, blocks: u64) {
+        self.0.retain(|(start, _)| *start < blocks);
+        if let Some((_, end)) = self.0.last_mut() {
+            *end = (*end).min(blocks);
+        }
+    }
+
+    fn evict(&mut self) -> Option<(u64, u64)> {
+        if self.0.len() <= MAX_HOLES {
+            return None;
+        }
+        let pos = (0..self.0.len()).min_by_key(|i| self.0[*i].1 - self.0[*i].0)?;
+        Some(self.0.remove(pos))
+    }
+
+    /// The header with the map encrypted, it's [`HOLES_HEADER_LEN`] long.
+    pub(crate) fn encode_header(
+        &self,
+        file_id: &FileId,
+        key: &LessSafeKey,
+        rng: &mut dyn RngCore,
+    ) -> io::Result<Vec<u8>> {
+        let mut header = Vec::with_capacity(HOLES_HEADER_LEN);
+        header.extend_from_slice(MAGIC_HOLES);
+        header.extend_from_slice(file_id);
+        let mut nonce = [0; NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+        header.extend_from_slice(&nonce);
+        let mut holes = Vec::with_capacity(HOLES_LEN);
+        holes.extend_from_slice(&(self.0.len() as u64).to_le_bytes());
+        for (start, end) in &self.0 {
+            holes.extend_from_slice(&start.to_le_bytes());
+            holes.extend_from_slice(&end.to_le_bytes());
+        }
+        holes.resize(HOLES_LEN, 0);
+        let tag = key
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(holes_aad(file_id)),
+                &mut holes,
+            )
+            .map_err(|err| io::Error::other(format!("error sealing holes: {err}")))?;
+        header.extend_from_slice(&holes);
+        header.extend_from_slice(tag.as_ref());
+        Ok(header)
+    }
+
+    /// Decrypt the map from a header of [`HOLES_HEADER_LEN`].
+    pub(crate) fn decode(header: &[u8], file_id: &FileId, key: &LessSafeKey) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+        if header.len() < HOLES_HEADER_LEN || key.algorithm().tag_len() != TAG_LEN {
+            return Err(invalid("invalid header"));
+        }
+        let nonce = Nonce::try_assume_unique_for_key(&header[HEADER_LEN..HEADER_LEN + NONCE_LEN])
+            .map_err(|_| invalid("invalid nonce"))?;
+        let mut holes = header[HEADER_LEN + NONCE_LEN..HOLES_HEADER_LEN].to_vec();
+        let holes = key
+            .open_in_place(nonce, Aad::from(holes_aad(file_id)), &mut holes)
+            .map_err(|_| invalid("cannot decrypt the holes"))?;
+        let u64_at = |pos: usize| u64::from_le_bytes(holes[pos..pos + 8].try_into().unwrap());
+        #[allow(clippy::cast_possible_truncation)]
+        let len = u64_at(0) as usize;
+        if len > MAX_HOLES {
+            return Err(invalid("invalid holes"));
+        }
+        Ok(Self(
+            (0..len)
+                .map(|i| (u64_at(8 + i * 16), u64_at(16 + i * 16)))
+                .collect(),
+        ))
+    }
+}
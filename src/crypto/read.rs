@@ -1,16 +1,18 @@
 use std::io;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Cursor, Read, Seek, SeekFrom};
 use std::sync::{Arc, Mutex};
 
 use ring::aead::{
-    Aad, Algorithm, BoundKey, Nonce, NonceSequence, OpeningKey, UnboundKey, NONCE_LEN,
+    Aad, Algorithm, BoundKey, LessSafeKey, Nonce, NonceSequence, OpeningKey, UnboundKey, NONCE_LEN,
 };
 use ring::error;
 use shush_rs::{ExposeSecret, SecretVec};
 use tracing::{error, instrument, warn};
 
 use crate::crypto::buf_mut::BufMut;
+use crate::crypto::header::{self, FileId, Holes, HEADER_LEN, HOLES_HEADER_LEN};
 use crate::crypto::write::BLOCK_SIZE;
+use crate::crypto::Legacy;
 use crate::stream_util;
 
 mod test;
@@ -25,7 +27,7 @@ pub trait CryptoRead<R: Read + Send + Sync>: Read + Send + Sync {
 /// ring
 #[macro_export]
 macro_rules! decrypt_block {
-    ($block_index:expr, $buf:expr, $input:expr, $last_nonce:expr, $opening_key:expr) => {{
+    ($block_index:expr, $buf:expr, $input:expr, $last_nonce:expr, $opening_key:expr, $file_id:expr, $in_holes:expr) => {{
         let len = {
             $buf.clear();
             let buffer = $buf.as_mut_remaining();
@@ -44,9 +46,16 @@ macro_rules! decrypt_block {
                 }
                 pos
             };
-            if len != 0 {
+            if len != 0 && $in_holes && buffer[..len].iter().all(|b| *b == 0) {
+                // a hole, the block was never written and it reads as zeros, if it's zeroed but
+                // not in the map it fails to decrypt
+                len = len.saturating_sub(NONCE_LEN + $opening_key.algorithm().tag_len());
+            } else if len != 0 {
                 let data = &mut buffer[..len];
-                let aad = Aad::from(($block_index).to_le_bytes());
+                let aad = Aad::from($crate::crypto::header::block_aad(
+                    $file_id.as_ref(),
+                    $block_index,
+                ));
                 // extract nonce
                 $last_nonce
                     .lock()
@@ -55,7 +64,7 @@ macro_rules! decrypt_block {
                 let data = &mut data[NONCE_LEN..];
                 let plaintext = $opening_key.open_within(aad, data, 0..).map_err(|err| {
                     error!("error opening within: {}", err);
-                    io::Error::other("error opening within")
+                    io::Error::new(io::ErrorKind::InvalidData, "error opening within")
                 })?;
                 len = plaintext.len();
             }
@@ -82,6 +91,16 @@ pub struct RingCryptoRead<R: Read> {
     ciphertext_block_size: usize,
     plaintext_block_size: usize,
     block_index: u64,
+    detect_header: bool,
+    legacy: Legacy,
+    header_read: bool,
+    header_len: u64,
+    file_id: Option<FileId>,
+    // to decrypt the map of the holes in the header
+    holes_key: LessSafeKey,
+    holes: Option<Holes>,
+    // bytes read while looking for the header, in case the file doesn't have one
+    prefix: Cursor<Vec<u8>>,
 }
 
 impl<R: Read> RingCryptoRead<R> {
@@ -93,6 +112,7 @@ impl<R: Read> RingCryptoRead<R> {
         let unbound_key = UnboundKey::new(algorithm, &key.expose_secret()).unwrap();
         let nonce_sequence = ExistingNonceSequence::new(last_nonce.clone());
         let opening_key = OpeningKey::new(unbound_key, nonce_sequence);
+        let holes_key = LessSafeKey::new(UnboundKey::new(algorithm, &key.expose_secret()).unwrap());
         Self {
             input: Some(reader),
             opening_key,
@@ -101,8 +121,77 @@ impl<R: Read> RingCryptoRead<R> {
             ciphertext_block_size,
             plaintext_block_size: BLOCK_SIZE,
             block_index: 0,
+            detect_header: true,
+            legacy: Legacy::None,
+            header_read: false,
+            header_len: 0,
+            file_id: None,
+            holes_key,
+            holes: None,
+            prefix: Cursor::new(vec![]),
         }
     }
+
+    /// Content was written without a header, see [`RingCryptoWrite::without_header`](crate::crypto::write::RingCryptoWrite::without_header).
+    #[must_use]
+    pub const fn without_header(mut self) -> Self {
+        self.detect_header = false;
+        self
+    }
+
+    /// Accept what older versions wrote as `legacy`, it's detected when reading.
+    #[must_use]
+    pub const fn allow_legacy(mut self, legacy: Legacy) -> Self {
+        self.legacy = legacy;
+        self
+    }
+
+    /// If the block can read as zeros, content without the map of the holes can have them only if we
+    /// allow the zeroed holes of format version 4.
+    fn in_holes(&self, index: u64) -> bool {
+        self.holes
+            .as_ref()
+            .map_or(matches!(self.legacy, Legacy::ZeroedHoles), |holes| {
+                holes.contains(index)
+            })
+    }
+
+    /// Reads the header, content without it is accepted only if we allow it as legacy content.
+    fn read_header(&mut self) -> io::Result<()> {
+        if self.header_read {
+            return Ok(());
+        }
+        self.header_read = true;
+        if !self.detect_header {
+            return Ok(());
+        }
+        let mut buf = vec![0; HEADER_LEN];
+        let len = stream_util::read(self.input.as_mut().unwrap(), &mut buf)?;
+        buf.truncate(len);
+        if let Some(file_id) = header::decode(&buf) {
+            if header::has_holes(&buf) {
+                buf.resize(HOLES_HEADER_LEN, 0);
+                self.input
+                    .as_mut()
+                    .unwrap()
+                    .read_exact(&mut buf[HEADER_LEN..])?;
+                self.holes = Some(Holes::decode(&buf, &file_id, &self.holes_key)?);
+                self.header_len = HOLES_HEADER_LEN as u64;
+            } else {
+                self.header_len = HEADER_LEN as u64;
+            }
+            self.file_id = Some(file_id);
+        } else if matches!(self.legacy, Legacy::WithoutHeader) || buf.is_empty() {
+            // written by an older version, what we read is part of the first block
+            self.prefix = Cursor::new(buf);
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "content without header",
+            ));
+        }
+        Ok(())
+    }
 }
 
 impl<R: Read> Read for RingCryptoRead<R> {
@@ -113,13 +202,18 @@ impl<R: Read> Read for RingCryptoRead<R> {
         if len != 0 {
             return Ok(len);
         }
+        self.read_header()?;
         // we read all the data from the buffer, so we need to read a new block and decrypt it
+        let in_holes = self.in_holes(self.block_index);
+        let mut input = Read::chain(&mut self.prefix, self.input.as_mut().unwrap());
         decrypt_block!(
             self.block_index,
             self.buf,
-            self.input.as_mut().unwrap(),
+            input,
             self.last_nonce,
-            self.opening_key
+            self.opening_key,
+            self.file_id,
+            in_holes
         );
         let len = self.buf.read(buf)?;
         Ok(len)
@@ -165,7 +259,13 @@ impl<R: Read + Seek> RingCryptoRead<R> {
     }
 
     fn get_plaintext_len(&mut self) -> io::Result<u64> {
-        let ciphertext_len = self.input.as_mut().unwrap().stream_len()?;
+        self.read_header()?;
+        let ciphertext_len = self
+            .input
+            .as_mut()
+            .unwrap()
+            .stream_len()?
+            .saturating_sub(self.header_len);
         if ciphertext_len == 0 {
             return Ok(0);
         }
@@ -195,12 +295,15 @@ impl<R: Read + Seek> Seek for RingCryptoRead<R> {
         // keep in bounds
         let mut new_pos = new_pos as u64;
         new_pos = new_pos.min(plaintext_len);
-        if self.pos() == new_pos {
+        // reading at the end clears the block, then we don't know where we are in it, so we seek
+        // like changing the block
+        let has_block = self.buf.available() > 0;
+        if has_block && self.pos() == new_pos {
             return Ok(new_pos);
         }
         let block_index = self.pos() / self.plaintext_block_size as u64;
         let new_block_index = new_pos / self.plaintext_block_size as u64;
-        if block_index == new_block_index {
+        if has_block && block_index == new_block_index {
             let at_full_block_end = self.pos() % self.plaintext_block_size as u64 == 0
                 && self.buf.available_read() == 0;
             if self.buf.available() > 0
@@ -220,8 +323,9 @@ impl<R: Read + Seek> Seek for RingCryptoRead<R> {
         } else {
             // change block
             self.input.as_mut().unwrap().seek(SeekFrom::Start(
-                new_block_index * self.ciphertext_block_size as u64,
+                self.header_len + new_block_index * self.ciphertext_block_size as u64,
             ))?;
+            self.prefix = Cursor::new(vec![]);
             self.buf.clear();
             self.block_index = new_block_index;
             if new_pos % self.plaintext_block_size as u64 == 0 {
@@ -229,12 +333,15 @@ impl<R: Read + Seek> Seek for RingCryptoRead<R> {
                 // the block_index but the seek seek_forward from below will not decrypt anything
                 // as the offset in new block is 0. In that case the po()
                 // method is affected as it will use the wrong block_index value
+                let in_holes = self.in_holes(self.block_index);
                 decrypt_block!(
                     self.block_index,
                     self.buf,
                     self.input.as_mut().unwrap(),
                     self.last_nonce,
-                    self.opening_key
+                    self.opening_key,
+                    self.file_id,
+                    in_holes
                 );
             }
             // seek inside new block
@@ -566,6 +566,42 @@ fn test_ring_crypto_read_seek_in_second_block() {
     );
 }
 
+#[test]
+#[traced_test]
+fn test_ring_crypto_read_seek_back_after_eof() {
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    use crate::crypto::read::RingCryptoRead;
+    use crate::crypto::write::{CryptoWrite, RingCryptoWrite, BLOCK_SIZE};
+
+    let data = (0..=255)
+        .cycle()
+        .take(2 * BLOCK_SIZE + 7)
+        .collect::<Vec<u8>>();
+    let algorithm = &AES_256_GCM;
+    let key = SecretVec::new(Box::new(vec![0; algorithm.key_len()]));
+    let mut writer = RingCryptoWrite::new(Cursor::new(vec![]), false, algorithm, &key);
+    writer.write_all(&data).unwrap();
+    let mut cursor = writer.finish().unwrap();
+    cursor.seek(SeekFrom::Start(0)).unwrap();
+    let mut reader = RingCryptoRead::new_seek(cursor, algorithm, &key);
+
+    let mut buf = vec![];
+    reader.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, data);
+    // in the last block
+    let pos = 2 * BLOCK_SIZE as u64 + 2;
+    assert_eq!(reader.seek(SeekFrom::Start(pos)).unwrap(), pos);
+    let mut buf = vec![];
+    reader.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, data[2 * BLOCK_SIZE + 2..]);
+    // in the one before
+    assert_eq!(reader.seek(SeekFrom::End(-10)).unwrap(), pos - 5);
+    let mut buf = [0; 10];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data[data.len() - 10..]);
+}
+
 #[test]
 #[traced_test]
 fn finish_seek() {
@@ -638,3 +674,81 @@ fn reader_with_seeks() {
     reader.seek(SeekFrom::Start(42)).unwrap();
     assert_eq!(reader.stream_position().unwrap(), 42);
 }
+
+#[test]
+#[traced_test]
+fn test_read_block_from_other_file_fails() {
+    use crate::crypto::header::{HEADER_LEN, MAGIC};
+    use crate::crypto::read::{RingCryptoRead, BLOCK_SIZE};
+    use ring::aead::{CHACHA20_POLY1305, NONCE_LEN};
+    use std::io::{Cursor, Read};
+
+    let key = create_secret_key(CHACHA20_POLY1305.key_len());
+    let data = vec![42_u8; BLOCK_SIZE * 2];
+    let encrypted_a = create_encrypted_data(&data, &key);
+    let mut encrypted_b = create_encrypted_data(&data, &key);
+    assert_eq!(&MAGIC[..], &encrypted_a[..MAGIC.len()]);
+    assert_ne!(encrypted_a[..HEADER_LEN], encrypted_b[..HEADER_LEN]);
+
+    // move the second block from A into B at the same index
+    let ciphertext_block_size = NONCE_LEN + BLOCK_SIZE + CHACHA20_POLY1305.tag_len();
+    let block = HEADER_LEN + ciphertext_block_size..HEADER_LEN + ciphertext_block_size * 2;
+    encrypted_b[block.clone()].copy_from_slice(&encrypted_a[block]);
+
+    let mut reader = RingCryptoRead::new(Cursor::new(encrypted_b), &CHACHA20_POLY1305, &key);
+    let mut buf = vec![];
+    assert!(reader.read_to_end(&mut buf).is_err());
+
+    let mut reader = RingCryptoRead::new(Cursor::new(encrypted_a), &CHACHA20_POLY1305, &key);
+    let mut buf = vec![];
+    reader.read_to_end(&mut buf).unwrap();
+    assert_eq!(data, buf);
+}
+
+#[test]
+#[traced_test]
+fn test_read_without_header() {
+    use crate::crypto::header::MAGIC;
+    use crate::crypto::read::{RingCryptoRead, BLOCK_SIZE};
+    use crate::crypto::write::{CryptoWrite, RingCryptoWrite};
+    use crate::crypto::Legacy;
+    use ring::aead::CHACHA20_POLY1305;
+    use std::io::{Cursor, Read, SeekFrom, Write};
+
+    // like the content written by older versions
+    let key = create_secret_key(CHACHA20_POLY1305.key_len());
+    let data: Vec<u8> = (0..BLOCK_SIZE * 3 + 7).map(|i| (i % 256) as u8).collect();
+    let mut writer =
+        RingCryptoWrite::new(Cursor::new(vec![]), false, &CHACHA20_POLY1305, &key).without_header();
+    writer.write_all(&data).unwrap();
+    let encrypted = writer.finish().unwrap().into_inner();
+    assert_ne!(&MAGIC[..], &encrypted[..MAGIC.len()]);
+
+    // not accepted, unless we allow legacy content
+    let mut reader = RingCryptoRead::new(Cursor::new(encrypted.clone()), &CHACHA20_POLY1305, &key);
+    let mut buf = vec![];
+    assert!(reader.read_to_end(&mut buf).is_err());
+
+    // detected when reading
+    let mut reader = RingCryptoRead::new(Cursor::new(encrypted.clone()), &CHACHA20_POLY1305, &key)
+        .allow_legacy(Legacy::WithoutHeader);
+    let mut buf = vec![];
+    reader.read_to_end(&mut buf).unwrap();
+    assert_eq!(data, buf);
+
+    let mut reader =
+        RingCryptoRead::new_seek(Cursor::new(encrypted.clone()), &CHACHA20_POLY1305, &key)
+            .allow_legacy(Legacy::WithoutHeader);
+    assert_eq!(data.len() as u64, reader.seek(SeekFrom::End(0)).unwrap());
+    reader.seek(SeekFrom::Start(BLOCK_SIZE as u64 + 3)).unwrap();
+    let mut buf = vec![0; 10];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&data[BLOCK_SIZE + 3..BLOCK_SIZE + 13], &buf[..]);
+
+    // it's upgraded before we write to it
+    let mut writer = RingCryptoWrite::new(Cursor::new(encrypted), true, &CHACHA20_POLY1305, &key);
+    let res = writer
+        .seek(SeekFrom::Start(BLOCK_SIZE as u64 * 2))
+        .and_then(|_| writer.write_all(b"legacy"));
+    assert!(res.is_err());
+}
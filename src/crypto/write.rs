@@ -13,6 +13,7 @@ use shush_rs::{ExposeSecret, SecretVec};
 use tracing::error;
 
 use crate::crypto::buf_mut::BufMut;
+use crate::crypto::header::{self, FileId, HEADER_LEN};
 use crate::crypto::read::ExistingNonceSequence;
 use crate::{crypto, decrypt_block, stream_util};
 
@@ -78,6 +79,20 @@ pub struct RingCryptoWrite<W: CryptoInnerWriter + Send + Sync> {
     opening_key: Option<OpeningKey<ExistingNonceSequence>>,
     last_nonce: Option<Arc<Mutex<Option<Vec<u8>>>>>,
     decrypt_buf: Option<BufMut>,
+    with_header: bool,
+    header_state: HeaderState,
+    header_len: u64,
+    file_id: Option<FileId>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HeaderState {
+    /// We didn't check yet if the content has a header.
+    Unknown,
+    /// New content, the header is written with the first block.
+    Pending,
+    /// The header was written or the content doesn't have one.
+    Done,
 }
 
 impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
@@ -119,12 +134,96 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
             opening_key,
             last_nonce,
             decrypt_buf,
+            with_header: true,
+            header_state: HeaderState::Unknown,
+            header_len: 0,
+            file_id: None,
         }
     }
 
+    /// Don't write the header, the blocks will not be bound to this content.
+    ///
+    /// Useful for short values like file names where the header would add too much overhead.
+    #[must_use]
+    pub const fn without_header(mut self) -> Self {
+        self.with_header = false;
+        self
+    }
+
+    /// Reads the header from existing content or prepares a new one.
+    fn init_header(&mut self) -> io::Result<()> {
+        if self.header_state != HeaderState::Unknown {
+            return Ok(());
+        }
+        if !self.with_header {
+            self.header_state = HeaderState::Done;
+            return Ok(());
+        }
+        if self.seek {
+            // we might write over existing content
+            let writer = self
+                .writer
+                .as_mut()
+                .ok_or(io::Error::new(io::ErrorKind::NotConnected, "no writer"))?
+                .as_write_seek_read()
+                .ok_or(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "downcast failed",
+                ))?;
+            if writer.stream_len()? > 0 {
+                let pos = writer.stream_position()?;
+                writer.seek(SeekFrom::Start(0))?;
+                let mut buf = vec![0; HEADER_LEN];
+                let len = stream_util::read(&mut *writer, &mut buf)?;
+                writer.seek(SeekFrom::Start(pos))?;
+                // content written by older versions without the header is upgraded before we
+                // write to it
+                self.file_id = Some(header::decode(&buf[..len]).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "content without header")
+                })?);
+                if header::has_holes(&buf) {
+                    // we would need to update the map when writing over a hole
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "content with holes is changed with the block store",
+                    ));
+                }
+                self.header_len = HEADER_LEN as u64;
+                self.header_state = HeaderState::Done;
+                return Ok(());
+            }
+        }
+        self.file_id = Some(header::new_file_id());
+        self.header_len = HEADER_LEN as u64;
+        self.header_state = HeaderState::Pending;
+        Ok(())
+    }
+
+    /// Offset of the block in the underlying writer.
+    const fn block_offset(&self, block_index: u64) -> u64 {
+        self.header_len + block_index * self.ciphertext_block_size as u64
+    }
+
     fn encrypt_and_write(&mut self) -> io::Result<()> {
+        self.init_header()?;
+        if self.header_state == HeaderState::Pending {
+            let header = header::encode(self.file_id.as_ref().unwrap());
+            let writer = self
+                .writer
+                .as_mut()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "no writer"))?;
+            if let Some(writer) = writer.as_write_seek_read() {
+                let pos = writer.stream_position()?;
+                writer.seek(SeekFrom::Start(0))?;
+                writer.write_all(&header)?;
+                writer.seek(SeekFrom::Start(pos.max(HEADER_LEN as u64)))?;
+            } else {
+                writer.write_all(&header)?;
+            }
+            self.header_state = HeaderState::Done;
+        }
         let data = self.buf.as_mut();
-        let aad = Aad::from(self.block_index.to_le_bytes());
+        let aad = Aad::from(header::block_aad(self.file_id.as_ref(), self.block_index));
 
         let tag = self
             .sealing_key
@@ -166,7 +265,9 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
             self.decrypt_buf.as_mut().unwrap(),
             writer,
             self.last_nonce.as_ref().unwrap(),
-            self.opening_key.as_mut().unwrap()
+            self.opening_key.as_mut().unwrap(),
+            self.file_id,
+            false
         );
         if old_block_index == self.block_index {
             // no decryption happened
@@ -177,6 +278,7 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
             // bring back block index to current block, it's incremented by decrypt_block if it can decrypt something
             self.block_index -= 1;
             // bring back file pos also so the next writing will write to the same block
+            let offset = self.block_offset(self.block_index);
             let writer = self
                 .writer
                 .as_mut()
@@ -186,9 +288,7 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
                     io::ErrorKind::NotConnected,
                     "downcast failed",
                 ))?;
-            writer.seek(SeekFrom::Start(
-                self.block_index * self.ciphertext_block_size as u64,
-            ))?;
+            writer.seek(SeekFrom::Start(offset))?;
             // copy plaintext
             self.buf.seek_available(SeekFrom::Start(
                 self.decrypt_buf.as_ref().unwrap().available_read() as u64,
@@ -210,9 +310,11 @@ impl<W: CryptoInnerWriter + Send + Sync> Write for RingCryptoWrite<W> {
         if self.writer.is_none() {
             return Err(io::Error::other("write called on already finished writer"));
         }
+        self.init_header()?;
         if self.pos() == 0 && self.buf.available() == 0 {
             if self.seek {
                 // first write since we opened the writer, try to load the first block
+                let offset = self.block_offset(0);
                 let writer = self
                     .writer
                     .as_mut()
@@ -222,26 +324,23 @@ impl<W: CryptoInnerWriter + Send + Sync> Write for RingCryptoWrite<W> {
                         io::ErrorKind::NotConnected,
                         "downcast failed",
                     ))?;
-                writer.seek(SeekFrom::Start(0))?;
+                writer.seek(SeekFrom::Start(offset))?;
                 self.block_index = 0;
                 self.decrypt_block()?;
             }
         } else if self.buf.is_dirty() && self.buf.remaining() == 0 {
             self.flush()?;
-            // try to decrypt the next block if we have any
-            let block_index = self.pos() / self.plaintext_block_size as u64;
+            // try to decrypt the next block if we have any, we can't read from a write only writer
+            let block_offset = self.block_offset(self.pos() / self.plaintext_block_size as u64);
             let writer = self
                 .writer
                 .as_mut()
-                .ok_or(io::Error::new(io::ErrorKind::NotConnected, "no writer"))?
-                .as_write_seek_read()
-                .ok_or(io::Error::new(
-                    io::ErrorKind::NotConnected,
-                    "downcast failed",
-                ))?;
-            let stream_len = writer.stream_len()?;
-            if stream_len > block_index * self.ciphertext_block_size as u64 {
-                self.decrypt_block()?;
+                .ok_or(io::Error::new(io::ErrorKind::NotConnected, "no writer"))?;
+            if let Some(writer) = writer.as_write_seek_read() {
+                let stream_len = writer.stream_len()?;
+                if stream_len > block_offset {
+                    self.decrypt_block()?;
+                }
             }
         }
         if self.buf.is_dirty() && self.buf.remaining() == 0 {
@@ -267,6 +366,7 @@ impl<W: CryptoInnerWriter + Send + Sync> Write for RingCryptoWrite<W> {
 impl<W: CryptoInnerWriter + Send + Sync> CryptoWrite<W> for RingCryptoWrite<W> {
     fn finish(&mut self) -> io::Result<W> {
         if self.buf.is_dirty() {
+            self.init_header()?;
             // encrypt and write last block, use as many bytes as we have
             self.encrypt_and_write()?;
         }
@@ -321,6 +421,8 @@ impl NonceSequence for RandomNonceSequenceWrapper {
 
 impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
     fn get_plaintext_len(&mut self) -> io::Result<u64> {
+        self.init_header()?;
+        let header_len = self.header_len;
         let writer = self
             .writer
             .as_mut()
@@ -330,7 +432,7 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
                 io::ErrorKind::NotConnected,
                 "downcast failed",
             ))?;
-        let ciphertext_len = writer.stream_len()?;
+        let ciphertext_len = writer.stream_len()?.saturating_sub(header_len);
         if ciphertext_len == 0 && self.buf.available() == 0 {
             return Ok(0);
         }
@@ -352,6 +454,7 @@ impl<W: CryptoInnerWriter + Send + Sync> Seek for RingCryptoWrite<W> {
     #[allow(clippy::cast_possible_wrap)]
     #[allow(clippy::cast_sign_loss)]
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.init_header()?;
         let new_pos = match pos {
             SeekFrom::Start(pos) => pos as i64,
             SeekFrom::End(pos) => self.get_plaintext_len()? as i64 + pos,
@@ -369,6 +472,7 @@ impl<W: CryptoInnerWriter + Send + Sync> Seek for RingCryptoWrite<W> {
         if current_block_index == new_block_index {
             if self.pos() == 0 && self.buf.available() == 0 {
                 // first write since we opened the writer, try to load the first block
+                let offset = self.block_offset(0);
                 let writer = self
                     .writer
                     .as_mut()
@@ -378,7 +482,7 @@ impl<W: CryptoInnerWriter + Send + Sync> Seek for RingCryptoWrite<W> {
                         io::ErrorKind::NotConnected,
                         "downcast failed",
                     ))?;
-                writer.seek(SeekFrom::Start(0))?;
+                writer.seek(SeekFrom::Start(offset))?;
                 self.block_index = 0;
                 self.decrypt_block()?;
             }
@@ -410,6 +514,7 @@ impl<W: CryptoInnerWriter + Send + Sync> Seek for RingCryptoWrite<W> {
                 self.encrypt_and_write()?;
             }
             // seek to new block, or until the last block in stream
+            let header_len = self.header_len;
             let writer = self
                 .writer
                 .as_mut()
@@ -419,10 +524,11 @@ impl<W: CryptoInnerWriter + Send + Sync> Seek for RingCryptoWrite<W> {
                     io::ErrorKind::NotConnected,
                     "downcast failed",
                 ))?;
-            let last_block_index = writer.stream_len()? / self.ciphertext_block_size as u64;
+            let last_block_index =
+                writer.stream_len()?.saturating_sub(header_len) / self.ciphertext_block_size as u64;
             let target_block_index = new_block_index.min(last_block_index);
             writer.seek(SeekFrom::Start(
-                target_block_index * self.ciphertext_block_size as u64,
+                header_len + target_block_index * self.ciphertext_block_size as u64,
             ))?;
             // try to decrypt target block
             self.block_index = target_block_index;
@@ -7,6 +7,7 @@ use std::sync::{Arc, Mutex};
 use tracing_test::traced_test;
 
 use crate::crypto;
+use crate::crypto::header::{self, HEADER_LEN};
 use crate::crypto::read::{CryptoRead, ExistingNonceSequence};
 use crate::crypto::Cipher;
 
@@ -29,6 +30,10 @@ fn verify_encryption(
     if encrypted.len() < plaintext.len() {
         return false;
     }
+    let Some(file_id) = header::decode(encrypted) else {
+        return false;
+    };
+    let encrypted = &encrypted[HEADER_LEN..];
     let nonce = &encrypted[..NONCE_LEN];
 
     let key_bytes = &key.expose_secret();
@@ -39,7 +44,7 @@ fn verify_encryption(
     let mut decrypted = encrypted[NONCE_LEN..].to_vec();
 
     let block_index: u64 = 0;
-    let aad = Aad::from(block_index.to_le_bytes());
+    let aad = Aad::from(header::block_aad(Some(&file_id), block_index));
     matches!(opening_key.open_in_place(aad, &mut decrypted), Ok(decrypted_data) if decrypted_data == plaintext)
 }
 
@@ -140,6 +145,7 @@ fn test_encrypt_and_write_nonce_uniqueness() {
     crypto_writer.write_all(&[0u8; BLOCK_SIZE]).unwrap();
     let encrypted = crypto_writer.finish().unwrap().into_inner();
 
+    let encrypted = &encrypted[HEADER_LEN..];
     let nonce1 = &encrypted[..NONCE_LEN];
     let nonce2 = &encrypted[BLOCK_SIZE + NONCE_LEN + CHACHA20_POLY1305.tag_len()..][..NONCE_LEN];
     assert_ne!(nonce1, nonce2, "Nonces should be unique for each block");
@@ -1353,6 +1359,59 @@ fn writer_only_write() {
     // _writer.seek(io::SeekFrom::Start(0)).unwrap();
 }
 
+#[test]
+#[traced_test]
+fn writer_only_write_blocks() {
+    use std::any::Any;
+    use std::io::{self, Read, Write};
+
+    use rand::RngCore;
+    use shush_rs::SecretVec;
+
+    use crate::crypto;
+    use crate::crypto::write::{CryptoInnerWriter, CryptoWrite, WriteSeekRead, BLOCK_SIZE};
+    use crate::crypto::Cipher;
+
+    struct WriteOnly(Vec<u8>);
+    impl Write for WriteOnly {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl CryptoInnerWriter for WriteOnly {
+        fn into_any(self) -> Box<dyn Any> {
+            Box::new(self)
+        }
+        fn as_write(&mut self) -> Option<&mut dyn Write> {
+            Some(self)
+        }
+        fn as_write_seek_read(&mut self) -> Option<&mut dyn WriteSeekRead> {
+            None
+        }
+    }
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let mut key: Vec<u8> = vec![0; cipher.key_len()];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = SecretVec::from(key);
+
+    let mut data = vec![0; 3 * BLOCK_SIZE + BLOCK_SIZE / 2];
+    rand::thread_rng().fill_bytes(&mut data);
+    let mut writer = crypto::create_write(WriteOnly(vec![]), cipher, &key);
+    // more than a block at once and then the rest, after full blocks
+    writer.write_all(&data[..BLOCK_SIZE + 1]).unwrap();
+    writer.write_all(&data[BLOCK_SIZE + 1..]).unwrap();
+    let ciphertext = writer.finish().unwrap().0;
+
+    let mut reader = crypto::create_read(&ciphertext[..], cipher, &key);
+    let mut plaintext = vec![];
+    reader.read_to_end(&mut plaintext).unwrap();
+    assert_eq!(plaintext, data);
+}
+
 #[test]
 #[traced_test]
 fn writer_with_seeks() {
@@ -1,52 +1,86 @@
 use argon2::password_hash::rand_core::RngCore;
 use async_trait::async_trait;
-use futures_util::TryStreamExt;
+use futures_util::stream::{self, BoxStream, Stream};
+use futures_util::{future, StreamExt, TryStreamExt};
 use lru::LruCache;
 use num_format::{Locale, ToFormattedString};
+use ring::aead::NONCE_LEN;
 use serde::{Deserialize, Serialize};
 use shush_rs::{ExposeSecret, SecretBox, SecretString, SecretVec};
 use std::backtrace::Backtrace;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
-use std::fs::{DirEntry, File, OpenOptions, ReadDir};
+use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::num::{NonZeroUsize, ParseIntError};
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, LazyLock, Weak};
+use std::task::{Context, Poll};
 use std::time::{Duration, SystemTime};
 use std::{fs, io};
 use thiserror::Error;
 use tokio::runtime::Runtime;
-use tokio::sync::{Mutex, RwLock};
-use tokio::task::{JoinError, JoinSet};
+use tokio::sync::{Mutex, MutexGuard, RwLock, RwLockWriteGuard};
+use tokio::task::JoinError;
 use tokio_stream::wrappers::ReadDirStream;
 use tracing::{debug, error, info, instrument, warn, Level};
 
-use crate::arc_hashmap::ArcHashMap;
+use crate::arc_hashmap::{ArcHashMap, Holder};
+use crate::crypto::block_store::{BlockStorage, BlockStore};
+use crate::crypto::header::{self, HEADER_LEN};
 use crate::crypto::read::{CryptoRead, CryptoReadSeek};
-use crate::crypto::write::{CryptoInnerWriter, CryptoWrite, CryptoWriteSeek};
-use crate::crypto::Cipher;
+use crate::crypto::write::{CryptoInnerWriter, CryptoWrite, CryptoWriteSeek, BLOCK_SIZE};
+use crate::crypto::{Cipher, KdfParams, Legacy};
 use crate::expire_value::{ExpireValue, ValueProvider};
 use crate::{crypto, fs_util, stream_util};
 use bon::bon;
 
+mod archive;
 mod bench;
+mod file;
+mod fsck;
+mod journal;
+mod key_material;
+mod keyslots;
+mod path;
+mod rekey;
 #[cfg(test)]
 mod test;
+mod volume;
+
+pub use file::EncryptedFile;
+pub use fsck::{FsckProblem, FsckReport, LOST_AND_FOUND};
+pub use key_material::{
+    InMemoryPasswordProvider, KeyMaterialProvider, KeyfileProvider, PasswordKeyfileProvider,
+};
+pub use keyslots::{KeySlot, KeySlots};
+pub use path::PathFs;
+pub use rekey::RekeyProgress;
+pub use volume::{VolumeMetadata, CURRENT_FORMAT_VERSION};
+
+use journal::{Journal, JournalOp, WAL_DIR};
+use rekey::RekeyState;
 
 pub(crate) const INODES_DIR: &str = "inodes";
 pub(crate) const CONTENTS_DIR: &str = "contents";
 pub(crate) const SECURITY_DIR: &str = "security";
+pub(crate) const XATTRS_DIR: &str = "xattrs";
 pub(crate) const KEY_ENC_FILENAME: &str = "key.enc";
 pub(crate) const KEY_SALT_FILENAME: &str = "key.salt";
+pub(crate) const LOCK_FILENAME: &str = "lock";
 
 pub(crate) const LS_DIR: &str = "ls";
 pub(crate) const HASH_DIR: &str = "hash";
 
 pub(crate) const ROOT_INODE: u64 = 1;
 
+/// Same limits as Linux `XATTR_NAME_MAX` and `XATTR_SIZE_MAX`.
+pub(crate) const XATTR_NAME_MAX: usize = 255;
+pub(crate) const XATTR_VALUE_MAX: usize = 64 * 1024;
+
 fn spawn_runtime() -> Runtime {
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -58,6 +92,9 @@ fn spawn_runtime() -> Runtime {
 static DIR_ENTRIES_RT: LazyLock<Runtime> = LazyLock::new(spawn_runtime);
 static NOD_RT: LazyLock<Runtime> = LazyLock::new(spawn_runtime);
 
+/// How many directory entries are decrypted in parallel when listing.
+const DIR_ENTRIES_PARALLELISM: usize = 64;
+
 /// File attributes.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct FileAttr {
@@ -106,8 +143,8 @@ pub enum FileType {
     Directory,
     /// Regular file (`S_IFREG`)
     RegularFile,
-    // /// Symbolic link (S_IFLNK)
-    // Symlink,
+    /// Symbolic link (`S_IFLNK`)
+    Symlink,
     // /// Unix domain socket (S_IFSOCK)
     // Socket,
 }
@@ -241,6 +278,39 @@ impl From<CreateFileAttr> for FileAttr {
     }
 }
 
+/// Filesystem statistics, see [`EncryptedFs::statfs`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatFs {
+    /// Total data blocks in the filesystem
+    pub blocks: u64,
+    /// Free blocks in filesystem
+    pub bfree: u64,
+    /// Free blocks available to unprivileged user
+    pub bavail: u64,
+    /// Total file nodes in filesystem
+    pub files: u64,
+    /// Free file nodes in filesystem
+    pub ffree: u64,
+    /// Optimal transfer block size
+    pub bsize: u32,
+    /// Maximum length of filenames
+    pub namelen: u32,
+    /// Fragment size, the unit of `blocks`, `bfree` and `bavail`
+    pub frsize: u32,
+}
+
+/// How [`EncryptedFs::set_xattr`] behaves when the attribute exists or not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SetXattrMode {
+    /// Create the attribute or replace the existing value.
+    #[default]
+    Upsert,
+    /// Fail with [`FsError::AlreadyExists`] if the attribute exists.
+    Create,
+    /// Fail with [`FsError::XattrNotFound`] if the attribute doesn't exist.
+    Replace,
+}
+
 #[derive(Error, Debug)]
 pub enum FsError {
     #[error("IO error: {source}")]
@@ -271,12 +341,20 @@ pub enum FsError {
     AlreadyOpenForWrite,
     #[error("not empty")]
     NotEmpty,
+    #[error("extended attribute not found")]
+    XattrNotFound,
     #[error("other: {0}")]
     Other(&'static str),
     #[error("invalid password")]
     InvalidPassword,
     #[error("invalid structure of data directory")]
     InvalidDataDirStructure,
+    #[error("data directory is already initialized")]
+    DataDirAlreadyInitialized,
+    #[error("data directory is not initialized")]
+    DataDirNotInitialized,
+    #[error("unsupported format version {0} of data directory")]
+    UnsupportedFormatVersion(u32),
     #[error("crypto error: {source}")]
     Crypto {
         #[from]
@@ -460,6 +538,35 @@ impl Iterator for DirectoryEntryPlusIterator {
     }
 }
 
+/// Entries of [`EncryptedFs::read_dir_stream`] with their offsets.
+pub struct DirectoryEntryStream(BoxStream<'static, FsResult<(u64, DirectoryEntry)>>);
+
+impl Stream for DirectoryEntryStream {
+    type Item = FsResult<(u64, DirectoryEntry)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.as_mut().poll_next(cx)
+    }
+}
+
+/// Entries of [`EncryptedFs::read_dir_plus_stream`] with their offsets.
+pub struct DirectoryEntryPlusStream(BoxStream<'static, FsResult<(u64, DirectoryEntryPlus)>>);
+
+impl Stream for DirectoryEntryPlusStream {
+    type Item = FsResult<(u64, DirectoryEntryPlus)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.as_mut().poll_next(cx)
+    }
+}
+
+/// Listing of a directory opened with [`EncryptedFs::open_dir`], `None` until it's read from the
+/// start.
+struct DirHandleContext {
+    ino: u64,
+    entries: Option<Arc<Vec<(u64, PathBuf)>>>,
+}
+
 struct ReadHandleContext {
     ino: u64,
     attr: TimesFileAttr,
@@ -493,24 +600,85 @@ impl WriteHandleContextOperation {
 struct WriteHandleContext {
     ino: u64,
     attr: TimesAndSizeFileAttr,
-    writer: Option<Box<dyn CryptoWriteSeek<File>>>,
+    // writes change only the blocks they touch
+    store: BlockStore<File>,
+}
+
+/// Locks of an inode, taken in this order everywhere: the contents, the read-modify-write of the
+/// attributes and the inode file.
+///
+/// Holding all of them, nothing can open the inode, change its links or read it meanwhile.
+struct InodeLocks<'a> {
+    read_write: Holder<'a, u64, RwLock<bool>>,
+    update: Holder<'a, u64, Mutex<bool>>,
+    inode: Holder<'a, u64, RwLock<bool>>,
 }
 
+impl InodeLocks<'_> {
+    async fn lock(
+        &self,
+    ) -> (
+        RwLockWriteGuard<'_, bool>,
+        MutexGuard<'_, bool>,
+        RwLockWriteGuard<'_, bool>,
+    ) {
+        (
+            self.read_write.write().await,
+            self.update.lock().await,
+            self.inode.write().await,
+        )
+    }
+}
+
+#[derive(Clone)]
 struct KeyProvider {
-    key_path: PathBuf,
-    salt_path: PathBuf,
-    password_provider: Box<dyn PasswordProvider>,
+    data_dir: PathBuf,
+    key_material_provider: Arc<dyn KeyMaterialProvider>,
     cipher: Cipher,
+    kdf: KdfParams,
+}
+
+impl KeyProvider {
+    /// The key the key slots unlock, while a rekey is in progress it's the old one.
+    fn unlock(&self) -> FsResult<SecretVec<u8>> {
+        let password = self
+            .key_material_provider
+            .get_key_material()
+            .ok_or(FsError::InvalidPassword)?;
+        if let Some(key_slots) = KeySlots::read(&self.data_dir)? {
+            return key_slots.unlock(&password, self.cipher).map(|(_, key)| key);
+        }
+        // not upgraded to key slots yet
+        read_legacy_key(
+            &self.data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME),
+            &self.data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
+            &password,
+            self.cipher,
+            &self.kdf,
+        )
+    }
 }
 
 #[async_trait]
 impl ValueProvider<SecretVec<u8>, FsError> for KeyProvider {
     async fn provide(&self) -> Result<SecretVec<u8>, FsError> {
+        let key = self.unlock()?;
         let password = self
-            .password_provider
-            .get_password()
+            .key_material_provider
+            .get_key_material()
             .ok_or(FsError::InvalidPassword)?;
-        read_or_create_key(&self.key_path, &self.salt_path, &password, self.cipher)
+        // while a rekey is in progress we write with the new key
+        Ok(rekey::pending_key(&self.data_dir, &key, &password, self.cipher)?.unwrap_or(key))
+    }
+}
+
+/// Master key from before the rekey in progress.
+struct OldKeyProvider(KeyProvider);
+
+#[async_trait]
+impl ValueProvider<SecretVec<u8>, FsError> for OldKeyProvider {
+    async fn provide(&self) -> Result<SecretVec<u8>, FsError> {
+        self.0.unlock()
     }
 }
 
@@ -518,6 +686,12 @@ pub trait PasswordProvider: Send + Sync + 'static {
     fn get_password(&self) -> Option<SecretString>;
 }
 
+impl PasswordProvider for Box<dyn PasswordProvider> {
+    fn get_password(&self) -> Option<SecretString> {
+        self.as_ref().get_password()
+    }
+}
+
 struct DirEntryNameCacheProvider {}
 #[async_trait]
 impl ValueProvider<Mutex<LruCache<String, SecretString>>, FsError> for DirEntryNameCacheProvider {
@@ -547,13 +721,15 @@ type DirEntryMetaCache = LruCache<String, (u64, FileType)>;
 /// Encrypted FS that stores encrypted files in a dedicated directory with a specific structure based on `inode`.
 pub struct EncryptedFs {
     pub(crate) data_dir: PathBuf,
-    write_handles: RwLock<HashMap<u64, Mutex<WriteHandleContext>>>,
+    // the handles of the same inode share the context, so the writes go through the same writer
+    write_handles: RwLock<HashMap<u64, Arc<Mutex<WriteHandleContext>>>>,
     read_handles: RwLock<HashMap<u64, Mutex<ReadHandleContext>>>,
+    dir_handles: Mutex<HashMap<u64, DirHandleContext>>,
     current_handle: AtomicU64,
     cipher: Cipher,
     // (ino, fh)
     opened_files_for_read: RwLock<HashMap<u64, HashSet<u64>>>,
-    opened_files_for_write: RwLock<HashMap<u64, u64>>,
+    opened_files_for_write: RwLock<HashMap<u64, HashSet<u64>>>,
     // used for rw ops of actual serialization
     // use std::sync::RwLock instead of tokio::sync::RwLock because we need to use it also in sync code in `DirectoryEntryIterator` and `DirectoryEntryPlusIterator`
     serialize_inode_locks: Arc<ArcHashMap<u64, RwLock<bool>>>,
@@ -563,7 +739,22 @@ pub struct EncryptedFs {
     serialize_dir_entries_ls_locks: Arc<ArcHashMap<String, RwLock<bool>>>,
     serialize_dir_entries_hash_locks: Arc<ArcHashMap<String, RwLock<bool>>>,
     read_write_locks: ArcHashMap<u64, RwLock<bool>>,
+    // used for reading and updating the extended attributes of an inode
+    xattr_locks: ArcHashMap<u64, RwLock<bool>>,
     key: ExpireValue<SecretVec<u8>, FsError, KeyProvider>,
+    // used only while a rekey is in progress, for what was not re-encrypted yet
+    old_key: ExpireValue<SecretVec<u8>, FsError, OldKeyProvider>,
+    // derived from the master key, used to hash file names in `hash` dirs,
+    // it's `None` for not migrated data dirs opened in read-only mode, which use unkeyed hashes
+    name_hash_key: Option<SecretVec<u8>>,
+    // derived from the old key, it's set while a rekey is in progress
+    old_name_hash_key: std::sync::RwLock<Option<SecretVec<u8>>>,
+    // the rekey holds it for write while it changes a directory entry, the ops which remove
+    // entries hold it for read
+    rekey_lock: RwLock<()>,
+    rekey_running: Mutex<()>,
+    // journal for the changes to directory entries, `None` in read-only mode
+    journal: Option<Journal>,
     self_weak: std::sync::Mutex<Option<Weak<Self>>>,
     attr_cache: ExpireValue<RwLock<LruCache<u64, FileAttr>>, FsError, AttrCacheProvider>,
     dir_entries_name_cache:
@@ -574,32 +765,126 @@ pub struct EncryptedFs {
     sizes_read: Mutex<HashMap<u64, AtomicU64>>,
     requested_read: Mutex<HashMap<u64, AtomicU64>>,
     read_only: bool,
+    // what the older format of the data dir wrote, only in read-only mode as we upgrade it
+    legacy_content: Legacy,
+    // exclusive lock on `security/lock` in read-write mode, released when we're dropped
+    _data_dir_lock: Option<File>,
 }
 
 impl EncryptedFs {
+    /// Open the filesystem in `data_dir`, create it first with [`Self::init`].
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
     pub async fn new(
         data_dir: PathBuf,
-        password_provider: Box<dyn PasswordProvider>,
+        key_material_provider: Box<dyn KeyMaterialProvider>,
         cipher: Cipher,
         read_only: bool,
     ) -> FsResult<Arc<Self>> {
+        if !Self::is_initialized(&data_dir) {
+            return Err(FsError::DataDirNotInitialized);
+        }
+        ensure_structure_created(&data_dir.clone()).await?;
+        let data_dir_lock = if read_only {
+            None
+        } else {
+            Some(lock_data_dir(&data_dir)?)
+        };
+        let mut volume = if let Some(volume) = VolumeMetadata::read(&data_dir)? {
+            if volume.cipher != cipher {
+                debug!(
+                    "using cipher {} the data dir was created with",
+                    volume.cipher
+                );
+            }
+            volume
+        } else if data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME).is_file() {
+            // created before we had the metadata, we need to trust the cipher we're given
+            VolumeMetadata::legacy(cipher)
+        } else {
+            return Err(FsError::Other("data dir doesn't have the metadata"));
+        };
+        let cipher = volume.cipher;
+
         let key_provider = KeyProvider {
-            key_path: data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME),
-            salt_path: data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
-            password_provider,
+            data_dir: data_dir.clone(),
+            key_material_provider: Arc::from(key_material_provider),
             cipher,
+            kdf: volume.kdf,
         };
+        let old_key = ExpireValue::new(
+            OldKeyProvider(key_provider.clone()),
+            Duration::from_secs(10 * 60),
+        );
         let key = ExpireValue::new(key_provider, Duration::from_secs(10 * 60));
 
-        ensure_structure_created(&data_dir.clone()).await?;
-        key.get().await?; // this will check the password
+        let master_key = key.get().await?; // this will check the password
+        let old_master_key = if RekeyState::exists(&data_dir) {
+            let old_master_key = old_key.get().await?;
+            if *old_master_key.expose_secret() == *master_key.expose_secret() {
+                // the rekey finished but we crashed before removing its state
+                if !read_only {
+                    RekeyState::remove(&data_dir)?;
+                }
+                None
+            } else {
+                Some(old_master_key)
+            }
+        } else {
+            None
+        };
+        // it was written with the old key if the rekey didn't finish
+        VolumeMetadata::check_mac(&data_dir, &master_key).or_else(|err| {
+            old_master_key.as_ref().map_or(Err(err), |old_master_key| {
+                VolumeMetadata::check_mac(&data_dir, old_master_key)
+            })
+        })?;
+        if volume.format_version < CURRENT_FORMAT_VERSION {
+            if read_only {
+                // we cannot upgrade, keep using the old format
+                warn!(
+                    "data dir has format version {}, open it in read-write mode to upgrade",
+                    volume.format_version
+                );
+            } else {
+                upgrade_volume(
+                    &data_dir,
+                    &mut volume,
+                    &master_key,
+                    old_master_key.as_deref(),
+                )?;
+            }
+        }
+        let legacy_content = volume.legacy_content();
+        let name_hash_key = if volume.format_version >= 2 {
+            Some(crypto::derive_subkey(
+                &master_key,
+                crypto::FILE_NAME_HASH_KEY_CONTEXT,
+            ))
+        } else {
+            None
+        };
+        let journal = if read_only {
+            None
+        } else {
+            Some(Journal::open_and_replay(
+                &data_dir,
+                cipher,
+                &master_key,
+                old_master_key.as_deref(),
+            )?)
+        };
+        let old_name_hash_key = old_master_key
+            .as_ref()
+            .map(|key| crypto::derive_subkey(key, crypto::FILE_NAME_HASH_KEY_CONTEXT));
+        drop(master_key);
+        drop(old_master_key);
 
         let fs = Self {
             data_dir,
             write_handles: RwLock::new(HashMap::new()),
             read_handles: RwLock::new(HashMap::new()),
+            dir_handles: Mutex::new(HashMap::new()),
             current_handle: AtomicU64::new(1),
             cipher,
             opened_files_for_read: RwLock::new(HashMap::new()),
@@ -609,8 +894,15 @@ impl EncryptedFs {
             serialize_dir_entries_ls_locks: Arc::new(ArcHashMap::default()),
             serialize_dir_entries_hash_locks: Arc::new(ArcHashMap::default()),
             key,
+            old_key,
+            name_hash_key,
+            old_name_hash_key: std::sync::RwLock::new(old_name_hash_key),
+            rekey_lock: RwLock::new(()),
+            rekey_running: Mutex::new(()),
+            journal,
             self_weak: std::sync::Mutex::new(None),
             read_write_locks: ArcHashMap::default(),
+            xattr_locks: ArcHashMap::default(),
             // todo: take duration from param
             attr_cache: ExpireValue::new(AttrCacheProvider {}, Duration::from_secs(10 * 60)),
             // todo: take duration from param
@@ -627,6 +919,8 @@ impl EncryptedFs {
             sizes_read: Mutex::default(),
             requested_read: Mutex::default(),
             read_only,
+            legacy_content,
+            _data_dir_lock: data_dir_lock,
         };
 
         let arc = Arc::new(fs);
@@ -683,16 +977,7 @@ impl EncryptedFs {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
-        if *name.expose_secret() == "." || *name.expose_secret() == ".." {
-            return Err(FsError::InvalidInput("name cannot be '.' or '..'"));
-        }
-        if !self.exists(parent) {
-            return Err(FsError::InodeNotFound);
-        }
-        if self.exists_by_name(parent, name)? {
-            return Err(FsError::AlreadyExists);
-        }
-        self.validate_filename(name)?;
+        self.validate_new_entry(parent, name)?;
 
         // spawn on a dedicated runtime to not interfere with other higher priority tasks
         let self_clone = self
@@ -708,111 +993,8 @@ impl EncryptedFs {
             .spawn(async move {
                 let mut attr: FileAttr = create_attr.into();
                 attr.ino = self_clone.generate_next_inode();
+                self_clone.create_node(parent, name_clone, attr).await?;
 
-                let fs = self_clone;
-                let mut join_set = JoinSet::new();
-
-                // write inode
-                let self_clone = fs.clone();
-                self_clone.write_inode_to_storage(&attr).await?;
-
-                match attr.kind {
-                    FileType::RegularFile => {
-                        let self_clone = fs.clone();
-                        join_set.spawn(async move {
-                            // create in contents directory
-                            let file = File::create(self_clone.contents_path(attr.ino))?;
-                            // sync_all file and parent
-                            // these operations are a bit slow, but are necessary to make sure the file is correctly created
-                            // i.e. creating 100 files takes 0.965 sec with sync_all and 0.130 sec without
-                            file.sync_all()?;
-                            File::open(
-                                self_clone
-                                    .contents_path(attr.ino)
-                                    .parent()
-                                    .expect("oops, we don't have a parent"),
-                            )?
-                            .sync_all()?;
-                            Ok::<(), FsError>(())
-                        });
-                    }
-                    FileType::Directory => {
-                        let self_clone = fs.clone();
-                        let attr_clone = attr;
-                        join_set.spawn(async move {
-                            // create in contents directory
-                            let contents_dir = self_clone.contents_path(attr.ino);
-                            fs::create_dir(contents_dir.clone())?;
-                            // used to keep encrypted file names used by [`read_dir`] and [`read_dir_plus`]
-                            fs::create_dir(contents_dir.join(LS_DIR))?;
-                            // used to keep hashes of encrypted file names used by [`exists_by_name`] and [`find_by_name`]
-                            // this optimizes the search process as we don't need to decrypt all file names and search
-                            fs::create_dir(contents_dir.join(HASH_DIR))?;
-
-                            // add "." and ".." entries
-                            self_clone
-                                .insert_directory_entry(
-                                    attr_clone.ino,
-                                    &DirectoryEntry {
-                                        ino: attr_clone.ino,
-                                        name: SecretString::new(Box::new("$.".into())),
-                                        kind: FileType::Directory,
-                                    },
-                                )
-                                .await?;
-                            self_clone
-                                .insert_directory_entry(
-                                    attr_clone.ino,
-                                    &DirectoryEntry {
-                                        ino: parent,
-                                        name: SecretString::new(Box::new("$..".into())),
-                                        kind: FileType::Directory,
-                                    },
-                                )
-                                .await?;
-                            Ok::<(), FsError>(())
-                        });
-                    }
-                }
-
-                // edd entry in parent directory, used for listing
-                let self_clone = fs.clone();
-                let attr_clone = attr;
-                join_set.spawn(async move {
-                    self_clone
-                        .insert_directory_entry(
-                            parent,
-                            &DirectoryEntry {
-                                ino: attr_clone.ino,
-                                name: name_clone,
-                                kind: attr_clone.kind,
-                            },
-                        )
-                        .await?;
-                    Ok::<(), FsError>(())
-                });
-
-                let self_clone = fs.clone();
-                join_set.spawn(async move {
-                    let now = SystemTime::now();
-                    self_clone
-                        .set_attr(
-                            parent,
-                            SetFileAttr::default()
-                                .with_mtime(now)
-                                .with_ctime(now)
-                                .with_atime(now),
-                        )
-                        .await?;
-                    Ok::<(), FsError>(())
-                });
-
-                // wait for all tasks to finish
-                while let Some(res) = join_set.join_next().await {
-                    res??;
-                }
-
-                let self_clone = fs.clone();
                 let handle = if attr.kind == FileType::RegularFile {
                     if read || write {
                         self_clone.open(attr.ino, read, write).await?
@@ -830,6 +1012,255 @@ impl EncryptedFs {
             .await?
     }
 
+    /// Create a symbolic link named `name` in `parent` pointing to `target`.
+    ///
+    /// The target is stored encrypted in the contents file, the same way as regular file content.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create_symlink(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        target: &SecretString,
+        mut create_attr: CreateFileAttr,
+    ) -> FsResult<FileAttr> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if target.expose_secret().is_empty() {
+            return Err(FsError::InvalidInput("symlink target cannot be empty"));
+        }
+        self.validate_new_entry(parent, name)?;
+        create_attr.kind = FileType::Symlink;
+        let mut attr: FileAttr = create_attr.into();
+        attr.ino = self.generate_next_inode();
+        attr.size = target.expose_secret().len() as u64;
+
+        // the target is written before the inode, which keeps it, so the link is never empty
+        let path = self.contents_path(attr.ino);
+        let mut writer = self
+            .create_write(fs_util::open_atomic_write(&path)?)
+            .await?;
+        writer.write_all(target.expose_secret().as_bytes())?;
+        writer.finish()?.commit()?;
+        self.create_node(parent, name.clone(), attr).await?;
+
+        self.get_attr(attr.ino).await
+    }
+
+    fn validate_new_entry(&self, parent: u64, name: &SecretString) -> FsResult<()> {
+        if *name.expose_secret() == "." || *name.expose_secret() == ".." {
+            return Err(FsError::InvalidInput("name cannot be '.' or '..'"));
+        }
+        if !self.exists(parent) {
+            return Err(FsError::InodeNotFound);
+        }
+        if self.exists_by_name(parent, name)? {
+            return Err(FsError::AlreadyExists);
+        }
+        self.validate_filename(name)
+    }
+
+    /// Create the inode and its entries.
+    ///
+    /// The inode with its contents and the entries are created in one transaction, so if we crash
+    /// there is no orphaned inode or entry without inode.
+    async fn create_node(&self, parent: u64, name: SecretString, attr: FileAttr) -> FsResult<()> {
+        let mut ops = vec![JournalOp::CreateInode { attr }];
+        if attr.kind == FileType::Directory {
+            // add "." and ".." entries, so it's never listed without them
+            for (name, ino) in [("$.", attr.ino), ("$..", parent)] {
+                ops.extend(
+                    self.insert_directory_entry_ops(
+                        attr.ino,
+                        &DirectoryEntry {
+                            ino,
+                            name: SecretString::new(Box::new(name.into())),
+                            kind: FileType::Directory,
+                        },
+                    )
+                    .await?,
+                );
+            }
+        }
+        ops.extend(
+            self.insert_directory_entry_ops(
+                parent,
+                &DirectoryEntry {
+                    ino: attr.ino,
+                    name,
+                    kind: attr.kind,
+                },
+            )
+            .await?,
+        );
+        self.journal(ops).await?;
+
+        let now = SystemTime::now();
+        self.set_attr(
+            parent,
+            SetFileAttr::default()
+                .with_mtime(now)
+                .with_ctime(now)
+                .with_atime(now),
+        )
+        .await
+    }
+
+    /// Read the target of a symbolic link.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_link(&self, ino: u64) -> FsResult<SecretString> {
+        let attr = self.get_attr(ino).await?;
+        if attr.kind != FileType::Symlink {
+            return Err(FsError::InvalidInodeType);
+        }
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _read_guard = lock.read().await;
+        let mut reader = self.read_with_key(
+            File::open(self.contents_path(ino))?,
+            &*self.content_key(ino).await?,
+        );
+        let mut target = String::new();
+        reader.read_to_string(&mut target)?;
+        Ok(SecretString::new(Box::new(target)))
+    }
+
+    /// Set the value of an extended attribute.
+    ///
+    /// Names and values are stored encrypted, per inode.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn set_xattr(
+        &self,
+        ino: u64,
+        name: &SecretString,
+        value: &[u8],
+        mode: SetXattrMode,
+    ) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        validate_xattr_name(name)?;
+        if value.len() > XATTR_VALUE_MAX {
+            return Err(FsError::InvalidInput("extended attribute value too large"));
+        }
+
+        let lock = self
+            .xattr_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.write().await;
+
+        let mut xattrs = self.read_xattrs(ino).await?;
+        let exists = xattrs.contains_key(&*name.expose_secret());
+        match mode {
+            SetXattrMode::Create if exists => return Err(FsError::AlreadyExists),
+            SetXattrMode::Replace if !exists => return Err(FsError::XattrNotFound),
+            _ => {}
+        }
+        xattrs.insert(name.expose_secret().clone(), value.to_vec());
+        self.write_xattrs(ino, &xattrs).await?;
+
+        self.set_attr(ino, SetFileAttr::default().with_ctime(SystemTime::now()))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Get the value of an extended attribute.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn get_xattr(&self, ino: u64, name: &SecretString) -> FsResult<SecretVec<u8>> {
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        let lock = self
+            .xattr_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.read().await;
+
+        self.read_xattrs(ino)
+            .await?
+            .remove(&*name.expose_secret())
+            .map(|value| SecretVec::new(Box::new(value)))
+            .ok_or(FsError::XattrNotFound)
+    }
+
+    /// List the names of the extended attributes of an inode.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn list_xattrs(&self, ino: u64) -> FsResult<Vec<SecretString>> {
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        let lock = self
+            .xattr_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.read().await;
+
+        Ok(self
+            .read_xattrs(ino)
+            .await?
+            .into_keys()
+            .map(|name| SecretString::new(Box::new(name)))
+            .collect())
+    }
+
+    /// Remove an extended attribute.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn remove_xattr(&self, ino: u64, name: &SecretString) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        let lock = self
+            .xattr_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.write().await;
+
+        let mut xattrs = self.read_xattrs(ino).await?;
+        if xattrs.remove(&*name.expose_secret()).is_none() {
+            return Err(FsError::XattrNotFound);
+        }
+        if xattrs.is_empty() {
+            fs::remove_file(self.xattrs_path(ino))?;
+        } else {
+            self.write_xattrs(ino, &xattrs).await?;
+        }
+
+        self.set_attr(ino, SetFileAttr::default().with_ctime(SystemTime::now()))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn read_xattrs(&self, ino: u64) -> FsResult<BTreeMap<String, Vec<u8>>> {
+        let path = self.xattrs_path(ino);
+        if !path.is_file() {
+            return Ok(BTreeMap::new());
+        }
+        self.decrypt_with_keys(|key| {
+            Ok(bincode::deserialize_from(
+                self.read_with_key(File::open(&path)?, key),
+            )?)
+        })
+        .await
+    }
+
+    async fn write_xattrs(&self, ino: u64, xattrs: &BTreeMap<String, Vec<u8>>) -> FsResult<()> {
+        let path = self.xattrs_path(ino);
+        if let Some(parent) = path.parent() {
+            // data dirs created by older versions don't have it
+            if !parent.exists() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        crypto::atomic_serialize_encrypt_into(&path, xattrs, self.cipher, &*self.key.get().await?)?;
+        Ok(())
+    }
+
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
     pub async fn find_by_name(
@@ -843,22 +1274,22 @@ impl EncryptedFs {
         if !self.is_dir(parent) {
             return Err(FsError::InvalidInodeType);
         }
-        let hash = crypto::hash_file_name(name);
-        let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
-        if !hash_path.is_file() {
+        let Some(hash_path) = self.find_hash_path(parent, name) else {
             return Ok(None);
-        }
+        };
         let lock = self
             .serialize_dir_entries_hash_locks
             .get_or_insert_with(hash_path.to_str().unwrap().to_owned(), || {
                 RwLock::new(false)
             });
         let guard = lock.read().await;
-        let (ino, _, _): (u64, FileType, String) = bincode::deserialize_from(crypto::create_read(
-            File::open(hash_path)?,
-            self.cipher,
-            &*self.key.get().await?,
-        ))?;
+        let (ino, _, _): (u64, FileType, String) = self
+            .decrypt_with_keys(|key| {
+                Ok(bincode::deserialize_from(
+                    self.read_with_key(File::open(&hash_path)?, key),
+                )?)
+            })
+            .await?;
         drop(guard);
         self.get_inode_from_cache_or_storage(ino).await.map(Some)
     }
@@ -887,6 +1318,8 @@ impl EncryptedFs {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
+        // the rekey doesn't change the entries meanwhile
+        let _rekey_guard = self.rekey_lock.read().await;
         if !self.is_dir(parent) {
             return Err(FsError::InvalidInodeType);
         }
@@ -917,29 +1350,15 @@ impl EncryptedFs {
         let name_clone = name.clone();
         NOD_RT
             .spawn(async move {
-                // remove inode file
-                {
-                    let lock = self_clone
-                        .serialize_inode_locks
-                        .get_or_insert_with(attr.ino, || RwLock::new(false));
-                    let _guard = lock.write();
-                    fs::remove_file(self_clone.ino_file(attr.ino))?;
-                }
-
-                // remove contents directory
-                fs::remove_dir_all(self_clone.contents_path(attr.ino))?;
-                // remove from parent directory
-                self_clone
-                    .remove_directory_entry(parent, &name_clone)
+                // the entry first, in the same transaction, so there is no entry without inode
+                let locks = self_clone.inode_locks(attr.ino);
+                let guards = locks.lock().await;
+                let mut ops = self_clone
+                    .remove_directory_entry_ops(parent, &name_clone)
                     .await?;
-                // remove from cache
-                self_clone
-                    .attr_cache
-                    .get()
-                    .await?
-                    .write()
-                    .await
-                    .demote(&attr.ino);
+                ops.push(JournalOp::RemoveInode { ino: attr.ino });
+                self_clone.journal(ops).await?;
+                drop(guards);
 
                 let now = SystemTime::now();
                 self_clone
@@ -964,6 +1383,8 @@ impl EncryptedFs {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
+        // the rekey doesn't change the entries meanwhile
+        let _rekey_guard = self.rekey_lock.read().await;
         if !self.is_dir(parent) {
             return Err(FsError::InvalidInodeType);
         }
@@ -975,7 +1396,7 @@ impl EncryptedFs {
             .find_by_name(parent, name)
             .await?
             .ok_or(FsError::NotFound("name not found"))?;
-        if !matches!(attr.kind, FileType::RegularFile) {
+        if !matches!(attr.kind, FileType::RegularFile | FileType::Symlink) {
             return Err(FsError::InvalidInodeType);
         }
         // todo move to method
@@ -990,29 +1411,15 @@ impl EncryptedFs {
         let name_clone = name.clone();
         NOD_RT
             .spawn(async move {
-                // remove inode file
-                {
-                    let lock = self_clone
-                        .serialize_inode_locks
-                        .get_or_insert_with(attr.ino, || RwLock::new(false));
-                    let _guard = lock.write();
-                    fs::remove_file(self_clone.ino_file(attr.ino))?;
-                }
-
-                // remove from contents directory
-                fs::remove_file(self_clone.contents_path(attr.ino))?;
-                // remove from parent directory
-                self_clone
-                    .remove_directory_entry(parent, &name_clone)
+                // remove from parent directory and drop the link in one transaction
+                let locks = self_clone.inode_locks(attr.ino);
+                let guards = locks.lock().await;
+                let mut ops = self_clone
+                    .remove_directory_entry_ops(parent, &name_clone)
                     .await?;
-                // remove from cache
-                self_clone
-                    .attr_cache
-                    .get()
-                    .await?
-                    .write()
-                    .await
-                    .demote(&attr.ino);
+                ops.extend(self_clone.unlink_inode_ops(attr.ino).await?);
+                self_clone.journal(ops).await?;
+                drop(guards);
 
                 let now = SystemTime::now();
                 self_clone
@@ -1030,38 +1437,255 @@ impl EncryptedFs {
             .await?
     }
 
-    #[allow(clippy::missing_panics_doc)]
+    /// Create a hard link named `name` in `new_parent` to the existing inode `ino`.
+    ///
+    /// Directories cannot be hard linked.
     #[allow(clippy::missing_errors_doc)]
-    pub fn exists_by_name(&self, parent: u64, name: &SecretString) -> FsResult<bool> {
-        if !self.exists(parent) {
+    pub async fn link(&self, ino: u64, new_parent: u64, name: &SecretString) -> FsResult<FileAttr> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if *name.expose_secret() == "." || *name.expose_secret() == ".." {
+            return Err(FsError::InvalidInput("name cannot be '.' or '..'"));
+        }
+        if !self.exists(ino) || !self.exists(new_parent) {
             return Err(FsError::InodeNotFound);
         }
-        if !self.is_dir(parent) {
+        if !self.is_dir(new_parent) {
             return Err(FsError::InvalidInodeType);
         }
-        let hash = crypto::hash_file_name(name);
-        let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
-        Ok(hash_path.is_file())
-    }
-
-    #[allow(clippy::missing_errors_doc)]
-    pub async fn read_dir(&self, ino: u64) -> FsResult<DirectoryEntryIterator> {
-        if !self.is_dir(ino) {
-            return Err(FsError::InvalidInodeType);
+        if self.exists_by_name(new_parent, name)? {
+            return Err(FsError::AlreadyExists);
         }
-        let ls_dir = self.contents_path(ino).join(LS_DIR);
-        if !ls_dir.is_dir() {
+        self.validate_filename(name)?;
+        let attr = self.get_attr(ino).await?;
+        if attr.kind == FileType::Directory {
             return Err(FsError::InvalidInodeType);
         }
 
-        let iter = fs::read_dir(ls_dir)?;
-        let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
-        self.set_attr(ino, set_attr).await?;
-        Ok(self.create_directory_entry_iterator(iter).await)
+        {
+            // so it's not removed meanwhile
+            let locks = self.inode_locks(ino);
+            let _guards = locks.lock().await;
+            if !self.exists(ino) {
+                return Err(FsError::InodeNotFound);
+            }
+            let nlink = self.read_inode_file(ino).await?.nlink + 1;
+            let mut ops = self
+                .insert_directory_entry_ops(
+                    new_parent,
+                    &DirectoryEntry {
+                        ino,
+                        name: name.clone(),
+                        kind: attr.kind,
+                    },
+                )
+                .await?;
+            ops.push(JournalOp::SetNlink {
+                ino,
+                nlink,
+                ctime: SystemTime::now(),
+            });
+            self.journal(ops).await?;
+        }
+        let attr = self.get_attr(ino).await?;
+
+        let now = SystemTime::now();
+        self.set_attr(
+            new_parent,
+            SetFileAttr::default()
+                .with_mtime(now)
+                .with_ctime(now)
+                .with_atime(now),
+        )
+        .await?;
+
+        Ok(attr)
+    }
+
+    /// Ops to drop one link of the inode, it's removed if it was the last one and it's not opened.
+    ///
+    /// Call it holding [`Self::inode_locks`] until the ops are journaled.
+    async fn unlink_inode_ops(&self, ino: u64) -> FsResult<Vec<JournalOp>> {
+        let nlink = self.read_inode_file(ino).await?.nlink.saturating_sub(1);
+        if nlink == 0 && !self.is_opened(ino).await {
+            return Ok(vec![JournalOp::RemoveInode { ino }]);
+        }
+        Ok(vec![JournalOp::SetNlink {
+            ino,
+            nlink,
+            ctime: SystemTime::now(),
+        }])
+    }
+
+    /// Remove the inode and its content if there are no links to it and no opened handles.
+    async fn remove_inode_if_unlinked(&self, ino: u64) -> FsResult<()> {
+        // nothing can open or link it between the check and the removal
+        let locks = self.inode_locks(ino);
+        let _guards = locks.lock().await;
+        if !self.exists(ino)
+            || self.read_inode_file(ino).await?.nlink > 0
+            || self.is_opened(ino).await
+        {
+            return Ok(());
+        }
+        self.journal(vec![JournalOp::RemoveInode { ino }]).await
+    }
+
+    async fn is_opened(&self, ino: u64) -> bool {
+        self.opened_files_for_read.read().await.contains_key(&ino)
+            || self.opened_files_for_write.read().await.contains_key(&ino)
+    }
+
+    /// Locks for changing the links of the inode or removing it, see [`InodeLocks`].
+    fn inode_locks(&self, ino: u64) -> InodeLocks<'_> {
+        InodeLocks {
+            read_write: self
+                .read_write_locks
+                .get_or_insert_with(ino, || RwLock::new(false)),
+            update: self
+                .serialize_update_inode_locks
+                .get_or_insert_with(ino, || Mutex::new(false)),
+            inode: self
+                .serialize_inode_locks
+                .get_or_insert_with(ino, || RwLock::new(false)),
+        }
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub fn exists_by_name(&self, parent: u64, name: &SecretString) -> FsResult<bool> {
+        if !self.exists(parent) {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_dir(parent) {
+            return Err(FsError::InvalidInodeType);
+        }
+        Ok(self.find_hash_path(parent, name).is_some())
+    }
+
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_dir(&self, ino: u64) -> FsResult<DirectoryEntryIterator> {
+        let entries = self
+            .read_dir_stream(ino, 0, 0)
+            .await?
+            .map(|entry| entry.map(|(_, entry)| entry))
+            .collect()
+            .await;
+        Ok(DirectoryEntryIterator(entries))
     }
 
     /// Like [`EncryptedFs::read_dir`] but with [`FileAttr`] so we don't need to query again for those.
+    #[allow(clippy::missing_errors_doc)]
     pub async fn read_dir_plus(&self, ino: u64) -> FsResult<DirectoryEntryPlusIterator> {
+        let entries = self
+            .read_dir_plus_stream(ino, 0, 0)
+            .await?
+            .map(|entry| entry.map(|(_, entry)| entry))
+            .collect()
+            .await;
+        Ok(DirectoryEntryPlusIterator(entries))
+    }
+
+    /// Like [`EncryptedFs::read_dir`] but the entries are decrypted while the stream is polled, not
+    /// all up front, so the first ones come right away even for big directories.
+    ///
+    /// Each entry comes with its offset, pass it to continue the listing after that entry, `0` is
+    /// the start. It's derived from the encrypted name and the entries come in its order, so it
+    /// stays valid while entries are added or removed. Skipping the entries before the offset
+    /// doesn't decrypt them.
+    ///
+    /// With a handle from [`EncryptedFs::open_dir`] the entries are listed once when it's read from
+    /// the start and the next batches continue from there, with `0` as `fh` they are listed on
+    /// each call.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_dir_stream(
+        &self,
+        ino: u64,
+        offset: u64,
+        fh: u64,
+    ) -> FsResult<DirectoryEntryStream> {
+        let fs = self.self_arc();
+        let entries = self
+            .ls_entries(ino, offset, fh)
+            .await?
+            .map(move |(offset, entry)| {
+                let fs = fs.clone();
+                DIR_ENTRIES_RT.spawn(async move {
+                    fs.create_directory_entry(entry)
+                        .await
+                        .map(|entry| (offset, entry))
+                })
+            })
+            // do them in parallel, keeping the order
+            .buffered(DIR_ENTRIES_PARALLELISM)
+            .map(|res| res?)
+            .filter_map(|res| future::ready(skip_removed_entry(res)));
+        Ok(DirectoryEntryStream(entries.boxed()))
+    }
+
+    /// Like [`EncryptedFs::read_dir_stream`] but with [`FileAttr`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_dir_plus_stream(
+        &self,
+        ino: u64,
+        offset: u64,
+        fh: u64,
+    ) -> FsResult<DirectoryEntryPlusStream> {
+        let fs = self.self_arc();
+        let entries = self
+            .ls_entries(ino, offset, fh)
+            .await?
+            .map(move |(offset, entry)| {
+                let fs = fs.clone();
+                DIR_ENTRIES_RT.spawn(async move {
+                    fs.create_directory_entry_plus(entry)
+                        .await
+                        .map(|entry| (offset, entry))
+                })
+            })
+            .buffered(DIR_ENTRIES_PARALLELISM)
+            .map(|res| res?)
+            .filter_map(|res| future::ready(skip_removed_entry(res)));
+        Ok(DirectoryEntryPlusStream(entries.boxed()))
+    }
+
+    /// Open the directory to list it with [`EncryptedFs::read_dir_stream`] in batches.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn open_dir(&self, ino: u64) -> FsResult<u64> {
+        if !self.is_dir(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        let fh = self.next_handle();
+        self.dir_handles
+            .lock()
+            .await
+            .insert(fh, DirHandleContext { ino, entries: None });
+        Ok(fh)
+    }
+
+    /// Release the handle from [`EncryptedFs::open_dir`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn release_dir(&self, fh: u64) -> FsResult<()> {
+        if fh == 0 {
+            return Ok(());
+        }
+        self.dir_handles
+            .lock()
+            .await
+            .remove(&fh)
+            .ok_or(FsError::InvalidFileHandle)?;
+        Ok(())
+    }
+
+    /// Entries in `ls/` of the directory after the offset, with their offsets, see
+    /// [`ls_entries_with_offsets`], in that order.
+    async fn ls_entries(
+        &self,
+        ino: u64,
+        offset: u64,
+        fh: u64,
+    ) -> FsResult<impl Stream<Item = (u64, PathBuf)> + Send + 'static> {
         if !self.is_dir(ino) {
             return Err(FsError::InvalidInodeType);
         }
@@ -1070,17 +1694,44 @@ impl EncryptedFs {
             return Err(FsError::InvalidInodeType);
         }
 
-        let iter = fs::read_dir(ls_dir)?;
-        let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
-        self.set_attr(ino, set_attr).await?;
-        Ok(self.create_directory_entry_plus_iterator(iter).await)
+        // only the names, they are decrypted when the stream is polled
+        let entries = if fh == 0 {
+            Arc::new(ls_entries_with_offsets(&ls_dir)?)
+        } else {
+            let mut dir_handles = self.dir_handles.lock().await;
+            let ctx = dir_handles
+                .get_mut(&fh)
+                .filter(|ctx| ctx.ino == ino)
+                .ok_or(FsError::InvalidFileHandle)?;
+            match &ctx.entries {
+                // from the start again, like after `rewinddir(3)`, we see the changes since then
+                Some(entries) if offset != 0 => entries.clone(),
+                _ => ctx
+                    .entries
+                    .insert(Arc::new(ls_entries_with_offsets(&ls_dir)?))
+                    .clone(),
+            }
+        };
+        let start = entries.partition_point(|(entry_offset, _)| *entry_offset <= offset);
+        if !self.read_only {
+            let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
+            self.set_attr(ino, set_attr).await?;
+        }
+        Ok(stream::iter(start..entries.len()).map(move |i| entries[i].clone()))
     }
 
-    async fn create_directory_entry_plus(
-        &self,
-        entry: io::Result<DirEntry>,
-    ) -> FsResult<DirectoryEntryPlus> {
-        let entry = self.create_directory_entry(entry).await?;
+    fn self_arc(&self) -> Arc<Self> {
+        self.self_weak
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .upgrade()
+            .unwrap()
+    }
+
+    async fn create_directory_entry_plus(&self, path: PathBuf) -> FsResult<DirectoryEntryPlus> {
+        let entry = self.create_directory_entry(path).await?;
         let lock = self.serialize_inode_locks.clone();
         let lock_ino = lock.get_or_insert_with(entry.ino, || RwLock::new(false));
         let _ino_guard = lock_ino.read();
@@ -1093,48 +1744,16 @@ impl EncryptedFs {
         })
     }
 
-    async fn create_directory_entry_plus_iterator(
-        &self,
-        read_dir: ReadDir,
-    ) -> DirectoryEntryPlusIterator {
-        #[allow(clippy::cast_possible_truncation)]
-        let futures: Vec<_> = read_dir
-            .into_iter()
-            .map(|entry| {
-                let fs = {
-                    self.self_weak
-                        .lock()
-                        .unwrap()
-                        .as_ref()
-                        .unwrap()
-                        .upgrade()
-                        .unwrap()
-                };
-                DIR_ENTRIES_RT.spawn(async move { fs.create_directory_entry_plus(entry).await })
-            })
-            .collect();
-
-        // do these futures in parallel and return them
-        let mut res = VecDeque::with_capacity(futures.len());
-        for f in futures {
-            res.push_back(f.await.unwrap());
-        }
-        DirectoryEntryPlusIterator(res)
-    }
-
-    async fn create_directory_entry(
-        &self,
-        entry: io::Result<DirEntry>,
-    ) -> FsResult<DirectoryEntry> {
-        if entry.is_err() {
-            return Err(entry.err().unwrap().into());
-        }
-        if let Err(e) = entry {
-            error!(err = %e, "reading directory entry");
-            return Err(e.into());
-        }
-        let entry = entry.unwrap();
-        let name = entry.file_name().to_string_lossy().to_string();
+    async fn create_directory_entry(&self, path: PathBuf) -> FsResult<DirectoryEntry> {
+        if !path.exists() {
+            // removed since we listed the directory, it might still be in the cache
+            return Err(io::Error::from(io::ErrorKind::NotFound).into());
+        }
+        let name = path
+            .file_name()
+            .ok_or(FsError::InvalidInput("invalid file name"))?
+            .to_string_lossy()
+            .to_string();
         let name = {
             if name == "$." {
                 SecretString::new(Box::new(".".into()))
@@ -1148,12 +1767,15 @@ impl EncryptedFs {
                     name_cached
                 } else {
                     drop(cache);
-                    if let Ok(decrypted_name) =
-                        crypto::decrypt_file_name(&name, self.cipher, &*self.key.get().await?)
-                            .map_err(|err| {
-                                error!(err = %err, "decrypting file name");
-                                err
-                            })
+                    if let Ok(decrypted_name) = self
+                        .decrypt_with_keys(|key| {
+                            Ok(crypto::decrypt_file_name(&name, self.cipher, key)?)
+                        })
+                        .await
+                        .map_err(|err| {
+                            error!(err = %err, "decrypting file name");
+                            err
+                        })
                     {
                         lock.lock().await.put(name.clone(), decrypted_name.clone());
                         decrypted_name
@@ -1166,7 +1788,7 @@ impl EncryptedFs {
 
         self.validate_filename(&name)?;
 
-        let file_path = entry.path().to_str().unwrap().to_owned();
+        let file_path = path.to_str().unwrap().to_owned();
         // try from cache
         let lock = self.dir_entries_meta_cache.get().await?;
         let mut cache = lock.lock().await;
@@ -1182,16 +1804,17 @@ impl EncryptedFs {
             .serialize_dir_entries_ls_locks
             .get_or_insert_with(file_path.clone(), || RwLock::new(false));
         let guard = lock.read().await;
-        let file = File::open(entry.path())?;
-        let res: bincode::Result<(u64, FileType)> = bincode::deserialize_from(crypto::create_read(
-            file,
-            self.cipher,
-            &*self.key.get().await?,
-        ));
+        let res: FsResult<(u64, FileType)> = self
+            .decrypt_with_keys(|key| {
+                Ok(bincode::deserialize_from(
+                    self.read_with_key(File::open(&path)?, key),
+                )?)
+            })
+            .await;
         drop(guard);
         if let Err(e) = res {
             error!(err = %e, "deserializing directory entry");
-            return Err(e.into());
+            return Err(e);
         }
         let (ino, kind): (u64, FileType) = res.unwrap();
         // add to cache
@@ -1210,32 +1833,6 @@ impl EncryptedFs {
         self.dir_entries_name_cache.get().await
     }
 
-    async fn create_directory_entry_iterator(&self, read_dir: ReadDir) -> DirectoryEntryIterator {
-        #[allow(clippy::cast_possible_truncation)]
-        let futures: Vec<_> = read_dir
-            .into_iter()
-            .map(|entry| {
-                let fs = {
-                    self.self_weak
-                        .lock()
-                        .unwrap()
-                        .as_ref()
-                        .unwrap()
-                        .upgrade()
-                        .unwrap()
-                };
-                DIR_ENTRIES_RT.spawn(async move { fs.create_directory_entry(entry).await })
-            })
-            .collect();
-
-        // do these futures in parallel and return them
-        let mut res = VecDeque::with_capacity(futures.len());
-        for f in futures {
-            res.push_back(f.await.unwrap());
-        }
-        DirectoryEntryIterator(res)
-    }
-
     #[allow(clippy::missing_errors_doc)]
     async fn get_inode_from_storage(&self, ino: u64) -> FsResult<FileAttr> {
         let lock = self
@@ -1243,19 +1840,23 @@ impl EncryptedFs {
             .get_or_insert_with(ino, || RwLock::new(false));
         let _guard = lock.read();
 
+        self.read_inode_file(ino).await
+    }
+
+    /// Like [`Self::get_inode_from_storage`] but without the lock, for when we hold it.
+    async fn read_inode_file(&self, ino: u64) -> FsResult<FileAttr> {
         let path = self.ino_file(ino);
         if !path.is_file() {
             return Err(FsError::InodeNotFound);
         }
-        let file = OpenOptions::new().read(true).open(path).map_err(|err| {
-            error!(err = %err, "opening file");
-            FsError::InodeNotFound
-        })?;
-        Ok(bincode::deserialize_from(crypto::create_read(
-            file,
-            self.cipher,
-            &*self.key.get().await?,
-        ))?)
+        self.decrypt_with_keys(|key| {
+            let file = OpenOptions::new().read(true).open(&path).map_err(|err| {
+                error!(err = %err, "opening file");
+                FsError::InodeNotFound
+            })?;
+            Ok(bincode::deserialize_from(self.read_with_key(file, key))?)
+        })
+        .await
     }
 
     async fn get_inode_from_cache_or_storage(&self, ino: u64) -> FsResult<FileAttr> {
@@ -1296,7 +1897,7 @@ impl EncryptedFs {
         // merge time info and size with any open write handles
         let open_writes = { self.opened_files_for_write.read().await.contains_key(&ino) };
         if open_writes {
-            let fh = self.opened_files_for_write.read().await.get(&ino).copied();
+            let fh = self.any_write_handle(ino).await;
             if let Some(fh) = fh {
                 let lock = self.write_handles.read().await;
                 if let Some(ctx) = lock.get(&fh) {
@@ -1389,6 +1990,11 @@ impl EncryptedFs {
         let lock = self
             .read_write_locks
             .get_or_insert_with(ino, || RwLock::new(false));
+        if self.has_dirty_writer(ino).await {
+            // write what the handles opened for write have in memory, so we read it
+            let _write_guard = lock.write().await;
+            self.flush_and_reset_writers(ino).await?;
+        }
         let _read_guard = lock.read().await;
 
         let guard = self.read_handles.read().await;
@@ -1507,7 +2113,12 @@ impl EncryptedFs {
             let set_attr: SetFileAttr = ctx.attr.clone().into();
             let ino = ctx.ino;
             drop(ctx);
-            self.set_attr(ino, set_attr).await?;
+            // in read-only mode nothing can be unlinked and we don't keep the access time
+            if !self.read_only {
+                self.set_attr(ino, set_attr).await?;
+                // remove the inode if it was unlinked while opened
+                self.remove_inode_if_unlinked(ino).await?;
+            }
 
             valid_fh = true;
         }
@@ -1518,19 +2129,31 @@ impl EncryptedFs {
             if self.read_only {
                 return Err(FsError::ReadOnly);
             }
-            let mut ctx = ctx.lock().await;
-
-            let mut writer = ctx.writer.take().unwrap();
+            let ino = ctx.lock().await.ino;
             let lock = self
                 .read_write_locks
-                .get_or_insert_with(ctx.ino, || RwLock::new(false));
+                .get_or_insert_with(ino, || RwLock::new(false));
+            // lock it before the context, like `write` does
             let write_guard = lock.write().await;
-            let file = writer.finish()?;
-            file.sync_all()?;
+            let last = {
+                let mut opened_files_for_write = self.opened_files_for_write.write().await;
+                let handles = opened_files_for_write.entry(ino).or_default();
+                handles.remove(&handle);
+                handles.is_empty()
+            };
+            if !last {
+                // other handles still use the writer, only save what was written so far
+                drop(write_guard);
+                self.reset_handles(ino, None, true).await?;
+                return Ok(());
+            }
+            let mut ctx = ctx.lock().await;
+
+            ctx.store.flush()?;
+            ctx.store.get_ref().sync_all()?;
             File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
             // write attr only here to avoid serializing it multiple times while writing
             // it will merge time fields with existing data because it might got change while we kept the handle
-            let ino = ctx.ino;
             let attr = ctx.attr.clone();
             drop(ctx);
             self.set_attr(ino, attr.into()).await?;
@@ -1571,9 +2194,12 @@ impl EncryptedFs {
             self.sizes_write.lock().await.remove(&ino);
             self.sizes_read.lock().await.remove(&ino);
             self.requested_read.lock().await.remove(&ino);
-            drop(write_guard);
+            // while we hold the lock, so a new handle doesn't get this context
             self.opened_files_for_write.write().await.remove(&ino);
+            drop(write_guard);
             self.reset_handles(ino, Some(handle), true).await?;
+            // remove the inode if it was unlinked while opened
+            self.remove_inode_if_unlinked(ino).await?;
 
             valid_fh = true;
         }
@@ -1642,15 +2268,6 @@ impl EncryptedFs {
                     self.cipher.max_plaintext_len(),
                 ));
             }
-            let writer = ctx.writer.as_mut().unwrap();
-            let pos = writer.seek(SeekFrom::Start(offset)).map_err(|err| {
-                error!(err = %err, "seeking");
-                err
-            })?;
-            if offset != pos {
-                // we could not seek to the desired position
-                return Ok(0);
-            }
             // keep block size to max the cipher can handle
             #[allow(clippy::cast_possible_truncation)]
             let buf = if offset + buf.len() as u64 > self.cipher.max_plaintext_len() as u64 {
@@ -1659,11 +2276,12 @@ impl EncryptedFs {
             } else {
                 buf
             };
-            let len = writer.write(buf).map_err(|err| {
+            // only the blocks we write to are read and encrypted again
+            ctx.store.write_at(offset, buf).map_err(|err| {
                 error!(err = %err, "writing");
                 err
             })?;
-            (writer.stream_position()?, len)
+            (offset + buf.len() as u64, buf.len())
         };
 
         // let size = ctx.attr.size;
@@ -1720,16 +2338,17 @@ impl EncryptedFs {
         let mut valid_fh = lock.get(&handle).is_some();
         let lock = self.write_handles.read().await;
         if let Some(ctx) = lock.get(&handle) {
-            let mut ctx = ctx.lock().await;
+            let ino = ctx.lock().await.ino;
             let lock = self
                 .read_write_locks
-                .get_or_insert_with(ctx.ino, || RwLock::new(false));
+                .get_or_insert_with(ino, || RwLock::new(false));
+            // lock it before the context, like `write` does
             let write_guard = lock.write().await;
-            ctx.writer.as_mut().expect("writer is missing").flush()?;
+            let mut ctx = ctx.lock().await;
+            ctx.store.flush()?;
             File::open(self.contents_path(ctx.ino))?.sync_all()?;
             File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
             drop(write_guard);
-            let ino = ctx.ino;
             drop(ctx);
             self.reset_handles(ino, Some(handle), true).await?;
             valid_fh = true;
@@ -1742,6 +2361,129 @@ impl EncryptedFs {
         Ok(())
     }
 
+    /// Make sure the data and metadata of the file reached the storage, like `fsync(2)`, also
+    /// what was written with the other handles of the file.
+    ///
+    /// With `datasync`, like `fdatasync(2)`, the metadata is saved only if it's needed to read
+    /// the data, that is the size.
+    ///
+    /// Unlike with `fsync(2)`, the parent doesn't need [`Self::fsyncdir`] for the entry of the
+    /// file, the entries are synced before `create`, `link` and `rename` return.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn fsync(&self, handle: u64, datasync: bool) -> FsResult<()> {
+        if handle == 0 || self.read_only {
+            // nothing was written
+            return Ok(());
+        }
+        // this syncs the content file and its parent
+        self.flush(handle).await?;
+
+        let lock = self.write_handles.read().await;
+        let Some(ctx) = lock.get(&handle) else {
+            drop(lock);
+            // read handle, the writes of the file go through the context the write handles
+            // share, so syncing one of them is enough, the times are saved on release
+            let lock = self.read_handles.read().await;
+            let ino = lock
+                .get(&handle)
+                .ok_or(FsError::InvalidFileHandle)?
+                .lock()
+                .await
+                .ino;
+            drop(lock);
+            let write_handle = self
+                .opened_files_for_write
+                .read()
+                .await
+                .get(&ino)
+                .and_then(|handles| handles.iter().next().copied());
+            return match write_handle {
+                Some(write_handle) => match Box::pin(self.fsync(write_handle, datasync)).await {
+                    // it was flushed when released meanwhile
+                    Err(FsError::InvalidFileHandle) => Ok(()),
+                    res => res,
+                },
+                // what they wrote was flushed when they were released
+                None => Ok(()),
+            };
+        };
+        let ctx = ctx.lock().await;
+        let ino = ctx.ino;
+        let attr = ctx.attr.clone();
+        drop(ctx);
+        drop(lock);
+        // not `get_attr`, it has the size from the handle
+        if datasync && self.get_inode_from_cache_or_storage(ino).await?.size == attr.size {
+            return Ok(());
+        }
+        // the inode is written atomically, with sync of the file and its parent
+        self.set_attr(ino, attr.into()).await
+    }
+
+    /// Make sure the entries of the directory reached the storage, like `fsync(2)` on a directory.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn fsyncdir(&self, ino: u64) -> FsResult<()> {
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_dir(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        if self.read_only {
+            return Ok(());
+        }
+        for dir in [LS_DIR, HASH_DIR] {
+            File::open(self.contents_path(ino).join(dir))?.sync_all()?;
+        }
+        File::open(self.ino_file(ino))?.sync_all()?;
+        File::open(self.data_dir.join(INODES_DIR))?.sync_all()?;
+        Ok(())
+    }
+
+    /// Offset of the next data from `offset`, like `lseek(2)` with `SEEK_DATA`.
+    ///
+    /// Returns `None` if `offset` is after the end or there are only holes until the end.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn seek_data(&self, ino: u64, offset: u64) -> FsResult<Option<u64>> {
+        self.seek_data_or_hole(ino, offset, true).await
+    }
+
+    /// Offset of the next hole from `offset`, like `lseek(2)` with `SEEK_HOLE`, the end of the
+    /// file counts as a hole.
+    ///
+    /// Returns `None` if `offset` is after the end.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn seek_hole(&self, ino: u64, offset: u64) -> FsResult<Option<u64>> {
+        self.seek_data_or_hole(ino, offset, false).await
+    }
+
+    async fn seek_data_or_hole(&self, ino: u64, offset: u64, data: bool) -> FsResult<Option<u64>> {
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_file(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        if self.has_dirty_writer(ino).await {
+            // blocks written meanwhile are not holes anymore
+            let _write_guard = lock.write().await;
+            self.flush_and_reset_writers(ino).await?;
+        }
+        let _read_guard = lock.read().await;
+        let mut store = self.block_store_with_key(
+            File::open(self.contents_path(ino))?,
+            &*self.content_key(ino).await?,
+        )?;
+        Ok(if data {
+            store.seek_data(offset)?
+        } else {
+            store.seek_hole(offset)?
+        })
+    }
+
     /// Helpful when we want to copy just some portions of the file.
     pub async fn copy_file_range(
         &self,
@@ -1786,7 +2528,10 @@ impl EncryptedFs {
         Ok(len)
     }
 
-    /// Open a file. We can open multiple times for read but only one to write at a time.
+    /// Open a file. We can open it multiple times for read and write.
+    ///
+    /// The handles opened for write on the same file share the writer, so the writes are applied
+    /// block by block in the order they come and every handle reads what the others wrote.
     #[allow(clippy::missing_panics_doc)]
     pub async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
         if write && self.read_only {
@@ -1797,7 +2542,7 @@ impl EncryptedFs {
                 "read and write cannot be false at the same time",
             ));
         }
-        if self.is_dir(ino) {
+        if self.is_dir(ino) || self.get_attr(ino).await?.kind == FileType::Symlink {
             return Err(FsError::InvalidInodeType);
         }
 
@@ -1811,9 +2556,6 @@ impl EncryptedFs {
             .await?;
         }
         if write {
-            if self.opened_files_for_write.read().await.contains_key(&ino) {
-                return Err(FsError::AlreadyOpenForWrite);
-            }
             if handle.is_none() {
                 handle = Some(self.next_handle());
             }
@@ -1861,7 +2603,7 @@ impl EncryptedFs {
         }
         info!("truncate {ino} to {size}");
         let attr = self.get_attr(ino).await?;
-        if matches!(attr.kind, FileType::Directory) {
+        if matches!(attr.kind, FileType::Directory | FileType::Symlink) {
             return Err(FsError::InvalidInodeType);
         }
 
@@ -1879,39 +2621,17 @@ impl EncryptedFs {
         self.flush_and_reset_writers(ino).await?;
 
         let file_path = self.contents_path(ino);
-        if size == 0 {
-            debug!("truncate to zero");
-            // truncate to zero
-            let file = File::create(&file_path)?;
-            file.set_len(0)?;
-            file.sync_all()?;
-        } else {
-            debug!("truncate size to {}", size.to_formatted_string(&Locale::en));
-
-            let mut file = fs_util::open_atomic_write(&file_path)?;
-            {
-                // have a new scope, so we drop the reader before moving new content files
-                let mut reader = self.create_read(File::open(file_path.as_path())?).await?;
-
-                let mut writer = self.create_write(file).await?;
-
-                let len = if size > attr.size {
-                    // increase size, copy existing data until existing size
-                    attr.size
-                } else {
-                    // decrease size, copy existing data until new size
-                    size
-                };
-                stream_util::copy_exact(&mut reader, &mut writer, len)?;
-                if size > attr.size {
-                    // increase size, seek to new size will write zeros
-                    stream_util::fill_zeros(&mut writer, size - attr.size)?;
-                }
-                file = writer.finish()?;
+        debug!("truncate size to {}", size.to_formatted_string(&Locale::en));
+        // only the new last block is changed, the new blocks are holes when we extend
+        self.do_with_block_store(ino, |store| store.set_len(size))
+            .await?;
+        File::open(file_path.parent().unwrap())?.sync_all()?;
+        // the writers have the old size, when shrinking it would be merged back
+        if let Some(fh) = self.any_write_handle(ino).await {
+            if let Some(ctx) = self.write_handles.read().await.get(&fh).cloned() {
+                ctx.lock().await.attr.size = size;
             }
-            file.commit()?;
         }
-        File::open(file_path.parent().unwrap())?.sync_all()?;
 
         let now = SystemTime::now();
         let set_attr = SetFileAttr::default()
@@ -1921,18 +2641,10 @@ impl EncryptedFs {
             .with_atime(now);
         self.set_attr2(ino, set_attr, true).await?;
 
-        let attr = self.get_inode_from_storage(ino).await?;
-        println!("attr 1: {:?}", attr.size);
-        let attr = self.get_attr(ino).await?;
-        println!("attr 1: {:?}", attr.size);
-
         // reset handles because the file has changed
-        self.reset_handles(attr.ino, None, false).await?;
+        self.reset_handles(ino, None, false).await?;
 
-        let attr = self.get_inode_from_storage(ino).await?;
-        println!("attr 2: {:?}", attr.size);
         let attr = self.get_attr(ino).await?;
-        println!("attr 2: {:?}", attr.size);
 
         if size != attr.size {
             error!("error truncating file expected {size} actual {}", attr.size);
@@ -1941,57 +2653,170 @@ impl EncryptedFs {
         Ok(())
     }
 
-    /// This will write any dirty data to the file from all writers and reset them.
-    /// Timestamps and size will be updated to the storage.
-    /// > ⚠️ **Warning**
-    /// > Need to be called in a context with write lock on `self.read_write_inode.lock().await.get(ino)`.
-    /// > That is because we want to make sure caller is holding a lock while all writers flush and we can't
-    /// > lock here also as we would end-up in a deadlock.
-    async fn flush_and_reset_writers(&self, ino: u64) -> FsResult<()> {
+    /// Allocate, deallocate or zero a range of the file, like `fallocate(2)`.
+    ///
+    /// Only the blocks at the ends of the range are written, if they are covered partially, the
+    /// others become holes or are allocated on the storage.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn fallocate(
+        &self,
+        ino: u64,
+        offset: u64,
+        len: u64,
+        mode: FallocateMode,
+    ) -> FsResult<()> {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
-        let opened_files_for_write_guard = self.opened_files_for_write.read().await;
-        let handle = opened_files_for_write_guard.get(&ino);
-        if let Some(handle) = handle {
-            let write_handles_guard = self.write_handles.write().await;
-            let ctx = write_handles_guard.get(handle);
-            if let Some(lock) = ctx {
-                let mut ctx = lock.lock().await;
-
-                let mut writer = ctx.writer.take().unwrap();
-                let file = writer.finish()?;
-                file.sync_all()?;
-                File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
-                let handle = *handle;
-                let set_attr: SetFileAttr = ctx.attr.clone().into();
-                drop(ctx);
-                drop(opened_files_for_write_guard);
-                drop(write_handles_guard);
-                self.set_attr(ino, set_attr).await?;
-                self.reset_handles(ino, Some(handle), true).await?;
-                let write_handles_guard = self.write_handles.write().await;
-                let mut ctx = write_handles_guard.get(&handle).unwrap().lock().await;
-                let writer = self
-                    .create_write_seek(
-                        OpenOptions::new()
-                            .read(true)
-                            .write(true)
-                            .open(self.contents_path(ino))?,
-                    )
-                    .await?;
-                ctx.writer = Some(Box::new(writer));
-                let attr = self.get_inode_from_storage(ino).await?;
-                ctx.attr = attr.into();
-            }
+        if self.get_attr(ino).await?.kind != FileType::RegularFile {
+            return Err(FsError::InvalidInodeType);
         }
-        Ok(())
-    }
+        if len == 0 {
+            return Err(FsError::InvalidInput("length cannot be zero"));
+        }
+        let end = offset
+            .checked_add(len)
+            .filter(|end| *end <= self.cipher.max_plaintext_len() as u64)
+            .ok_or(FsError::MaxFilesizeExceeded(
+                self.cipher.max_plaintext_len(),
+            ))?;
 
-    #[allow(clippy::missing_panics_doc)]
-    pub async fn rename(
-        &self,
-        parent: u64,
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _write_guard = lock.write().await;
+
+        // flush writers
+        self.flush_and_reset_writers(ino).await?;
+
+        let size = self.get_inode_from_storage(ino).await?.size;
+        let (new_size, zero, allocate) = match mode {
+            FallocateMode::Allocate { keep_size } => {
+                (if keep_size { size } else { size.max(end) }, false, true)
+            }
+            FallocateMode::PunchHole => (size, true, false),
+            FallocateMode::ZeroRange { keep_size } => {
+                (if keep_size { size } else { size.max(end) }, true, true)
+            }
+        };
+        self.do_with_block_store(ino, |store| {
+            if zero {
+                store.punch_hole(offset, len)?;
+            }
+            if new_size > size {
+                store.set_len(new_size)?;
+            }
+            if allocate {
+                store.allocate(offset, len)?;
+            }
+            Ok(())
+        })
+        .await?;
+        File::open(self.contents_path(ino).parent().unwrap())?.sync_all()?;
+
+        if zero || new_size != size {
+            let now = SystemTime::now();
+            let set_attr = SetFileAttr::default()
+                .with_size(new_size)
+                .with_mtime(now)
+                .with_ctime(now);
+            self.set_attr2(ino, set_attr, true).await?;
+        }
+
+        // reset handles because the file has changed
+        self.reset_handles(ino, None, false).await?;
+
+        Ok(())
+    }
+
+    /// Change the content with the block store of the writers if the file is opened for write, so
+    /// they see the change, or with a new one.
+    ///
+    /// > ⚠️ **Warning**
+    /// > Need to be called in a context with write lock on `self.read_write_locks` and after
+    /// > `flush_and_reset_writers`.
+    async fn do_with_block_store<T>(
+        &self,
+        ino: u64,
+        f: impl FnOnce(&mut BlockStore<File>) -> std::io::Result<T>,
+    ) -> FsResult<T> {
+        let ctx = match self.any_write_handle(ino).await {
+            Some(fh) => self.write_handles.read().await.get(&fh).cloned(),
+            None => None,
+        };
+        if let Some(ctx) = ctx {
+            let mut ctx = ctx.lock().await;
+            let res = f(&mut ctx.store)?;
+            ctx.store.get_ref().sync_all()?;
+            Ok(res)
+        } else {
+            let mut store = self.block_store_with_key(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(self.contents_path(ino))?,
+                &*self.content_key(ino).await?,
+            )?;
+            let res = f(&mut store)?;
+            store.finish()?.sync_all()?;
+            Ok(res)
+        }
+    }
+
+    /// This will write any dirty data to the file from all writers and reset them.
+    /// Timestamps and size will be updated to the storage.
+    /// > ⚠️ **Warning**
+    /// > Need to be called in a context with write lock on `self.read_write_inode.lock().await.get(ino)`.
+    /// > That is because we want to make sure caller is holding a lock while all writers flush and we can't
+    /// > lock here also as we would end-up in a deadlock.
+    async fn flush_and_reset_writers(&self, ino: u64) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let handle = self.any_write_handle(ino).await;
+        if let Some(handle) = handle {
+            let ctx = self.write_handles.read().await.get(&handle).cloned();
+            if let Some(ctx) = ctx {
+                let mut ctx_guard = ctx.lock().await;
+
+                ctx_guard.store.flush()?;
+                ctx_guard.store.get_ref().sync_all()?;
+                File::open(self.contents_path(ino).parent().unwrap())?.sync_all()?;
+                let set_attr: SetFileAttr = ctx_guard.attr.clone().into();
+                drop(ctx_guard);
+                self.set_attr(ino, set_attr).await?;
+                self.reset_handles(ino, Some(handle), true).await?;
+                let attr = self.get_inode_from_storage(ino).await?;
+                ctx.lock().await.attr = attr.into();
+            }
+        }
+        Ok(())
+    }
+
+    async fn has_dirty_writer(&self, ino: u64) -> bool {
+        let Some(fh) = self.any_write_handle(ino).await else {
+            return false;
+        };
+        let ctx = self.write_handles.read().await.get(&fh).cloned();
+        match ctx {
+            Some(ctx) => ctx.lock().await.store.is_dirty(),
+            None => false,
+        }
+    }
+
+    /// One of the handles opened for write for the inode, they all share the same context.
+    async fn any_write_handle(&self, ino: u64) -> Option<u64> {
+        self.opened_files_for_write
+            .read()
+            .await
+            .get(&ino)
+            .and_then(|fhs| fhs.iter().next().copied())
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn rename(
+        &self,
+        parent: u64,
         name: &SecretString,
         new_parent: u64,
         new_name: &SecretString,
@@ -1999,6 +2824,8 @@ impl EncryptedFs {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
+        // the rekey doesn't change the entries meanwhile
+        let _rekey_guard = self.rekey_lock.read().await;
         if !self.exists(parent) {
             return Err(FsError::InodeNotFound);
         }
@@ -2022,7 +2849,8 @@ impl EncryptedFs {
         }
 
         // Only overwrite an existing directory if it's empty
-        if let Ok(Some(new_attr)) = self.find_by_name(new_parent, new_name).await {
+        let new_attr = self.find_by_name(new_parent, new_name).await.ok().flatten();
+        if let Some(new_attr) = &new_attr {
             if new_attr.kind == FileType::Directory && self.len(new_attr.ino)? > 0 {
                 return Err(FsError::NotEmpty);
             }
@@ -2032,35 +2860,65 @@ impl EncryptedFs {
             .find_by_name(parent, name)
             .await?
             .ok_or(FsError::NotFound("name not found"))?;
+        if new_attr
+            .as_ref()
+            .is_some_and(|new_attr| new_attr.ino == attr.ino)
+        {
+            // both names are hard links to the same inode, nothing to do
+            return Ok(());
+        }
+        // change all the entries in one transaction, so we don't lose the file if we crash
         // remove from parent contents
-        self.remove_directory_entry(parent, name).await?;
-        // remove from new_parent contents, if exists
-        if self.exists_by_name(new_parent, new_name)? {
-            self.remove_directory_entry(new_parent, new_name).await?;
+        let mut ops = self.remove_directory_entry_ops(parent, name).await?;
+        // remove from new_parent contents, if exists, with the link to the replaced inode
+        let replaced =
+            new_attr.filter(|_| self.exists_by_name(new_parent, new_name).unwrap_or(false));
+        let replaced_locks = replaced.map(|new_attr| self.inode_locks(new_attr.ino));
+        let _replaced_guards = if let Some(locks) = &replaced_locks {
+            Some(locks.lock().await)
+        } else {
+            None
+        };
+        if let Some(new_attr) = replaced {
+            ops.extend(
+                self.remove_directory_entry_ops(new_parent, new_name)
+                    .await?,
+            );
+            if new_attr.kind == FileType::Directory {
+                // it's empty
+                ops.push(JournalOp::RemoveInode { ino: new_attr.ino });
+            } else {
+                ops.extend(self.unlink_inode_ops(new_attr.ino).await?);
+            }
         }
         // add to new parent contents
-        self.insert_directory_entry(
-            new_parent,
-            &DirectoryEntry {
-                ino: attr.ino,
-                name: new_name.clone(),
-                kind: attr.kind,
-            },
-        )
-        .await?;
-
-        if attr.kind == FileType::Directory {
-            // add the parent link to the new directory
-            self.insert_directory_entry(
-                attr.ino,
+        ops.extend(
+            self.insert_directory_entry_ops(
+                new_parent,
                 &DirectoryEntry {
-                    ino: new_parent,
-                    name: SecretBox::new(Box::new("$..".to_owned())),
-                    kind: FileType::Directory,
+                    ino: attr.ino,
+                    name: new_name.clone(),
+                    kind: attr.kind,
                 },
             )
-            .await?;
+            .await?,
+        );
+        if attr.kind == FileType::Directory {
+            // add the parent link to the new directory
+            ops.extend(
+                self.insert_directory_entry_ops(
+                    attr.ino,
+                    &DirectoryEntry {
+                        ino: new_parent,
+                        name: SecretBox::new(Box::new("$..".to_owned())),
+                        kind: FileType::Directory,
+                    },
+                )
+                .await?,
+            );
         }
+        self.journal(ops).await?;
+        drop(_replaced_guards);
 
         let now = SystemTime::now();
         let set_attr = SetFileAttr::default()
@@ -2081,6 +2939,33 @@ impl EncryptedFs {
         Ok(())
     }
 
+    /// Get the stats of the underlying filesystem of the data dir.
+    ///
+    /// Space is reported as plaintext capacity, without the nonce and tag we add to each encrypted block.
+    /// The same overhead is subtracted from the max name length.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::cast_possible_truncation)]
+    #[cfg(unix)]
+    pub fn statfs(&self) -> FsResult<StatFs> {
+        let stat = fs_util::statvfs(&self.data_dir)?;
+        let ciphertext_block_size = (NONCE_LEN + BLOCK_SIZE + self.cipher.tag_len()) as u128;
+        let to_plaintext =
+            |blocks: u64| (u128::from(blocks) * BLOCK_SIZE as u128 / ciphertext_block_size) as u64;
+        #[allow(clippy::useless_conversion)]
+        Ok(StatFs {
+            blocks: to_plaintext(stat.f_blocks.into()),
+            bfree: to_plaintext(stat.f_bfree.into()),
+            bavail: to_plaintext(stat.f_bavail.into()),
+            files: stat.f_files.into(),
+            ffree: stat.f_ffree.into(),
+            bsize: stat.f_bsize as u32,
+            // names are stored encrypted and base64 encoded
+            namelen: ((stat.f_namemax as usize * 3 / 4)
+                .saturating_sub(NONCE_LEN + self.cipher.tag_len())) as u32,
+            frsize: stat.f_frsize as u32,
+        })
+    }
+
     /// Create a crypto writer using internal encryption info.
     pub async fn create_write<W: CryptoInnerWriter + Seek + Send + Sync + 'static>(
         &self,
@@ -2105,16 +2990,57 @@ impl EncryptedFs {
         ))
     }
 
+    /// Create a store to change the blocks in place using internal encryption info.
+    pub async fn create_block_store<F: BlockStorage>(&self, file: F) -> FsResult<BlockStore<F>> {
+        Ok(self.block_store_with_key(file, &*self.key.get().await?)?)
+    }
+
+    /// Reader for the records we store, they might not have the header in older formats. They
+    /// never had holes, only the contents of the files.
+    fn read_with_key<R: Read + Send + Sync>(
+        &self,
+        reader: R,
+        key: &SecretVec<u8>,
+    ) -> impl CryptoRead<R> {
+        let legacy = match self.legacy_content {
+            Legacy::ZeroedHoles => Legacy::None,
+            legacy => legacy,
+        };
+        crypto::create_read_compat(reader, self.cipher, key, legacy)
+    }
+
+    /// Reader for the contents of the files, they might not have the header or the map of the
+    /// holes in older formats.
+    fn read_content_with_key<R: Read + Send + Sync>(
+        &self,
+        reader: R,
+        key: &SecretVec<u8>,
+    ) -> impl CryptoRead<R> {
+        crypto::create_read_compat(reader, self.cipher, key, self.legacy_content)
+    }
+
+    fn read_seek_with_key<R: Read + Seek + Send + Sync>(
+        &self,
+        reader: R,
+        key: &SecretVec<u8>,
+    ) -> impl CryptoReadSeek<R> {
+        crypto::create_read_seek_compat(reader, self.cipher, key, self.legacy_content)
+    }
+
+    fn block_store_with_key<F: BlockStorage>(
+        &self,
+        file: F,
+        key: &SecretVec<u8>,
+    ) -> io::Result<BlockStore<F>> {
+        crypto::create_block_store_compat(file, self.cipher, key, self.legacy_content)
+    }
+
     /// Create a crypto reader using internal encryption info.
     pub async fn create_read<R: Read + Send + Sync>(
         &self,
         reader: R,
     ) -> FsResult<impl CryptoRead<R>> {
-        Ok(crypto::create_read(
-            reader,
-            self.cipher,
-            &*self.key.get().await?,
-        ))
+        Ok(self.read_content_with_key(reader, &*self.key.get().await?))
     }
 
     /// Create a crypto reader with seek using internal encryption info.
@@ -2122,11 +3048,83 @@ impl EncryptedFs {
         &self,
         reader: R,
     ) -> FsResult<impl CryptoReadSeek<R>> {
-        Ok(crypto::create_read_seek(
-            reader,
-            self.cipher,
-            &*self.key.get().await?,
-        ))
+        Ok(self.read_seek_with_key(reader, &*self.key.get().await?))
+    }
+
+    /// Create a new filesystem in `data_dir`, which needs to be missing or empty.
+    ///
+    /// The key is derived from the password with `kdf`.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn init(
+        data_dir: &Path,
+        password: SecretString,
+        cipher: Cipher,
+        kdf: KdfParams,
+    ) -> FsResult<()> {
+        if Self::is_initialized(data_dir) {
+            return Err(FsError::DataDirAlreadyInitialized);
+        }
+        if data_dir.exists() && fs::read_dir(data_dir)?.next().is_some() {
+            return Err(FsError::NotEmpty);
+        }
+        ensure_structure_created(&data_dir.to_path_buf()).await?;
+        let volume = VolumeMetadata {
+            kdf,
+            ..VolumeMetadata::new(cipher)
+        };
+        create_volume(data_dir, &password, &volume)
+    }
+
+    /// If `data_dir` holds a filesystem, created with [`Self::init`].
+    #[must_use]
+    pub fn is_initialized(data_dir: &Path) -> bool {
+        KeySlots::path(data_dir).is_file()
+            || data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME).is_file()
+    }
+
+    /// List the key slots, each of them has a password which can unlock the filesystem.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn list_key_slots(data_dir: &Path) -> FsResult<Vec<KeySlot>> {
+        Ok(read_key_slots(data_dir)?.slots().to_vec())
+    }
+
+    /// Add a key slot for `new_password`, `password` needs to unlock one of the existing slots.
+    ///
+    /// Returns the id of the new slot.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn add_key_slot(
+        data_dir: &Path,
+        password: SecretString,
+        new_password: SecretString,
+        cipher: Cipher,
+        kdf: KdfParams,
+    ) -> FsResult<u32> {
+        check_structure(data_dir, false).await?;
+        let _lock = lock_key_slots(data_dir)?;
+        let cipher = VolumeMetadata::read(data_dir)?.map_or(cipher, |v| v.cipher);
+        let mut key_slots = read_key_slots(data_dir)?;
+        let (_, key) = key_slots.unlock(&password, cipher)?;
+        let id = key_slots.add(&key, &new_password, cipher, kdf)?;
+        key_slots.write(data_dir)?;
+        Ok(id)
+    }
+
+    /// Remove a key slot, `password` needs to unlock one of the slots, it can be the removed one.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn remove_key_slot(
+        data_dir: &Path,
+        password: SecretString,
+        id: u32,
+        cipher: Cipher,
+    ) -> FsResult<()> {
+        check_structure(data_dir, false).await?;
+        let _lock = lock_key_slots(data_dir)?;
+        let cipher = VolumeMetadata::read(data_dir)?.map_or(cipher, |v| v.cipher);
+        let mut key_slots = read_key_slots(data_dir)?;
+        key_slots.unlock(&password, cipher)?;
+        key_slots.remove(id)?;
+        key_slots.write(data_dir)?;
+        Ok(())
     }
 
     /// Change the password of the filesystem used to access the encryption key.
@@ -2135,26 +3133,48 @@ impl EncryptedFs {
         old_password: SecretString,
         new_password: SecretString,
         cipher: Cipher,
+    ) -> FsResult<()> {
+        Self::passwd_with_kdf(data_dir, old_password, new_password, cipher, None).await
+    }
+
+    /// Like [`Self::passwd`], but if `kdf` is set the key is derived from the new password with
+    /// these params from now on, which is how the cost of an existing data dir can be raised.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn passwd_with_kdf(
+        data_dir: &Path,
+        old_password: SecretString,
+        new_password: SecretString,
+        cipher: Cipher,
+        kdf: Option<KdfParams>,
     ) -> FsResult<()> {
         check_structure(data_dir, false).await?;
-        // decrypt key
-        let salt: Vec<u8> = bincode::deserialize_from(File::open(
-            data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
-        )?)?;
-        let initial_key = crypto::derive_key(&old_password, cipher, &salt)?;
-        let enc_file = data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME);
-        let reader = crypto::create_read(File::open(enc_file)?, cipher, &initial_key);
-        let key: Vec<u8> =
-            bincode::deserialize_from(reader).map_err(|_| FsError::InvalidPassword)?;
-        let key = SecretBox::new(Box::new(key));
-        // encrypt it with a new key derived from new password
-        let new_key = crypto::derive_key(&new_password, cipher, &salt)?;
-        crypto::atomic_serialize_encrypt_into(
-            &data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME),
-            &*key.expose_secret(),
-            cipher,
-            &new_key,
-        )?;
+        let _lock = lock_key_slots(data_dir)?;
+        let mut volume =
+            VolumeMetadata::read(data_dir)?.unwrap_or_else(|| VolumeMetadata::legacy(cipher));
+        let cipher = volume.cipher;
+        let mut key_slots = if let Some(key_slots) = KeySlots::read(data_dir)? {
+            key_slots
+        } else {
+            // the master key is still in `key.enc`, we move it to a key slot first, so the new
+            // password is saved with one atomic write and a crash cannot lock us out
+            let (_, key) =
+                KeySlots::from_legacy(data_dir, volume.kdf)?.unlock(&old_password, cipher)?;
+            VolumeMetadata::check_mac(data_dir, &key)?;
+            // a rekey needs the key slots, so it cannot be in progress
+            upgrade_volume(data_dir, &mut volume, &key, None)?;
+            KeySlots::read(data_dir)?.ok_or(FsError::InvalidDataDirStructure)?
+        };
+        // change it only in the slot the old password unlocks
+        let (id, key) = key_slots.unlock(&old_password, cipher)?;
+        let kdf = kdf.unwrap_or_else(|| {
+            key_slots
+                .slots()
+                .iter()
+                .find(|s| s.id == id)
+                .map_or(volume.kdf, |s| s.kdf)
+        });
+        key_slots.replace(id, &key, &new_password, cipher, kdf)?;
+        key_slots.write(data_dir)?;
         Ok(())
     }
 
@@ -2190,7 +3210,8 @@ impl EncryptedFs {
                 self.set_attr(ino, set_attr).await?;
                 let attr = self.get_inode_from_storage(ino).await?;
                 let mut ctx = guard.get(handle).unwrap().lock().await;
-                let reader = self.create_read_seek(File::open(&path)?).await?;
+                let reader =
+                    self.read_seek_with_key(File::open(&path)?, &*self.content_key(ino).await?);
                 ctx.reader = Some(Box::new(reader));
                 ctx.attr = attr.into();
             }
@@ -2198,18 +3219,21 @@ impl EncryptedFs {
 
         // write
         let lock = self.opened_files_for_write.read().await;
-        if let Some(fh) = lock.get(&ino) {
+        if let Some(fhs) = lock.get(&ino) {
             if let Some(handle) = skip_write_fh {
-                if *fh == handle {
+                // they all share the writer
+                if fhs.contains(&handle) {
                     return Ok(());
                 }
             }
+            let Some(fh) = fhs.iter().next() else {
+                return Ok(());
+            };
             let lock = self.write_handles.read().await;
             if let Some(lock) = lock.get(fh) {
                 let mut ctx = lock.lock().await;
-                let writer = ctx.writer.as_mut().unwrap();
-                let file = writer.finish()?;
-                file.sync_all()?;
+                ctx.store.flush()?;
+                ctx.store.get_ref().sync_all()?;
                 File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
                 let set_attr: Option<SetFileAttr> = if save_attr {
                     Some(ctx.attr.clone().into())
@@ -2220,11 +3244,7 @@ impl EncryptedFs {
                 if let Some(set_attr) = set_attr {
                     self.set_attr(ino, set_attr).await?;
                 }
-                let writer = self
-                    .create_write_seek(OpenOptions::new().read(true).write(true).open(&path)?)
-                    .await?;
                 let mut ctx = lock.lock().await;
-                ctx.writer = Some(Box::new(writer));
                 let attr = self.get_inode_from_storage(ino).await?;
                 ctx.attr = attr.into();
             }
@@ -2244,7 +3264,13 @@ impl EncryptedFs {
         match op {
             ReadHandleContextOperation::Create { ino } => {
                 let attr: TimesFileAttr = attr.into();
-                let reader = self.create_read_seek(File::open(&path)?).await?;
+                let lock = self
+                    .read_write_locks
+                    .get_or_insert_with(ino, || RwLock::new(false));
+                // so the contents are not re-encrypted meanwhile
+                let _read_guard = lock.read().await;
+                let reader =
+                    self.read_seek_with_key(File::open(&path)?, &*self.content_key(ino).await?);
                 let ctx = ReadHandleContext {
                     ino,
                     attr,
@@ -2274,23 +3300,34 @@ impl EncryptedFs {
         let path = self.contents_path(ino);
         match op {
             WriteHandleContextOperation::Create { ino } => {
-                let attr = self.get_attr(ino).await?.into();
-                let writer = self
-                    .create_write_seek(OpenOptions::new().read(true).write(true).open(&path)?)
-                    .await?;
-                let ctx = WriteHandleContext {
-                    ino,
-                    attr,
-                    writer: Some(Box::new(writer)),
+                let lock = self
+                    .read_write_locks
+                    .get_or_insert_with(ino, || RwLock::new(false));
+                // so the last handle is not released meanwhile, and the writes don't change the file
+                let _write_guard = lock.write().await;
+                let ctx = if let Some(fh) = self.any_write_handle(ino).await {
+                    // share it, so writes from all handles go through the same writer
+                    self.write_handles.read().await.get(&fh).cloned()
+                } else {
+                    None
                 };
-                self.write_handles
-                    .write()
-                    .await
-                    .insert(handle, Mutex::new(ctx));
+                let ctx = if let Some(ctx) = ctx {
+                    ctx
+                } else {
+                    let attr = self.get_attr(ino).await?.into();
+                    let store = self.block_store_with_key(
+                        OpenOptions::new().read(true).write(true).open(&path)?,
+                        &*self.content_key(ino).await?,
+                    )?;
+                    Arc::new(Mutex::new(WriteHandleContext { ino, attr, store }))
+                };
+                self.write_handles.write().await.insert(handle, ctx);
                 self.opened_files_for_write
                     .write()
                     .await
-                    .insert(ino, handle);
+                    .entry(ino)
+                    .or_insert_with(HashSet::new)
+                    .insert(handle);
             }
         }
 
@@ -2337,80 +3374,9 @@ impl EncryptedFs {
         Ok(())
     }
 
-    async fn insert_directory_entry(
-        &self,
-        ino_contents_dir: u64,
-        entry: &DirectoryEntry,
-    ) -> FsResult<()> {
-        let parent_path = self.contents_path(ino_contents_dir);
-        let encrypted_name =
-            crypto::encrypt_file_name(&entry.name, self.cipher, &*self.key.get().await?)?;
-        // add to LS directory
-        let self_clone = self
-            .self_weak
-            .lock()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .upgrade()
-            .unwrap();
-        let parent_path_clone = parent_path.clone();
-        let encrypted_name_clone = encrypted_name.clone();
-        let entry_clone = entry.clone();
-        // spawn a task to do concurrently with adding to HASH directory
-        let h = tokio::spawn(async move {
-            let file_path = parent_path_clone
-                .join(LS_DIR)
-                .join(encrypted_name_clone.clone());
-            let lock = self_clone
-                .serialize_dir_entries_ls_locks
-                .get_or_insert_with(file_path.to_str().unwrap().to_owned(), || {
-                    RwLock::new(false)
-                });
-            let _guard = lock.write().await;
-            // write inode and file type
-            let entry = (entry_clone.ino, entry_clone.kind);
-            crypto::atomic_serialize_encrypt_into(
-                &file_path,
-                &entry,
-                self_clone.cipher,
-                &*self_clone.key.get().await?,
-            )?;
-            Ok::<(), FsError>(())
-        });
-        // add to HASH directory
-        let self_clone = self
-            .self_weak
-            .lock()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .upgrade()
-            .unwrap();
-        let entry_hash = entry.clone();
-        tokio::spawn(async move {
-            let name = crypto::hash_file_name(&entry_hash.name);
-            let file_path = parent_path.join(HASH_DIR).join(name);
-            let lock = self_clone
-                .serialize_dir_entries_hash_locks
-                .get_or_insert_with(file_path.to_str().unwrap().to_owned(), || {
-                    RwLock::new(false)
-                });
-            let _guard = lock.write().await;
-            // write inode and file type
-            // we save the encrypted name also because we need it to remove the entry on [`remove_directory_entry`]
-            let entry = (entry_hash.ino, entry_hash.kind, encrypted_name);
-            crypto::atomic_serialize_encrypt_into(
-                &file_path,
-                &entry,
-                self_clone.cipher,
-                &*self_clone.key.get().await?,
-            )?;
-            Ok::<(), FsError>(())
-        })
-        .await??;
-        h.await??;
-        Ok(())
+    async fn insert_directory_entry(&self, parent: u64, entry: &DirectoryEntry) -> FsResult<()> {
+        self.journal(self.insert_directory_entry_ops(parent, entry).await?)
+            .await
     }
 
     fn ino_file(&self, ino: u64) -> PathBuf {
@@ -2421,31 +3387,15 @@ impl EncryptedFs {
         self.data_dir.join(CONTENTS_DIR).join(ino.to_string())
     }
 
-    async fn remove_directory_entry(&self, parent: u64, name: &SecretString) -> FsResult<()> {
-        let parent_path = self.contents_path(parent);
-        // remove from HASH
-        let name = crypto::hash_file_name(name);
-        let path = parent_path.join(HASH_DIR).join(name);
-        let lock = self
-            .serialize_dir_entries_hash_locks
-            .get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
-        let guard = lock.write().await;
-        let (_, _, name): (u64, FileType, String) =
-            bincode::deserialize_from(crypto::create_read(
-                File::open(path.clone())?,
-                self.cipher,
-                &*self.key.get().await?,
-            ))?;
-        fs::remove_file(path)?;
-        drop(guard);
-        // remove from LS
-        let path = parent_path.join(LS_DIR).join(name);
-        let lock = self
-            .serialize_dir_entries_ls_locks
-            .get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
-        let _guard = lock.write().await;
-        fs::remove_file(path)?;
-        Ok(())
+    fn hash_file_name(&self, name: &SecretString) -> String {
+        self.name_hash_key.as_ref().map_or_else(
+            || crypto::legacy_hash_file_name(name),
+            |hash_key| crypto::hash_file_name(name, hash_key),
+        )
+    }
+
+    fn xattrs_path(&self, ino: u64) -> PathBuf {
+        self.data_dir.join(XATTRS_DIR).join(ino.to_string())
     }
 
     fn generate_next_inode(&self) -> u64 {
@@ -2463,6 +3413,60 @@ impl EncryptedFs {
         }
     }
 }
+/// Entries in `ls/` with their offsets in the listing of the directory, in that order.
+///
+/// The offset is the same as long as the entry exists, `.` and `..` come first. Others are a hash
+/// of the encrypted name, in 63 bits so it's a valid `off_t`. Two names with the same hash are
+/// ordered by name and the next one gets the following offset, so all of them are listed.
+fn ls_entries_with_offsets(ls_dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(ls_dir)? {
+        let path = entry?.path();
+        let offset = match path.file_name().unwrap_or_default().to_str() {
+            Some("$.") => 1,
+            Some("$..") => 2,
+            _ => {
+                let hash = blake3::hash(path.file_name().unwrap_or_default().as_encoded_bytes());
+                let offset = u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap());
+                (offset >> 1).max(3)
+            }
+        };
+        entries.push((offset, path));
+    }
+    make_offsets_unique(&mut entries);
+    Ok(entries)
+}
+
+/// Sort by the offset and then by the name, the ones with the same offset as the one before get
+/// the next one.
+fn make_offsets_unique(entries: &mut [(u64, PathBuf)]) {
+    entries.sort_unstable();
+    let mut last = 0;
+    for (offset, _) in entries {
+        *offset = (*offset).max(last + 1);
+        last = *offset;
+    }
+}
+
+/// An entry that was removed since we listed the directory is skipped.
+fn skip_removed_entry<T>(res: FsResult<T>) -> Option<FsResult<T>> {
+    match res {
+        Err(FsError::Io { source, .. }) if source.kind() == io::ErrorKind::NotFound => None,
+        res => Some(res),
+    }
+}
+
+/// What [`EncryptedFs::fallocate`] does, like the `mode` of `fallocate(2)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FallocateMode {
+    /// Reserve space for the range, the file is extended if it ends after it, unless `keep_size`
+    Allocate { keep_size: bool },
+    /// Deallocate the range, it reads as zeros after, the size doesn't change
+    PunchHole,
+    /// Zero the range and reserve space for it, the file is extended like with `Allocate`
+    ZeroRange { keep_size: bool },
+}
+
 pub struct CopyFileRangeReq {
     src_ino: u64,
     src_offset: u64,
@@ -2494,59 +3498,80 @@ impl CopyFileRangeReq {
     }
 }
 
-fn read_or_create_key(
-    key_path: &PathBuf,
-    salt_path: &PathBuf,
+/// Read the master key from `key.enc`, used before we had key slots.
+fn read_legacy_key(
+    key_path: &Path,
+    salt_path: &Path,
     password: &SecretString,
     cipher: Cipher,
+    kdf: &KdfParams,
 ) -> FsResult<SecretVec<u8>> {
-    let salt = if salt_path.exists() {
-        bincode::deserialize_from(File::open(salt_path)?).map_err(|_| FsError::InvalidPassword)?
-    } else {
-        let mut salt = vec![0; 16];
-        crypto::create_rng().fill_bytes(&mut salt);
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(salt_path)?;
-        bincode::serialize_into(&mut file, &salt)?;
-        file.flush()?;
-        file.sync_all()?;
-        File::open(salt_path.parent().expect("oops, we don't have a parent"))?.sync_all()?;
-        salt
-    };
+    let salt: Vec<u8> =
+        bincode::deserialize_from(File::open(salt_path)?).map_err(|_| FsError::InvalidPassword)?;
     // derive key from password
-    let derived_key = crypto::derive_key(password, cipher, &salt)?;
-    if key_path.exists() {
-        // read key
-        let reader = crypto::create_read(File::open(key_path)?, cipher, &derived_key);
-        let key: Vec<u8> =
-            bincode::deserialize_from(reader).map_err(|_| FsError::InvalidPassword)?;
-        Ok(SecretBox::new(Box::new(key)))
-    } else {
-        // first time, create a random key and encrypt it with the derived key from password
-        let mut key: Vec<u8> = vec![];
-        let key_len = cipher.key_len();
-        key.resize(key_len, 0);
-        crypto::create_rng().fill_bytes(&mut key);
-        let mut writer = crypto::create_write(
-            OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(key_path)?,
-            cipher,
-            &derived_key,
-        );
-        bincode::serialize_into(&mut writer, &key)?;
-        let file = writer.finish()?;
-        file.sync_all()?;
-        File::open(key_path.parent().unwrap())?.sync_all()?;
-        Ok(SecretBox::new(Box::new(key)))
+    let derived_key = crypto::derive_key_with_params(password, cipher, &salt, kdf)?;
+    // written by older versions without the header
+    let reader = crypto::create_read_compat(
+        File::open(key_path)?,
+        cipher,
+        &derived_key,
+        Legacy::WithoutHeader,
+    );
+    let key: Vec<u8> = bincode::deserialize_from(reader).map_err(|_| FsError::InvalidPassword)?;
+    Ok(SecretBox::new(Box::new(key)))
+}
+
+/// Create a random master key and the first key slot for it.
+/// Write the metadata and the first key slot with a new master key.
+///
+/// The key slots are written last, until then the data dir is not initialized.
+fn create_volume(
+    data_dir: &Path,
+    password: &SecretString,
+    volume: &VolumeMetadata,
+) -> FsResult<()> {
+    let mut key: Vec<u8> = vec![0; volume.cipher.key_len()];
+    crypto::create_rng().fill_bytes(&mut key);
+    let key = SecretBox::new(Box::new(key));
+    volume.write(data_dir, &key)?;
+    let mut key_slots = KeySlots::default();
+    key_slots.add(&key, password, volume.cipher, volume.kdf)?;
+    key_slots.write(data_dir)
+}
+
+fn read_key_slots(data_dir: &Path) -> FsResult<KeySlots> {
+    if !EncryptedFs::is_initialized(data_dir) {
+        return Err(FsError::InvalidDataDirStructure);
+    }
+    KeySlots::read(data_dir)?.ok_or(FsError::Other(
+        "data dir doesn't have key slots, mount it in read-write mode to upgrade it",
+    ))
+}
+
+/// Lock the data dir to change the key slots. Not while a rekey is in progress, it replaces them
+/// when it's done.
+fn lock_key_slots(data_dir: &Path) -> FsResult<File> {
+    let lock = lock_data_dir(data_dir)?;
+    if RekeyState::exists(data_dir) {
+        return Err(FsError::Other("rekey in progress, finish it first"));
+    }
+    Ok(lock)
+}
+
+/// So two of us don't change the data dir at the same time, like when it's mounted and we rekey or
+/// import into it.
+pub(crate) fn lock_data_dir(data_dir: &Path) -> FsResult<File> {
+    let file = File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(data_dir.join(SECURITY_DIR).join(LOCK_FILENAME))?;
+    if !fs_util::try_lock_exclusive(&file)? {
+        return Err(FsError::Other(
+            "data dir is already opened in read-write mode",
+        ));
     }
+    Ok(file)
 }
 
 async fn ensure_structure_created(data_dir: &PathBuf) -> FsResult<()> {
@@ -2557,7 +3582,7 @@ async fn ensure_structure_created(data_dir: &PathBuf) -> FsResult<()> {
     }
 
     // create directories
-    let dirs = vec![INODES_DIR, CONTENTS_DIR, SECURITY_DIR];
+    let dirs = vec![INODES_DIR, CONTENTS_DIR, SECURITY_DIR, XATTRS_DIR];
     for dir in dirs {
         let path = data_dir.join(dir);
         if !path.exists() {
@@ -2568,6 +3593,302 @@ async fn ensure_structure_created(data_dir: &PathBuf) -> FsResult<()> {
     Ok(())
 }
 
+/// Upgrade the data dir to [`CURRENT_FORMAT_VERSION`], one version at a time.
+///
+/// After each step the new version is saved, so an interrupted upgrade continues from there.
+///
+/// While a rekey is in progress, what was not re-encrypted yet is still with `old_key`.
+fn upgrade_volume(
+    data_dir: &Path,
+    volume: &mut VolumeMetadata,
+    key: &SecretVec<u8>,
+    old_key: Option<&SecretVec<u8>>,
+) -> FsResult<()> {
+    let from_version = volume.format_version;
+    while volume.format_version < CURRENT_FORMAT_VERSION {
+        info!(
+            "upgrading data dir from format version {}",
+            volume.format_version
+        );
+        match volume.format_version {
+            1 => {
+                let hash_key = crypto::derive_subkey(key, crypto::FILE_NAME_HASH_KEY_CONTEXT);
+                migrate_to_keyed_name_hashes(data_dir, volume.cipher, key, &hash_key)?;
+            }
+            2 => migrate_to_key_slots(data_dir, volume.kdf)?,
+            3 => migrate_to_headers(data_dir, volume.cipher, key, old_key)?,
+            4 => migrate_to_holes_maps(data_dir, volume.cipher, key, old_key, from_version == 4)?,
+            version => return Err(FsError::UnsupportedFormatVersion(version)),
+        }
+        volume.format_version += 1;
+        volume.write(data_dir, key)?;
+    }
+    Ok(())
+}
+
+/// Move the master key from `key.enc` to the first key slot, it's still encrypted the same way.
+///
+/// It's idempotent, the old files are removed only after the slots are saved.
+fn migrate_to_key_slots(data_dir: &Path, kdf: KdfParams) -> FsResult<()> {
+    if !KeySlots::path(data_dir).is_file() {
+        KeySlots::from_legacy(data_dir, kdf)?.write(data_dir)?;
+    }
+    for file in [KEY_ENC_FILENAME, KEY_SALT_FILENAME] {
+        let path = data_dir.join(SECURITY_DIR).join(file);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    File::open(data_dir.join(SECURITY_DIR))?.sync_all()?;
+    Ok(())
+}
+
+/// Rewrite with the header what older versions wrote without it, the inodes, extended attributes,
+/// contents and directory entries.
+///
+/// It's idempotent, each file is replaced atomically.
+fn migrate_to_headers(
+    data_dir: &Path,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    old_key: Option<&SecretVec<u8>>,
+) -> FsResult<()> {
+    let mut migrated = 0_u64;
+    let mut dirs = vec![data_dir.join(INODES_DIR), data_dir.join(XATTRS_DIR)];
+    for entry in fs::read_dir(data_dir.join(CONTENTS_DIR))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            dirs.push(path.join(LS_DIR));
+            dirs.push(path.join(HASH_DIR));
+        } else if add_header(&path, cipher, key, old_key)? {
+            migrated += 1;
+        }
+    }
+    File::open(data_dir.join(CONTENTS_DIR))?.sync_all()?;
+    for dir in dirs {
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&dir)? {
+            if add_header(&entry?.path(), cipher, key, old_key)? {
+                migrated += 1;
+            }
+        }
+        File::open(&dir)?.sync_all()?;
+    }
+    if migrated > 0 {
+        info!("added the header to {migrated} files");
+    }
+    Ok(())
+}
+
+/// Returns `false` if the file has the header already or it's empty.
+fn add_header(
+    path: &Path,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    old_key: Option<&SecretVec<u8>>,
+) -> FsResult<bool> {
+    let mut buf = vec![0; HEADER_LEN];
+    let len = stream_util::read(&mut File::open(path)?, &mut buf)?;
+    if len == 0 || header::decode(&buf[..len]).is_some() {
+        return Ok(false);
+    }
+    // it keeps the key it has, the rekey re-encrypts it later
+    for key in std::iter::once(key).chain(old_key) {
+        let mut reader =
+            crypto::create_read_compat(File::open(path)?, cipher, key, Legacy::WithoutHeader);
+        let mut writer = crypto::create_write(fs_util::open_atomic_write(path)?, cipher, key);
+        if io::copy(&mut reader, &mut writer).is_ok() {
+            writer.finish()?.commit()?;
+            return Ok(true);
+        }
+    }
+    error!(path = %path.display(), "cannot decrypt");
+    Err(FsError::Other("cannot decrypt content without header"))
+}
+
+/// Rewrite the contents with the map of the holes in the header, the zeroed blocks older versions
+/// wrote for the holes are recorded in it.
+///
+/// It's idempotent, each file is replaced atomically.
+fn migrate_to_holes_maps(
+    data_dir: &Path,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    old_key: Option<&SecretVec<u8>>,
+    zeroed_holes: bool,
+) -> FsResult<()> {
+    let mut migrated = 0_u64;
+    let contents_dir = data_dir.join(CONTENTS_DIR);
+    // only the writer of format version 4 wrote the holes as zeroed blocks, if we upgraded from
+    // an older version they are just zeroed blocks
+    let legacy = if zeroed_holes {
+        Legacy::ZeroedHoles
+    } else {
+        Legacy::None
+    };
+    let sparse_block = if zeroed_holes {
+        sparse_block_size(&contents_dir)?
+    } else {
+        None
+    };
+    for ino in fsck::list_inos(&contents_dir)? {
+        if contents_dir.join(ino.to_string()).is_file()
+            && add_holes_map(
+                &contents_dir,
+                ino,
+                cipher,
+                key,
+                old_key,
+                legacy,
+                sparse_block,
+            )?
+        {
+            migrated += 1;
+        }
+    }
+    File::open(&contents_dir)?.sync_all()?;
+    if migrated > 0 {
+        info!("added the map of the holes to {migrated} files");
+    }
+    Ok(())
+}
+
+/// Size of the blocks of the storage in `dir`, `None` if it doesn't have sparse files.
+fn sparse_block_size(dir: &Path) -> io::Result<Option<u64>> {
+    #[allow(clippy::useless_conversion)]
+    let block_size = u64::from(fs_util::statvfs(dir)?.f_bsize).max(1);
+    let mut probe = tempfile::tempfile_in(dir)?;
+    BlockStorage::set_len(&mut probe, block_size * 16)?;
+    Ok(probe.next_data(0)?.is_none().then_some(block_size))
+}
+
+/// If the writer of format version 4 could have left the zeroed block at `offset` as a hole. It
+/// never wrote the holes, so the storage blocks inside it must be a hole too, the ones at the ends
+/// can have data of the blocks around.
+fn is_format4_hole(
+    file: &mut File,
+    offset: u64,
+    len: u64,
+    sparse_block: Option<u64>,
+) -> io::Result<bool> {
+    let Some(sparse_block) = sparse_block else {
+        return Ok(true);
+    };
+    let start = offset.div_ceil(sparse_block) * sparse_block;
+    let end = (offset + len) / sparse_block * sparse_block;
+    if start >= end {
+        return Ok(true);
+    }
+    Ok(file.next_data(start)?.is_none_or(|data| data >= end))
+}
+
+/// Returns `false` if the file has the map already or it's empty.
+fn add_holes_map(
+    contents_dir: &Path,
+    ino: u64,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    old_key: Option<&SecretVec<u8>>,
+    legacy: Legacy,
+    sparse_block: Option<u64>,
+) -> FsResult<bool> {
+    let path = contents_dir.join(ino.to_string());
+    let mut buf = vec![0; HEADER_LEN];
+    let len = stream_util::read(&mut File::open(&path)?, &mut buf)?;
+    if len == 0 || header::has_holes(&buf[..len]) {
+        return Ok(false);
+    }
+    // the zeroed blocks don't depend on the key
+    let mut file = File::open(&path)?;
+    for (offset, len) in crypto::create_block_store_compat(File::open(&path)?, cipher, key, legacy)?
+        .legacy_hole_ranges()?
+    {
+        if !is_format4_hole(&mut file, offset, len, sparse_block)? {
+            error!(path = %path.display(), offset, "zeroed block that is not a hole");
+            return Err(FsError::Other(
+                "content has a zeroed block which format version 4 didn't write as a hole",
+            ));
+        }
+    }
+    // the blocks don't have the same offsets, so we write a copy and replace it
+    let tmp_path = contents_dir.join(format!(".{ino}.holes"));
+    // it keeps the key it has, the rekey re-encrypts it later
+    for key in std::iter::once(key).chain(old_key) {
+        let mut src = crypto::create_block_store_compat(File::open(&path)?, cipher, key, legacy)?;
+        let mut dst = crypto::create_block_store(
+            File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&tmp_path)?,
+            cipher,
+            key,
+        )?;
+        if src.copy_to(&mut dst).is_ok() {
+            dst.finish()?.sync_all()?;
+            fs::rename(&tmp_path, &path)?;
+            return Ok(true);
+        }
+    }
+    fs::remove_file(&tmp_path)?;
+    error!(path = %path.display(), "cannot decrypt");
+    Err(FsError::Other(
+        "cannot decrypt content without the map of the holes",
+    ))
+}
+
+/// Rename the entries in all `hash` dirs from the unkeyed hash of the name to the keyed one.
+///
+/// It's idempotent, so if interrupted it's safe to run again.
+fn migrate_to_keyed_name_hashes(
+    data_dir: &Path,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    hash_key: &SecretVec<u8>,
+) -> FsResult<()> {
+    let mut migrated = 0_u64;
+    for dir in fs::read_dir(data_dir.join(CONTENTS_DIR))? {
+        let hash_dir = dir?.path().join(HASH_DIR);
+        if !hash_dir.is_dir() {
+            // not a directory inode
+            continue;
+        }
+        for entry in fs::read_dir(&hash_dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if file_name.starts_with('$') {
+                // `.` and `..` are not hashed
+                continue;
+            }
+            // the entry keeps the encrypted name
+            let (_, _, encrypted_name): (u64, FileType, String) =
+                bincode::deserialize_from(crypto::create_read_compat(
+                    File::open(entry.path())?,
+                    cipher,
+                    key,
+                    Legacy::WithoutHeader,
+                ))?;
+            let name = crypto::decrypt_file_name(&encrypted_name, cipher, key)?;
+            let new_file_name = crypto::hash_file_name(&name, hash_key);
+            if new_file_name != file_name {
+                fs::rename(entry.path(), hash_dir.join(new_file_name))?;
+                migrated += 1;
+            }
+        }
+        File::open(&hash_dir)?.sync_all()?;
+    }
+    if migrated > 0 {
+        info!("migrated {migrated} file name hashes to keyed hashes");
+    }
+    Ok(())
+}
+
+/// Dirs which might be missing in data dirs created by older versions.
+const OPTIONAL_DIRS: [&str; 2] = [XATTRS_DIR, WAL_DIR];
+
 async fn check_structure(data_dir: &Path, ignore_empty: bool) -> FsResult<()> {
     if !data_dir.exists() || !data_dir.is_dir() {
         return Err(FsError::InvalidDataDirStructure);
@@ -2581,6 +3902,8 @@ async fn check_structure(data_dir: &Path, ignore_empty: bool) -> FsResult<()> {
     if vec.is_empty() && ignore_empty {
         return Ok(());
     }
+    // dirs added in later versions are optional, they are created when missing
+    vec.retain(|dir| !OPTIONAL_DIRS.contains(&dir.as_str()));
     if vec.len() != 3 {
         return Err(FsError::InvalidDataDirStructure);
     }
@@ -2588,19 +3911,31 @@ async fn check_structure(data_dir: &Path, ignore_empty: bool) -> FsResult<()> {
     vec.sort_unstable();
     let mut vec2 = vec![INODES_DIR, CONTENTS_DIR, SECURITY_DIR];
     vec2.sort_unstable();
-    if vec != vec2
-        || !data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME).is_file()
-        || !data_dir
+    let has_legacy_key = data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME).is_file()
+        && data_dir
             .join(SECURITY_DIR)
             .join(KEY_SALT_FILENAME)
-            .is_file()
-    {
+            .is_file();
+    if vec != vec2 || !(KeySlots::path(data_dir).is_file() || has_legacy_key) {
         return Err(FsError::InvalidDataDirStructure);
     }
 
     Ok(())
 }
 
+fn validate_xattr_name(name: &SecretString) -> FsResult<()> {
+    let len = name.expose_secret().len();
+    if len == 0 {
+        return Err(FsError::InvalidInput(
+            "extended attribute name cannot be empty",
+        ));
+    }
+    if len > XATTR_NAME_MAX {
+        return Err(FsError::InvalidInput("extended attribute name too long"));
+    }
+    Ok(())
+}
+
 fn merge_attr(attr: &mut FileAttr, set_attr: &SetFileAttr, overwrite_size: bool) {
     if let Some(size) = set_attr.size {
         if overwrite_size {
@@ -0,0 +1,442 @@
+use std::any::Any;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use argon2::password_hash::rand_core::RngCore;
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use shush_rs::{ExposeSecret, SecretString};
+use tokio::sync::Mutex;
+use tracing::{debug, info};
+
+use crate::crypto::write::{CryptoInnerWriter, CryptoWrite, WriteSeekRead};
+use crate::crypto::{self, Cipher, KdfParams};
+use crate::encryptedfs::{
+    CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsResult, SetXattrMode,
+};
+
+const MAGIC: [u8; 8] = *b"RENCFSAR";
+const VERSION: u32 = 2;
+const CHUNK_SIZE: usize = 64 * 1024;
+/// The archive might be changed, so we don't trust the lengths in it when allocating
+const MAX_RECORD_LEN: u64 = 16 * 1024 * 1024;
+
+/// Unencrypted start of the archive, we need it to derive the key.
+///
+/// It's repeated as the first encrypted record, so changing it is detected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ArchiveHeader {
+    magic: [u8; 8],
+    version: u32,
+    cipher: Cipher,
+    kdf: KdfParams,
+    salt: [u8; 32],
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArchiveAttr {
+    perm: u16,
+    uid: u32,
+    gid: u32,
+    atime: SystemTime,
+    mtime: SystemTime,
+    flags: u32,
+    xattrs: Vec<(String, Vec<u8>)>,
+}
+
+/// Records after the header, paths are relative to the root and each directory comes before its
+/// entries.
+#[derive(Serialize, Deserialize)]
+enum ArchiveEntry {
+    Dir {
+        path: String,
+        attr: ArchiveAttr,
+    },
+    /// Followed by `size` bytes of content
+    File {
+        path: String,
+        attr: ArchiveAttr,
+        size: u64,
+    },
+    Symlink {
+        path: String,
+        attr: ArchiveAttr,
+        target: String,
+    },
+    /// So we know the archive is not truncated, the blocks are authenticated with their index
+    End {
+        entries: u64,
+    },
+}
+
+/// Hides [`Seek`](std::io::Seek) from the crypto writer, which would otherwise write its header
+/// at the start of `out`, over ours.
+struct Appending<W>(W);
+
+impl<W: Write> Write for Appending<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write + 'static> CryptoInnerWriter for Appending<W> {
+    fn into_any(self) -> Box<dyn Any> {
+        Box::new(self)
+    }
+
+    fn as_write(&mut self) -> Option<&mut dyn Write> {
+        Some(self)
+    }
+
+    fn as_write_seek_read(&mut self) -> Option<&mut dyn WriteSeekRead> {
+        None
+    }
+}
+
+/// Copies what's read from `input` to `out`.
+struct Tee<R, W> {
+    input: R,
+    out: W,
+}
+
+impl<R: Read, W: Write> Read for Tee<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.input.read(buf)?;
+        self.out.write_all(&buf[..len])?;
+        Ok(len)
+    }
+}
+
+impl EncryptedFs {
+    /// Write the whole tree to a portable archive, encrypted with a key derived from `password`,
+    /// independent of the volume key and of the inode numbers.
+    ///
+    /// The content is in the same format as the files in the data dir, each block is
+    /// authenticated. Hard links are exported as separate files.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn export_archive<W: Write + Send + Sync + 'static>(
+        &self,
+        mut out: W,
+        password: &SecretString,
+        kdf: KdfParams,
+    ) -> FsResult<W> {
+        let mut salt = [0; 32];
+        crypto::create_rng().fill_bytes(&mut salt);
+        let header = ArchiveHeader {
+            magic: MAGIC,
+            version: VERSION,
+            cipher: self.cipher,
+            kdf,
+            salt,
+        };
+        bincode::serialize_into(&mut out, &header)?;
+        let key = crypto::derive_key_with_params(password, self.cipher, &salt, &kdf)?;
+        let mut crypto_writer = crypto::create_write(Appending(out), self.cipher, &key);
+        let mut writer = &mut crypto_writer;
+        bincode::serialize_into(&mut writer, &header)?;
+
+        let mut entries = 0;
+        for (path, attr) in self.path().walk("/").await? {
+            let path = path.to_str().unwrap().to_owned();
+            debug!(path, "exporting");
+            let archive_attr = self.archive_attr(&attr).await?;
+            match attr.kind {
+                FileType::Directory => {
+                    bincode::serialize_into(
+                        &mut writer,
+                        &ArchiveEntry::Dir {
+                            path,
+                            attr: archive_attr,
+                        },
+                    )?;
+                }
+                FileType::RegularFile => {
+                    bincode::serialize_into(
+                        &mut writer,
+                        &ArchiveEntry::File {
+                            path,
+                            attr: archive_attr,
+                            size: attr.size,
+                        },
+                    )?;
+                    self.export_content(&attr, &mut writer).await?;
+                }
+                FileType::Symlink => {
+                    bincode::serialize_into(
+                        &mut writer,
+                        &ArchiveEntry::Symlink {
+                            path,
+                            attr: archive_attr,
+                            target: self.read_link(attr.ino).await?.expose_secret().clone(),
+                        },
+                    )?;
+                }
+            }
+            entries += 1;
+        }
+        bincode::serialize_into(&mut writer, &ArchiveEntry::End { entries })?;
+        info!(entries, "exported");
+        Ok(crypto_writer.finish()?.0)
+    }
+
+    /// Recreate the tree from an archive made with [`EncryptedFs::export_archive`], over what's
+    /// already in the volume, existing files are replaced.
+    ///
+    /// Returns the number of entries. The whole archive is checked before anything is imported,
+    /// if it's truncated or changed the volume is left as it was.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn import_archive<R: Read + Send + Sync>(
+        &self,
+        mut input: R,
+        password: &SecretString,
+    ) -> FsResult<u64> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let header: ArchiveHeader =
+            read_record(&mut input).map_err(|_| FsError::InvalidInput("not an archive"))?;
+        if header.magic != MAGIC {
+            return Err(FsError::InvalidInput("not an archive"));
+        }
+        if header.version != VERSION {
+            return Err(FsError::InvalidInput("unsupported archive version"));
+        }
+        // before deriving, so a changed header cannot make us use all the memory
+        header
+            .kdf
+            .check()
+            .map_err(|_| FsError::InvalidInput("archive kdf params are too high"))?;
+        let key =
+            crypto::derive_key_with_params(password, header.cipher, &header.salt, &header.kdf)?;
+
+        // we can read the input only once, so while checking it we keep it in an unnamed file to
+        // read it again when applying, it's still encrypted
+        let mut staged = tempfile::tempfile_in(&self.data_dir)?;
+        let entries = {
+            let mut reader = crypto::create_read(
+                Tee {
+                    input,
+                    out: &mut staged,
+                },
+                header.cipher,
+                &key,
+            );
+            // if we cannot decrypt the first block the password is wrong
+            let encrypted_header: ArchiveHeader =
+                read_record(&mut reader).map_err(|_| FsError::InvalidPassword)?;
+            if encrypted_header != header {
+                return Err(FsError::InvalidInput("archive header was changed"));
+            }
+            verify_entries(&mut reader)?
+        };
+
+        staged.seek(SeekFrom::Start(0))?;
+        let mut reader = crypto::create_read(io::BufReader::new(staged), header.cipher, &key);
+        let _: ArchiveHeader = read_record(&mut reader)?;
+        self.apply_entries(&mut reader).await?;
+        info!(entries, "imported");
+        Ok(entries)
+    }
+
+    /// Create the entries read after the header, until the end record.
+    async fn apply_entries(&self, reader: &mut impl Read) -> FsResult<()> {
+        // set after their content is added, which changes the times
+        let mut dirs = vec![];
+        loop {
+            let entry: ArchiveEntry = read_record(&mut *reader)?;
+            match entry {
+                ArchiveEntry::Dir { path, attr } => {
+                    debug!(path, "importing");
+                    let ino = self.path().create_dir_all(&path).await?.ino;
+                    dirs.push((ino, attr));
+                }
+                ArchiveEntry::File { path, attr, size } => {
+                    debug!(path, "importing");
+                    let (fh, file_attr) = self.path().create(&path).await?;
+                    let res = self
+                        .import_content(file_attr.ino, fh, &mut *reader, size)
+                        .await;
+                    self.release(fh).await?;
+                    res?;
+                    self.set_archive_attr(file_attr.ino, attr).await?;
+                }
+                ArchiveEntry::Symlink { path, attr, target } => {
+                    debug!(path, "importing");
+                    if self.path().exists(&path).await? {
+                        self.path().remove_file(&path).await?;
+                    }
+                    let path = Path::new(&path);
+                    let parent = self
+                        .path()
+                        .metadata(path.parent().unwrap_or(Path::new("/")))
+                        .await?;
+                    let name = symlink_name(path)?;
+                    let link_attr = self
+                        .create_symlink(
+                            parent.ino,
+                            &SecretString::from_str(name).unwrap(),
+                            &SecretString::new(Box::new(target)),
+                            CreateFileAttr {
+                                kind: FileType::Symlink,
+                                perm: attr.perm,
+                                uid: attr.uid,
+                                gid: attr.gid,
+                                rdev: 0,
+                                flags: attr.flags,
+                            },
+                        )
+                        .await?;
+                    self.set_archive_attr(link_attr.ino, attr).await?;
+                }
+                ArchiveEntry::End { .. } => break,
+            }
+        }
+        for (ino, attr) in dirs.into_iter().rev() {
+            self.set_archive_attr(ino, attr).await?;
+        }
+        Ok(())
+    }
+
+    async fn archive_attr(&self, attr: &FileAttr) -> FsResult<ArchiveAttr> {
+        let mut xattrs = vec![];
+        for name in self.list_xattrs(attr.ino).await? {
+            let value = self.get_xattr(attr.ino, &name).await?;
+            xattrs.push((name.expose_secret().clone(), value.expose_secret().to_vec()));
+        }
+        Ok(ArchiveAttr {
+            perm: attr.perm,
+            uid: attr.uid,
+            gid: attr.gid,
+            atime: attr.atime,
+            mtime: attr.mtime,
+            flags: attr.flags,
+            xattrs,
+        })
+    }
+
+    async fn set_archive_attr(&self, ino: u64, attr: ArchiveAttr) -> FsResult<()> {
+        for (name, value) in attr.xattrs {
+            self.set_xattr(
+                ino,
+                &SecretString::new(Box::new(name)),
+                &value,
+                SetXattrMode::Upsert,
+            )
+            .await?;
+        }
+        // set_attr only moves the times forward, we need them as they were
+        let serialize_update_lock = self
+            .serialize_update_inode_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _serialize_update_guard = serialize_update_lock.lock().await;
+        let mut file_attr = self.get_attr(ino).await?;
+        file_attr.perm = attr.perm;
+        file_attr.uid = attr.uid;
+        file_attr.gid = attr.gid;
+        file_attr.atime = attr.atime;
+        file_attr.mtime = attr.mtime;
+        file_attr.ctime = SystemTime::now();
+        file_attr.flags = attr.flags;
+        self.write_inode_to_storage(&file_attr).await
+    }
+
+    /// Write exactly `attr.size` bytes of the file.
+    async fn export_content(&self, attr: &FileAttr, out: &mut impl Write) -> FsResult<()> {
+        let fh = self.open(attr.ino, true, false).await?;
+        let res = async {
+            let mut buf = vec![0; CHUNK_SIZE];
+            let mut pos = 0;
+            while pos < attr.size {
+                #[allow(clippy::cast_possible_truncation)]
+                let len = (attr.size - pos).min(CHUNK_SIZE as u64) as usize;
+                let len = self.read(attr.ino, pos, &mut buf[..len], fh).await?;
+                if len == 0 {
+                    return Err(FsError::Other("file was truncated while exporting"));
+                }
+                out.write_all(&buf[..len])?;
+                pos += len as u64;
+            }
+            Ok(())
+        }
+        .await;
+        self.release(fh).await?;
+        res
+    }
+
+    async fn import_content(
+        &self,
+        ino: u64,
+        fh: u64,
+        input: &mut impl Read,
+        size: u64,
+    ) -> FsResult<()> {
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut pos = 0;
+        while pos < size {
+            #[allow(clippy::cast_possible_truncation)]
+            let len = (size - pos).min(CHUNK_SIZE as u64) as usize;
+            input.read_exact(&mut buf[..len])?;
+            let mut written = 0;
+            while written < len {
+                let n = self
+                    .write(ino, pos + written as u64, &buf[written..len], fh)
+                    .await?;
+                if n == 0 {
+                    return Err(FsError::Other("Failed to write all bytes"));
+                }
+                written += n;
+            }
+            pos += len as u64;
+        }
+        self.flush(fh).await
+    }
+}
+
+/// Read all the records and content after the header, so they are authenticated, and check we
+/// got to the end record with the expected count. Returns the number of entries.
+fn verify_entries(reader: &mut impl Read) -> FsResult<u64> {
+    let mut entries = 0;
+    loop {
+        let entry: ArchiveEntry = read_record(&mut *reader)?;
+        match entry {
+            ArchiveEntry::Dir { .. } => {}
+            ArchiveEntry::File { size, .. } => {
+                if io::copy(&mut (&mut *reader).take(size), &mut io::sink())? != size {
+                    return Err(FsError::InvalidInput("archive is truncated"));
+                }
+            }
+            ArchiveEntry::Symlink { path, .. } => {
+                symlink_name(Path::new(&path))?;
+            }
+            ArchiveEntry::End { entries: expected } => {
+                if expected != entries {
+                    return Err(FsError::InvalidInput("archive was changed"));
+                }
+                return Ok(entries);
+            }
+        }
+        entries += 1;
+    }
+}
+
+fn symlink_name(path: &Path) -> FsResult<&str> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(FsError::InvalidInput("invalid path in archive"))
+}
+
+/// Like [`bincode::deserialize_from`], with a limit.
+fn read_record<T: DeserializeOwned>(reader: impl Read) -> bincode::Result<T> {
+    bincode::options()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_RECORD_LEN)
+        .deserialize_from(reader)
+}
@@ -0,0 +1,250 @@
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use tokio::runtime::Handle;
+use tracing::error;
+
+use crate::encryptedfs::{EncryptedFs, FsError, FsResult};
+
+type OpFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Operation in progress, it's polled until it completes even if the caller changes the
+/// operation meanwhile, like [`tokio::fs::File`] does.
+enum State {
+    Idle,
+    Read(OpFuture<(Vec<u8>, FsResult<usize>)>),
+    Write(OpFuture<FsResult<usize>>),
+    Flush(OpFuture<FsResult<()>>),
+    Seek(OpFuture<FsResult<u64>>),
+}
+
+/// Opened file which implements [`AsyncRead`], [`AsyncWrite`] and [`AsyncSeek`], so it can be
+/// used with [`tokio::io::copy`] and the like.
+///
+/// It keeps the position, like a file descriptor. The handle is released on drop, in the
+/// background, use [`EncryptedFile::close`] to wait for it and get the errors.
+///
+/// Get it with [`EncryptedFs::open_file`] or [`PathFs::open`](crate::encryptedfs::PathFs::open).
+pub struct EncryptedFile {
+    fs: Arc<EncryptedFs>,
+    ino: u64,
+    fh: u64,
+    pos: u64,
+    state: State,
+    released: bool,
+}
+
+impl EncryptedFs {
+    /// Like [`EncryptedFs::open`] but returns an [`EncryptedFile`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn open_file(
+        self: &Arc<Self>,
+        ino: u64,
+        read: bool,
+        write: bool,
+    ) -> FsResult<EncryptedFile> {
+        let fh = self.open(ino, read, write).await?;
+        Ok(EncryptedFile {
+            fs: self.clone(),
+            ino,
+            fh,
+            pos: 0,
+            state: State::Idle,
+            released: false,
+        })
+    }
+}
+
+impl EncryptedFile {
+    #[must_use]
+    pub const fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    /// The handle to use with [`EncryptedFs`] methods, it's valid until this is dropped.
+    #[must_use]
+    pub const fn handle(&self) -> u64 {
+        self.fh
+    }
+
+    /// Flush and release the handle.
+    ///
+    /// The operation in progress, if any, is abandoned.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn close(mut self) -> FsResult<()> {
+        self.state = State::Idle;
+        self.released = true;
+        self.fs.flush(self.fh).await?;
+        self.fs.release(self.fh).await
+    }
+
+    /// Complete the operation in progress, so we can start another one.
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let res = match &mut self.state {
+            State::Idle => return Poll::Ready(Ok(())),
+            // the caller gave up on it, so the position is not changed
+            State::Read(fut) => ready!(fut.as_mut().poll(cx)).1.map(|_| ()),
+            State::Write(fut) => ready!(fut.as_mut().poll(cx)).map(|len| {
+                self.pos += len as u64;
+            }),
+            State::Flush(fut) => ready!(fut.as_mut().poll(cx)),
+            State::Seek(fut) => ready!(fut.as_mut().poll(cx)).map(|pos| {
+                self.pos = pos;
+            }),
+        };
+        self.state = State::Idle;
+        Poll::Ready(res.map_err(to_io_error))
+    }
+}
+
+impl AsyncRead for EncryptedFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if let State::Read(fut) = &mut this.state {
+                let (data, res) = ready!(fut.as_mut().poll(cx));
+                this.state = State::Idle;
+                // the caller might give a smaller buffer when polling again
+                let len = res.map_err(to_io_error)?.min(buf.remaining());
+                buf.put_slice(&data[..len]);
+                this.pos += len as u64;
+                return Poll::Ready(Ok(()));
+            }
+            ready!(this.poll_idle(cx))?;
+            let (fs, ino, fh, pos) = (this.fs.clone(), this.ino, this.fh, this.pos);
+            let mut data = vec![0; buf.remaining()];
+            this.state = State::Read(Box::pin(async move {
+                let res = fs.read(ino, pos, &mut data, fh).await;
+                (data, res)
+            }));
+        }
+    }
+}
+
+impl AsyncWrite for EncryptedFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        loop {
+            if let State::Write(fut) = &mut this.state {
+                // it's the same data, the caller needs to poll again with it
+                let res = ready!(fut.as_mut().poll(cx));
+                this.state = State::Idle;
+                let len = res.map_err(to_io_error)?;
+                this.pos += len as u64;
+                return Poll::Ready(Ok(len));
+            }
+            ready!(this.poll_idle(cx))?;
+            let (fs, ino, fh, pos) = (this.fs.clone(), this.ino, this.fh, this.pos);
+            let data = buf.to_vec();
+            this.state = State::Write(Box::pin(async move { fs.write(ino, pos, &data, fh).await }));
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if let State::Flush(fut) = &mut this.state {
+                let res = ready!(fut.as_mut().poll(cx));
+                this.state = State::Idle;
+                return Poll::Ready(res.map_err(to_io_error));
+            }
+            ready!(this.poll_idle(cx))?;
+            let (fs, fh) = (this.fs.clone(), this.fh);
+            this.state = State::Flush(Box::pin(async move { fs.flush(fh).await }));
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // the handle is released on drop
+        self.poll_flush(cx)
+    }
+}
+
+impl AsyncSeek for EncryptedFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        if !matches!(self.state, State::Idle) {
+            return Err(io::Error::other(
+                "other operation is pending, call poll_complete before start_seek",
+            ));
+        }
+        let pos = self.pos;
+        match position {
+            SeekFrom::Start(offset) => self.pos = offset,
+            SeekFrom::Current(offset) => {
+                self.pos = pos
+                    .checked_add_signed(offset)
+                    .ok_or_else(|| invalid_seek(offset))?;
+            }
+            SeekFrom::End(offset) => {
+                let (fs, ino) = (self.fs.clone(), self.ino);
+                self.state = State::Seek(Box::pin(async move {
+                    // it includes the changes of the writers
+                    let size = fs.get_attr(ino).await?.size;
+                    size.checked_add_signed(offset)
+                        .ok_or(FsError::InvalidInput("invalid seek to a negative position"))
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        ready!(self.poll_idle(cx))?;
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+impl Drop for EncryptedFile {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        // drop the operation in progress first, so it doesn't use the handle after release
+        self.state = State::Idle;
+        let (fs, fh) = (self.fs.clone(), self.fh);
+        match Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(async move {
+                    if let Err(err) = fs.release(fh).await {
+                        error!(err = %err, fh, "cannot release handle");
+                    }
+                });
+            }
+            Err(err) => error!(err = %err, fh, "cannot release handle, no runtime"),
+        }
+    }
+}
+
+fn invalid_seek(offset: i64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid seek by {offset}, to a negative or overflowing position"),
+    )
+}
+
+fn to_io_error(err: FsError) -> io::Error {
+    match err {
+        FsError::Io { source, .. } => source,
+        FsError::InodeNotFound | FsError::NotFound(_) => {
+            io::Error::new(io::ErrorKind::NotFound, err)
+        }
+        FsError::InvalidInput(_) | FsError::InvalidInodeType => {
+            io::Error::new(io::ErrorKind::InvalidInput, err)
+        }
+        FsError::ReadOnly => io::Error::new(io::ErrorKind::ReadOnlyFilesystem, err),
+        FsError::MaxFilesizeExceeded(_) => io::Error::new(io::ErrorKind::FileTooLarge, err),
+        err => io::Error::other(err),
+    }
+}
@@ -0,0 +1,479 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use shush_rs::{SecretString, SecretVec};
+use tracing::{info, warn};
+
+use crate::crypto;
+use crate::encryptedfs::journal::JournalOp;
+use crate::encryptedfs::{
+    CreateFileAttr, DirectoryEntry, EncryptedFs, FileAttr, FileType, FsError, FsResult,
+    SetFileAttr, CONTENTS_DIR, HASH_DIR, INODES_DIR, LS_DIR, ROOT_INODE,
+};
+
+/// Name of the directory in root where [`EncryptedFs::fsck`] puts orphaned inodes.
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+/// A problem found by [`EncryptedFs::fsck`].
+///
+/// Only inode numbers are reported, names are secret.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckProblem {
+    /// Inode file cannot be decrypted
+    CorruptInode { ino: u64 },
+    /// A file in `ls/` or `hash/` of the directory cannot be decrypted
+    CorruptEntry { parent: u64 },
+    /// Entry in `ls/` points to an inode which doesn't exist
+    DanglingEntry { parent: u64, ino: u64 },
+    /// Entry in `ls/` without the matching one in `hash/`
+    MissingHashEntry { parent: u64, ino: u64 },
+    /// Entry in `hash/` without the matching one in `ls/`
+    StaleHashEntry { parent: u64, ino: u64 },
+    /// Inode not referenced by any directory
+    Orphan { ino: u64 },
+    /// Contents file or directory of the inode is missing
+    MissingContents { ino: u64 },
+    /// Contents without inode
+    OrphanContents { ino: u64 },
+    /// Some blocks of the contents fail authentication
+    CorruptContents { ino: u64 },
+    /// Size in the inode differs from the size of the decrypted contents
+    SizeMismatch { ino: u64, attr_size: u64, size: u64 },
+    /// Number of hard links in the inode differs from the number of entries pointing to it
+    NlinkMismatch { ino: u64, nlink: u32, entries: u32 },
+}
+
+impl fmt::Display for FsckProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CorruptInode { ino } => write!(f, "inode {ino} cannot be decrypted"),
+            Self::CorruptEntry { parent } => {
+                write!(
+                    f,
+                    "directory {parent} has entries which cannot be decrypted"
+                )
+            }
+            Self::DanglingEntry { parent, ino } => {
+                write!(f, "directory {parent} has an entry for missing inode {ino}")
+            }
+            Self::MissingHashEntry { parent, ino } => {
+                write!(f, "directory {parent} has no hash entry for inode {ino}")
+            }
+            Self::StaleHashEntry { parent, ino } => write!(
+                f,
+                "directory {parent} has a hash entry for inode {ino} without a listing entry"
+            ),
+            Self::Orphan { ino } => write!(f, "inode {ino} is not in any directory"),
+            Self::MissingContents { ino } => write!(f, "inode {ino} has no contents"),
+            Self::OrphanContents { ino } => write!(f, "contents {ino} have no inode"),
+            Self::CorruptContents { ino } => {
+                write!(f, "contents of inode {ino} fail authentication")
+            }
+            Self::SizeMismatch {
+                ino,
+                attr_size,
+                size,
+            } => write!(
+                f,
+                "inode {ino} has size {attr_size} but the contents have {size} bytes"
+            ),
+            Self::NlinkMismatch {
+                ino,
+                nlink,
+                entries,
+            } => write!(
+                f,
+                "inode {ino} has {nlink} links but {entries} entries point to it"
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub problems: Vec<FsckProblem>,
+    /// How many of the problems were repaired
+    pub repaired: usize,
+}
+
+impl FsckReport {
+    #[must_use]
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Entry from `ls/`.
+struct LsEntry {
+    ino: u64,
+    kind: FileType,
+    name: SecretString,
+}
+
+impl EncryptedFs {
+    /// Check the data dir, it should not be used by anything else meanwhile, like a mount.
+    ///
+    /// It decrypts all inodes, directory entries and contents. With `repair`, it rebuilds `hash/`
+    /// from `ls/`, removes entries pointing to missing inodes, fixes link counts and moves
+    /// orphaned inodes to [`LOST_AND_FOUND`] in root.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn fsck(&self, repair: bool) -> FsResult<FsckReport> {
+        if repair && self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if self.rekey_in_progress() {
+            // part of the data is encrypted with the old key
+            return Err(FsError::Other("rekey in progress, finish it first"));
+        }
+        let key = self.key.get().await?;
+        let mut report = FsckReport::default();
+
+        let mut inodes = BTreeMap::new();
+        for ino in list_inos(&self.data_dir.join(INODES_DIR))? {
+            match self.get_inode_from_storage(ino).await {
+                Ok(attr) => {
+                    inodes.insert(ino, attr);
+                }
+                Err(_) => report.problems.push(FsckProblem::CorruptInode { ino }),
+            }
+        }
+        for ino in list_inos(&self.data_dir.join(CONTENTS_DIR))? {
+            if !self.ino_file(ino).exists() {
+                report.problems.push(FsckProblem::OrphanContents { ino });
+            }
+        }
+
+        // directory entries
+        let mut references: HashMap<u64, u32> = HashMap::new();
+        for attr in inodes.values().filter(|a| a.kind == FileType::Directory) {
+            if !self.contents_path(attr.ino).is_dir() {
+                report
+                    .problems
+                    .push(FsckProblem::MissingContents { ino: attr.ino });
+                continue;
+            }
+            self.fsck_dir(
+                attr.ino,
+                &inodes,
+                &key,
+                &mut references,
+                &mut report,
+                repair,
+            )?;
+        }
+
+        // contents
+        for attr in inodes
+            .values()
+            .filter(|a| a.kind == FileType::RegularFile || a.kind == FileType::Symlink)
+        {
+            let path = self.contents_path(attr.ino);
+            if !path.is_file() {
+                report
+                    .problems
+                    .push(FsckProblem::MissingContents { ino: attr.ino });
+                continue;
+            }
+            let reader = self.read_content_with_key(File::open(path)?, &key);
+            match io::copy(&mut io::BufReader::new(reader), &mut io::sink()) {
+                Ok(size) if size != attr.size => {
+                    report.problems.push(FsckProblem::SizeMismatch {
+                        ino: attr.ino,
+                        attr_size: attr.size,
+                        size,
+                    });
+                    if repair {
+                        self.set_attr2(attr.ino, SetFileAttr::default().with_size(size), true)
+                            .await?;
+                        report.repaired += 1;
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => report
+                    .problems
+                    .push(FsckProblem::CorruptContents { ino: attr.ino }),
+            }
+        }
+
+        // link counts, directories don't count links
+        for attr in inodes.values().filter(|a| a.kind != FileType::Directory) {
+            let entries = references.get(&attr.ino).copied().unwrap_or(0);
+            if entries > 0 && entries != attr.nlink {
+                report.problems.push(FsckProblem::NlinkMismatch {
+                    ino: attr.ino,
+                    nlink: attr.nlink,
+                    entries,
+                });
+                if repair {
+                    self.set_nlink(attr, entries).await?;
+                    report.repaired += 1;
+                }
+            }
+        }
+
+        // orphans
+        let orphans: Vec<&FileAttr> = inodes
+            .values()
+            .filter(|a| a.ino != ROOT_INODE && !references.contains_key(&a.ino))
+            .collect();
+        for attr in &orphans {
+            report.problems.push(FsckProblem::Orphan { ino: attr.ino });
+        }
+        if repair && !orphans.is_empty() {
+            let lost_and_found = self.lost_and_found().await?;
+            for attr in orphans {
+                self.move_to_lost_and_found(attr, lost_and_found).await?;
+                report.repaired += 1;
+            }
+        }
+
+        if report.is_clean() {
+            info!("no problems found");
+        } else {
+            warn!(
+                "found {} problems, repaired {}",
+                report.problems.len(),
+                report.repaired
+            );
+        }
+        Ok(report)
+    }
+
+    fn fsck_dir(
+        &self,
+        ino: u64,
+        inodes: &BTreeMap<u64, FileAttr>,
+        key: &SecretVec<u8>,
+        references: &mut HashMap<u64, u32>,
+        report: &mut FsckReport,
+        repair: bool,
+    ) -> FsResult<()> {
+        let ls_dir = self.contents_path(ino).join(LS_DIR);
+        let hash_dir = self.contents_path(ino).join(HASH_DIR);
+        let mut corrupt = false;
+        let mut rebuild_hash = false;
+
+        let mut ls_entries: HashMap<String, LsEntry> = HashMap::new();
+        let mut dangling = HashSet::new();
+        for file_name in list_names(&ls_dir)? {
+            if file_name == "$." || file_name == "$.." {
+                continue;
+            }
+            let path = ls_dir.join(&file_name);
+            let entry: Option<(u64, FileType)> =
+                bincode::deserialize_from(self.read_with_key(File::open(&path)?, key)).ok();
+            let name = crypto::decrypt_file_name(&file_name, self.cipher, key).ok();
+            let (Some((child, kind)), Some(name)) = (entry, name) else {
+                corrupt = true;
+                if repair {
+                    fs::remove_file(&path)?;
+                    rebuild_hash = true;
+                }
+                continue;
+            };
+            if !inodes.contains_key(&child) {
+                dangling.insert(file_name);
+                report.problems.push(FsckProblem::DanglingEntry {
+                    parent: ino,
+                    ino: child,
+                });
+                if repair {
+                    fs::remove_file(&path)?;
+                    rebuild_hash = true;
+                    report.repaired += 1;
+                }
+                continue;
+            }
+            *references.entry(child).or_default() += 1;
+            ls_entries.insert(
+                file_name,
+                LsEntry {
+                    ino: child,
+                    kind,
+                    name,
+                },
+            );
+        }
+
+        let mut hashed = HashSet::new();
+        for file_name in list_names(&hash_dir)? {
+            if file_name == "$." || file_name == "$.." {
+                continue;
+            }
+            let path = hash_dir.join(&file_name);
+            let entry: Option<(u64, FileType, String)> =
+                bincode::deserialize_from(self.read_with_key(File::open(&path)?, key)).ok();
+            let Some((child, _, encrypted_name)) = entry else {
+                corrupt = true;
+                rebuild_hash = true;
+                continue;
+            };
+            match ls_entries.get(&encrypted_name) {
+                Some(ls_entry)
+                    if ls_entry.ino == child
+                        && self.hash_file_name(&ls_entry.name) == file_name =>
+                {
+                    hashed.insert(encrypted_name);
+                }
+                // already reported with the listing entry
+                _ if dangling.contains(&encrypted_name) => rebuild_hash = true,
+                _ => {
+                    report.problems.push(FsckProblem::StaleHashEntry {
+                        parent: ino,
+                        ino: child,
+                    });
+                    rebuild_hash = true;
+                }
+            }
+        }
+        for (encrypted_name, ls_entry) in &ls_entries {
+            if !hashed.contains(encrypted_name) {
+                report.problems.push(FsckProblem::MissingHashEntry {
+                    parent: ino,
+                    ino: ls_entry.ino,
+                });
+                rebuild_hash = true;
+            }
+        }
+        if corrupt {
+            report
+                .problems
+                .push(FsckProblem::CorruptEntry { parent: ino });
+        }
+
+        if repair && rebuild_hash {
+            let problems = report
+                .problems
+                .iter()
+                .filter(|p| match p {
+                    FsckProblem::StaleHashEntry { parent, .. }
+                    | FsckProblem::MissingHashEntry { parent, .. }
+                    | FsckProblem::CorruptEntry { parent } => *parent == ino,
+                    _ => false,
+                })
+                .count();
+            self.rebuild_hash_dir(ino, &ls_entries, key)?;
+            report.repaired += problems;
+        }
+        Ok(())
+    }
+
+    /// Recreate all entries in `hash/`, except `.` and `..`, from the ones in `ls/`.
+    fn rebuild_hash_dir(
+        &self,
+        ino: u64,
+        ls_entries: &HashMap<String, LsEntry>,
+        key: &SecretVec<u8>,
+    ) -> FsResult<()> {
+        let hash_dir = self.contents_path(ino).join(HASH_DIR);
+        for file_name in list_names(&hash_dir)? {
+            if file_name != "$." && file_name != "$.." {
+                fs::remove_file(hash_dir.join(file_name))?;
+            }
+        }
+        for (encrypted_name, ls_entry) in ls_entries {
+            crypto::atomic_serialize_encrypt_into(
+                &hash_dir.join(self.hash_file_name(&ls_entry.name)),
+                &(ls_entry.ino, ls_entry.kind, encrypted_name),
+                self.cipher,
+                key,
+            )?;
+        }
+        File::open(&hash_dir)?.sync_all()?;
+        info!(ino, "rebuilt hash entries");
+        Ok(())
+    }
+
+    async fn set_nlink(&self, attr: &FileAttr, nlink: u32) -> FsResult<()> {
+        let mut attr = *attr;
+        attr.nlink = nlink;
+        self.write_inode_to_storage(&attr).await
+    }
+
+    async fn lost_and_found(&self) -> FsResult<u64> {
+        let name = SecretString::from_str(LOST_AND_FOUND).expect("cannot create name");
+        if let Some(attr) = self.find_by_name(ROOT_INODE, &name).await? {
+            return Ok(attr.ino);
+        }
+        let (_, attr) = self
+            .create(
+                ROOT_INODE,
+                &name,
+                CreateFileAttr {
+                    kind: FileType::Directory,
+                    perm: 0o700,
+                    uid: 0,
+                    gid: 0,
+                    rdev: 0,
+                    flags: 0,
+                },
+                false,
+                false,
+            )
+            .await?;
+        Ok(attr.ino)
+    }
+
+    /// Add an entry named `#<ino>` for it, like `e2fsck` does.
+    ///
+    /// A directory gets its `..` pointing to [`LOST_AND_FOUND`], in the same transaction.
+    async fn move_to_lost_and_found(&self, attr: &FileAttr, lost_and_found: u64) -> FsResult<()> {
+        let mut ops = self
+            .insert_directory_entry_ops(
+                lost_and_found,
+                &DirectoryEntry {
+                    ino: attr.ino,
+                    name: SecretString::new(Box::new(format!("#{}", attr.ino))),
+                    kind: attr.kind,
+                },
+            )
+            .await?;
+        if attr.kind == FileType::Directory {
+            ops.extend(
+                self.insert_directory_entry_ops(
+                    attr.ino,
+                    &DirectoryEntry {
+                        ino: lost_and_found,
+                        name: SecretString::new(Box::new("$..".into())),
+                        kind: FileType::Directory,
+                    },
+                )
+                .await?,
+            );
+        } else if attr.nlink != 1 {
+            ops.push(JournalOp::SetNlink {
+                ino: attr.ino,
+                nlink: 1,
+                ctime: SystemTime::now(),
+            });
+        }
+        self.journal(ops).await?;
+        info!(ino = attr.ino, "moved to {LOST_AND_FOUND}");
+        Ok(())
+    }
+}
+
+/// Inode numbers from the names of the files in `dir`.
+pub(crate) fn list_inos(dir: &Path) -> io::Result<Vec<u64>> {
+    Ok(list_names(dir)?
+        .iter()
+        .filter_map(|name| name.parse().ok())
+        .collect())
+}
+
+/// Names of the files in `dir`, without the temporary ones used for atomic writes.
+fn list_names(dir: &Path) -> io::Result<Vec<String>> {
+    let mut names = vec![];
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        if !name.starts_with('.') {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
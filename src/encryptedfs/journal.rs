@@ -0,0 +1,435 @@
+use std::fs::{self, File};
+use std::io::{self, Cursor};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use okaywal::{Entry, EntryId, LogManager, SegmentReader, WriteAheadLog};
+use serde::{Deserialize, Serialize};
+use shush_rs::{SecretString, SecretVec};
+use tokio::sync::RwLock;
+use tracing::{error, info};
+
+use crate::crypto::{self, Cipher};
+use crate::encryptedfs::{
+    DirectoryEntry, EncryptedFs, FileAttr, FileType, FsError, FsResult, CONTENTS_DIR, HASH_DIR,
+    INODES_DIR, LS_DIR, XATTRS_DIR,
+};
+
+pub(crate) const WAL_DIR: &str = "wal";
+
+/// Change to the directory entries and inodes, made in a transaction with [`EncryptedFs::journal`].
+///
+/// They are idempotent, so replaying them in order gives the same result. The entries are changed
+/// only by them. The inodes are also written by [`EncryptedFs::set_attr`], so the ops on them
+/// don't overwrite more than they own: the inode of a new node is written only if it's missing and
+/// only the link count is set after.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum JournalOp {
+    WriteLs {
+        parent: u64,
+        encrypted_name: String,
+        ino: u64,
+        kind: FileType,
+    },
+    WriteHash {
+        parent: u64,
+        hash_name: String,
+        ino: u64,
+        kind: FileType,
+        encrypted_name: String,
+    },
+    RemoveLs {
+        parent: u64,
+        encrypted_name: String,
+    },
+    RemoveHash {
+        parent: u64,
+        hash_name: String,
+    },
+    /// Create the empty contents and write the inode, it comes before the entries of the node
+    CreateInode {
+        attr: FileAttr,
+    },
+    SetNlink {
+        ino: u64,
+        nlink: u32,
+        ctime: SystemTime,
+    },
+    /// Remove the inode with its contents and extended attributes
+    RemoveInode {
+        ino: u64,
+    },
+}
+
+/// Write-ahead log in `wal/`, each entry is a transaction of [`JournalOp`]s encrypted with the
+/// master key.
+///
+/// The entries are applied right after they are committed, we only read them back on replay.
+pub(crate) struct Journal {
+    wal: WriteAheadLog,
+    // transactions hold it for read until they are applied, checkpoint waits for them
+    in_flight: Arc<RwLock<()>>,
+}
+
+#[derive(Debug)]
+struct JournalManager {
+    in_flight: Arc<RwLock<()>>,
+    recovered: Arc<std::sync::Mutex<Vec<Vec<u8>>>>,
+}
+
+impl LogManager for JournalManager {
+    fn recover(&mut self, entry: &mut Entry<'_>) -> io::Result<()> {
+        // not fully written entries were not committed, so they were not applied either
+        if let Some(chunks) = entry.read_all_chunks()? {
+            self.recovered
+                .lock()
+                .expect("cannot obtain lock")
+                .push(chunks.concat());
+        }
+        Ok(())
+    }
+
+    fn checkpoint_to(
+        &mut self,
+        _last_checkpointed_id: EntryId,
+        _checkpointed_entries: &mut SegmentReader,
+        _wal: &WriteAheadLog,
+    ) -> io::Result<()> {
+        // this runs on the checkpoint thread, the committed entries are applied and synced after
+        // the transactions release the lock
+        let _guard = self.in_flight.blocking_write();
+        Ok(())
+    }
+}
+
+impl Journal {
+    /// Returns the entries which were not checkpointed yet, in order.
+    fn open(data_dir: &Path) -> io::Result<(Self, Vec<Vec<u8>>)> {
+        let in_flight = Arc::new(RwLock::new(()));
+        let recovered = Arc::new(std::sync::Mutex::new(vec![]));
+        let wal = WriteAheadLog::recover(
+            data_dir.join(WAL_DIR),
+            JournalManager {
+                in_flight: in_flight.clone(),
+                recovered: recovered.clone(),
+            },
+        )?;
+        let entries = std::mem::take(&mut *recovered.lock().expect("cannot obtain lock"));
+        Ok((Self { wal, in_flight }, entries))
+    }
+
+    /// Open the journal and apply the entries which were not checkpointed, in case we crashed
+    /// before applying them. It needs to be done before making any other change.
+    ///
+    /// While a rekey is in progress, entries from before it are encrypted with `old_key`, they
+    /// are applied with it too, so the entries they write are re-encrypted by the rekey.
+    pub(crate) fn open_and_replay(
+        data_dir: &Path,
+        cipher: Cipher,
+        key: &SecretVec<u8>,
+        old_key: Option<&SecretVec<u8>>,
+    ) -> FsResult<Self> {
+        let (journal, entries) = Self::open(data_dir)?;
+        if entries.is_empty() {
+            return Ok(journal);
+        }
+        info!("replaying {} journal entries", entries.len());
+        for entry in entries {
+            let decrypt = |key: &SecretVec<u8>| -> bincode::Result<Vec<JournalOp>> {
+                bincode::deserialize_from(crypto::create_read(&entry[..], cipher, key))
+            };
+            let (ops, key) = match (decrypt(key), old_key) {
+                (Ok(ops), _) => (ops, key),
+                (Err(err), Some(old_key)) => match decrypt(old_key) {
+                    Ok(ops) => (ops, old_key),
+                    Err(_) => {
+                        error!(err = %err, "cannot decrypt journal entry, skipping it");
+                        continue;
+                    }
+                },
+                (Err(err), None) => {
+                    error!(err = %err, "cannot decrypt journal entry, skipping it");
+                    continue;
+                }
+            };
+            for op in &ops {
+                apply_op(data_dir, op, cipher, key, old_key)?;
+            }
+        }
+        // everything is applied, checkpoint and open it again so it's not replayed next time
+        journal.wal.checkpoint_active()?;
+        journal.wal.shutdown()?;
+        Ok(Self::open(data_dir)?.0)
+    }
+
+    pub(crate) fn append(&self, data: &[u8]) -> io::Result<()> {
+        let mut writer = self.wal.begin_entry()?;
+        writer.write_chunk(data)?;
+        writer.commit()?;
+        Ok(())
+    }
+}
+
+impl EncryptedFs {
+    /// Write the ops to the journal and then apply them.
+    ///
+    /// If we crash meanwhile, they are applied when opening the data dir next time.
+    ///
+    /// The callers of the ops on inodes hold the locks of the inode, see
+    /// [`EncryptedFs::remove_inode_if_unlinked`].
+    pub(crate) async fn journal(&self, ops: Vec<JournalOp>) -> FsResult<()> {
+        let key = self.key.get().await?;
+        // the inodes might be encrypted with the old key still
+        let old_key = if self.rekey_in_progress() {
+            Some(self.old_key.get().await?)
+        } else {
+            None
+        };
+        let _guard = if let Some(journal) = &self.journal {
+            let guard = journal.in_flight.read().await;
+            let entry =
+                crypto::serialize_encrypt_into(Cursor::new(vec![]), &ops, self.cipher, &key)?
+                    .into_inner();
+            journal.append(&entry)?;
+            Some(guard)
+        } else {
+            None
+        };
+        for op in &ops {
+            let (path, locks) = match op {
+                JournalOp::CreateInode { attr } => {
+                    apply_op(&self.data_dir, op, self.cipher, &key, old_key.as_deref())?;
+                    self.attr_cache
+                        .get()
+                        .await?
+                        .write()
+                        .await
+                        .put(attr.ino, *attr);
+                    continue;
+                }
+                JournalOp::SetNlink { ino, .. } => {
+                    apply_op(&self.data_dir, op, self.cipher, &key, old_key.as_deref())?;
+                    self.attr_cache.get().await?.write().await.pop(ino);
+                    continue;
+                }
+                JournalOp::RemoveInode { ino } => {
+                    let lock = self
+                        .xattr_locks
+                        .get_or_insert_with(*ino, || RwLock::new(false));
+                    let _guard = lock.write().await;
+                    apply_op(&self.data_dir, op, self.cipher, &key, old_key.as_deref())?;
+                    self.attr_cache.get().await?.write().await.pop(ino);
+                    continue;
+                }
+                JournalOp::WriteLs {
+                    parent,
+                    encrypted_name,
+                    ..
+                }
+                | JournalOp::RemoveLs {
+                    parent,
+                    encrypted_name,
+                } => (
+                    self.contents_path(*parent)
+                        .join(LS_DIR)
+                        .join(encrypted_name),
+                    &self.serialize_dir_entries_ls_locks,
+                ),
+                JournalOp::WriteHash {
+                    parent, hash_name, ..
+                }
+                | JournalOp::RemoveHash { parent, hash_name } => (
+                    self.contents_path(*parent).join(HASH_DIR).join(hash_name),
+                    &self.serialize_dir_entries_hash_locks,
+                ),
+            };
+            let lock =
+                locks.get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
+            let _guard = lock.write().await;
+            apply_op(&self.data_dir, op, self.cipher, &key, old_key.as_deref())?;
+        }
+        Ok(())
+    }
+
+    /// Ops to add the entry to `ls/` and `hash/` of the directory.
+    pub(crate) async fn insert_directory_entry_ops(
+        &self,
+        parent: u64,
+        entry: &DirectoryEntry,
+    ) -> FsResult<Vec<JournalOp>> {
+        let encrypted_name =
+            crypto::encrypt_file_name(&entry.name, self.cipher, &*self.key.get().await?)?;
+        Ok(vec![
+            JournalOp::WriteLs {
+                parent,
+                encrypted_name: encrypted_name.clone(),
+                ino: entry.ino,
+                kind: entry.kind,
+            },
+            // we save the encrypted name also because we need it to remove the entry
+            JournalOp::WriteHash {
+                parent,
+                hash_name: self.hash_file_name(&entry.name),
+                ino: entry.ino,
+                kind: entry.kind,
+                encrypted_name,
+            },
+        ])
+    }
+
+    /// Ops to remove the entry from `ls/` and `hash/` of the directory.
+    pub(crate) async fn remove_directory_entry_ops(
+        &self,
+        parent: u64,
+        name: &SecretString,
+    ) -> FsResult<Vec<JournalOp>> {
+        let path = self
+            .find_hash_path(parent, name)
+            .ok_or(FsError::NotFound("name not found"))?;
+        // it might be hashed with the old key, while a rekey is in progress
+        let hash_name = path.file_name().unwrap().to_string_lossy().to_string();
+        let lock = self
+            .serialize_dir_entries_hash_locks
+            .get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
+        let guard = lock.read().await;
+        let (_, _, encrypted_name): (u64, FileType, String) = self
+            .decrypt_with_keys(|key| {
+                Ok(bincode::deserialize_from(crypto::create_read(
+                    File::open(&path)?,
+                    self.cipher,
+                    key,
+                ))?)
+            })
+            .await?;
+        drop(guard);
+        Ok(vec![
+            JournalOp::RemoveHash { parent, hash_name },
+            JournalOp::RemoveLs {
+                parent,
+                encrypted_name,
+            },
+        ])
+    }
+}
+
+fn apply_op(
+    data_dir: &Path,
+    op: &JournalOp,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    old_key: Option<&SecretVec<u8>>,
+) -> FsResult<()> {
+    let contents_path = |parent: u64, dir: &str| {
+        data_dir
+            .join(CONTENTS_DIR)
+            .join(parent.to_string())
+            .join(dir)
+    };
+    let ino_file = |ino: u64| data_dir.join(INODES_DIR).join(ino.to_string());
+    match op {
+        JournalOp::WriteLs {
+            parent,
+            encrypted_name,
+            ino,
+            kind,
+        } => {
+            let dir = contents_path(*parent, LS_DIR);
+            // the directory was removed after, on replay
+            if dir.is_dir() {
+                crypto::atomic_serialize_encrypt_into(
+                    &dir.join(encrypted_name),
+                    &(*ino, *kind),
+                    cipher,
+                    key,
+                )?;
+            }
+        }
+        JournalOp::WriteHash {
+            parent,
+            hash_name,
+            ino,
+            kind,
+            encrypted_name,
+        } => {
+            let dir = contents_path(*parent, HASH_DIR);
+            if dir.is_dir() {
+                crypto::atomic_serialize_encrypt_into(
+                    &dir.join(hash_name),
+                    &(*ino, *kind, encrypted_name),
+                    cipher,
+                    key,
+                )?;
+            }
+        }
+        JournalOp::RemoveLs {
+            parent,
+            encrypted_name,
+        } => remove_entry(&contents_path(*parent, LS_DIR), encrypted_name)?,
+        JournalOp::RemoveHash { parent, hash_name } => {
+            remove_entry(&contents_path(*parent, HASH_DIR), hash_name)?;
+        }
+        JournalOp::CreateInode { attr } => {
+            // contents first, so the inode never exists without them
+            let contents = data_dir.join(CONTENTS_DIR).join(attr.ino.to_string());
+            if attr.kind == FileType::Directory {
+                for dir in [
+                    contents.clone(),
+                    contents.join(LS_DIR),
+                    contents.join(HASH_DIR),
+                ] {
+                    if !dir.is_dir() {
+                        fs::create_dir(&dir)?;
+                    }
+                }
+            } else if !contents.is_file() {
+                File::create(&contents)?.sync_all()?;
+            }
+            File::open(data_dir.join(CONTENTS_DIR))?.sync_all()?;
+            // it was changed after, on replay
+            if !ino_file(attr.ino).is_file() {
+                crypto::atomic_serialize_encrypt_into(&ino_file(attr.ino), attr, cipher, key)?;
+            }
+        }
+        JournalOp::SetNlink { ino, nlink, ctime } => {
+            let path = ino_file(*ino);
+            // the inode was removed after, on replay
+            if path.is_file() {
+                let read = |key: &SecretVec<u8>| -> FsResult<FileAttr> {
+                    Ok(bincode::deserialize_from(crypto::create_read(
+                        File::open(&path)?,
+                        cipher,
+                        key,
+                    ))?)
+                };
+                let mut attr = match (read(key), old_key) {
+                    (Err(_), Some(old_key)) => read(old_key)?,
+                    (res, _) => res?,
+                };
+                attr.nlink = *nlink;
+                attr.ctime = *ctime;
+                crypto::atomic_serialize_encrypt_into(&path, &attr, cipher, key)?;
+            }
+        }
+        JournalOp::RemoveInode { ino } => {
+            let contents = data_dir.join(CONTENTS_DIR).join(ino.to_string());
+            if contents.is_dir() {
+                fs::remove_dir_all(&contents)?;
+            } else {
+                remove_entry(&data_dir.join(CONTENTS_DIR), &ino.to_string())?;
+            }
+            remove_entry(&data_dir.join(XATTRS_DIR), &ino.to_string())?;
+            remove_entry(&data_dir.join(INODES_DIR), &ino.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_entry(dir: &Path, name: &str) -> io::Result<()> {
+    match fs::remove_file(dir.join(name)) {
+        Ok(()) => File::open(dir)?.sync_all(),
+        // already removed, on replay
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
@@ -0,0 +1,131 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+use shush_rs::{ExposeSecret, SecretString};
+use tracing::error;
+
+use crate::crypto;
+use crate::encryptedfs::PasswordProvider;
+
+/// Provides the secret the key is derived from, with the KDF.
+///
+/// Each [`PasswordProvider`] is one, the password is the key material.
+pub trait KeyMaterialProvider: Send + Sync + 'static {
+    fn get_key_material(&self) -> Option<SecretString>;
+}
+
+impl<T: PasswordProvider> KeyMaterialProvider for T {
+    fn get_key_material(&self) -> Option<SecretString> {
+        self.get_password()
+    }
+}
+
+/// Key material from a keyfile, it's read on each call.
+pub struct KeyfileProvider {
+    path: PathBuf,
+}
+
+impl KeyfileProvider {
+    #[must_use]
+    pub const fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    fn read(&self) -> Option<Vec<u8>> {
+        let mut content = vec![];
+        File::open(&self.path)
+            .and_then(|mut file| file.read_to_end(&mut content))
+            .map_err(|err| error!(err = %err, path = ?self.path, "cannot read keyfile"))
+            .ok()?;
+        if content.is_empty() {
+            error!(path = ?self.path, "keyfile is empty");
+            return None;
+        }
+        Some(content)
+    }
+}
+
+impl KeyMaterialProvider for KeyfileProvider {
+    fn get_key_material(&self) -> Option<SecretString> {
+        self.read()
+            .map(|content| crypto::keyfile_key_material(&content))
+    }
+}
+
+/// Password and keyfile, both are needed to derive the key.
+pub struct PasswordKeyfileProvider {
+    password_provider: Box<dyn PasswordProvider>,
+    keyfile: KeyfileProvider,
+}
+
+impl PasswordKeyfileProvider {
+    #[must_use]
+    pub fn new(password_provider: Box<dyn PasswordProvider>, keyfile: PathBuf) -> Self {
+        Self {
+            password_provider,
+            keyfile: KeyfileProvider::new(keyfile),
+        }
+    }
+}
+
+impl KeyMaterialProvider for PasswordKeyfileProvider {
+    fn get_key_material(&self) -> Option<SecretString> {
+        let password = self.password_provider.get_password()?;
+        let keyfile = self.keyfile.get_key_material()?;
+        // the keyfile material has a fixed length, so it cannot be confused with the password
+        Some(SecretString::new(Box::new(format!(
+            "{}\0{}",
+            password.expose_secret(),
+            keyfile.expose_secret()
+        ))))
+    }
+}
+
+/// Password kept in memory, it can be read once from a reader like stdin or a file descriptor,
+/// as we cannot read it again.
+pub struct InMemoryPasswordProvider {
+    password: SecretString,
+}
+
+impl InMemoryPasswordProvider {
+    #[must_use]
+    pub const fn new(password: SecretString) -> Self {
+        Self { password }
+    }
+
+    /// Read until EOF, a trailing newline is removed.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn read_from(mut reader: impl Read) -> std::io::Result<Self> {
+        let mut password = String::new();
+        reader.read_to_string(&mut password)?;
+        if password.ends_with('\n') {
+            password.pop();
+            if password.ends_with('\r') {
+                password.pop();
+            }
+        }
+        Ok(Self::new(SecretString::new(Box::new(password))))
+    }
+
+    #[allow(clippy::missing_errors_doc)]
+    pub fn from_stdin() -> std::io::Result<Self> {
+        Self::read_from(std::io::stdin().lock())
+    }
+
+    /// # Safety
+    ///
+    /// `fd` needs to be an open file descriptor which is not used anywhere else, it's closed after reading.
+    #[cfg(unix)]
+    #[allow(clippy::missing_errors_doc)]
+    pub unsafe fn from_fd(fd: std::os::fd::RawFd) -> std::io::Result<Self> {
+        use std::os::fd::FromRawFd;
+        Self::read_from(File::from_raw_fd(fd))
+    }
+}
+
+impl PasswordProvider for InMemoryPasswordProvider {
+    fn get_password(&self) -> Option<SecretString> {
+        Some(self.password.clone())
+    }
+}
@@ -0,0 +1,197 @@
+use std::fs::File;
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+
+use rand_chacha::rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+use shush_rs::{ExposeSecret, SecretBox, SecretString, SecretVec};
+
+use crate::crypto::{self, Cipher, KdfParams, Legacy};
+use crate::encryptedfs::{FsError, FsResult, KEY_ENC_FILENAME, KEY_SALT_FILENAME, SECURITY_DIR};
+use crate::fs_util;
+
+pub(crate) const KEYSLOTS_FILENAME: &str = "keyslots";
+
+/// A copy of the master key encrypted with a key derived from one password.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeySlot {
+    pub id: u32,
+    pub kdf: KdfParams,
+    pub(crate) salt: Vec<u8>,
+    /// Master key encrypted like `key.enc` was
+    pub(crate) wrapped_key: Vec<u8>,
+}
+
+impl KeySlot {
+    pub(crate) fn new(
+        id: u32,
+        key: &SecretVec<u8>,
+        password: &SecretString,
+        cipher: Cipher,
+        kdf: KdfParams,
+    ) -> FsResult<Self> {
+        let mut salt = vec![0; 16];
+        crypto::create_rng().fill_bytes(&mut salt);
+        let derived_key = crypto::derive_key_with_params(password, cipher, &salt, &kdf)?;
+        let wrapped_key = crypto::serialize_encrypt_into(
+            Cursor::new(vec![]),
+            &*key.expose_secret(),
+            cipher,
+            &derived_key,
+        )?
+        .into_inner();
+        Ok(Self {
+            id,
+            kdf,
+            salt,
+            wrapped_key,
+        })
+    }
+
+    pub(crate) fn unlock(
+        &self,
+        password: &SecretString,
+        cipher: Cipher,
+    ) -> FsResult<SecretVec<u8>> {
+        let derived_key = crypto::derive_key_with_params(password, cipher, &self.salt, &self.kdf)?;
+        // the slot moved from `key.enc` keeps its format, without the header
+        let reader = crypto::create_read_compat(
+            &self.wrapped_key[..],
+            cipher,
+            &derived_key,
+            Legacy::WithoutHeader,
+        );
+        let key: Vec<u8> =
+            bincode::deserialize_from(reader).map_err(|_| FsError::InvalidPassword)?;
+        Ok(SecretBox::new(Box::new(key)))
+    }
+}
+
+/// Key slots stored in `security/keyslots`, each of them can unlock the master key.
+///
+/// The file itself is not encrypted, each slot has the master key encrypted with a key derived
+/// from its password. The data is encrypted with the master key, so adding or removing a slot
+/// doesn't need to re-encrypt anything else.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct KeySlots {
+    slots: Vec<KeySlot>,
+}
+
+impl KeySlots {
+    /// Returns `None` if the data dir doesn't have key slots yet.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn read(data_dir: &Path) -> FsResult<Option<Self>> {
+        let path = Self::path(data_dir);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(bincode::deserialize_from(File::open(path)?)?))
+    }
+
+    #[allow(clippy::missing_errors_doc)]
+    pub fn write(&self, data_dir: &Path) -> FsResult<()> {
+        let mut file = fs_util::open_atomic_write(&Self::path(data_dir))?;
+        bincode::serialize_into(&mut file, self)?;
+        file.flush()?;
+        file.commit()?;
+        File::open(data_dir.join(SECURITY_DIR))?.sync_all()?;
+        Ok(())
+    }
+
+    pub(crate) fn path(data_dir: &Path) -> std::path::PathBuf {
+        data_dir.join(SECURITY_DIR).join(KEYSLOTS_FILENAME)
+    }
+
+    /// Slot made from `key.enc` and `key.salt` used before we had key slots.
+    pub(crate) fn from_legacy(data_dir: &Path, kdf: KdfParams) -> FsResult<Self> {
+        let salt: Vec<u8> = bincode::deserialize_from(File::open(
+            data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
+        )?)?;
+        let mut wrapped_key = vec![];
+        File::open(data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME))?
+            .read_to_end(&mut wrapped_key)?;
+        Ok(Self {
+            slots: vec![KeySlot {
+                id: 0,
+                kdf,
+                salt,
+                wrapped_key,
+            }],
+        })
+    }
+
+    #[must_use]
+    pub fn slots(&self) -> &[KeySlot] {
+        &self.slots
+    }
+
+    /// Try the password with each slot, returns the id of the slot it unlocked and the master key.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn unlock(
+        &self,
+        password: &SecretString,
+        cipher: Cipher,
+    ) -> FsResult<(u32, SecretVec<u8>)> {
+        for slot in &self.slots {
+            match slot.unlock(password, cipher) {
+                Ok(key) => return Ok((slot.id, key)),
+                Err(FsError::InvalidPassword) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Err(FsError::InvalidPassword)
+    }
+
+    /// Add a slot for `password` and return its id.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn add(
+        &mut self,
+        key: &SecretVec<u8>,
+        password: &SecretString,
+        cipher: Cipher,
+        kdf: KdfParams,
+    ) -> FsResult<u32> {
+        let id = self.slots.iter().map(|s| s.id + 1).max().unwrap_or(0);
+        self.slots
+            .push(KeySlot::new(id, key, password, cipher, kdf)?);
+        Ok(id)
+    }
+
+    /// Set a new password for the slot, used to change the password.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn replace(
+        &mut self,
+        id: u32,
+        key: &SecretVec<u8>,
+        password: &SecretString,
+        cipher: Cipher,
+        kdf: KdfParams,
+    ) -> FsResult<()> {
+        let slot = self
+            .slots
+            .iter_mut()
+            .find(|s| s.id == id)
+            .ok_or(FsError::NotFound("key slot"))?;
+        *slot = KeySlot::new(id, key, password, cipher, kdf)?;
+        Ok(())
+    }
+
+    /// Keep only the slot with the new master key, used by rekey. We don't know the passwords of
+    /// the other slots, so they couldn't unlock the new key.
+    pub(crate) fn rekey(&mut self, slot: KeySlot) {
+        self.slots = vec![slot];
+    }
+
+    /// We don't allow removing the last slot, the data couldn't be decrypted anymore.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn remove(&mut self, id: u32) -> FsResult<()> {
+        if !self.slots.iter().any(|s| s.id == id) {
+            return Err(FsError::NotFound("key slot"));
+        }
+        if self.slots.len() == 1 {
+            return Err(FsError::Other("cannot remove the last key slot"));
+        }
+        self.slots.retain(|s| s.id != id);
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+use std::path::{Component, Path, PathBuf};
+
+use shush_rs::{ExposeSecret, SecretString};
+
+use crate::encryptedfs::{
+    CreateFileAttr, DirectoryEntryIterator, EncryptedFile, EncryptedFs, FileAttr, FileType,
+    FsError, FsResult, ROOT_INODE,
+};
+
+/// Access [`EncryptedFs`] by paths, like [`std::fs`], it resolves them to inodes for you.
+///
+/// Paths are relative to the root, `/a/b` and `a/b` are the same. `.` and `..` are resolved
+/// lexically and symlinks are not followed, they are the nodes themselves.
+///
+/// Get it with [`EncryptedFs::path`].
+pub struct PathFs<'a> {
+    fs: &'a EncryptedFs,
+}
+
+impl EncryptedFs {
+    /// Path based API on top of the inode operations.
+    #[must_use]
+    pub const fn path(&self) -> PathFs<'_> {
+        PathFs { fs: self }
+    }
+}
+
+impl PathFs<'_> {
+    /// Attributes of the node, like [`std::fs::symlink_metadata`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn metadata(&self, path: impl AsRef<Path>) -> FsResult<FileAttr> {
+        let mut attr = self.fs.get_attr(ROOT_INODE).await?;
+        for name in resolve(path.as_ref())? {
+            attr = self
+                .fs
+                .find_by_name(attr.ino, &name)
+                .await?
+                .ok_or(FsError::NotFound("path not found"))?;
+        }
+        Ok(attr)
+    }
+
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn exists(&self, path: impl AsRef<Path>) -> FsResult<bool> {
+        match self.metadata(path).await {
+            Ok(_) => Ok(true),
+            Err(FsError::NotFound(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Open an existing file, like [`std::fs::OpenOptions::open`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn open(
+        &self,
+        path: impl AsRef<Path>,
+        read: bool,
+        write: bool,
+    ) -> FsResult<EncryptedFile> {
+        let attr = self.metadata(path).await?;
+        if attr.kind != FileType::RegularFile {
+            return Err(FsError::InvalidInodeType);
+        }
+        self.fs.self_arc().open_file(attr.ino, read, write).await
+    }
+
+    /// Open the file for write, it's created if it doesn't exist or truncated if it does, like
+    /// [`std::fs::File::create`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create(&self, path: impl AsRef<Path>) -> FsResult<(u64, FileAttr)> {
+        let (parent, name) = self.parent_and_name(path.as_ref()).await?;
+        if let Some(attr) = self.fs.find_by_name(parent, &name).await? {
+            if attr.kind != FileType::RegularFile {
+                return Err(FsError::InvalidInodeType);
+            }
+            self.fs.set_len(attr.ino, 0).await?;
+            let fh = self.fs.open(attr.ino, false, true).await?;
+            return Ok((fh, self.fs.get_attr(attr.ino).await?));
+        }
+        self.fs
+            .create(
+                parent,
+                &name,
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+    }
+
+    /// Create a directory, the parent needs to exist, like [`std::fs::create_dir`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create_dir(&self, path: impl AsRef<Path>) -> FsResult<FileAttr> {
+        let (parent, name) = self.parent_and_name(path.as_ref()).await?;
+        let (_, attr) = self
+            .fs
+            .create(
+                parent,
+                &name,
+                create_attr(FileType::Directory),
+                false,
+                false,
+            )
+            .await?;
+        Ok(attr)
+    }
+
+    /// Create the directory and all the missing parents, like [`std::fs::create_dir_all`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create_dir_all(&self, path: impl AsRef<Path>) -> FsResult<FileAttr> {
+        let mut attr = self.fs.get_attr(ROOT_INODE).await?;
+        for name in resolve(path.as_ref())? {
+            attr = match self.fs.find_by_name(attr.ino, &name).await? {
+                Some(attr) if attr.kind == FileType::Directory => attr,
+                Some(_) => return Err(FsError::InvalidInodeType),
+                None => {
+                    self.fs
+                        .create(
+                            attr.ino,
+                            &name,
+                            create_attr(FileType::Directory),
+                            false,
+                            false,
+                        )
+                        .await?
+                        .1
+                }
+            };
+        }
+        Ok(attr)
+    }
+
+    /// Read the whole file, like [`std::fs::read`].
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub async fn read_to_end(&self, path: impl AsRef<Path>) -> FsResult<Vec<u8>> {
+        let attr = self.metadata(path).await?;
+        if attr.kind != FileType::RegularFile {
+            return Err(FsError::InvalidInodeType);
+        }
+        let fh = self.fs.open(attr.ino, true, false).await?;
+        let mut buf = vec![0; attr.size as usize];
+        let res = async {
+            let mut pos = 0;
+            loop {
+                if pos == buf.len() {
+                    // it might have grown meanwhile
+                    buf.resize(pos + 4096, 0);
+                }
+                let len = self
+                    .fs
+                    .read(attr.ino, pos as u64, &mut buf[pos..], fh)
+                    .await?;
+                if len == 0 {
+                    break;
+                }
+                pos += len;
+            }
+            buf.truncate(pos);
+            Ok(buf)
+        }
+        .await;
+        self.fs.release(fh).await?;
+        res
+    }
+
+    /// Write the whole content to the file, it's created if it doesn't exist or replaced if it
+    /// does, like [`std::fs::write`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn write_all(&self, path: impl AsRef<Path>, buf: &[u8]) -> FsResult<()> {
+        let (fh, attr) = self.create(path).await?;
+        let res = async {
+            let mut pos = 0;
+            while pos < buf.len() {
+                let len = self.fs.write(attr.ino, pos as u64, &buf[pos..], fh).await?;
+                if len == 0 {
+                    return Err(FsError::Other("Failed to write all bytes"));
+                }
+                pos += len;
+            }
+            self.fs.flush(fh).await
+        }
+        .await;
+        self.fs.release(fh).await?;
+        res
+    }
+
+    /// Entries of the directory, without `.` and `..`.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_dir(&self, path: impl AsRef<Path>) -> FsResult<DirectoryEntryIterator> {
+        let attr = self.metadata(path).await?;
+        let entries = self.fs.read_dir(attr.ino).await?.filter(|entry| {
+            entry.as_ref().map_or(true, |entry| {
+                let name = entry.name.expose_secret();
+                *name != "." && *name != ".."
+            })
+        });
+        Ok(DirectoryEntryIterator(entries.collect()))
+    }
+
+    /// All the nodes under the directory, recursively, with their paths. Each directory comes
+    /// before its entries, the order of the entries in a directory is not defined.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn walk(&self, path: impl AsRef<Path>) -> FsResult<Vec<(PathBuf, FileAttr)>> {
+        Ok(self
+            .walk_entries(path.as_ref())
+            .await?
+            .into_iter()
+            .map(|entry| (entry.path, entry.attr))
+            .collect())
+    }
+
+    /// Remove a file or a symlink, like [`std::fs::remove_file`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn remove_file(&self, path: impl AsRef<Path>) -> FsResult<()> {
+        let (parent, name) = self.parent_and_name(path.as_ref()).await?;
+        self.fs.remove_file(parent, &name).await
+    }
+
+    /// Remove an empty directory, like [`std::fs::remove_dir`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn remove_dir(&self, path: impl AsRef<Path>) -> FsResult<()> {
+        let (parent, name) = self.parent_and_name(path.as_ref()).await?;
+        self.fs.remove_dir(parent, &name).await
+    }
+
+    /// Remove the directory and everything in it, like [`std::fs::remove_dir_all`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn remove_dir_all(&self, path: impl AsRef<Path>) -> FsResult<()> {
+        let (parent, name) = self.parent_and_name(path.as_ref()).await?;
+        // entries come after their directory, so in reverse we remove the content first
+        for entry in self.walk_entries(path.as_ref()).await?.into_iter().rev() {
+            if entry.attr.kind == FileType::Directory {
+                self.fs.remove_dir(entry.parent, &entry.name).await?;
+            } else {
+                self.fs.remove_file(entry.parent, &entry.name).await?;
+            }
+        }
+        self.fs.remove_dir(parent, &name).await
+    }
+
+    /// Move the node, replacing the destination if it exists, like [`std::fs::rename`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> FsResult<()> {
+        let (parent, name) = self.parent_and_name(from.as_ref()).await?;
+        let (new_parent, new_name) = self.parent_and_name(to.as_ref()).await?;
+        self.fs.rename(parent, &name, new_parent, &new_name).await
+    }
+
+    /// Inode of the parent directory and the name in it, the root has none.
+    async fn parent_and_name(&self, path: &Path) -> FsResult<(u64, SecretString)> {
+        let mut names = resolve(path)?;
+        let name = names
+            .pop()
+            .ok_or(FsError::InvalidInput("the root has no parent"))?;
+        let mut parent = ROOT_INODE;
+        for name in names {
+            let attr = self
+                .fs
+                .find_by_name(parent, &name)
+                .await?
+                .ok_or(FsError::NotFound("path not found"))?;
+            parent = attr.ino;
+        }
+        if !self.fs.is_dir(parent) {
+            return Err(FsError::InvalidInodeType);
+        }
+        Ok((parent, name))
+    }
+
+    /// Directories come before their entries, depth first.
+    async fn walk_entries(&self, path: &Path) -> FsResult<Vec<WalkEntry>> {
+        let attr = self.metadata(path).await?;
+        if attr.kind != FileType::Directory {
+            return Err(FsError::InvalidInodeType);
+        }
+        let mut entries = vec![];
+        let mut stack = self.dir_entries(path, attr.ino).await?;
+        while let Some(entry) = stack.pop() {
+            if entry.attr.kind == FileType::Directory {
+                stack.extend(self.dir_entries(&entry.path, entry.attr.ino).await?);
+            }
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    /// In reverse, so they are popped in order.
+    async fn dir_entries(&self, path: &Path, ino: u64) -> FsResult<Vec<WalkEntry>> {
+        let mut entries = vec![];
+        for entry in self.fs.read_dir_plus(ino).await? {
+            let entry = entry?;
+            let name = entry.name.expose_secret();
+            if *name == "." || *name == ".." {
+                continue;
+            }
+            entries.push(WalkEntry {
+                path: path.join(&**name),
+                parent: ino,
+                name: entry.name.clone(),
+                attr: entry.attr,
+            });
+        }
+        entries.reverse();
+        Ok(entries)
+    }
+}
+
+struct WalkEntry {
+    path: PathBuf,
+    parent: u64,
+    name: SecretString,
+    attr: FileAttr,
+}
+
+/// Names from the root to the node, `.` and `..` are resolved.
+fn resolve(path: &Path) -> FsResult<Vec<SecretString>> {
+    let mut names = vec![];
+    for component in path.components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => {
+                // the parent of the root is the root
+                names.pop();
+            }
+            Component::Normal(name) => names.push(SecretString::new(Box::new(
+                name.to_str()
+                    .ok_or(FsError::InvalidInput("path is not valid UTF-8"))?
+                    .to_owned(),
+            ))),
+            Component::Prefix(_) => return Err(FsError::InvalidInput("path prefix not supported")),
+        }
+    }
+    Ok(names)
+}
+
+/// Owned by the current user, like the root is.
+fn create_attr(kind: FileType) -> CreateFileAttr {
+    #[allow(unused_mut)]
+    let mut attr = CreateFileAttr {
+        kind,
+        perm: if kind == FileType::Directory {
+            0o755
+        } else {
+            0o644
+        },
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+    };
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    unsafe {
+        attr.uid = libc::getuid();
+        attr.gid = libc::getgid();
+    }
+    attr
+}
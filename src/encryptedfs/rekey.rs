@@ -0,0 +1,578 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use rand_chacha::rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+use shush_rs::{ExposeSecret, SecretBox, SecretString, SecretVec};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use super::keyslots::KeySlot;
+
+use crate::crypto::write::BLOCK_SIZE;
+use crate::crypto::{self, Cipher};
+use crate::encryptedfs::fsck::list_inos;
+use crate::encryptedfs::journal::JournalOp;
+use crate::encryptedfs::{
+    check_structure, lock_data_dir, read_key_slots, DirectoryEntry, EncryptedFs, FileAttr,
+    FileType, FsError, FsResult, VolumeMetadata, CONTENTS_DIR, HASH_DIR, INODES_DIR, LS_DIR,
+    SECURITY_DIR,
+};
+use crate::fs_util;
+
+pub(crate) const REKEY_FILENAME: &str = "rekey";
+
+/// Context used to derive [`RekeyState::check`] from the new master key.
+const REKEY_CHECK_CONTEXT: &str = "rencfs 2026-10-18 rekey check";
+
+/// The progress is saved and reported after this many inodes.
+const PROGRESS_INTERVAL: u64 = 100;
+
+/// Progress of [`EncryptedFs::rekey`], in inodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RekeyProgress {
+    pub done: u64,
+    pub total: u64,
+}
+
+/// Rekey in progress, stored in `security/rekey` so it can be resumed.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct RekeyState {
+    /// New master key in a slot for the password which started the rekey, it replaces the slots
+    /// when done. It's not encrypted with the old key, as whoever had that one shouldn't get the
+    /// new one
+    pending: KeySlot,
+    /// Subkey of the new master key, if the key slots unlock a key with the same subkey they
+    /// were already switched to the new key
+    check: Vec<u8>,
+    /// The inodes before it are re-encrypted
+    next_ino: u64,
+}
+
+impl RekeyState {
+    fn path(data_dir: &Path) -> PathBuf {
+        data_dir.join(SECURITY_DIR).join(REKEY_FILENAME)
+    }
+
+    pub(crate) fn exists(data_dir: &Path) -> bool {
+        Self::path(data_dir).is_file()
+    }
+
+    fn read(data_dir: &Path) -> FsResult<Option<Self>> {
+        let path = Self::path(data_dir);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(bincode::deserialize_from(File::open(path)?)?))
+    }
+
+    fn write(&self, data_dir: &Path) -> FsResult<()> {
+        let mut file = fs_util::open_atomic_write(&Self::path(data_dir))?;
+        bincode::serialize_into(&mut file, self)?;
+        file.flush()?;
+        file.commit()?;
+        File::open(data_dir.join(SECURITY_DIR))?.sync_all()?;
+        Ok(())
+    }
+
+    pub(crate) fn remove(data_dir: &Path) -> FsResult<()> {
+        match fs::remove_file(Self::path(data_dir)) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        }
+        File::open(data_dir.join(SECURITY_DIR))?.sync_all()?;
+        Ok(())
+    }
+}
+
+fn check_subkey(key: &SecretVec<u8>) -> Vec<u8> {
+    crypto::derive_subkey(key, REKEY_CHECK_CONTEXT)
+        .expose_secret()
+        .clone()
+}
+
+/// The new master key while a rekey is in progress, `key` is the one the key slots unlock with
+/// `password`.
+pub(crate) fn pending_key(
+    data_dir: &Path,
+    key: &SecretVec<u8>,
+    password: &SecretString,
+    cipher: Cipher,
+) -> FsResult<Option<SecretVec<u8>>> {
+    let Some(state) = RekeyState::read(data_dir)? else {
+        return Ok(None);
+    };
+    if check_subkey(key) == state.check {
+        // the slots were switched but we crashed before removing the state
+        return Ok(None);
+    }
+    let new_key = state
+        .pending
+        .unlock(password, cipher)
+        .map_err(|err| match err {
+            FsError::InvalidPassword => {
+                FsError::Other("the rekey was started with the password of another key slot")
+            }
+            err => err,
+        })?;
+    Ok(Some(new_key))
+}
+
+/// If the error is because the data doesn't decrypt with the key, not from reading it.
+fn is_decrypt_error(err: &FsError) -> bool {
+    match err {
+        FsError::Io { source, .. } => source.kind() == io::ErrorKind::InvalidData,
+        FsError::SerializeError { source, .. } => {
+            matches!(&**source, bincode::ErrorKind::Io(err) if err.kind() == io::ErrorKind::InvalidData)
+        }
+        _ => false,
+    }
+}
+
+/// What we cannot re-encrypt stops the rekey, so it's not finished while there is data only the
+/// old key can read.
+fn not_reencrypted(ino: u64, what: &str) -> FsError {
+    warn!(ino, "cannot decrypt {what} with the old or the new key");
+    FsError::Other(
+        "cannot decrypt some data with the old or the new key, the rekey is not finished",
+    )
+}
+
+impl EncryptedFs {
+    /// Start replacing the master key with a new random one, the data is re-encrypted with it
+    /// by [`Self::rekey`].
+    ///
+    /// `password` needs to unlock one of the key slots, only it unlocks the data while the rekey
+    /// is in progress and after. The other key slots are removed when it's done, if there are
+    /// any we fail unless `drop_other_slots`. If a rekey was already started it's kept, so it can
+    /// be resumed.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn start_rekey(
+        data_dir: &Path,
+        password: SecretString,
+        cipher: Cipher,
+        drop_other_slots: bool,
+    ) -> FsResult<()> {
+        check_structure(data_dir, false).await?;
+        // not while it's opened in read-write mode, it wouldn't know about the new key
+        let _lock = lock_data_dir(data_dir)?;
+        let cipher = VolumeMetadata::read(data_dir)?.map_or(cipher, |v| v.cipher);
+        let key_slots = read_key_slots(data_dir)?;
+        let (id, key) = key_slots.unlock(&password, cipher)?;
+        if pending_key(data_dir, &key, &password, cipher)?.is_some() {
+            return Ok(());
+        }
+        if key_slots.slots().len() > 1 && !drop_other_slots {
+            return Err(FsError::Other("the other key slots would be removed"));
+        }
+        let kdf = key_slots
+            .slots()
+            .iter()
+            .find(|slot| slot.id == id)
+            .ok_or(FsError::NotFound("key slot"))?
+            .kdf;
+        let mut new_key = vec![0; cipher.key_len()];
+        crypto::create_rng().fill_bytes(&mut new_key);
+        let new_key = SecretBox::new(Box::new(new_key));
+        RekeyState {
+            pending: KeySlot::new(id, &new_key, &password, cipher, kdf)?,
+            check: check_subkey(&new_key),
+            next_ino: 0,
+        }
+        .write(data_dir)
+    }
+
+    /// If a rekey was started and not finished yet.
+    #[allow(clippy::missing_panics_doc)]
+    #[must_use]
+    pub fn rekey_in_progress(&self) -> bool {
+        self.old_name_hash_key
+            .read()
+            .expect("cannot obtain lock")
+            .is_some()
+    }
+
+    /// Re-encrypt all inodes, contents, extended attributes and directory entries with the new
+    /// master key from [`Self::start_rekey`], then replace the key slots with the one for its
+    /// password.
+    ///
+    /// The other key slots are removed, we don't know their passwords so they cannot have the
+    /// new key. Add them again after. If something doesn't decrypt with the old or the new key
+    /// we fail and the rekey stays in progress.
+    ///
+    /// It can run while the filesystem is used, what was not re-encrypted yet is read with the
+    /// old key. The progress is saved, if it's interrupted it continues from there next time.
+    /// Nothing to do if there is no rekey in progress.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn rekey(&self, mut progress: impl FnMut(RekeyProgress) + Send) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let _running = self
+            .rekey_running
+            .try_lock()
+            .map_err(|_| FsError::Other("rekey is already running"))?;
+        if !self.rekey_in_progress() {
+            return Ok(());
+        }
+        let Some(mut state) = RekeyState::read(&self.data_dir)? else {
+            return Ok(());
+        };
+        self.remove_rekey_temp_files()?;
+
+        let mut inos = list_inos(&self.data_dir.join(INODES_DIR))?;
+        inos.sort_unstable();
+        let total = inos.len() as u64;
+        let start = state.next_ino;
+        let mut done = inos.iter().filter(|ino| **ino < start).count() as u64;
+        info!("re-encrypting {} inodes", total - done);
+        progress(RekeyProgress { done, total });
+        for ino in inos.into_iter().filter(|ino| *ino >= start) {
+            self.rekey_inode(ino).await?;
+            done += 1;
+            if done % PROGRESS_INTERVAL == 0 && done < total {
+                state.next_ino = ino.saturating_add(1);
+                state.write(&self.data_dir)?;
+                progress(RekeyProgress { done, total });
+            }
+        }
+
+        self.finish_rekey(state).await?;
+        progress(RekeyProgress { done, total });
+        Ok(())
+    }
+
+    /// If the content of the file is encrypted with the key, `None` if there is no data, which
+    /// reads the same with any key.
+    fn content_encrypted_with(&self, ino: u64, key: &SecretVec<u8>) -> FsResult<Option<bool>> {
+        let mut store = match crypto::create_block_store(
+            File::open(self.contents_path(ino))?,
+            self.cipher,
+            key,
+        ) {
+            Ok(store) => store,
+            // the map of the holes doesn't decrypt
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => return Ok(Some(false)),
+            Err(err) => return Err(err.into()),
+        };
+        if store.is_empty()? {
+            return Ok(None);
+        }
+        if store.has_holes_map() {
+            return Ok(Some(true));
+        }
+        // holes read the same with any key, the first block with data tells which one it is
+        let Some(offset) = store.seek_data(0)? else {
+            return Ok(None);
+        };
+        match store.read_block(offset / BLOCK_SIZE as u64) {
+            Ok(_) => Ok(Some(true)),
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => Ok(Some(false)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Key to use for the content of the file, while a rekey is in progress it might be the old
+    /// one still.
+    pub(crate) async fn content_key(&self, ino: u64) -> FsResult<Arc<SecretVec<u8>>> {
+        if self.rekey_in_progress() {
+            let old_key = self.old_key.get().await?;
+            if self.content_encrypted_with(ino, &old_key)? == Some(true) {
+                return Ok(old_key);
+            }
+        }
+        self.key.get().await
+    }
+
+    /// Decrypt with the master key, while a rekey is in progress fall back to the old one for
+    /// what was not re-encrypted yet.
+    pub(crate) async fn decrypt_with_keys<T>(
+        &self,
+        f: impl Fn(&SecretVec<u8>) -> FsResult<T> + Send,
+    ) -> FsResult<T> {
+        match f(&*self.key.get().await?) {
+            Err(err) if self.rekey_in_progress() => f(&*self.old_key.get().await?).map_err(|_| err),
+            res => res,
+        }
+    }
+
+    /// Path of the file in `hash/` of the directory for the name, `None` if there is no entry.
+    ///
+    /// While a rekey is in progress the name might be hashed with the old key still.
+    pub(crate) fn find_hash_path(&self, parent: u64, name: &SecretString) -> Option<PathBuf> {
+        let hash_dir = self.contents_path(parent).join(HASH_DIR);
+        let path = hash_dir.join(self.hash_file_name(name));
+        if path.is_file() {
+            return Some(path);
+        }
+        let old_path = self
+            .old_name_hash_key
+            .read()
+            .expect("cannot obtain lock")
+            .as_ref()
+            .map(|hash_key| hash_dir.join(crypto::hash_file_name(name, hash_key)))?;
+        if old_path.is_file() {
+            return Some(old_path);
+        }
+        // it might have been re-encrypted meanwhile, the new entry is written before the old one
+        // is removed
+        path.is_file().then_some(path)
+    }
+
+    /// Leftovers of the contents we were re-encrypting when interrupted.
+    fn remove_rekey_temp_files(&self) -> FsResult<()> {
+        for entry in fs::read_dir(self.data_dir.join(CONTENTS_DIR))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') && name.ends_with(".rekey") {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn rekey_inode(&self, ino: u64) -> FsResult<()> {
+        let Some(attr) = self.rekey_inode_file(ino).await? else {
+            // removed meanwhile
+            return Ok(());
+        };
+        self.rekey_xattrs(ino).await?;
+        match attr.kind {
+            FileType::RegularFile | FileType::Symlink => self.rekey_contents(ino).await,
+            FileType::Directory => self.rekey_dir_entries(ino).await,
+        }
+    }
+
+    async fn rekey_inode_file(&self, ino: u64) -> FsResult<Option<FileAttr>> {
+        let lock = self
+            .serialize_inode_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.write().await;
+        let path = self.ino_file(ino);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let read = |key: &SecretVec<u8>| -> FsResult<FileAttr> {
+            Ok(bincode::deserialize_from(crypto::create_read(
+                File::open(&path)?,
+                self.cipher,
+                key,
+            ))?)
+        };
+        let key = self.key.get().await?;
+        match read(&key) {
+            Ok(attr) => return Ok(Some(attr)),
+            Err(err) if is_decrypt_error(&err) => {}
+            Err(err) => return Err(err),
+        }
+        let attr = match read(&*self.old_key.get().await?) {
+            Ok(attr) => attr,
+            Err(err) if is_decrypt_error(&err) => return Err(not_reencrypted(ino, "inode")),
+            Err(err) => return Err(err),
+        };
+        crypto::atomic_serialize_encrypt_into(&path, &attr, self.cipher, &key)?;
+        Ok(Some(attr))
+    }
+
+    async fn rekey_xattrs(&self, ino: u64) -> FsResult<()> {
+        let lock = self
+            .xattr_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.write().await;
+        let path = self.xattrs_path(ino);
+        if !path.is_file() {
+            return Ok(());
+        }
+        let read = |key: &SecretVec<u8>| -> FsResult<std::collections::BTreeMap<String, Vec<u8>>> {
+            Ok(bincode::deserialize_from(crypto::create_read(
+                File::open(&path)?,
+                self.cipher,
+                key,
+            ))?)
+        };
+        let key = self.key.get().await?;
+        match read(&key) {
+            Ok(_) => return Ok(()),
+            Err(err) if is_decrypt_error(&err) => {}
+            Err(err) => return Err(err),
+        }
+        let xattrs = match read(&*self.old_key.get().await?) {
+            Ok(xattrs) => xattrs,
+            Err(err) if is_decrypt_error(&err) => {
+                return Err(not_reencrypted(ino, "extended attributes"))
+            }
+            Err(err) => return Err(err),
+        };
+        crypto::atomic_serialize_encrypt_into(&path, &xattrs, self.cipher, &key)?;
+        Ok(())
+    }
+
+    /// Copy the blocks with data to a new file encrypted with the new key and replace the old one.
+    async fn rekey_contents(&self, ino: u64) -> FsResult<()> {
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _write_guard = lock.write().await;
+        // the writes of the open handles are in the file after
+        self.flush_and_reset_writers(ino).await?;
+        let path = self.contents_path(ino);
+        if !path.is_file() {
+            return Ok(());
+        }
+        let key = self.key.get().await?;
+        let old_key = self.old_key.get().await?;
+        match self.content_encrypted_with(ino, &old_key)? {
+            Some(true) => {}
+            // it's written with the new key from now on
+            None => return Ok(()),
+            Some(false) => {
+                if self.content_encrypted_with(ino, &key)? == Some(false) {
+                    return Err(not_reencrypted(ino, "content"));
+                }
+                return Ok(());
+            }
+        }
+
+        let tmp_path = self
+            .data_dir
+            .join(CONTENTS_DIR)
+            .join(format!(".{ino}.rekey"));
+        let mut src = crypto::create_block_store(File::open(&path)?, self.cipher, &old_key)?;
+        let mut dst = crypto::create_block_store(
+            File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&tmp_path)?,
+            self.cipher,
+            &key,
+        )?;
+        src.copy_to(&mut dst)?;
+        dst.finish()?.sync_all()?;
+        {
+            // so it's not removed meanwhile, or we would bring back its contents
+            let lock = self
+                .serialize_inode_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _guard = lock.read().await;
+            if !self.ino_file(ino).is_file() {
+                fs::remove_file(&tmp_path)?;
+                return Ok(());
+            }
+            fs::rename(&tmp_path, &path)?;
+            File::open(self.data_dir.join(CONTENTS_DIR))?.sync_all()?;
+        }
+
+        // the handles still have the old file
+        if let Some(fh) = self.any_write_handle(ino).await {
+            if let Some(ctx) = self.write_handles.read().await.get(&fh).cloned() {
+                ctx.lock().await.store = crypto::create_block_store(
+                    File::options().read(true).write(true).open(&path)?,
+                    self.cipher,
+                    &key,
+                )?;
+            }
+        }
+        self.reset_handles(ino, None, false).await?;
+        Ok(())
+    }
+
+    /// Write the entries which are not encrypted with the new key again, with their name hashes,
+    /// and remove the old ones, each in a transaction.
+    async fn rekey_dir_entries(&self, ino: u64) -> FsResult<()> {
+        let ls_dir = self.contents_path(ino).join(LS_DIR);
+        let names = match fs::read_dir(&ls_dir) {
+            Ok(iter) => iter
+                .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().to_string()))
+                .collect::<io::Result<Vec<_>>>()?,
+            // removed meanwhile
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let key = self.key.get().await?;
+        let old_key = self.old_key.get().await?;
+        let old_hash_key = crypto::derive_subkey(&old_key, crypto::FILE_NAME_HASH_KEY_CONTEXT);
+        // temporary files of atomic writes start with '.'
+        for name in names.into_iter().filter(|name| !name.starts_with('.')) {
+            // so the entry is not changed by others meanwhile
+            let _guard = self.rekey_lock.write().await;
+            let path = ls_dir.join(&name);
+            if !path.is_file() {
+                continue;
+            }
+            let read = |key: &SecretVec<u8>| -> FsResult<(u64, FileType)> {
+                Ok(bincode::deserialize_from(crypto::create_read(
+                    File::open(&path)?,
+                    self.cipher,
+                    key,
+                ))?)
+            };
+            match read(&key) {
+                Ok(_) => continue,
+                Err(err) if is_decrypt_error(&err) => {}
+                Err(err) => return Err(err),
+            }
+            let (entry_ino, kind) = match read(&old_key) {
+                Ok(entry) => entry,
+                Err(err) if is_decrypt_error(&err) => {
+                    return Err(not_reencrypted(ino, "directory entry"))
+                }
+                Err(err) => return Err(err),
+            };
+            let special = name == "$." || name == "$..";
+            let entry_name = if special {
+                SecretString::from_str(&name).unwrap()
+            } else {
+                crypto::decrypt_file_name(&name, self.cipher, &old_key)
+                    .map_err(|_| not_reencrypted(ino, "directory entry name"))?
+            };
+            let old_hash_name = crypto::hash_file_name(&entry_name, &old_hash_key);
+            let mut ops = self
+                .insert_directory_entry_ops(
+                    ino,
+                    &DirectoryEntry {
+                        ino: entry_ino,
+                        name: entry_name.clone(),
+                        kind,
+                    },
+                )
+                .await?;
+            // `$.` and `$..` keep the names, they were replaced
+            if old_hash_name != self.hash_file_name(&entry_name) {
+                ops.push(JournalOp::RemoveHash {
+                    parent: ino,
+                    hash_name: old_hash_name,
+                });
+            }
+            if !special {
+                ops.push(JournalOp::RemoveLs {
+                    parent: ino,
+                    encrypted_name: name,
+                });
+            }
+            self.journal(ops).await?;
+        }
+        Ok(())
+    }
+
+    /// Switch the key slots to the pending one with the new key and forget the old one.
+    async fn finish_rekey(&self, state: RekeyState) -> FsResult<()> {
+        // before the key slots, while it's in progress it opens with both keys
+        if let Some(volume) = VolumeMetadata::read(&self.data_dir)? {
+            volume.write(&self.data_dir, &*self.key.get().await?)?;
+        }
+        let mut key_slots = read_key_slots(&self.data_dir)?;
+        key_slots.rekey(state.pending);
+        key_slots.write(&self.data_dir)?;
+        RekeyState::remove(&self.data_dir)?;
+        *self.old_name_hash_key.write().expect("cannot obtain lock") = None;
+        self.old_key.clear().await;
+        info!("rekey finished");
+        Ok(())
+    }
+}
@@ -1,24 +1,41 @@
+use std::collections::HashSet;
+use std::io::{Cursor, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::string::ToString;
 use std::time::SystemTime;
 
+use futures_util::StreamExt;
+use ring::aead::{CHACHA20_POLY1305, NONCE_LEN};
 use shush_rs::{ExposeSecret, SecretString};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tracing_test::traced_test;
 
-use crate::crypto::Cipher;
+use crate::crypto::write::{CryptoWrite, RingCryptoWrite, BLOCK_SIZE};
+use crate::crypto::{Cipher, KdfParams};
+use crate::encryptedfs::fsck::list_inos;
+use crate::encryptedfs::journal::{JournalOp, WAL_DIR};
+use crate::encryptedfs::keyslots::KEYSLOTS_FILENAME;
+use crate::encryptedfs::rekey::REKEY_FILENAME;
+use crate::encryptedfs::volume::VOLUME_FILENAME;
 use crate::encryptedfs::write_all_bytes_to_fs;
 use crate::encryptedfs::INODES_DIR;
 use crate::encryptedfs::KEY_ENC_FILENAME;
 use crate::encryptedfs::KEY_SALT_FILENAME;
 use crate::encryptedfs::SECURITY_DIR;
-use crate::encryptedfs::{CopyFileRangeReq, HASH_DIR};
+use crate::encryptedfs::{CopyFileRangeReq, HASH_DIR, LS_DIR};
 use crate::encryptedfs::{
-    DirectoryEntry, DirectoryEntryPlus, EncryptedFs, FileType, FsError, FsResult, SetFileAttr,
-    CONTENTS_DIR, ROOT_INODE,
+    DirectoryEntry, DirectoryEntryPlus, EncryptedFs, FallocateMode, FileType, FsError, FsResult,
+    FsckProblem, SetFileAttr, SetXattrMode, VolumeMetadata, CONTENTS_DIR, CURRENT_FORMAT_VERSION,
+    LOST_AND_FOUND, ROOT_INODE, XATTRS_DIR, XATTR_VALUE_MAX,
+};
+use crate::encryptedfs::{
+    InMemoryPasswordProvider, KeyMaterialProvider, KeySlots, KeyfileProvider,
+    PasswordKeyfileProvider, PasswordProvider,
 };
 use crate::test_common::run_test;
 use crate::test_common::TestSetup;
-use crate::test_common::{create_attr, get_fs, PasswordProviderImpl};
+use crate::test_common::{create_attr, get_fs, take_fs, PasswordProviderImpl};
 use crate::{crypto, test_common};
 
 static ROOT_INODE_STR: &str = "1";
@@ -1173,13 +1190,13 @@ async fn test_create_structure_and_root() {
             assert!(fs
                 .data_dir
                 .join(SECURITY_DIR)
-                .join(KEY_ENC_FILENAME)
+                .join(KEYSLOTS_FILENAME)
                 .is_file());
-            assert!(fs
+            assert!(!fs
                 .data_dir
                 .join(SECURITY_DIR)
-                .join(KEY_SALT_FILENAME)
-                .is_file());
+                .join(KEY_ENC_FILENAME)
+                .exists());
 
             assert!(fs.data_dir.join(INODES_DIR).join(ROOT_INODE_STR).is_file());
             assert!(fs.data_dir.join(CONTENTS_DIR).join(ROOT_INODE_STR).is_dir());
@@ -1229,7 +1246,7 @@ async fn test_create() {
                 .join(CONTENTS_DIR)
                 .join(ROOT_INODE_STR)
                 .join(HASH_DIR)
-                .join(crypto::hash_file_name(&test_file))
+                .join(fs.hash_file_name(&test_file))
                 .is_file());
             assert!(fs.exists(attr.ino));
             assert_eq!(attr, fs.get_attr(attr.ino).await.unwrap());
@@ -1278,7 +1295,7 @@ async fn test_create() {
                 .join(CONTENTS_DIR)
                 .join(ROOT_INODE_STR)
                 .join(HASH_DIR)
-                .join(crypto::hash_file_name(&test_dir))
+                .join(fs.hash_file_name(&test_dir))
                 .is_file());
             assert!(fs.exists(attr.ino));
             assert_eq!(attr, fs.get_attr(attr.ino).await.unwrap());
@@ -1329,7 +1346,7 @@ async fn test_create() {
                 .join(CONTENTS_DIR)
                 .join(parent.to_string())
                 .join(HASH_DIR)
-                .join(crypto::hash_file_name(&test_dir_2))
+                .join(fs.hash_file_name(&test_dir_2))
                 .is_file());
             assert!(fs.exists(attr.ino));
             assert_eq!(attr, fs.get_attr(attr.ino).await.unwrap());
@@ -2303,28 +2320,67 @@ async fn test_open() {
             let fh_2 = fs.open(attr.ino, true, false).await.unwrap();
             assert_ne!(fh_2, 0);
             // write and read
-            let _ = fs.open(attr.ino, false, true).await.unwrap();
-            // ensure cannot open multiple write
-            assert!(matches!(
-                fs.open(attr.ino, false, true).await,
-                Err(FsError::AlreadyOpenForWrite)
-            ));
+            let fh_3 = fs.open(attr.ino, false, true).await.unwrap();
+            // multiple write
+            let fh_4 = fs.open(attr.ino, false, true).await.unwrap();
+            assert_ne!(fh_3, fh_4);
         },
     )
     .await;
 }
 
-// #[tokio::test]
-// #[traced_test]
-#[allow(clippy::too_many_lines)]
-async fn _test_sample() {
+#[tokio::test]
+#[traced_test]
+async fn test_multiple_writers() {
     run_test(
         TestSetup {
-            key: "test_sample",
+            key: "test_multiple_writers",
             read_only: false,
         },
         async {
-            let _ = get_fs().await;
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let fh_2 = fs.open(attr.ino, false, true).await.unwrap();
+            let read_fh = fs.open(attr.ino, true, false).await.unwrap();
+
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"aaaaaaaaaa", fh)
+                .await
+                .unwrap();
+            // overwrite in the middle with the other handle
+            write_all_bytes_to_fs(&fs, attr.ino, 4, b"bb", fh_2)
+                .await
+                .unwrap();
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 10);
+            // they are seen before release
+            let mut buf = [0; 10];
+            let len = fs.read(attr.ino, 0, &mut buf, read_fh).await.unwrap();
+            assert_eq!(&buf[..len], b"aaaabbaaaa");
+
+            // the other one can still write
+            fs.release(fh).await.unwrap();
+            assert!(!fs.is_write_handle(fh).await);
+            assert_eq!(fs.get_inode_from_storage(attr.ino).await.unwrap().size, 10);
+            write_all_bytes_to_fs(&fs, attr.ino, 10, b"cc", fh_2)
+                .await
+                .unwrap();
+            fs.release(fh_2).await.unwrap();
+            fs.release(read_fh).await.unwrap();
+            assert_eq!(
+                test_common::read_to_string(attr.ino, &fs).await,
+                "aaaabbaaaacc"
+            );
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 12);
         },
     )
     .await;
@@ -2332,28 +2388,106 @@ async fn _test_sample() {
 
 #[tokio::test]
 #[traced_test]
-#[allow(clippy::too_many_lines)]
-async fn test_read_only_create() {
+async fn test_write_in_place() {
     run_test(
         TestSetup {
-            key: "test_read_only_create",
-            read_only: true,
+            key: "test_write_in_place",
+            read_only: false,
         },
         async {
             let fs = get_fs().await;
 
-            // Check creating a file in a read only fs
-            let test_file = SecretString::from_str("test-file").unwrap();
-            let create_file_result = fs
+            let (fh, attr) = fs
                 .create(
                     ROOT_INODE,
-                    &test_file,
+                    &SecretString::from_str("test-file").unwrap(),
                     create_attr(FileType::RegularFile),
-                    true,
                     false,
+                    true,
                 )
-                .await;
-            assert!(matches!(create_file_result, Err(FsError::ReadOnly)));
+                .await
+                .unwrap();
+            let read_fhs = [
+                fs.open(attr.ino, true, false).await.unwrap(),
+                fs.open(attr.ino, true, false).await.unwrap(),
+            ];
+            // all the handles see the same, at any offset
+            let check = |expected: Vec<u8>| {
+                let fs = fs.clone();
+                async move {
+                    assert_eq!(
+                        fs.get_attr(attr.ino).await.unwrap().size,
+                        expected.len() as u64
+                    );
+                    for fh in read_fhs {
+                        let mut buf = vec![0; expected.len() + 10];
+                        let len = fs.read(attr.ino, 0, &mut buf, fh).await.unwrap();
+                        assert_eq!(&buf[..len], &expected[..]);
+                        let offset = expected.len() / 3;
+                        let len = fs
+                            .read(attr.ino, offset as u64, &mut buf, fh)
+                            .await
+                            .unwrap();
+                        assert_eq!(&buf[..len], &expected[offset..]);
+                    }
+                }
+            };
+
+            let mut expected: Vec<u8> = (0..BLOCK_SIZE * 7 / 2).map(|i| i as u8).collect();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &expected, fh)
+                .await
+                .unwrap();
+            check(expected.clone()).await;
+
+            // across a block boundary and across whole blocks
+            for (offset, len, byte) in [
+                (BLOCK_SIZE - 5, 10, b'x'),
+                (BLOCK_SIZE / 2, BLOCK_SIZE * 2, b'y'),
+            ] {
+                write_all_bytes_to_fs(&fs, attr.ino, offset as u64, &vec![byte; len], fh)
+                    .await
+                    .unwrap();
+                expected[offset..offset + len].fill(byte);
+                check(expected.clone()).await;
+            }
+            fs.flush(fh).await.unwrap();
+            check(expected.clone()).await;
+
+            // shrink in a block with the writer open, then write after the end
+            let size = BLOCK_SIZE + 10;
+            fs.set_len(attr.ino, size as u64).await.unwrap();
+            expected.truncate(size);
+            check(expected.clone()).await;
+            write_all_bytes_to_fs(&fs, attr.ino, (size + 20) as u64, b"after", fh)
+                .await
+                .unwrap();
+            expected.resize(size + 20, 0);
+            expected.extend_from_slice(b"after");
+            check(expected.clone()).await;
+
+            // grow, the new part reads as zeros, and write in it
+            let size = BLOCK_SIZE * 3 + 7;
+            fs.set_len(attr.ino, size as u64).await.unwrap();
+            expected.resize(size, 0);
+            check(expected.clone()).await;
+            write_all_bytes_to_fs(&fs, attr.ino, (BLOCK_SIZE * 2 - 1) as u64, b"zz", fh)
+                .await
+                .unwrap();
+            expected[BLOCK_SIZE * 2 - 1..BLOCK_SIZE * 2 + 1].copy_from_slice(b"zz");
+            check(expected.clone()).await;
+
+            fs.release(fh).await.unwrap();
+            for fh in read_fhs {
+                fs.release(fh).await.unwrap();
+            }
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; expected.len()];
+            assert_eq!(
+                fs.read(attr.ino, 0, &mut buf, fh).await.unwrap(),
+                expected.len()
+            );
+            assert_eq!(buf, expected);
+            fs.release(fh).await.unwrap();
         },
     )
     .await;
@@ -2362,82 +2496,3210 @@ async fn test_read_only_create() {
 #[tokio::test]
 #[traced_test]
 #[allow(clippy::too_many_lines)]
-async fn test_read_only_write() {
+async fn test_symlink() {
     run_test(
         TestSetup {
-            key: "read_only_test_write",
+            key: "test_symlink",
             read_only: false,
         },
         async {
-            let fs_rw = get_fs().await;
-            let data_dir = fs_rw.data_dir.clone();
-            let cipher = Cipher::ChaCha20Poly1305;
-            let file1 = SecretString::from_str("file1").unwrap();
-            let file_dest = SecretString::from_str("file_dest").unwrap();
-            let dir1 = SecretString::from_str("dir1").unwrap();
-            let data = "Hello, world!";
+            let fs = get_fs().await;
 
-            let (fh, attr) = fs_rw
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (_, file_attr) = fs
                 .create(
                     ROOT_INODE,
-                    &file1,
+                    &test_file,
                     create_attr(FileType::RegularFile),
-                    true,
-                    true,
+                    false,
+                    false,
                 )
                 .await
-                .expect("read_only_test_create: Error creating file.");
-            let (fh_dest, attr_dest) = fs_rw
+                .unwrap();
+
+            let test_link = SecretString::from_str("test-link").unwrap();
+            let target = SecretString::from_str("../some/secret-target.txt").unwrap();
+            let attr = fs
+                .create_symlink(
+                    ROOT_INODE,
+                    &test_link,
+                    &target,
+                    create_attr(FileType::RegularFile),
+                )
+                .await
+                .unwrap();
+            assert_eq!(FileType::Symlink, attr.kind);
+            assert_eq!(target.expose_secret().len() as u64, attr.size);
+            assert_eq!(
+                target.expose_secret(),
+                fs.read_link(attr.ino).await.unwrap().expose_secret()
+            );
+            assert_eq!(
+                Some(attr),
+                fs.find_by_name(ROOT_INODE, &test_link).await.unwrap()
+            );
+            assert!(fs.read_dir(ROOT_INODE).await.unwrap().any(|entry| {
+                let entry = entry.unwrap();
+                entry.ino == attr.ino && entry.kind == FileType::Symlink
+            }));
+
+            // target is not stored in plaintext
+            let raw =
+                std::fs::read(fs.data_dir.join(CONTENTS_DIR).join(attr.ino.to_string())).unwrap();
+            assert!(!raw
+                .windows(target.expose_secret().len())
+                .any(|w| w == target.expose_secret().as_bytes()));
+
+            // symlinks cannot be opened or truncated and regular files are not links
+            assert!(matches!(
+                fs.open(attr.ino, true, false).await,
+                Err(FsError::InvalidInodeType)
+            ));
+            assert!(matches!(
+                fs.set_len(attr.ino, 0).await,
+                Err(FsError::InvalidInodeType)
+            ));
+            assert!(matches!(
+                fs.read_link(file_attr.ino).await,
+                Err(FsError::InvalidInodeType)
+            ));
+            assert!(matches!(
+                fs.create_symlink(
+                    ROOT_INODE,
+                    &test_link,
+                    &target,
+                    create_attr(FileType::Symlink)
+                )
+                .await,
+                Err(FsError::AlreadyExists)
+            ));
+
+            fs.remove_file(ROOT_INODE, &test_link).await.unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &test_link).unwrap());
+            assert!(!fs.exists(attr.ino));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_link() {
+    run_test(
+        TestSetup {
+            key: "test_link",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
                 .create(
                     ROOT_INODE,
-                    &file_dest,
+                    &test_file,
                     create_attr(FileType::RegularFile),
-                    true,
+                    false,
                     true,
                 )
                 .await
-                .expect("read_only_test_create: Error creating file.");
-            let (_, _) = fs_rw
+                .unwrap();
+            let data = "test-42";
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            let test_dir = SecretString::from_str("test-dir").unwrap();
+            let (_, dir_attr) = fs
                 .create(
                     ROOT_INODE,
-                    &dir1,
+                    &test_dir,
                     create_attr(FileType::Directory),
                     false,
-                    true,
+                    false,
                 )
                 .await
-                .expect("read_only_test_create: Error creating dir.");
+                .unwrap();
 
-            // Create a succesful write on the file
-            crate::encryptedfs::write_all_string_to_fs(&fs_rw, attr.ino, 0, data, fh)
+            // link in another directory
+            let test_link = SecretString::from_str("test-link").unwrap();
+            let link_attr = fs.link(attr.ino, dir_attr.ino, &test_link).await.unwrap();
+            assert_eq!(attr.ino, link_attr.ino);
+            assert_eq!(2, link_attr.nlink);
+            assert_eq!(2, fs.get_attr(attr.ino).await.unwrap().nlink);
+            assert_eq!(
+                attr.ino,
+                fs.find_by_name(dir_attr.ino, &test_link)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            assert_eq!(data, test_common::read_to_string(link_attr.ino, &fs).await);
+
+            // existing name and directories
+            assert!(matches!(
+                fs.link(attr.ino, dir_attr.ino, &test_link).await,
+                Err(FsError::AlreadyExists)
+            ));
+            assert!(matches!(
+                fs.link(
+                    dir_attr.ino,
+                    ROOT_INODE,
+                    &SecretString::from_str("test-dir-link").unwrap()
+                )
+                .await,
+                Err(FsError::InvalidInodeType)
+            ));
+
+            // removing one name keeps the inode
+            fs.remove_file(ROOT_INODE, &test_file).await.unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+            assert!(fs.exists(attr.ino));
+            assert_eq!(1, fs.get_attr(attr.ino).await.unwrap().nlink);
+            assert_eq!(data, test_common::read_to_string(attr.ino, &fs).await);
+
+            // removing the last name while opened defers the removal until release
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            fs.remove_file(dir_attr.ino, &test_link).await.unwrap();
+            assert!(!fs.exists_by_name(dir_attr.ino, &test_link).unwrap());
+            assert!(fs.exists(attr.ino));
+            let mut buf = vec![0; data.len()];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(data.as_bytes(), &buf[..]);
+            fs.release(fh).await.unwrap();
+            assert!(!fs.exists(attr.ino));
+
+            // rename between links of the same inode is a no-op
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
                 .await
                 .unwrap();
-            fs_rw.flush(fh).await.unwrap();
-            fs_rw.release(fh).await.unwrap();
-            drop(fs_rw);
-            let fs_ro = EncryptedFs::new(data_dir, Box::new(PasswordProviderImpl {}), cipher, true)
+            fs.link(attr.ino, ROOT_INODE, &test_link).await.unwrap();
+            fs.rename(ROOT_INODE, &test_file, ROOT_INODE, &test_link)
                 .await
-                .expect("test_read_only_write: Error creating rw fs.");
-            let fh = fs_ro
-                .open(attr.ino, true, false)
+                .unwrap();
+            assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+            assert!(fs.exists_by_name(ROOT_INODE, &test_link).unwrap());
+            assert_eq!(2, fs.get_attr(attr.ino).await.unwrap().nlink);
+
+            // replacing a link by rename drops only that link
+            let test_file_2 = SecretString::from_str("test-file-2").unwrap();
+            let (_, attr_2) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file_2,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
                 .await
-                .expect("read_only_test_create: Error opening file in ro.");
+                .unwrap();
+            fs.rename(ROOT_INODE, &test_file_2, ROOT_INODE, &test_link)
+                .await
+                .unwrap();
+            assert_eq!(
+                attr_2.ino,
+                fs.find_by_name(ROOT_INODE, &test_link)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            assert!(fs.exists(attr.ino));
+            assert_eq!(1, fs.get_attr(attr.ino).await.unwrap().nlink);
+        },
+    )
+    .await;
+}
 
-            // Test a succesful reading the file in rw mode
-            let mut buf = vec![0; data.len()];
-            fs_ro.read(attr.ino, 0, &mut buf, fh).await.unwrap();
-            assert_eq!(data, String::from_utf8(buf).unwrap());
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_xattr() {
+    run_test(
+        TestSetup {
+            key: "test_xattr",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
 
-            // Test creating a file
-            let file2 = SecretString::from_str("file2").unwrap();
-            let create_file_result = fs_ro
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (_, attr) = fs
                 .create(
                     ROOT_INODE,
-                    &file2,
+                    &test_file,
                     create_attr(FileType::RegularFile),
-                    true,
-                    true,
-                )
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert!(fs.list_xattrs(attr.ino).await.unwrap().is_empty());
+
+            let name = SecretString::from_str("user.secret-tag").unwrap();
+            let value = b"secret-value-42";
+            fs.set_xattr(attr.ino, &name, value, SetXattrMode::Upsert)
+                .await
+                .unwrap();
+            assert_eq!(
+                value,
+                &fs.get_xattr(attr.ino, &name).await.unwrap().expose_secret()[..]
+            );
+            let names = fs.list_xattrs(attr.ino).await.unwrap();
+            assert_eq!(1, names.len());
+            assert_eq!(name.expose_secret(), names[0].expose_secret());
+
+            // name and value are not stored in plaintext
+            let raw =
+                std::fs::read(fs.data_dir.join(XATTRS_DIR).join(attr.ino.to_string())).unwrap();
+            assert!(!raw.windows(value.len()).any(|w| w == value));
+            assert!(!raw
+                .windows(name.expose_secret().len())
+                .any(|w| w == name.expose_secret().as_bytes()));
+
+            // create and replace modes
+            assert!(matches!(
+                fs.set_xattr(attr.ino, &name, b"other", SetXattrMode::Create)
+                    .await,
+                Err(FsError::AlreadyExists)
+            ));
+            let name2 = SecretString::from_str("user.other").unwrap();
+            assert!(matches!(
+                fs.set_xattr(attr.ino, &name2, b"other", SetXattrMode::Replace)
+                    .await,
+                Err(FsError::XattrNotFound)
+            ));
+            fs.set_xattr(attr.ino, &name, b"", SetXattrMode::Replace)
+                .await
+                .unwrap();
+            assert!(fs
+                .get_xattr(attr.ino, &name)
+                .await
+                .unwrap()
+                .expose_secret()
+                .is_empty());
+            fs.set_xattr(attr.ino, &name2, b"other", SetXattrMode::Create)
+                .await
+                .unwrap();
+            assert_eq!(2, fs.list_xattrs(attr.ino).await.unwrap().len());
+
+            // invalid input
+            assert!(matches!(
+                fs.set_xattr(
+                    attr.ino,
+                    &SecretString::from_str("").unwrap(),
+                    b"",
+                    SetXattrMode::Upsert
+                )
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+            assert!(matches!(
+                fs.set_xattr(
+                    attr.ino,
+                    &name,
+                    &vec![0; XATTR_VALUE_MAX + 1],
+                    SetXattrMode::Upsert
+                )
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+
+            // remove
+            fs.remove_xattr(attr.ino, &name).await.unwrap();
+            assert!(matches!(
+                fs.get_xattr(attr.ino, &name).await,
+                Err(FsError::XattrNotFound)
+            ));
+            assert!(matches!(
+                fs.remove_xattr(attr.ino, &name).await,
+                Err(FsError::XattrNotFound)
+            ));
+            assert_eq!(1, fs.list_xattrs(attr.ino).await.unwrap().len());
+
+            // removed together with the inode
+            fs.remove_file(ROOT_INODE, &test_file).await.unwrap();
+            assert!(!fs
+                .data_dir
+                .join(XATTRS_DIR)
+                .join(attr.ino.to_string())
+                .exists());
+            assert!(matches!(
+                fs.list_xattrs(attr.ino).await,
+                Err(FsError::InodeNotFound)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_statfs() {
+    run_test(
+        TestSetup {
+            key: "test_statfs",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let stat = fs.statfs().unwrap();
+            let raw = crate::fs_util::statvfs(&fs.data_dir).unwrap();
+            assert!(stat.blocks > 0);
+            // nonce and tag overhead is subtracted
+            let ciphertext_block_size = NONCE_LEN + BLOCK_SIZE + fs.cipher.tag_len();
+            assert_eq!(
+                raw.f_blocks as u128 * BLOCK_SIZE as u128 / ciphertext_block_size as u128,
+                u128::from(stat.blocks)
+            );
+            assert!(stat.bfree <= stat.blocks);
+            assert!(stat.bavail <= stat.bfree);
+            assert_eq!(raw.f_files as u64, stat.files);
+            assert_eq!(raw.f_frsize as u32, stat.frsize);
+            assert!(stat.namelen > 0 && u64::from(stat.namelen) < raw.f_namemax as u64);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_keyed_name_hashes_migration() {
+    run_test(
+        TestSetup {
+            key: "test_keyed_name_hashes_migration",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let volume_file = data_dir.join(SECURITY_DIR).join(VOLUME_FILENAME);
+            assert!(volume_file.is_file());
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            fs.create(
+                ROOT_INODE,
+                &test_file,
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+            let test_dir = SecretString::from_str("test-dir").unwrap();
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_dir,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let test_file_2 = SecretString::from_str("test-file-2").unwrap();
+            fs.create(
+                dir_attr.ino,
+                &test_file_2,
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+
+            // names cannot be checked with the plain hash
+            let entries = [
+                (ROOT_INODE, &test_file),
+                (ROOT_INODE, &test_dir),
+                (dir_attr.ino, &test_file_2),
+            ];
+            let hash_dir = |ino: u64| {
+                data_dir
+                    .join(CONTENTS_DIR)
+                    .join(ino.to_string())
+                    .join(HASH_DIR)
+            };
+            for (ino, name) in entries {
+                assert!(hash_dir(ino).join(fs.hash_file_name(name)).is_file());
+                assert!(!hash_dir(ino)
+                    .join(crypto::legacy_hash_file_name(name))
+                    .exists());
+            }
+
+            // make it look like a data dir created by an older version
+            for (ino, name) in entries {
+                std::fs::rename(
+                    hash_dir(ino).join(fs.hash_file_name(name)),
+                    hash_dir(ino).join(crypto::legacy_hash_file_name(name)),
+                )
+                .unwrap();
+            }
+            std::fs::remove_file(&volume_file).unwrap();
+            make_legacy_key(&data_dir);
+            drop(fs);
+
+            // read-only uses the unkeyed hashes
+            let fs = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                true,
+            )
+            .await
+            .unwrap();
+            for (ino, name) in entries {
+                assert!(fs.find_by_name(ino, name).await.unwrap().is_some());
+            }
+            assert!(!volume_file.exists());
+            drop(fs);
+
+            // read-write migrates
+            let fs = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                VolumeMetadata::read(&data_dir)
+                    .unwrap()
+                    .unwrap()
+                    .format_version,
+                CURRENT_FORMAT_VERSION
+            );
+            assert_eq!(EncryptedFs::list_key_slots(&data_dir).unwrap().len(), 1);
+            assert!(!data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME).exists());
+            assert!(!data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME).exists());
+            for (ino, name) in entries {
+                assert!(hash_dir(ino).join(fs.hash_file_name(name)).is_file());
+                assert!(!hash_dir(ino)
+                    .join(crypto::legacy_hash_file_name(name))
+                    .exists());
+                assert!(fs.find_by_name(ino, name).await.unwrap().is_some());
+            }
+            fs.remove_file(dir_attr.ino, &test_file_2).await.unwrap();
+            assert!(!fs.exists_by_name(dir_attr.ino, &test_file_2).unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_headers_migration() {
+    run_test(
+        TestSetup {
+            key: "test_headers_migration",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.join("headers");
+            test_common::init_fs(&data_dir).await;
+            let open = |read_only: bool| {
+                let data_dir = data_dir.clone();
+                async move {
+                    EncryptedFs::new(
+                        data_dir,
+                        Box::new(PasswordProviderImpl {}),
+                        Cipher::ChaCha20Poly1305,
+                        read_only,
+                    )
+                    .await
+                }
+            };
+            let fs = open(false).await.unwrap();
+            let name = SecretString::from_str("file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"legacy content", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let key = fs.key.get().await.unwrap();
+            let ls_dir = data_dir
+                .join(CONTENTS_DIR)
+                .join(ROOT_INODE.to_string())
+                .join(LS_DIR);
+            let files = [
+                data_dir.join(CONTENTS_DIR).join(attr.ino.to_string()),
+                data_dir.join(INODES_DIR).join(attr.ino.to_string()),
+                std::fs::read_dir(&ls_dir)
+                    .unwrap()
+                    .map(|entry| entry.unwrap().path())
+                    .find(|path| !path.file_name().unwrap().to_string_lossy().starts_with('$'))
+                    .unwrap(),
+            ];
+            drop(fs);
+
+            // like the files written by older versions
+            let strip_header = |path: &Path| {
+                let mut plaintext = vec![];
+                let mut reader = crypto::create_read(
+                    std::fs::File::open(path).unwrap(),
+                    Cipher::ChaCha20Poly1305,
+                    &key,
+                );
+                std::io::Read::read_to_end(&mut reader, &mut plaintext).unwrap();
+                let mut writer = RingCryptoWrite::new(
+                    std::fs::File::create(path).unwrap(),
+                    false,
+                    &CHACHA20_POLY1305,
+                    &key,
+                )
+                .without_header();
+                std::io::Write::write_all(&mut writer, &plaintext).unwrap();
+                writer.finish().unwrap();
+            };
+            for path in &files {
+                strip_header(path);
+            }
+            let mut volume = VolumeMetadata::read(&data_dir).unwrap().unwrap();
+            volume.format_version = 3;
+            volume.write(&data_dir, &key).unwrap();
+
+            // accepted only before the version which added the header to all files
+            let fs = open(true).await.unwrap();
+            assert_eq!(
+                test_common::read_to_string(attr.ino, &fs).await,
+                "legacy content"
+            );
+            drop(fs);
+
+            // read-write migrates
+            let fs = open(false).await.unwrap();
+            assert_eq!(
+                VolumeMetadata::read(&data_dir)
+                    .unwrap()
+                    .unwrap()
+                    .format_version,
+                CURRENT_FORMAT_VERSION
+            );
+            for path in &files {
+                assert!(crypto::header::decode(&std::fs::read(path).unwrap()).is_some());
+            }
+            assert!(fs.exists_by_name(ROOT_INODE, &name).unwrap());
+            assert_eq!(
+                test_common::read_to_string(attr.ino, &fs).await,
+                "legacy content"
+            );
+            drop(fs);
+
+            strip_header(&files[1]);
+            let fs = open(true).await.unwrap();
+            assert!(fs.get_attr(attr.ino).await.is_err());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_holes_migration() {
+    run_test(
+        TestSetup {
+            key: "test_holes_migration",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.join("holes");
+            test_common::init_fs(&data_dir).await;
+            let open = |read_only: bool| {
+                let data_dir = data_dir.clone();
+                async move {
+                    EncryptedFs::new(
+                        data_dir,
+                        Box::new(PasswordProviderImpl {}),
+                        Cipher::ChaCha20Poly1305,
+                        read_only,
+                    )
+                    .await
+                }
+            };
+            let fs = open(false).await.unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("sparse").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let offset = 3 * BLOCK_SIZE as u64;
+            write_all_bytes_to_fs(&fs, attr.ino, offset, b"data", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let key = fs.key.get().await.unwrap();
+            drop(fs);
+
+            // like older versions wrote it, the holes are zeroed blocks
+            let path = data_dir.join(CONTENTS_DIR).join(attr.ino.to_string());
+            let mut plaintext = vec![];
+            std::io::Read::read_to_end(
+                &mut crypto::create_read(
+                    std::fs::File::open(&path).unwrap(),
+                    Cipher::ChaCha20Poly1305,
+                    &key,
+                ),
+                &mut plaintext,
+            )
+            .unwrap();
+            let mut writer = RingCryptoWrite::new(
+                std::fs::File::create(&path).unwrap(),
+                false,
+                &CHACHA20_POLY1305,
+                &key,
+            );
+            std::io::Write::write_all(&mut writer, &plaintext).unwrap();
+            writer.finish().unwrap();
+            let block_len = NONCE_LEN + BLOCK_SIZE + CHACHA20_POLY1305.tag_len();
+            let mut content = std::fs::read(&path).unwrap();
+            content[crypto::header::HEADER_LEN..crypto::header::HEADER_LEN + 3 * block_len].fill(0);
+            std::fs::write(&path, &content).unwrap();
+            let mut volume = VolumeMetadata::read(&data_dir).unwrap().unwrap();
+            volume.format_version = 4;
+            volume.write(&data_dir, &key).unwrap();
+
+            // accepted only before the version which added the map of the holes
+            let fs = open(true).await.unwrap();
+            assert_eq!(fs.seek_data(attr.ino, 0).await.unwrap(), Some(offset));
+            drop(fs);
+
+            // read-write migrates, the zeroed blocks are holes in the map
+            let fs = open(false).await.unwrap();
+            assert_eq!(
+                VolumeMetadata::read(&data_dir)
+                    .unwrap()
+                    .unwrap()
+                    .format_version,
+                CURRENT_FORMAT_VERSION
+            );
+            let mut content = std::fs::read(&path).unwrap();
+            assert!(crypto::header::has_holes(&content));
+            assert_eq!(fs.seek_data(attr.ino, 0).await.unwrap(), Some(offset));
+            let mut expected = vec![0; offset as usize];
+            expected.extend_from_slice(b"data");
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; expected.len()];
+            assert_eq!(fs.read(attr.ino, 0, &mut buf, fh).await.unwrap(), buf.len());
+            assert_eq!(buf, expected);
+            fs.release(fh).await.unwrap();
+            drop(fs);
+
+            // zeroing the block with data doesn't make it a hole
+            let start = crypto::header::HOLES_HEADER_LEN + 3 * block_len;
+            content[start..].fill(0);
+            std::fs::write(&path, &content).unwrap();
+            let fs = open(false).await.unwrap();
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            assert!(fs.read(attr.ino, offset, &mut buf, fh).await.is_err());
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_zeroed_block_before_format4() {
+    run_test(
+        TestSetup {
+            key: "test_zeroed_block_before_format4",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &[1; 3 * BLOCK_SIZE], fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let key = fs.key.get().await.unwrap();
+            drop(fs);
+
+            // like format version 1 wrote it, without the header, then zero the first block
+            let path = data_dir.join(CONTENTS_DIR).join(attr.ino.to_string());
+            let mut writer = RingCryptoWrite::new(
+                std::fs::File::create(&path).unwrap(),
+                false,
+                &CHACHA20_POLY1305,
+                &key,
+            )
+            .without_header();
+            std::io::Write::write_all(&mut writer, &[1; 3 * BLOCK_SIZE]).unwrap();
+            writer.finish().unwrap();
+            let block_len = NONCE_LEN + BLOCK_SIZE + CHACHA20_POLY1305.tag_len();
+            let mut content = std::fs::read(&path).unwrap();
+            content[..block_len].fill(0);
+            std::fs::write(&path, &content).unwrap();
+            std::fs::remove_file(data_dir.join(SECURITY_DIR).join(VOLUME_FILENAME)).unwrap();
+            make_legacy_key(&data_dir);
+            let open = |read_only| {
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    read_only,
+                )
+            };
+
+            // only format version 4 wrote holes as zeroed blocks
+            let fs = open(true).await.unwrap();
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; BLOCK_SIZE];
+            assert!(fs.read(attr.ino, 0, &mut buf, fh).await.is_err());
+            fs.release(fh).await.unwrap();
+            drop(fs);
+
+            // and the upgrade doesn't make it a hole
+            assert!(open(false).await.is_err());
+            assert!(std::fs::read(&path).unwrap()[..block_len]
+                .iter()
+                .all(|b| *b == 0));
+        },
+    )
+    .await;
+}
+
+#[test]
+fn test_format4_hole() {
+    let dir = tempfile::tempdir().unwrap();
+    let Some(sparse_block) = super::sparse_block_size(dir.path()).unwrap() else {
+        return;
+    };
+    let mut file = tempfile::tempfile_in(dir.path()).unwrap();
+    std::io::Write::write_all(&mut file, &vec![0; 4 * sparse_block as usize]).unwrap();
+    file.set_len(8 * sparse_block).unwrap();
+    // zeros written as data
+    assert!(!super::is_format4_hole(&mut file, 0, 2 * sparse_block, Some(sparse_block)).unwrap());
+    // the storage blocks at the ends can have data
+    assert!(super::is_format4_hole(
+        &mut file,
+        4 * sparse_block - 1,
+        sparse_block + 1,
+        Some(sparse_block)
+    )
+    .unwrap());
+    assert!(super::is_format4_hole(
+        &mut file,
+        5 * sparse_block,
+        3 * sparse_block,
+        Some(sparse_block)
+    )
+    .unwrap());
+    // no whole storage block inside
+    assert!(super::is_format4_hole(&mut file, 1, sparse_block, Some(sparse_block)).unwrap());
+    // the storage doesn't have holes
+    assert!(super::is_format4_hole(&mut file, 0, 2 * sparse_block, None).unwrap());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_data_dir_lock() {
+    run_test(
+        TestSetup {
+            key: "test_data_dir_lock",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let open = |read_only| {
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    read_only,
+                )
+            };
+            assert!(matches!(open(false).await, Err(FsError::Other(_))));
+            // it doesn't change anything
+            open(true).await.unwrap();
+            drop(fs);
+            let fs = open(false).await.unwrap();
+            assert!(open(false).await.is_err());
+            drop(fs);
+            open(false).await.unwrap();
+        },
+    )
+    .await;
+}
+
+/// Move the master key from the key slots to `key.enc` like the data dirs before key slots.
+fn make_legacy_key(data_dir: &Path) {
+    let key_slots = KeySlots::read(data_dir).unwrap().unwrap();
+    let slot = &key_slots.slots()[0];
+    bincode::serialize_into(
+        std::fs::File::create(data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME)).unwrap(),
+        &slot.salt,
+    )
+    .unwrap();
+    std::fs::write(
+        data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME),
+        &slot.wrapped_key,
+    )
+    .unwrap();
+    std::fs::remove_file(KeySlots::path(data_dir)).unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_volume_metadata() {
+    run_test(
+        TestSetup {
+            key: "test_volume_metadata",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let volume = VolumeMetadata::read(&data_dir).unwrap().unwrap();
+            assert_eq!(volume.format_version, CURRENT_FORMAT_VERSION);
+            assert_eq!(volume.cipher, Cipher::ChaCha20Poly1305);
+            assert_eq!(volume.block_size as usize, BLOCK_SIZE);
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            drop(fs);
+
+            // the cipher from the metadata is used, not the one we're given
+            let fs = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::Aes256Gcm,
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!(test_common::read_to_string(attr.ino, &fs).await, "test-42");
+            let key = fs.key.get().await.unwrap();
+            drop(fs);
+
+            // it cannot be changed without the key, like lowering the format version
+            let open = |read_only| {
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    read_only,
+                )
+            };
+            let volume_file = data_dir.join(SECURITY_DIR).join(VOLUME_FILENAME);
+            let original = std::fs::read(&volume_file).unwrap();
+            let mut volume = VolumeMetadata::read(&data_dir).unwrap().unwrap();
+            volume.format_version = 1;
+            let mut changed = bincode::serialize(&volume).unwrap();
+            let mac = &original[changed.len()..];
+            for content in [changed.clone(), {
+                changed.extend_from_slice(mac);
+                changed
+            }] {
+                std::fs::write(&volume_file, content).unwrap();
+                assert!(matches!(open(true).await, Err(FsError::Other(_))));
+                assert!(matches!(open(false).await, Err(FsError::Other(_))));
+            }
+            std::fs::remove_file(&volume_file).unwrap();
+            assert!(matches!(open(false).await, Err(FsError::Other(_))));
+            // with the key it can
+            volume.format_version = CURRENT_FORMAT_VERSION;
+            volume.write(&data_dir, &key).unwrap();
+            open(false).await.unwrap();
+
+            // format from the future
+            let mut volume = VolumeMetadata::read(&data_dir).unwrap().unwrap();
+            volume.format_version = CURRENT_FORMAT_VERSION + 1;
+            volume.write(&data_dir, &key).unwrap();
+            assert!(matches!(
+                VolumeMetadata::read(&data_dir),
+                Err(FsError::UnsupportedFormatVersion(_))
+            ));
+            assert!(matches!(
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                )
+                .await,
+                Err(FsError::UnsupportedFormatVersion(_))
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_passwd_with_kdf() {
+    run_test(
+        TestSetup {
+            key: "test_passwd_with_kdf",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            drop(fs);
+
+            let password = || SecretString::from_str("password").unwrap();
+            let kdf = KdfParams {
+                m_cost: KdfParams::default().m_cost * 2,
+                t_cost: KdfParams::default().t_cost + 1,
+                ..KdfParams::default()
+            };
+            EncryptedFs::passwd_with_kdf(
+                &data_dir,
+                password(),
+                password(),
+                Cipher::ChaCha20Poly1305,
+                Some(kdf),
+            )
+            .await
+            .unwrap();
+            assert_eq!(EncryptedFs::list_key_slots(&data_dir).unwrap()[0].kdf, kdf);
+
+            // keeps the params if not given
+            EncryptedFs::passwd(&data_dir, password(), password(), Cipher::ChaCha20Poly1305)
+                .await
+                .unwrap();
+            assert_eq!(EncryptedFs::list_key_slots(&data_dir).unwrap()[0].kdf, kdf);
+
+            let fs = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!(test_common::read_to_string(attr.ino, &fs).await, "test-42");
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_passwd_legacy_key() {
+    run_test(
+        TestSetup {
+            key: "test_passwd_legacy_key",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let key = fs.key.get().await.unwrap();
+            drop(fs);
+            // like before key slots
+            let mut volume = VolumeMetadata::read(&data_dir).unwrap().unwrap();
+            volume.format_version = 2;
+            volume.write(&data_dir, &key).unwrap();
+            make_legacy_key(&data_dir);
+
+            // it moves the key to a slot first, then changes the password there
+            EncryptedFs::passwd(
+                &data_dir,
+                SecretString::from_str("password").unwrap(),
+                SecretString::from_str("new-password").unwrap(),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+            assert!(!data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME).exists());
+            assert!(!data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME).exists());
+            assert_eq!(EncryptedFs::list_key_slots(&data_dir).unwrap().len(), 1);
+            assert_eq!(
+                VolumeMetadata::read(&data_dir)
+                    .unwrap()
+                    .unwrap()
+                    .format_version,
+                CURRENT_FORMAT_VERSION
+            );
+
+            let fs = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(FixedPasswordProvider("new-password")),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!(test_common::read_to_string(attr.ino, &fs).await, "test-42");
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_init() {
+    run_test(
+        TestSetup {
+            key: "test_init",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let password = || SecretString::from_str("password").unwrap();
+            assert!(EncryptedFs::is_initialized(&fs.data_dir));
+            assert!(matches!(
+                EncryptedFs::init(
+                    &fs.data_dir,
+                    password(),
+                    Cipher::ChaCha20Poly1305,
+                    KdfParams::default()
+                )
+                .await,
+                Err(FsError::DataDirAlreadyInitialized)
+            ));
+
+            let data_dir = fs.data_dir.join("new");
+            assert!(!EncryptedFs::is_initialized(&data_dir));
+            // opening doesn't create it
+            for create_dir in [false, true] {
+                if create_dir {
+                    std::fs::create_dir_all(&data_dir).unwrap();
+                }
+                assert!(matches!(
+                    EncryptedFs::new(
+                        data_dir.clone(),
+                        Box::new(PasswordProviderImpl {}),
+                        Cipher::ChaCha20Poly1305,
+                        false,
+                    )
+                    .await,
+                    Err(FsError::DataDirNotInitialized)
+                ));
+                if create_dir {
+                    assert_eq!(std::fs::read_dir(&data_dir).unwrap().count(), 0);
+                } else {
+                    assert!(!data_dir.exists());
+                }
+            }
+            let kdf = KdfParams {
+                t_cost: KdfParams::default().t_cost + 1,
+                ..KdfParams::default()
+            };
+            EncryptedFs::init(&data_dir, password(), Cipher::Aes256Gcm, kdf)
+                .await
+                .unwrap();
+            assert!(EncryptedFs::is_initialized(&data_dir));
+            let volume = VolumeMetadata::read(&data_dir).unwrap().unwrap();
+            assert_eq!(volume.cipher, Cipher::Aes256Gcm);
+            assert_eq!(volume.kdf, kdf);
+            let new_fs = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            assert!(new_fs.exists(ROOT_INODE));
+
+            // not ours
+            let data_dir = fs.data_dir.join("other");
+            std::fs::create_dir_all(&data_dir).unwrap();
+            std::fs::write(data_dir.join("file"), b"test").unwrap();
+            assert!(matches!(
+                EncryptedFs::init(
+                    &data_dir,
+                    password(),
+                    Cipher::ChaCha20Poly1305,
+                    KdfParams::default()
+                )
+                .await,
+                Err(FsError::NotEmpty)
+            ));
+        },
+    )
+    .await;
+}
+
+struct FixedPasswordProvider(&'static str);
+impl PasswordProvider for FixedPasswordProvider {
+    fn get_password(&self) -> Option<SecretString> {
+        Some(SecretString::from_str(self.0).unwrap())
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_key_slots() {
+    run_test(
+        TestSetup {
+            key: "test_key_slots",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            drop(fs);
+            let contents_path = data_dir.join(CONTENTS_DIR).join(attr.ino.to_string());
+            let content = std::fs::read(&contents_path).unwrap();
+
+            let password = |p: &str| SecretString::from_str(p).unwrap();
+            let slots = EncryptedFs::list_key_slots(&data_dir).unwrap();
+            assert_eq!(slots.len(), 1);
+            let first = slots[0].id;
+
+            assert!(matches!(
+                EncryptedFs::add_key_slot(
+                    &data_dir,
+                    password("wrong"),
+                    password("recovery"),
+                    Cipher::ChaCha20Poly1305,
+                    KdfParams::default(),
+                )
+                .await,
+                Err(FsError::InvalidPassword)
+            ));
+            let recovery = EncryptedFs::add_key_slot(
+                &data_dir,
+                password("password"),
+                password("recovery"),
+                Cipher::ChaCha20Poly1305,
+                KdfParams::default(),
+            )
+            .await
+            .unwrap();
+            assert_ne!(recovery, first);
+            assert_eq!(EncryptedFs::list_key_slots(&data_dir).unwrap().len(), 2);
+            // data is not re-encrypted
+            assert_eq!(std::fs::read(&contents_path).unwrap(), content);
+
+            // both passwords unlock it
+            for pass in ["password", "recovery"] {
+                let fs = EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(FixedPasswordProvider(pass)),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                )
+                .await
+                .unwrap();
+                assert_eq!(test_common::read_to_string(attr.ino, &fs).await, "test-42");
+            }
+
+            // changing the password of one slot keeps the other
+            EncryptedFs::passwd(
+                &data_dir,
+                password("recovery"),
+                password("recovery-2"),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+            assert_eq!(EncryptedFs::list_key_slots(&data_dir).unwrap().len(), 2);
+            for (pass, ok) in [
+                ("password", true),
+                ("recovery", false),
+                ("recovery-2", true),
+            ] {
+                let res = EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(FixedPasswordProvider(pass)),
+                    Cipher::ChaCha20Poly1305,
+                    true,
+                )
+                .await;
+                assert_eq!(res.is_ok(), ok, "{pass}");
+            }
+
+            // revoke the first one with the other password
+            EncryptedFs::remove_key_slot(
+                &data_dir,
+                password("recovery-2"),
+                first,
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+            assert!(matches!(
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(FixedPasswordProvider("password")),
+                    Cipher::ChaCha20Poly1305,
+                    true,
+                )
+                .await,
+                Err(FsError::InvalidPassword)
+            ));
+            assert!(matches!(
+                EncryptedFs::remove_key_slot(
+                    &data_dir,
+                    password("recovery-2"),
+                    first,
+                    Cipher::ChaCha20Poly1305,
+                )
+                .await,
+                Err(FsError::NotFound(_))
+            ));
+            // the last one stays
+            assert!(EncryptedFs::remove_key_slot(
+                &data_dir,
+                password("recovery-2"),
+                recovery,
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .is_err());
+            assert_eq!(EncryptedFs::list_key_slots(&data_dir).unwrap().len(), 1);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_key_slots_locked() {
+    run_test(
+        TestSetup {
+            key: "test_key_slots_locked",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let password = |p: &str| SecretString::from_str(p).unwrap();
+            let add = || {
+                EncryptedFs::add_key_slot(
+                    &data_dir,
+                    password("password"),
+                    password("other"),
+                    Cipher::ChaCha20Poly1305,
+                    KdfParams::default(),
+                )
+            };
+            let remove = |id| {
+                EncryptedFs::remove_key_slot(
+                    &data_dir,
+                    password("password"),
+                    id,
+                    Cipher::ChaCha20Poly1305,
+                )
+            };
+            let passwd = || {
+                EncryptedFs::passwd(
+                    &data_dir,
+                    password("password"),
+                    password("password"),
+                    Cipher::ChaCha20Poly1305,
+                )
+            };
+
+            // not while it's opened in read-write mode
+            assert!(matches!(add().await, Err(FsError::Other(_))));
+            assert!(matches!(passwd().await, Err(FsError::Other(_))));
+            drop(fs);
+            let other = add().await.unwrap();
+            let fs = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            assert!(matches!(remove(other).await, Err(FsError::Other(_))));
+            drop(fs);
+            remove(other).await.unwrap();
+
+            // nor while a rekey is in progress, it replaces the key slots when it's done
+            EncryptedFs::start_rekey(
+                &data_dir,
+                password("password"),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            assert!(matches!(add().await, Err(FsError::Other(_))));
+            assert!(matches!(passwd().await, Err(FsError::Other(_))));
+            let first = EncryptedFs::list_key_slots(&data_dir).unwrap()[0].id;
+            assert!(matches!(remove(first).await, Err(FsError::Other(_))));
+            assert_eq!(EncryptedFs::list_key_slots(&data_dir).unwrap().len(), 1);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_keyfile() {
+    run_test(
+        TestSetup {
+            key: "test_keyfile",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let keyfile = fs.data_dir.join("keyfile");
+            std::fs::write(&keyfile, b"\x00\xffkeyfile").unwrap();
+            let other_keyfile = fs.data_dir.join("other_keyfile");
+            std::fs::write(&other_keyfile, b"\x00\xffkeyfilf").unwrap();
+            let password = || -> Box<dyn PasswordProvider> { Box::new(PasswordProviderImpl {}) };
+
+            // keyfile only
+            let data_dir = fs.data_dir.join("keyfile_only");
+            let material = KeyfileProvider::new(keyfile.clone())
+                .get_key_material()
+                .unwrap();
+            EncryptedFs::init(
+                &data_dir,
+                material,
+                Cipher::ChaCha20Poly1305,
+                KdfParams::default(),
+            )
+            .await
+            .unwrap();
+            EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(KeyfileProvider::new(keyfile.clone())),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            for provider in [
+                Box::new(KeyfileProvider::new(other_keyfile.clone()))
+                    as Box<dyn KeyMaterialProvider>,
+                Box::new(PasswordProviderImpl {}),
+                Box::new(PasswordKeyfileProvider::new(password(), keyfile.clone())),
+            ] {
+                assert!(matches!(
+                    EncryptedFs::new(data_dir.clone(), provider, Cipher::ChaCha20Poly1305, false)
+                        .await,
+                    Err(FsError::InvalidPassword)
+                ));
+            }
+            // missing keyfile
+            assert!(matches!(
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(KeyfileProvider::new(fs.data_dir.join("missing"))),
+                    Cipher::ChaCha20Poly1305,
+                    false
+                )
+                .await,
+                Err(FsError::InvalidPassword)
+            ));
+
+            // password and keyfile
+            let data_dir = fs.data_dir.join("password_keyfile");
+            let material = PasswordKeyfileProvider::new(password(), keyfile.clone())
+                .get_key_material()
+                .unwrap();
+            EncryptedFs::init(
+                &data_dir,
+                material,
+                Cipher::ChaCha20Poly1305,
+                KdfParams::default(),
+            )
+            .await
+            .unwrap();
+            EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(PasswordKeyfileProvider::new(password(), keyfile.clone())),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            for provider in [
+                Box::new(KeyfileProvider::new(keyfile.clone())) as Box<dyn KeyMaterialProvider>,
+                Box::new(PasswordProviderImpl {}),
+                Box::new(PasswordKeyfileProvider::new(
+                    password(),
+                    other_keyfile.clone(),
+                )),
+                Box::new(PasswordKeyfileProvider::new(
+                    Box::new(FixedPasswordProvider("wrong")),
+                    keyfile.clone(),
+                )),
+            ] {
+                assert!(matches!(
+                    EncryptedFs::new(data_dir.clone(), provider, Cipher::ChaCha20Poly1305, false)
+                        .await,
+                    Err(FsError::InvalidPassword)
+                ));
+            }
+        },
+    )
+    .await;
+}
+
+#[test]
+fn test_in_memory_password_provider() {
+    for input in ["password\n", "password\r\n", "password"] {
+        let provider = InMemoryPasswordProvider::read_from(input.as_bytes()).unwrap();
+        assert_eq!(
+            provider
+                .get_key_material()
+                .unwrap()
+                .expose_secret()
+                .as_str(),
+            "password"
+        );
+    }
+    // only one newline is removed
+    let provider = InMemoryPasswordProvider::read_from("password\n\n".as_bytes()).unwrap();
+    assert_eq!(
+        provider.get_password().unwrap().expose_secret().as_str(),
+        "password\n"
+    );
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_fsck() {
+    run_test(
+        TestSetup {
+            key: "test_fsck",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let name = |s: &str| SecretString::from_str(s).unwrap();
+            let (fh, file_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name("file"),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, file_attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name("dir"),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, orphan_attr) = fs
+                .create(
+                    dir_attr.ino,
+                    &name("orphan"),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, dangling_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name("dangling"),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            let report = fs.fsck(false).await.unwrap();
+            assert!(report.is_clean(), "{:?}", report.problems);
+
+            // break it
+            let dir_path = |ino: u64, dir: &str| {
+                fs.data_dir
+                    .join(CONTENTS_DIR)
+                    .join(ino.to_string())
+                    .join(dir)
+            };
+            std::fs::remove_file(
+                dir_path(ROOT_INODE, HASH_DIR).join(fs.hash_file_name(&name("file"))),
+            )
+            .unwrap();
+            // the encrypted names are not deterministic, the only one in dir besides . and ..
+            let orphan_ls_entry = std::fs::read_dir(dir_path(dir_attr.ino, LS_DIR))
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .find(|path| !path.file_name().unwrap().to_str().unwrap().starts_with('$'))
+                .unwrap();
+            std::fs::remove_file(orphan_ls_entry).unwrap();
+            std::fs::remove_file(fs.ino_file(dangling_attr.ino)).unwrap();
+            let mut attr = fs.get_inode_from_storage(file_attr.ino).await.unwrap();
+            attr.size = 3;
+            fs.write_inode_to_storage(&attr).await.unwrap();
+
+            let report = fs.fsck(false).await.unwrap();
+            let expected = [
+                FsckProblem::MissingHashEntry {
+                    parent: ROOT_INODE,
+                    ino: file_attr.ino,
+                },
+                FsckProblem::StaleHashEntry {
+                    parent: dir_attr.ino,
+                    ino: orphan_attr.ino,
+                },
+                FsckProblem::DanglingEntry {
+                    parent: ROOT_INODE,
+                    ino: dangling_attr.ino,
+                },
+                FsckProblem::OrphanContents {
+                    ino: dangling_attr.ino,
+                },
+                FsckProblem::SizeMismatch {
+                    ino: file_attr.ino,
+                    attr_size: 3,
+                    size: 7,
+                },
+                FsckProblem::Orphan {
+                    ino: orphan_attr.ino,
+                },
+            ];
+            assert_eq!(
+                report.problems.len(),
+                expected.len(),
+                "{:?}",
+                report.problems
+            );
+            for problem in &expected {
+                assert!(report.problems.contains(problem), "{problem}");
+            }
+            assert_eq!(report.repaired, 0);
+            // checking doesn't change anything
+            assert_eq!(fs.fsck(false).await.unwrap().problems.len(), expected.len());
+
+            // contents cannot be repaired
+            let report = fs.fsck(true).await.unwrap();
+            assert_eq!(report.problems.len(), expected.len());
+            assert_eq!(report.repaired, expected.len() - 1);
+            let report = fs.fsck(false).await.unwrap();
+            assert_eq!(
+                report.problems,
+                vec![FsckProblem::OrphanContents {
+                    ino: dangling_attr.ino
+                }]
+            );
+
+            assert_eq!(
+                fs.find_by_name(ROOT_INODE, &name("file"))
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                file_attr.ino
+            );
+            assert_eq!(fs.get_attr(file_attr.ino).await.unwrap().size, 7);
+            assert!(!fs.exists_by_name(ROOT_INODE, &name("dangling")).unwrap());
+            let lost_and_found = fs
+                .find_by_name(ROOT_INODE, &name(LOST_AND_FOUND))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                fs.find_by_name(lost_and_found.ino, &name(&format!("#{}", orphan_attr.ino)))
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                orphan_attr.ino
+            );
+            assert!(!fs.exists_by_name(dir_attr.ino, &name("orphan")).unwrap());
+
+            // repair needs write access
+            let ro_fs = EncryptedFs::new(
+                fs.data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                true,
+            )
+            .await
+            .unwrap();
+            assert!(matches!(ro_fs.fsck(true).await, Err(FsError::ReadOnly)));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_fsck_lost_and_found() {
+    run_test(
+        TestSetup {
+            key: "test_fsck_lost_and_found",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let name = |s: &str| SecretString::from_str(s).unwrap();
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name("dir"),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (fh, file_attr) = fs
+                .create(
+                    dir_attr.ino,
+                    &name("file"),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data = vec![7_u8; BLOCK_SIZE * 2];
+            write_all_bytes_to_fs(&fs, file_attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            // the directory is in no other one
+            let root_dir = fs.data_dir.join(CONTENTS_DIR).join(ROOT_INODE.to_string());
+            let dir_ls_entry = std::fs::read_dir(root_dir.join(LS_DIR))
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .find(|path| !path.file_name().unwrap().to_str().unwrap().starts_with('$'))
+                .unwrap();
+            std::fs::remove_file(dir_ls_entry).unwrap();
+            std::fs::remove_file(
+                root_dir
+                    .join(HASH_DIR)
+                    .join(fs.hash_file_name(&name("dir"))),
+            )
+            .unwrap();
+            // a zeroed block is not a hole
+            let contents = fs.contents_path(file_attr.ino);
+            let mut content = std::fs::read(&contents).unwrap();
+            let block_len = NONCE_LEN + BLOCK_SIZE + CHACHA20_POLY1305.tag_len();
+            let start = crypto::header::HOLES_HEADER_LEN + block_len;
+            content[start..start + block_len].fill(0);
+            std::fs::write(&contents, &content).unwrap();
+
+            let report = fs.fsck(true).await.unwrap();
+            assert_eq!(
+                report.problems,
+                vec![
+                    FsckProblem::CorruptContents { ino: file_attr.ino },
+                    FsckProblem::Orphan { ino: dir_attr.ino },
+                ]
+            );
+            assert_eq!(report.repaired, 1);
+
+            let lost_and_found = fs
+                .find_by_name(ROOT_INODE, &name(LOST_AND_FOUND))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                fs.find_by_name(lost_and_found.ino, &name(&format!("#{}", dir_attr.ino)))
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                dir_attr.ino
+            );
+            // `..` is lost+found now
+            assert_eq!(
+                fs.find_by_name(dir_attr.ino, &name(".."))
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                lost_and_found.ino
+            );
+            let parents: Vec<u64> = fs
+                .read_dir(dir_attr.ino)
+                .await
+                .unwrap()
+                .map(|entry| entry.unwrap())
+                .filter(|entry| *entry.name.expose_secret() == "..")
+                .map(|entry| entry.ino)
+                .collect();
+            assert_eq!(parents, vec![lost_and_found.ino]);
+            assert_eq!(
+                fs.fsck(false).await.unwrap().problems,
+                vec![FsckProblem::CorruptContents { ino: file_attr.ino }]
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_fsync() {
+    run_test(
+        TestSetup {
+            key: "test_fsync",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data = "test-42";
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            // size is saved in the inode only on release, without fsync
+            assert_eq!(fs.get_inode_from_storage(attr.ino).await.unwrap().size, 0);
+            fs.fsync(fh, true).await.unwrap();
+            assert_eq!(
+                fs.get_inode_from_storage(attr.ino).await.unwrap().size,
+                data.len() as u64
+            );
+            fs.fsync(fh, false).await.unwrap();
+            // with a read handle it syncs what the write handles of the file wrote
+            write_all_bytes_to_fs(&fs, attr.ino, data.len() as u64, b"-more", fh)
+                .await
+                .unwrap();
+            let read_fh = fs.open(attr.ino, true, false).await.unwrap();
+            fs.fsync(read_fh, false).await.unwrap();
+            assert_eq!(
+                fs.get_inode_from_storage(attr.ino).await.unwrap().size,
+                data.len() as u64 + 5
+            );
+            fs.release(read_fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let data = format!("{data}-more");
+            assert_eq!(test_common::read_to_string(attr.ino, &fs).await, data);
+            // the entry is on the storage without `fsyncdir`, also after a rename
+            let renamed = SecretString::from_str("renamed").unwrap();
+            fs.rename(ROOT_INODE, &test_file, ROOT_INODE, &renamed)
+                .await
+                .unwrap();
+            let read_only = EncryptedFs::new(
+                fs.data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                true,
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                read_only
+                    .find_by_name(ROOT_INODE, &renamed)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                attr.ino
+            );
+            assert!(read_only
+                .find_by_name(ROOT_INODE, &test_file)
+                .await
+                .unwrap()
+                .is_none());
+            assert_eq!(
+                test_common::read_to_string(attr.ino, &read_only).await,
+                data
+            );
+            assert!(matches!(
+                fs.fsync(42_424_242, false).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+
+            fs.fsyncdir(ROOT_INODE).await.unwrap();
+            assert!(matches!(
+                fs.fsyncdir(attr.ino).await,
+                Err(FsError::InvalidInodeType)
+            ));
+            assert!(matches!(
+                fs.fsyncdir(42_424_242).await,
+                Err(FsError::InodeNotFound)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_sparse() {
+    run_test(
+        TestSetup {
+            key: "test_sparse",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            // after the end, the blocks before are holes
+            let offset = 5 * BLOCK_SIZE as u64 + 10;
+            write_all_bytes_to_fs(&fs, attr.ino, offset, b"data", fh)
+                .await
+                .unwrap();
+            // the write is not flushed yet
+            assert_eq!(
+                fs.seek_data(attr.ino, 0).await.unwrap(),
+                Some(5 * BLOCK_SIZE as u64)
+            );
+            assert_eq!(fs.seek_hole(attr.ino, 0).await.unwrap(), Some(0));
+            assert_eq!(
+                fs.seek_hole(attr.ino, offset).await.unwrap(),
+                Some(offset + 4)
+            );
+            assert_eq!(fs.seek_data(attr.ino, offset + 4).await.unwrap(), None);
+            fs.release(fh).await.unwrap();
+
+            fs.set_len(attr.ino, 10 * BLOCK_SIZE as u64).await.unwrap();
+            assert_eq!(
+                fs.seek_hole(attr.ino, offset).await.unwrap(),
+                Some(6 * BLOCK_SIZE as u64)
+            );
+            assert_eq!(
+                fs.seek_data(attr.ino, 6 * BLOCK_SIZE as u64).await.unwrap(),
+                None
+            );
+
+            let mut expected = vec![0; 10 * BLOCK_SIZE];
+            #[allow(clippy::cast_possible_truncation)]
+            expected[offset as usize..offset as usize + 4].copy_from_slice(b"data");
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; 10 * BLOCK_SIZE + 1];
+            let mut read = 0;
+            while read < buf.len() {
+                let len = fs
+                    .read(attr.ino, read as u64, &mut buf[read..], fh)
+                    .await
+                    .unwrap();
+                if len == 0 {
+                    break;
+                }
+                read += len;
+            }
+            assert_eq!(&buf[..read], &expected[..]);
+            fs.release(fh).await.unwrap();
+
+            assert!(matches!(
+                fs.seek_data(ROOT_INODE, 0).await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_fallocate() {
+    run_test(
+        TestSetup {
+            key: "test_fallocate",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let ino = attr.ino;
+            for i in 0..5 {
+                fs.write(ino, (i * BLOCK_SIZE) as u64, &[1; BLOCK_SIZE], fh)
+                    .await
+                    .unwrap();
+            }
+            let size = 5 * BLOCK_SIZE as u64;
+
+            // the writer is still open, it needs to see the changes
+            fs.fallocate(ino, 0, 10, FallocateMode::Allocate { keep_size: false })
+                .await
+                .unwrap();
+            assert_eq!(fs.get_attr(ino).await.unwrap().size, size);
+            fs.fallocate(ino, size, 100, FallocateMode::Allocate { keep_size: true })
+                .await
+                .unwrap();
+            assert_eq!(fs.get_attr(ino).await.unwrap().size, size);
+
+            // in the middle, the ends are partial blocks
+            let hole_start = BLOCK_SIZE as u64 + 10;
+            let hole_end = 4 * BLOCK_SIZE as u64 - 10;
+            fs.fallocate(
+                ino,
+                hole_start,
+                hole_end - hole_start,
+                FallocateMode::PunchHole,
+            )
+            .await
+            .unwrap();
+            assert_eq!(fs.get_attr(ino).await.unwrap().size, size);
+            assert_eq!(
+                fs.seek_hole(ino, 0).await.unwrap(),
+                Some(2 * BLOCK_SIZE as u64)
+            );
+            assert_eq!(
+                fs.seek_data(ino, 2 * BLOCK_SIZE as u64).await.unwrap(),
+                Some(3 * BLOCK_SIZE as u64)
+            );
+
+            // extends the file
+            fs.fallocate(
+                ino,
+                size - 10,
+                BLOCK_SIZE as u64,
+                FallocateMode::ZeroRange { keep_size: false },
+            )
+            .await
+            .unwrap();
+            let new_size = size - 10 + BLOCK_SIZE as u64;
+            assert_eq!(fs.get_attr(ino).await.unwrap().size, new_size);
+            fs.write(ino, new_size - 1, &[2], fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            #[allow(clippy::cast_possible_truncation)]
+            let mut expected = vec![1; new_size as usize];
+            #[allow(clippy::cast_possible_truncation)]
+            expected[hole_start as usize..hole_end as usize].fill(0);
+            #[allow(clippy::cast_possible_truncation)]
+            expected[size as usize - 10..].fill(0);
+            *expected.last_mut().unwrap() = 2;
+            let fh = fs.open(ino, true, false).await.unwrap();
+            let mut buf = vec![0; expected.len() + 1];
+            let mut read = 0;
+            while read < buf.len() {
+                let len = fs
+                    .read(ino, read as u64, &mut buf[read..], fh)
+                    .await
+                    .unwrap();
+                if len == 0 {
+                    break;
+                }
+                read += len;
+            }
+            assert_eq!(&buf[..read], &expected[..]);
+            fs.release(fh).await.unwrap();
+
+            assert!(matches!(
+                fs.fallocate(ino, 0, 0, FallocateMode::PunchHole).await,
+                Err(FsError::InvalidInput(_))
+            ));
+            assert!(matches!(
+                fs.fallocate(ino, u64::MAX, 1, FallocateMode::PunchHole)
+                    .await,
+                Err(FsError::MaxFilesizeExceeded(_))
+            ));
+            assert!(matches!(
+                fs.fallocate(
+                    ROOT_INODE,
+                    0,
+                    1,
+                    FallocateMode::Allocate { keep_size: false }
+                )
+                .await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_path() {
+    run_test(
+        TestSetup {
+            key: "test_path",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let path_fs = fs.path();
+            let dir = path_fs.create_dir_all("/a/b/c").await.unwrap();
+            // again, it's there already
+            assert_eq!(path_fs.create_dir_all("a/b/c").await.unwrap().ino, dir.ino);
+            path_fs.create_dir("/a/d").await.unwrap();
+
+            // more than one block
+            let data = (0..=255)
+                .cycle()
+                .take(3 * BLOCK_SIZE + 7)
+                .collect::<Vec<u8>>();
+            path_fs.write_all("/a/b/c/file", &data).await.unwrap();
+            assert_eq!(path_fs.read_to_end("/a/b/c/file").await.unwrap(), data);
+            let attr = path_fs.metadata("/a/b/./c/../c/file").await.unwrap();
+            assert_eq!(attr.size, data.len() as u64);
+            assert_eq!(attr.kind, FileType::RegularFile);
+
+            let walk = path_fs
+                .walk("/")
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|(path, _)| path.to_str().unwrap().to_owned())
+                .collect::<Vec<_>>();
+            assert_eq!(walk.len(), 5);
+            let pos = |p: &str| walk.iter().position(|w| w == p).unwrap();
+            assert_eq!(pos("/a"), 0);
+            assert!(pos("/a/b") < pos("/a/b/c"));
+            assert!(pos("/a/b/c") < pos("/a/b/c/file"));
+            assert!(pos("/a/d") > 0);
+            let names = path_fs
+                .read_dir("/a")
+                .await
+                .unwrap()
+                .map(|entry| entry.unwrap().name.expose_secret().clone())
+                .collect::<HashSet<_>>();
+            assert_eq!(names, HashSet::from(["b".to_owned(), "d".to_owned()]));
+
+            path_fs.rename("/a/b/c/file", "/a/file").await.unwrap();
+            assert!(!path_fs.exists("/a/b/c/file").await.unwrap());
+            let mut file = path_fs.open("/a/file", true, false).await.unwrap();
+            let mut buf = [0; 10];
+            file.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, data[..10]);
+            file.close().await.unwrap();
+            assert!(matches!(
+                path_fs.open("/a", true, false).await,
+                Err(FsError::InvalidInodeType)
+            ));
+            // replaced
+            path_fs.write_all("/a/file", b"short").await.unwrap();
+            assert_eq!(path_fs.read_to_end("/a/file").await.unwrap(), b"short");
+
+            assert!(matches!(
+                path_fs.create_dir("/a/file/x").await,
+                Err(FsError::InvalidInodeType)
+            ));
+            assert!(matches!(
+                path_fs.metadata("/a/missing").await,
+                Err(FsError::NotFound(_))
+            ));
+            assert!(matches!(
+                path_fs.remove_dir("/a").await,
+                Err(FsError::NotEmpty)
+            ));
+            assert!(matches!(
+                path_fs.remove_dir_all("/").await,
+                Err(FsError::InvalidInput(_))
+            ));
+
+            path_fs.remove_dir_all("/a").await.unwrap();
+            assert!(!path_fs.exists("/a").await.unwrap());
+            assert_eq!(path_fs.walk("/").await.unwrap().len(), 0);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_encrypted_file() {
+    run_test(
+        TestSetup {
+            key: "test_encrypted_file",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            let mut file = fs.open_file(attr.ino, true, true).await.unwrap();
+            let data = (0..=255)
+                .cycle()
+                .take(3 * BLOCK_SIZE + 7)
+                .collect::<Vec<u8>>();
+            let len = tokio::io::copy(&mut &data[..], &mut file).await.unwrap();
+            assert_eq!(len, data.len() as u64);
+            file.flush().await.unwrap();
+
+            assert_eq!(file.rewind().await.unwrap(), 0);
+            let mut buf = vec![];
+            file.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, data);
+
+            assert_eq!(
+                file.seek(SeekFrom::End(-7)).await.unwrap(),
+                3 * BLOCK_SIZE as u64
+            );
+            let mut buf = [0; 7];
+            file.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, data[3 * BLOCK_SIZE..]);
+            assert_eq!(
+                file.seek(SeekFrom::Current(-10)).await.unwrap(),
+                3 * BLOCK_SIZE as u64 - 3
+            );
+            file.write_all(b"in the middle").await.unwrap();
+            assert_eq!(file.stream_position().await.unwrap(), data.len() as u64 + 3);
+            assert!(file.seek(SeekFrom::Current(-1000)).await.is_err());
+            file.close().await.unwrap();
+
+            let mut expected = data.clone();
+            expected.truncate(3 * BLOCK_SIZE - 3);
+            expected.extend_from_slice(b"in the middle");
+            let mut file = fs.open_file(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![];
+            file.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, expected);
+            assert!(file.write_all(b"read only").await.is_err());
+
+            // released on drop, in the background
+            let fh = file.handle();
+            assert!(fs.is_read_handle(fh).await);
+            drop(file);
+            tokio::time::timeout(std::time::Duration::from_secs(5), async {
+                while fs.is_read_handle(fh).await {
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_dir_stream() {
+    run_test(
+        TestSetup {
+            key: "test_read_dir_stream",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            for i in 0..10 {
+                let name = SecretString::from_str(&format!("file-{i}")).unwrap();
+                fs.create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            }
+
+            let entries = fs
+                .read_dir_stream(ROOT_INODE, 0, 0)
+                .await
+                .unwrap()
+                .map(|entry| entry.unwrap())
+                .map(|(offset, entry)| (offset, entry.name.expose_secret().clone()))
+                .collect::<Vec<_>>()
+                .await;
+            // with "." first, in the order of the offsets
+            assert_eq!(entries.len(), 11);
+            assert_eq!(entries[0], (1, ".".to_owned()));
+            assert!(entries.windows(2).all(|w| w[0].0 < w[1].0));
+            let names = fs
+                .read_dir(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(|entry| entry.unwrap().name.expose_secret().clone())
+                .collect::<Vec<_>>();
+            assert_eq!(
+                entries
+                    .iter()
+                    .map(|(_, name)| name.clone())
+                    .collect::<Vec<_>>(),
+                names
+            );
+
+            // continue after the 4th
+            let rest = fs
+                .read_dir_plus_stream(ROOT_INODE, entries[3].0, 0)
+                .await
+                .unwrap()
+                .map(|entry| entry.unwrap())
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(rest.len(), 7);
+            for ((offset, entry), expected) in rest.iter().zip(&entries[4..]) {
+                assert_eq!(*offset, expected.0);
+                assert_eq!(*entry.name.expose_secret(), expected.1);
+                assert_eq!(entry.attr.ino, entry.ino);
+            }
+            assert!(fs
+                .read_dir_stream(ROOT_INODE, entries[10].0, 0)
+                .await
+                .unwrap()
+                .next()
+                .await
+                .is_none());
+
+            // the offsets don't change when entries are added or removed meanwhile
+            for (_, name) in [&entries[2], &entries[5]] {
+                fs.remove_file(ROOT_INODE, &SecretString::from_str(name).unwrap())
+                    .await
+                    .unwrap();
+            }
+            for i in 10..20 {
+                let name = SecretString::from_str(&format!("file-{i}")).unwrap();
+                fs.create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            }
+            let collect = |offset| {
+                let fs = fs.clone();
+                async move {
+                    fs.read_dir_stream(ROOT_INODE, offset, 0)
+                        .await
+                        .unwrap()
+                        .map(|entry| entry.unwrap())
+                        .map(|(offset, entry)| (offset, entry.name.expose_secret().clone()))
+                        .collect::<Vec<_>>()
+                        .await
+                }
+            };
+            let after = collect(entries[3].0).await;
+            assert_eq!(
+                after,
+                collect(0)
+                    .await
+                    .into_iter()
+                    .filter(|(offset, _)| *offset > entries[3].0)
+                    .collect::<Vec<_>>()
+            );
+            for entry in entries[4..].iter().filter(|entry| **entry != entries[5]) {
+                assert!(after.contains(entry));
+            }
+
+            // with a handle it's listed once, the next batches continue from there
+            let fh = fs.open_dir(ROOT_INODE).await.unwrap();
+            let collect_fh = |offset| {
+                let fs = fs.clone();
+                async move {
+                    fs.read_dir_stream(ROOT_INODE, offset, fh)
+                        .await
+                        .unwrap()
+                        .map(|entry| entry.unwrap())
+                        .map(|(offset, entry)| (offset, entry.name.expose_secret().clone()))
+                        .collect::<Vec<_>>()
+                        .await
+                }
+            };
+            let all = collect_fh(0).await;
+            assert_eq!(all, collect(0).await);
+            // the removed ones are skipped, the new ones are seen only from the start again
+            fs.remove_file(ROOT_INODE, &SecretString::from_str(&all[10].1).unwrap())
+                .await
+                .unwrap();
+            let name = SecretString::from_str("file-new").unwrap();
+            fs.create(
+                ROOT_INODE,
+                &name,
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+            let mut expected = all[6..].to_vec();
+            expected.remove(4);
+            assert_eq!(collect_fh(all[5].0).await, expected);
+            let again = collect_fh(0).await;
+            assert_eq!(again.len(), all.len());
+            assert!(again.iter().any(|(_, n)| n == "file-new"));
+            fs.release_dir(fh).await.unwrap();
+            assert!(matches!(
+                fs.read_dir_stream(ROOT_INODE, 0, fh).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+
+            let file = rest
+                .iter()
+                .find(|(_, entry)| entry.kind == FileType::RegularFile)
+                .unwrap()
+                .1
+                .ino;
+            assert!(matches!(
+                fs.read_dir_stream(file, 0, 0).await,
+                Err(FsError::InvalidInodeType)
+            ));
+            assert!(matches!(
+                fs.open_dir(file).await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[test]
+fn test_dir_entry_offset_collisions() {
+    let entry = |offset, name: &str| (offset, PathBuf::from(name));
+    let mut entries = vec![
+        entry(10, "c"),
+        entry(3, "x"),
+        entry(10, "a"),
+        entry(11, "d"),
+        entry(1, "$."),
+        entry(3, "b"),
+    ];
+    super::make_offsets_unique(&mut entries);
+    // the same whatever order `ls/` lists them in
+    assert_eq!(
+        entries,
+        vec![
+            entry(1, "$."),
+            entry(3, "b"),
+            entry(4, "x"),
+            entry(10, "a"),
+            entry(11, "c"),
+            entry(12, "d"),
+        ]
+    );
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_archive() {
+    run_test(
+        TestSetup {
+            key: "test_archive",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let path_fs = fs.path();
+            path_fs.create_dir_all("/a/b").await.unwrap();
+            let data = (0..=255)
+                .cycle()
+                .take(5 * BLOCK_SIZE + 3)
+                .collect::<Vec<u8>>();
+            path_fs.write_all("/a/b/file", &data).await.unwrap();
+            path_fs.write_all("/empty", b"").await.unwrap();
+            let dir = path_fs.metadata("/a").await.unwrap();
+            fs.create_symlink(
+                dir.ino,
+                &SecretString::from_str("link").unwrap(),
+                &SecretString::from_str("b/file").unwrap(),
+                create_attr(FileType::Symlink),
+            )
+            .await
+            .unwrap();
+            let file = path_fs.metadata("/a/b/file").await.unwrap();
+            fs.set_xattr(
+                file.ino,
+                &SecretString::from_str("user.test").unwrap(),
+                b"value",
+                SetXattrMode::Upsert,
+            )
+            .await
+            .unwrap();
+            fs.set_attr(file.ino, SetFileAttr::default().with_perm(0o600))
+                .await
+                .unwrap();
+
+            let password = SecretString::from_str("archive password").unwrap();
+            let kdf = KdfParams {
+                m_cost: 8,
+                t_cost: 1,
+                p_cost: 1,
+            };
+            // like the CLI does, the volume is not changed
+            let read_only = EncryptedFs::new(
+                fs.data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                true,
+            )
+            .await
+            .unwrap();
+            let archive = read_only
+                .export_archive(Cursor::new(vec![]), &password, kdf)
+                .await
+                .unwrap()
+                .into_inner();
+
+            test_common::init_fs(&fs.data_dir.join("import")).await;
+            let import = EncryptedFs::new(
+                fs.data_dir.join("import"),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            // it replaces what's there
+            import.path().write_all("/empty", b"old").await.unwrap();
+            let entries = import
+                .import_archive(&archive[..], &password)
+                .await
+                .unwrap();
+            assert_eq!(entries, 5);
+            let import_fs = import.path();
+            assert_eq!(import_fs.read_to_end("/a/b/file").await.unwrap(), data);
+            assert!(import_fs.read_to_end("/empty").await.unwrap().is_empty());
+            let link = import_fs.metadata("/a/link").await.unwrap();
+            assert_eq!(link.kind, FileType::Symlink);
+            assert_eq!(
+                *import.read_link(link.ino).await.unwrap().expose_secret(),
+                "b/file"
+            );
+            let imported = import_fs.metadata("/a/b/file").await.unwrap();
+            assert_eq!(imported.perm, 0o600);
+            assert_eq!(
+                imported.mtime,
+                path_fs.metadata("/a/b/file").await.unwrap().mtime
+            );
+            assert_eq!(
+                &*import
+                    .get_xattr(imported.ino, &SecretString::from_str("user.test").unwrap())
+                    .await
+                    .unwrap()
+                    .expose_secret(),
+                b"value"
+            );
+
+            // nothing is imported from an archive that fails to check
+            import.path().write_all("/empty", b"changed").await.unwrap();
+            assert!(matches!(
+                import
+                    .import_archive(&archive[..], &SecretString::from_str("wrong").unwrap())
+                    .await,
+                Err(FsError::InvalidPassword)
+            ));
+            assert!(import
+                .import_archive(&archive[..archive.len() - 10], &password)
+                .await
+                .is_err());
+            // zeroed blocks are not holes there, they fail to decrypt
+            let block_len = NONCE_LEN + BLOCK_SIZE + 16;
+            // after the plain archive header and the one of the encrypted content
+            let blocks_start = 60 + crypto::header::HEADER_LEN;
+            for block in 0..(archive.len() - blocks_start) / block_len {
+                let mut zeroed = archive.clone();
+                let start = blocks_start + block * block_len;
+                zeroed[start..start + block_len].fill(0);
+                assert!(import.import_archive(&zeroed[..], &password).await.is_err());
+            }
+            assert!(matches!(
+                import
+                    .import_archive(&b"not an archive"[..], &password)
+                    .await,
+                Err(FsError::InvalidInput(_))
+            ));
+            // m_cost in the plain header, it's checked before deriving the key
+            let mut excessive = archive.clone();
+            excessive[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+            assert!(matches!(
+                import.import_archive(&excessive[..], &password).await,
+                Err(FsError::InvalidInput(_))
+            ));
+            assert_eq!(import_fs.read_to_end("/empty").await.unwrap(), b"changed");
+            assert_eq!(import_fs.read_to_end("/a/b/file").await.unwrap(), data);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_journal_replay() {
+    run_test(
+        TestSetup {
+            key: "test_journal_replay",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.join("journal");
+            test_common::init_fs(&data_dir).await;
+            let open = || async {
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                )
+                .await
+                .unwrap()
+            };
+            let old_name = SecretString::from_str("old").unwrap();
+            let new_name = SecretString::from_str("new").unwrap();
+
+            let fs = open().await;
+            assert!(data_dir.join(WAL_DIR).is_dir());
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &old_name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            // like a rename which crashed after committing to the journal, before applying
+            let mut ops = fs
+                .remove_directory_entry_ops(ROOT_INODE, &old_name)
+                .await
+                .unwrap();
+            ops.extend(
+                fs.insert_directory_entry_ops(
+                    ROOT_INODE,
+                    &DirectoryEntry {
+                        ino: attr.ino,
+                        name: new_name.clone(),
+                        kind: FileType::RegularFile,
+                    },
+                )
+                .await
+                .unwrap(),
+            );
+            let entry = crypto::serialize_encrypt_into(
+                Cursor::new(vec![]),
+                &ops,
+                fs.cipher,
+                &fs.key.get().await.unwrap(),
+            )
+            .unwrap()
+            .into_inner();
+            fs.journal.as_ref().unwrap().append(&entry).unwrap();
+            assert!(fs.exists_by_name(ROOT_INODE, &old_name).unwrap());
+            assert!(!fs.exists_by_name(ROOT_INODE, &new_name).unwrap());
+            drop(fs);
+
+            // replayed on open, only once
+            for _ in 0..2 {
+                let fs = open().await;
+                assert!(!fs.exists_by_name(ROOT_INODE, &old_name).unwrap());
+                assert_eq!(
+                    fs.find_by_name(ROOT_INODE, &new_name)
+                        .await
+                        .unwrap()
+                        .unwrap()
+                        .ino,
+                    attr.ino
+                );
+                assert_eq!(fs.read_dir(ROOT_INODE).await.unwrap().count(), 2);
+                assert!(fs.fsck(false).await.unwrap().is_clean());
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_journal_replay_inode_ops() {
+    run_test(
+        TestSetup {
+            key: "test_journal_replay_inode_ops",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.join("journal_inode_ops");
+            test_common::init_fs(&data_dir).await;
+            let open = || async {
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                )
+                .await
+                .unwrap()
+            };
+            let name = SecretString::from_str("file").unwrap();
+            let link_name = SecretString::from_str("link").unwrap();
+
+            let fs = open().await;
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            fs.link(attr.ino, ROOT_INODE, &link_name).await.unwrap();
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().nlink, 2);
+            // like a remove of both names which crashed after committing to the journal
+            let mut ops = fs
+                .remove_directory_entry_ops(ROOT_INODE, &name)
+                .await
+                .unwrap();
+            ops.push(JournalOp::SetNlink {
+                ino: attr.ino,
+                nlink: 1,
+                ctime: SystemTime::now(),
+            });
+            ops.extend(
+                fs.remove_directory_entry_ops(ROOT_INODE, &link_name)
+                    .await
+                    .unwrap(),
+            );
+            ops.push(JournalOp::RemoveInode { ino: attr.ino });
+            let entry = crypto::serialize_encrypt_into(
+                Cursor::new(vec![]),
+                &ops,
+                fs.cipher,
+                &fs.key.get().await.unwrap(),
+            )
+            .unwrap()
+            .into_inner();
+            fs.journal.as_ref().unwrap().append(&entry).unwrap();
+            drop(fs);
+
+            for _ in 0..2 {
+                let fs = open().await;
+                assert!(!fs.exists_by_name(ROOT_INODE, &name).unwrap());
+                assert!(!fs.exists_by_name(ROOT_INODE, &link_name).unwrap());
+                assert!(!fs.exists(attr.ino));
+                assert!(!data_dir
+                    .join(CONTENTS_DIR)
+                    .join(attr.ino.to_string())
+                    .exists());
+                assert!(fs.fsck(false).await.unwrap().is_clean());
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_rekey() {
+    run_test(
+        TestSetup {
+            key: "test_rekey",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.join("rekey");
+            test_common::init_fs(&data_dir).await;
+            let open = || async {
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                )
+                .await
+                .unwrap()
+            };
+            let password = |p: &str| SecretString::from_str(p).unwrap();
+            let unlock = |p: &str| {
+                KeySlots::read(&data_dir)
+                    .unwrap()
+                    .unwrap()
+                    .unlock(&password(p), Cipher::ChaCha20Poly1305)
+                    .map(|(_, key)| key)
+            };
+            let state_path = data_dir.join(SECURITY_DIR).join(REKEY_FILENAME);
+
+            let fs = open().await;
+            let path_fs = fs.path();
+            path_fs.create_dir_all("/a/b").await.unwrap();
+            let data = (0..=255)
+                .cycle()
+                .take(5 * BLOCK_SIZE + 3)
+                .collect::<Vec<u8>>();
+            path_fs.write_all("/a/b/file", &data).await.unwrap();
+            path_fs.write_all("/empty", b"").await.unwrap();
+            let (fh, sparse) = path_fs.create("/sparse").await.unwrap();
+            fs.write(sparse.ino, 3 * BLOCK_SIZE as u64, b"end", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let dir = path_fs.metadata("/a").await.unwrap();
+            fs.create_symlink(
+                dir.ino,
+                &password("link"),
+                &password("b/file"),
+                create_attr(FileType::Symlink),
+            )
+            .await
+            .unwrap();
+            let file = path_fs.metadata("/a/b/file").await.unwrap();
+            fs.set_xattr(
+                file.ino,
+                &password("user.test"),
+                b"value",
+                SetXattrMode::Upsert,
+            )
+            .await
+            .unwrap();
+            // more than the progress interval, so it's saved in between
+            for i in 0..120 {
+                path_fs
+                    .write_all(format!("/a/f{i}"), i.to_string().as_bytes())
+                    .await
+                    .unwrap();
+            }
+            drop(fs);
+            EncryptedFs::add_key_slot(
+                &data_dir,
+                password("password"),
+                password("other"),
+                Cipher::ChaCha20Poly1305,
+                KdfParams::default(),
+            )
+            .await
+            .unwrap();
+            let old_key = unlock("password").unwrap();
+
+            // the other slot would be removed
+            assert!(matches!(
+                EncryptedFs::start_rekey(
+                    &data_dir,
+                    password("password"),
+                    Cipher::ChaCha20Poly1305,
+                    false
+                )
+                .await,
+                Err(FsError::Other(_))
+            ));
+            assert!(!state_path.exists());
+            EncryptedFs::start_rekey(
+                &data_dir,
+                password("password"),
+                Cipher::ChaCha20Poly1305,
+                true,
+            )
+            .await
+            .unwrap();
+            let state = std::fs::read(&state_path).unwrap();
+            // it's kept when started again, so it can be resumed
+            EncryptedFs::start_rekey(
+                &data_dir,
+                password("password"),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!(std::fs::read(&state_path).unwrap(), state);
+            // the new key is only in the slot for the password which started it
+            assert!(EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(FixedPasswordProvider("other")),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .is_err());
+
+            let fs = open().await;
+            let path_fs = fs.path();
+            assert!(fs.rekey_in_progress());
+            assert!(matches!(fs.fsck(false).await, Err(FsError::Other(_))));
+            // part of it is re-encrypted, with a handle open meanwhile
+            let fh = fs.open(file.ino, true, true).await.unwrap();
+            fs.write(file.ino, 0, b"new", fh).await.unwrap();
+            for ino in [ROOT_INODE, dir.ino, file.ino] {
+                fs.rekey_inode(ino).await.unwrap();
+            }
+            fs.write(file.ino, 3, b"er", fh).await.unwrap();
+            fs.flush(fh).await.unwrap();
+            let mut buf = vec![0; 5];
+            fs.read(file.ino, 0, &mut buf, fh).await.unwrap();
+            assert_eq!(buf, b"newer");
+            fs.release(fh).await.unwrap();
+            let mut data = data;
+            data[..5].copy_from_slice(b"newer");
+            // changes in a directory which was not re-encrypted yet
+            path_fs.write_all("/a/b/new", b"new").await.unwrap();
+            path_fs.rename("/a/b/new", "/a/b/renamed").await.unwrap();
+            path_fs.remove_file("/a/f0").await.unwrap();
+            assert_eq!(path_fs.read_to_end("/a/b/file").await.unwrap(), data);
+            let f1 = path_fs.metadata("/a/f1").await.unwrap();
+            drop(fs);
+
+            // what doesn't decrypt with either key stops it, until it's repaired
+            let f1_path = data_dir.join(INODES_DIR).join(f1.ino.to_string());
+            let f1_inode = std::fs::read(&f1_path).unwrap();
+            std::fs::write(&f1_path, vec![0; f1_inode.len()]).unwrap();
+            let fs = open().await;
+            assert!(matches!(fs.rekey(|_| {}).await, Err(FsError::Other(_))));
+            assert!(fs.rekey_in_progress());
+            drop(fs);
+            assert!(state_path.exists());
+            assert!(unlock("other").is_ok());
+            std::fs::write(&f1_path, &f1_inode).unwrap();
+
+            // interrupted, it continues on open and reads what was not re-encrypted yet
+            std::fs::write(
+                data_dir
+                    .join(CONTENTS_DIR)
+                    .join(format!(".{}.rekey", sparse.ino)),
+                b"partial",
+            )
+            .unwrap();
+            let fs = open().await;
+            let path_fs = fs.path();
+            assert!(fs.rekey_in_progress());
+            assert_eq!(path_fs.read_to_end("/a/f1").await.unwrap(), b"1");
+            let mut progress = vec![];
+            fs.rekey(|p| progress.push(p)).await.unwrap();
+            let total = progress[0].total;
+            // inode numbers are random, the failed run above may have already saved past f1
+            assert!(progress.len() > 2 || progress[0].done >= 100);
+            assert_eq!(progress.last().unwrap().done, total);
+            assert!(progress.windows(2).all(|w| w[0].done < w[1].done));
+            assert!(!fs.rekey_in_progress());
+            assert!(!state_path.exists());
+            assert!(!data_dir
+                .join(CONTENTS_DIR)
+                .join(format!(".{}.rekey", sparse.ino))
+                .exists());
+
+            // the old key doesn't decrypt anything anymore
+            let new_key = unlock("password").unwrap();
+            assert_ne!(*new_key.expose_secret(), *old_key.expose_secret());
+            assert!(matches!(unlock("other"), Err(FsError::InvalidPassword)));
+            assert_eq!(EncryptedFs::list_key_slots(&data_dir).unwrap().len(), 1);
+            let decrypts = |path: &Path, key| {
+                let mut reader = crypto::create_read(
+                    std::fs::File::open(path).unwrap(),
+                    Cipher::ChaCha20Poly1305,
+                    key,
+                );
+                std::io::copy(&mut reader, &mut std::io::sink()).is_ok()
+            };
+            let xattrs_path = data_dir.join(XATTRS_DIR).join(file.ino.to_string());
+            assert!(decrypts(&xattrs_path, &new_key));
+            assert!(!decrypts(&xattrs_path, &old_key));
+            for ino in list_inos(&data_dir.join(INODES_DIR)).unwrap() {
+                let path = data_dir.join(INODES_DIR).join(ino.to_string());
+                assert!(decrypts(&path, &new_key));
+                assert!(!decrypts(&path, &old_key));
+            }
+            // the map of the holes in the header doesn't decrypt either
+            assert!(crypto::create_block_store(
+                std::fs::File::open(data_dir.join(CONTENTS_DIR).join(file.ino.to_string()))
+                    .unwrap(),
+                Cipher::ChaCha20Poly1305,
+                &old_key,
+            )
+            .is_err());
+
+            let check = |fs: std::sync::Arc<EncryptedFs>| {
+                let data = data.clone();
+                async move {
+                    let path_fs = fs.path();
+                    assert_eq!(path_fs.read_to_end("/a/b/file").await.unwrap(), data);
+                    assert_eq!(path_fs.read_to_end("/a/b/renamed").await.unwrap(), b"new");
+                    assert!(path_fs.read_to_end("/empty").await.unwrap().is_empty());
+                    let mut sparse_data = vec![0; 3 * BLOCK_SIZE];
+                    sparse_data.extend_from_slice(b"end");
+                    assert_eq!(path_fs.read_to_end("/sparse").await.unwrap(), sparse_data);
+                    assert!(!path_fs.exists("/a/f0").await.unwrap());
+                    for i in 1..120 {
+                        assert_eq!(
+                            path_fs.read_to_end(format!("/a/f{i}")).await.unwrap(),
+                            i.to_string().as_bytes()
+                        );
+                    }
+                    let link = path_fs.metadata("/a/link").await.unwrap();
+                    assert_eq!(
+                        *fs.read_link(link.ino).await.unwrap().expose_secret(),
+                        "b/file"
+                    );
+                    let file = path_fs.metadata("/a/b/file").await.unwrap();
+                    assert_eq!(
+                        *fs.get_xattr(file.ino, &SecretString::from_str("user.test").unwrap())
+                            .await
+                            .unwrap()
+                            .expose_secret(),
+                        b"value"
+                    );
+                    assert!(fs.fsck(false).await.unwrap().is_clean());
+                }
+            };
+            check(fs.clone()).await;
+            drop(fs);
+
+            // we crashed after switching the key slots, before removing the state
+            std::fs::write(&state_path, &state).unwrap();
+            let fs = open().await;
+            assert!(!fs.rekey_in_progress());
+            assert!(!state_path.exists());
+            check(fs).await;
+        },
+    )
+    .await;
+}
+
+// #[tokio::test]
+// #[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn _test_sample() {
+    run_test(
+        TestSetup {
+            key: "test_sample",
+            read_only: false,
+        },
+        async {
+            let _ = get_fs().await;
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_read_only_create() {
+    run_test(
+        TestSetup {
+            key: "test_read_only_create",
+            read_only: true,
+        },
+        async {
+            let fs = get_fs().await;
+
+            // Check creating a file in a read only fs
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let create_file_result = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    true,
+                    false,
+                )
+                .await;
+            assert!(matches!(create_file_result, Err(FsError::ReadOnly)));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_read_only_write() {
+    run_test(
+        TestSetup {
+            key: "read_only_test_write",
+            read_only: false,
+        },
+        async {
+            let fs_rw = get_fs().await;
+            let data_dir = fs_rw.data_dir.clone();
+            let cipher = Cipher::ChaCha20Poly1305;
+            let file1 = SecretString::from_str("file1").unwrap();
+            let file_dest = SecretString::from_str("file_dest").unwrap();
+            let dir1 = SecretString::from_str("dir1").unwrap();
+            let data = "Hello, world!";
+
+            let (fh, attr) = fs_rw
+                .create(
+                    ROOT_INODE,
+                    &file1,
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .expect("read_only_test_create: Error creating file.");
+            let (fh_dest, attr_dest) = fs_rw
+                .create(
+                    ROOT_INODE,
+                    &file_dest,
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .expect("read_only_test_create: Error creating file.");
+            let (_, _) = fs_rw
+                .create(
+                    ROOT_INODE,
+                    &dir1,
+                    create_attr(FileType::Directory),
+                    false,
+                    true,
+                )
+                .await
+                .expect("read_only_test_create: Error creating dir.");
+
+            // Create a succesful write on the file
+            crate::encryptedfs::write_all_string_to_fs(&fs_rw, attr.ino, 0, data, fh)
+                .await
+                .unwrap();
+            fs_rw.flush(fh).await.unwrap();
+            fs_rw.release(fh).await.unwrap();
+            drop(fs_rw);
+            let fs_ro = EncryptedFs::new(data_dir, Box::new(PasswordProviderImpl {}), cipher, true)
+                .await
+                .expect("test_read_only_write: Error creating rw fs.");
+            let fh = fs_ro
+                .open(attr.ino, true, false)
+                .await
+                .expect("read_only_test_create: Error opening file in ro.");
+
+            // Test a succesful reading the file in rw mode
+            let mut buf = vec![0; data.len()];
+            fs_ro.read(attr.ino, 0, &mut buf, fh).await.unwrap();
+            assert_eq!(data, String::from_utf8(buf).unwrap());
+
+            // Test creating a file
+            let file2 = SecretString::from_str("file2").unwrap();
+            let create_file_result = fs_ro
+                .create(
+                    ROOT_INODE,
+                    &file2,
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
                 .await;
             assert!(matches!(create_file_result, Err(FsError::ReadOnly)));
             // Test renaming the file
@@ -2477,6 +5739,15 @@ async fn test_read_only_write() {
             // Test flushing data to file
             let flush_result = fs_ro.flush(fh).await;
             assert!(matches!(flush_result, Err(FsError::ReadOnly)));
+            // Listing and releasing don't change anything, they work
+            let names = fs_ro
+                .read_dir(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(|entry| entry.unwrap().name.expose_secret().to_string())
+                .collect::<Vec<_>>();
+            assert!(names.contains(&"file1".to_string()));
+            fs_ro.release(fh).await.unwrap();
         },
     )
     .await;
@@ -0,0 +1,133 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use shush_rs::{ExposeSecret, SecretVec};
+
+use crate::crypto::write::BLOCK_SIZE;
+use crate::crypto::{self, Cipher, KdfParams, Legacy};
+use crate::encryptedfs::{FsError, FsResult, SECURITY_DIR};
+use crate::fs_util;
+
+pub(crate) const VOLUME_FILENAME: &str = "volume";
+
+/// Context used to derive the key of the MAC of the metadata from the master key.
+const VOLUME_MAC_CONTEXT: &str = "rencfs 2026-10-18 volume metadata mac";
+
+/// Version of the on-disk format of the data dir.
+///
+/// 1. data dirs created before the volume metadata, file names are hashed without a key
+/// 2. file names are hashed with a key derived from the master key, new files have a header
+/// 3. the master key is stored in key slots instead of `key.enc`
+/// 4. all files have a header, before it was only the new ones
+/// 5. contents have the map of the holes in the header, before zeroed blocks were holes
+pub const CURRENT_FORMAT_VERSION: u32 = 5;
+
+/// Metadata of the data dir.
+///
+/// It's stored unencrypted in `security/volume` because we need it before we can derive the key,
+/// followed by a MAC with a key derived from the master key, so it cannot be changed, like
+/// lowering the format version to have older formats accepted, without the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VolumeMetadata {
+    pub format_version: u32,
+    pub cipher: Cipher,
+    /// Used for the first key slot, before version 3 for `key.enc`
+    pub kdf: KdfParams,
+    /// Size of plaintext blocks in bytes
+    pub block_size: u32,
+}
+
+impl VolumeMetadata {
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn new(cipher: Cipher) -> Self {
+        Self {
+            format_version: CURRENT_FORMAT_VERSION,
+            cipher,
+            kdf: KdfParams::default(),
+            block_size: BLOCK_SIZE as u32,
+        }
+    }
+
+    /// For data dirs created before we had the metadata file, the cipher was given on each mount.
+    #[must_use]
+    pub(crate) fn legacy(cipher: Cipher) -> Self {
+        Self {
+            format_version: 1,
+            ..Self::new(cipher)
+        }
+    }
+
+    /// What the versions before [`CURRENT_FORMAT_VERSION`] wrote and we still need to read.
+    #[must_use]
+    pub(crate) const fn legacy_content(&self) -> Legacy {
+        match self.format_version {
+            0..=3 => Legacy::WithoutHeader,
+            4 => Legacy::ZeroedHoles,
+            _ => Legacy::None,
+        }
+    }
+
+    /// Returns `None` if the data dir doesn't have the metadata file.
+    ///
+    /// It's not authenticated yet, check it with [`Self::check_mac`] when we have the key.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn read(data_dir: &Path) -> FsResult<Option<Self>> {
+        let path = data_dir.join(SECURITY_DIR).join(VOLUME_FILENAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let metadata: Self = bincode::deserialize_from(File::open(path)?)?;
+        if metadata.format_version > CURRENT_FORMAT_VERSION {
+            return Err(FsError::UnsupportedFormatVersion(metadata.format_version));
+        }
+        if metadata.block_size as usize != BLOCK_SIZE {
+            return Err(FsError::Other(
+                "data dir was created with a different block size",
+            ));
+        }
+        Ok(Some(metadata))
+    }
+
+    /// Check that the metadata file was written with `key`, if the data dir has it.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn check_mac(data_dir: &Path, key: &SecretVec<u8>) -> FsResult<()> {
+        let path = data_dir.join(SECURITY_DIR).join(VOLUME_FILENAME);
+        if !path.is_file() {
+            return Ok(());
+        }
+        let mut file = File::open(path)?;
+        let metadata: Self = bincode::deserialize_from(&mut file)?;
+        let mut mac = [0; blake3::OUT_LEN];
+        file.read_exact(&mut mac)
+            .map_err(|_| FsError::Other("the metadata of the data dir doesn't have the MAC"))?;
+        // `Hash` compares in constant time
+        if metadata.mac(key)? != blake3::Hash::from(mac) {
+            return Err(FsError::Other("the metadata of the data dir was changed"));
+        }
+        Ok(())
+    }
+
+    /// Write it with the MAC keyed from the master `key`.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn write(&self, data_dir: &Path, key: &SecretVec<u8>) -> FsResult<()> {
+        let path = data_dir.join(SECURITY_DIR).join(VOLUME_FILENAME);
+        let mut file = fs_util::open_atomic_write(&path)?;
+        bincode::serialize_into(&mut file, self)?;
+        file.write_all(self.mac(key)?.as_bytes())?;
+        file.flush()?;
+        file.commit()?;
+        File::open(data_dir.join(SECURITY_DIR))?.sync_all()?;
+        Ok(())
+    }
+
+    fn mac(&self, key: &SecretVec<u8>) -> FsResult<blake3::Hash> {
+        let mac_key: [u8; blake3::KEY_LEN] = crypto::derive_subkey(key, VOLUME_MAC_CONTEXT)
+            .expose_secret()[..]
+            .try_into()
+            .expect("subkey has the length of the key");
+        Ok(blake3::keyed_hash(&mac_key, &bincode::serialize(self)?))
+    }
+}
@@ -43,3 +43,75 @@ pub fn open_atomic_write(file: &Path) -> io::Result<AtomicWriteFile> {
     opt.preserve_mode(true).preserve_owner(true);
     opt.open(file)
 }
+
+/// Stats of the filesystem containing `path`, as returned by `statvfs(3)`.
+#[cfg(unix)]
+pub fn statvfs(path: &Path) -> io::Result<libc::statvfs> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains nul byte"))?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { stat.assume_init() })
+}
+
+/// Start of the next data region from `pos`, like `lseek(2)` with `SEEK_DATA`.
+///
+/// Returns `None` if there is only a hole until the end.
+#[cfg(target_os = "linux")]
+#[allow(clippy::cast_possible_wrap)]
+#[allow(clippy::cast_sign_loss)]
+pub fn seek_data(file: &fs::File, pos: u64) -> io::Result<Option<u64>> {
+    use std::os::fd::AsRawFd;
+
+    let res = unsafe { libc::lseek(file.as_raw_fd(), pos as libc::off_t, libc::SEEK_DATA) };
+    if res < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ENXIO) {
+            return Ok(None);
+        }
+        return Err(err);
+    }
+    Ok(Some(res as u64))
+}
+
+/// Take an exclusive `flock(2)` on the file without waiting.
+///
+/// Returns `false` if it's held by someone else, it's released when the file is closed.
+#[cfg(unix)]
+pub fn try_lock_exclusive(file: &fs::File) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            return Ok(false);
+        }
+        return Err(err);
+    }
+    Ok(true)
+}
+
+/// Like `fallocate(2)`, `mode` is made of the `FALLOC_FL_*` flags.
+#[cfg(target_os = "linux")]
+#[allow(clippy::cast_possible_wrap)]
+pub fn fallocate(file: &fs::File, mode: i32, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let res = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            mode,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
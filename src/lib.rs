@@ -27,7 +27,7 @@
 //! use anyhow::Result;
 //! use shush_rs::SecretString;
 //!
-//! use rencfs::encryptedfs::PasswordProvider;
+//! use rencfs::encryptedfs::{EncryptedFs, PasswordProvider};
 //! use rencfs::mount::create_mount_point;
 //! use rencfs::mount::MountPoint;
 //!
@@ -41,7 +41,7 @@
 //!     args.next(); // skip program name
 //!     let mount_path = args.next().expect("mount_path expected");
 //!     let data_path = args.next().expect("data_path expected");
-//! use rencfs::crypto::Cipher;
+//! use rencfs::crypto::{Cipher, KdfParams};
 //!
 //! struct PasswordProviderImpl {}
 //!     impl PasswordProvider for PasswordProviderImpl {
@@ -50,6 +50,10 @@
 //!             Some(SecretString::new(Box::new(String::from("pass42"))))
 //!         }
 //!     }
+//!     if !EncryptedFs::is_initialized(Path::new(&data_path)) {
+//!         let password = PasswordProviderImpl {}.get_password().unwrap();
+//!         EncryptedFs::init(Path::new(&data_path), password, Cipher::ChaCha20Poly1305, KdfParams::default()).await?;
+//!     }
 //!     let mount_point = create_mount_point(
 //!         Path::new(&mount_path),
 //!         Path::new(&data_path),
@@ -86,7 +90,7 @@
 //! use std::fs;
 //! use shush_rs::SecretString;
 //! use rencfs::encryptedfs::{EncryptedFs, FileType, PasswordProvider, CreateFileAttr};
-//! use rencfs::crypto::Cipher;
+//! use rencfs::crypto::{Cipher, KdfParams};
 //! use anyhow::Result;
 //! use std::path::Path;
 //! use rencfs::encryptedfs::write_all_string_to_fs;
@@ -108,6 +112,7 @@
 //!     let data_dir = Path::new("/tmp/rencfs_data_test").to_path_buf();
 //!     let  _ = fs::remove_dir_all(data_dir.to_str().unwrap());
 //!     let cipher = Cipher::ChaCha20Poly1305;
+//!     EncryptedFs::init(&data_dir, PasswordProviderImpl{}.get_password().unwrap(), cipher, KdfParams::default()).await?;
 //!     let mut fs = EncryptedFs::new(data_dir.clone(), Box::new(PasswordProviderImpl{}), cipher, false).await?;
 //!
 //!     let  file1 = SecretString::new(Box::new(String::from("file-1")));
@@ -294,6 +299,7 @@ pub mod crypto;
 pub mod encryptedfs;
 pub mod expire_value;
 pub mod fs_util;
+pub mod lock_manager;
 pub mod log;
 pub mod mount;
 pub mod stream_util;
@@ -0,0 +1,360 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+/// Kind of an advisory lock, like `F_RDLCK` and `F_WRLCK`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    /// Shared, any number of owners can hold it at the same time
+    Read,
+    /// Exclusive
+    Write,
+}
+
+/// Advisory lock on a byte range of an inode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lock {
+    /// Who holds it, like the lock owner from FUSE, which identifies the process or the open file
+    pub owner: u64,
+    pub start: u64,
+    /// Inclusive, `u64::MAX` means until the end of the file, even if it grows
+    pub end: u64,
+    pub kind: LockKind,
+    /// Reported to whoever is blocked by it
+    pub pid: u32,
+}
+
+impl Lock {
+    /// Lock for the whole file, like `flock(2)` does.
+    #[must_use]
+    pub const fn whole_file(owner: u64, kind: LockKind, pid: u32) -> Self {
+        Self {
+            owner,
+            start: 0,
+            end: u64::MAX,
+            kind,
+            pid,
+        }
+    }
+
+    const fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn conflicts_with(&self, other: &Self) -> bool {
+        self.owner != other.owner
+            && (self.kind == LockKind::Write || other.kind == LockKind::Write)
+            && self.overlaps(other.start, other.end)
+    }
+}
+
+/// In memory POSIX advisory locks, like `fcntl(2)` record locks, for each inode.
+///
+/// Locks of the same owner never conflict, a new one replaces the part of the old ones it
+/// overlaps, so it can upgrade, downgrade or split them.
+#[derive(Default)]
+pub struct LockManager {
+    locks: Mutex<HashMap<u64, Vec<Lock>>>,
+    // woken when locks are released, so the blocked ones can try again
+    released: Notify,
+    interrupts: Mutex<Interrupts>,
+}
+
+/// Most interrupts we keep for requests which didn't start waiting yet.
+const EARLY_INTERRUPTS: usize = 64;
+
+/// Requests waiting for a lock, so they can be interrupted.
+#[derive(Default)]
+struct Interrupts {
+    waiting: HashMap<u64, Arc<Notify>>,
+    // the interrupt can come before the request starts waiting, the oldest are dropped
+    early: VecDeque<u64>,
+}
+
+impl LockManager {
+    /// The first lock of another owner which would prevent acquiring this one, like `F_GETLK`.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn get(&self, ino: u64, lock: &Lock) -> Option<Lock> {
+        self.locks
+            .lock()
+            .expect("cannot obtain lock")
+            .get(&ino)?
+            .iter()
+            .find(|l| l.conflicts_with(lock))
+            .copied()
+    }
+
+    /// Acquire the lock if there is no conflicting one, like `F_SETLK`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the conflicting lock.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn try_lock(&self, ino: u64, lock: Lock) -> Result<(), Lock> {
+        let mut locks = self.locks.lock().expect("cannot obtain lock");
+        let inode_locks = locks.entry(ino).or_default();
+        if let Some(conflict) = inode_locks.iter().find(|l| l.conflicts_with(&lock)) {
+            return Err(*conflict);
+        }
+        let downgrade = remove_range(inode_locks, lock.owner, lock.start, lock.end);
+        inode_locks.push(lock);
+        drop(locks);
+        if downgrade {
+            self.released.notify_waiters();
+        }
+        Ok(())
+    }
+
+    /// Wait until there is no conflicting lock and acquire it, like `F_SETLKW`.
+    pub async fn lock(&self, ino: u64, lock: Lock) {
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            // register before trying, so we don't miss a release in between
+            released.as_mut().enable();
+            if self.try_lock(ino, lock).is_ok() {
+                return;
+            }
+            released.await;
+        }
+    }
+
+    /// Like [`LockManager::lock`], but it gives up if [`LockManager::interrupt`] is called for the
+    /// request, like when the process waiting for it gets a signal.
+    ///
+    /// Returns `false` if it was interrupted, the lock is not acquired then.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn lock_interruptible(&self, ino: u64, lock: Lock, request: u64) -> bool {
+        let interrupted = Arc::new(Notify::new());
+        {
+            let mut interrupts = self.interrupts.lock().expect("cannot obtain lock");
+            if let Some(pos) = interrupts.early.iter().position(|r| *r == request) {
+                interrupts.early.remove(pos);
+                return false;
+            }
+            interrupts.waiting.insert(request, interrupted.clone());
+        }
+        let acquired = tokio::select! {
+            // if both are ready we keep the lock, it's released with the others of the owner
+            biased;
+            () = self.lock(ino, lock) => true,
+            () = interrupted.notified() => false,
+        };
+        self.interrupts
+            .lock()
+            .expect("cannot obtain lock")
+            .waiting
+            .remove(&request);
+        acquired
+    }
+
+    /// Interrupt the request waiting in [`LockManager::lock_interruptible`], or the next one
+    /// with this id.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn interrupt(&self, request: u64) {
+        let mut interrupts = self.interrupts.lock().expect("cannot obtain lock");
+        if let Some(interrupted) = interrupts.waiting.get(&request) {
+            // it's kept if it doesn't wait yet
+            interrupted.notify_one();
+            return;
+        }
+        if interrupts.early.len() == EARLY_INTERRUPTS {
+            interrupts.early.pop_front();
+        }
+        interrupts.early.push_back(request);
+    }
+
+    /// Release the range from the locks of the owner, like `F_UNLCK`.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn unlock(&self, ino: u64, owner: u64, start: u64, end: u64) {
+        let mut locks = self.locks.lock().expect("cannot obtain lock");
+        let Some(inode_locks) = locks.get_mut(&ino) else {
+            return;
+        };
+        let released = remove_range(inode_locks, owner, start, end);
+        if inode_locks.is_empty() {
+            locks.remove(&ino);
+        }
+        drop(locks);
+        if released {
+            self.released.notify_waiters();
+        }
+    }
+
+    /// Release all locks of the owner on the inode, when it closes the file.
+    pub fn unlock_all(&self, ino: u64, owner: u64) {
+        self.unlock(ino, owner, 0, u64::MAX);
+    }
+}
+
+/// Remove the range from the locks of the owner, splitting them if needed.
+///
+/// Returns `true` if any lock was changed.
+fn remove_range(locks: &mut Vec<Lock>, owner: u64, start: u64, end: u64) -> bool {
+    let mut changed = false;
+    let mut remaining = Vec::with_capacity(locks.len());
+    for lock in locks.drain(..) {
+        if lock.owner != owner || !lock.overlaps(start, end) {
+            remaining.push(lock);
+            continue;
+        }
+        changed = true;
+        if lock.start < start {
+            remaining.push(Lock {
+                end: start - 1,
+                ..lock
+            });
+        }
+        if lock.end > end {
+            remaining.push(Lock {
+                start: end + 1,
+                ..lock
+            });
+        }
+    }
+    *locks = remaining;
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    const fn lock(owner: u64, start: u64, end: u64, kind: LockKind) -> Lock {
+        Lock {
+            owner,
+            start,
+            end,
+            kind,
+            pid: owner as u32,
+        }
+    }
+
+    #[test]
+    fn test_lock_conflicts() {
+        let m = LockManager::default();
+        m.try_lock(1, lock(1, 0, 9, LockKind::Read)).unwrap();
+        // shared
+        m.try_lock(1, lock(2, 5, 14, LockKind::Read)).unwrap();
+        assert_eq!(
+            m.try_lock(1, lock(3, 9, 9, LockKind::Write)),
+            Err(lock(1, 0, 9, LockKind::Read))
+        );
+        assert_eq!(
+            m.get(1, &lock(3, 12, 20, LockKind::Write)),
+            Some(lock(2, 5, 14, LockKind::Read))
+        );
+        // not overlapping and other inode
+        m.try_lock(1, lock(3, 15, u64::MAX, LockKind::Write))
+            .unwrap();
+        m.try_lock(2, Lock::whole_file(3, LockKind::Write, 3))
+            .unwrap();
+        assert_eq!(
+            m.get(1, &lock(4, 100, 100, LockKind::Read)),
+            Some(lock(3, 15, u64::MAX, LockKind::Write))
+        );
+
+        m.unlock_all(1, 2);
+        m.unlock(1, 3, 0, u64::MAX);
+        m.try_lock(1, lock(3, 5, 9, LockKind::Read)).unwrap();
+        assert!(m.try_lock(1, lock(3, 5, 9, LockKind::Write)).is_err());
+        m.try_lock(1, lock(3, 10, 20, LockKind::Write)).unwrap();
+    }
+
+    #[test]
+    fn test_lock_same_owner() {
+        let m = LockManager::default();
+        m.try_lock(1, lock(1, 0, 99, LockKind::Read)).unwrap();
+        // upgrade the middle, it splits the read lock
+        m.try_lock(1, lock(1, 40, 59, LockKind::Write)).unwrap();
+        assert_eq!(
+            m.get(1, &lock(2, 0, 99, LockKind::Read)),
+            Some(lock(1, 40, 59, LockKind::Write))
+        );
+        m.try_lock(1, lock(2, 0, 39, LockKind::Read)).unwrap();
+        m.try_lock(1, lock(2, 60, 99, LockKind::Read)).unwrap();
+        assert!(m.try_lock(1, lock(2, 30, 40, LockKind::Read)).is_err());
+
+        // unlock a hole
+        m.unlock(1, 1, 45, 54);
+        m.try_lock(1, lock(2, 45, 54, LockKind::Write)).unwrap();
+        assert!(m.try_lock(1, lock(2, 44, 44, LockKind::Read)).is_err());
+        assert!(m.try_lock(1, lock(2, 55, 55, LockKind::Read)).is_err());
+
+        // downgrade
+        m.try_lock(1, lock(1, 40, 59, LockKind::Read)).unwrap_err();
+        m.unlock(1, 2, 45, 54);
+        m.try_lock(1, lock(1, 40, 59, LockKind::Read)).unwrap();
+        m.try_lock(1, lock(2, 40, 59, LockKind::Read)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lock_interrupted() {
+        let m = Arc::new(LockManager::default());
+        m.try_lock(1, Lock::whole_file(1, LockKind::Write, 1))
+            .unwrap();
+
+        let m_clone = m.clone();
+        let waiter = tokio::spawn(async move {
+            m_clone
+                .lock_interruptible(1, lock(2, 0, 9, LockKind::Read), 100)
+                .await
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+        m.interrupt(100);
+        assert!(!tokio::time::timeout(Duration::from_secs(5), waiter)
+            .await
+            .unwrap()
+            .unwrap());
+        // it doesn't get it when it's released
+        m.unlock_all(1, 1);
+        assert_eq!(m.get(1, &lock(3, 0, 9, LockKind::Write)), None);
+
+        // interrupted before it waits
+        m.try_lock(1, Lock::whole_file(1, LockKind::Write, 1))
+            .unwrap();
+        m.interrupt(101);
+        assert!(
+            !m.lock_interruptible(1, lock(2, 0, 9, LockKind::Read), 101)
+                .await
+        );
+        m.unlock_all(1, 1);
+        assert!(
+            m.lock_interruptible(1, lock(2, 0, 9, LockKind::Read), 102)
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lock_wait() {
+        let m = Arc::new(LockManager::default());
+        m.try_lock(1, Lock::whole_file(1, LockKind::Write, 1))
+            .unwrap();
+
+        let m_clone = m.clone();
+        let waiter = tokio::spawn(async move {
+            m_clone.lock(1, lock(2, 10, 20, LockKind::Write)).await;
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+        // still overlapping
+        m.unlock(1, 1, 0, 9);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        m.unlock_all(1, 1);
+        tokio::time::timeout(Duration::from_secs(5), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            m.get(1, &lock(1, 0, u64::MAX, LockKind::Read)),
+            Some(lock(2, 10, 20, LockKind::Write))
+        );
+    }
+}
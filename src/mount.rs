@@ -1,5 +1,5 @@
 use crate::crypto::Cipher;
-use crate::encryptedfs::{FsResult, PasswordProvider};
+use crate::encryptedfs::{FsResult, KeyMaterialProvider};
 use async_trait::async_trait;
 use futures_util::FutureExt;
 use std::future::Future;
@@ -30,7 +30,7 @@ pub trait MountPoint {
     fn new(
         mountpoint: PathBuf,
         data_dir: PathBuf,
-        password_provider: Box<dyn PasswordProvider>,
+        password_provider: Box<dyn KeyMaterialProvider>,
         cipher: Cipher,
         allow_root: bool,
         allow_other: bool,
@@ -66,10 +66,10 @@ pub(crate) trait MountHandleInner: Future<Output = io::Result<()>> {
 /// Available arguments
 ///
 /// **`mountpoint`** where it wil mount the filesystem
-/// **`data_dir`** the directory where the encrypted files will be stored  
-/// **`password_provider`** the password provider  
-/// **`cipher`** The encryption algorithm to use.
-/// Currently, it supports these ciphers [`Cipher`]
+/// **`data_dir`** the directory where the encrypted files are stored, create the filesystem in it
+/// first with [`EncryptedFs::init`](crate::encryptedfs::EncryptedFs::init)  
+/// **`password_provider`** provides the password or other key material, see [`KeyMaterialProvider`]  
+/// **`cipher`** The encryption algorithm, data dirs use the cipher they were created with, see [`Cipher`]
 ///
 /// **`allow_root`** allow root to access the file system  
 /// **`allow_other`** allow other users to access the file system  
@@ -80,7 +80,7 @@ pub(crate) trait MountHandleInner: Future<Output = io::Result<()>> {
 pub fn create_mount_point(
     mountpoint: &Path,
     data_dir: &Path,
-    password_provider: Box<dyn PasswordProvider>,
+    password_provider: Box<dyn KeyMaterialProvider>,
     cipher: Cipher,
     allow_root: bool,
     allow_other: bool,
@@ -7,7 +7,7 @@ use std::task::{Context, Poll};
 use tracing::error;
 
 use crate::crypto::Cipher;
-use crate::encryptedfs::{FsError, FsResult, PasswordProvider};
+use crate::encryptedfs::{FsError, FsResult, KeyMaterialProvider};
 use crate::mount;
 use crate::mount::{MountHandleInner, MountPoint};
 
@@ -16,7 +16,7 @@ use crate::mount::{MountHandleInner, MountPoint};
 pub struct MountPointImpl {
     mountpoint: PathBuf,
     data_dir: PathBuf,
-    password_provider: Option<Box<dyn PasswordProvider>>,
+    password_provider: Option<Box<dyn KeyMaterialProvider>>,
     cipher: Cipher,
     allow_root: bool,
     allow_other: bool,
@@ -28,7 +28,7 @@ impl MountPoint for MountPointImpl {
     fn new(
         mountpoint: PathBuf,
         data_dir: PathBuf,
-        password_provider: Box<dyn PasswordProvider>,
+        password_provider: Box<dyn KeyMaterialProvider>,
         cipher: Cipher,
         allow_root: bool,
         allow_other: bool,
@@ -3,14 +3,13 @@ use std::fs::File;
 use std::future::Future;
 use std::io;
 use std::io::{BufRead, BufReader};
-use std::iter::Skip;
 use std::num::NonZeroU32;
 use std::os::raw::c_int;
 use std::path::PathBuf;
 use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::task::{Context, Poll};
+use std::task::{ready, Context, Poll};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::fs;
 
@@ -18,129 +17,130 @@ use async_trait::async_trait;
 use bytes::Bytes;
 use fuse3::raw::prelude::{
     DirectoryEntry, DirectoryEntryPlus, ReplyAttr, ReplyCopyFileRange, ReplyCreated, ReplyData,
-    ReplyDirectory, ReplyDirectoryPlus, ReplyEntry, ReplyInit, ReplyOpen, ReplyStatFs, ReplyWrite,
+    ReplyDirectory, ReplyDirectoryPlus, ReplyEntry, ReplyInit, ReplyLSeek, ReplyLock, ReplyOpen,
+    ReplyStatFs, ReplyWrite, ReplyXAttr,
 };
 use fuse3::raw::{Filesystem, MountHandle, Request, Session};
 use fuse3::{Errno, Inode, MountOptions, Result, SetAttr, Timestamp};
-use futures_util::stream::Iter;
-use futures_util::{stream, FutureExt};
-use libc::{EACCES, EEXIST, EFBIG, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOTDIR, ENOTEMPTY, EPERM};
+use futures_util::{FutureExt, Stream, StreamExt};
+use libc::{
+    EACCES, EAGAIN, EBADF, EEXIST, EFBIG, EINTR, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENODEV, ENOENT,
+    ENOSPC, ENOTDIR, ENOTEMPTY, ENXIO, EOPNOTSUPP, EPERM, EROFS, FALLOC_FL_KEEP_SIZE,
+    FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE, F_RDLCK, F_UNLCK, F_WRLCK, SEEK_DATA, SEEK_HOLE,
+};
 use shush_rs::{ExposeSecret, SecretString};
 use tracing::{debug, error, instrument, trace, warn};
 use tracing::{info, Level};
 
 use crate::crypto::Cipher;
 use crate::encryptedfs::{
-    CopyFileRangeReq, CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsResult,
-    PasswordProvider, SetFileAttr,
+    CopyFileRangeReq, CreateFileAttr, EncryptedFs, FallocateMode, FileAttr, FileType, FsError,
+    FsResult, KeyMaterialProvider, SetFileAttr, SetXattrMode, StatFs, XATTR_NAME_MAX,
+    XATTR_VALUE_MAX,
 };
+use crate::lock_manager::{Lock, LockKind, LockManager};
 use crate::mount;
 use crate::mount::{MountHandleInner, MountPoint};
 
 const TTL: Duration = Duration::from_secs(1);
-const STATFS: ReplyStatFs = ReplyStatFs {
-    blocks: 1,
-    bfree: 0,
-    bavail: 0,
-    files: 1,
-    ffree: 0,
-    bsize: 4096,
-    namelen: u32::MAX,
-    frsize: 0,
-};
-
 const FMODE_EXEC: i32 = 0x20;
 
 // const MAX_NAME_LENGTH: u32 = 255 - ENCRYPT_FILENAME_OVERHEAD_CHARS as u32;
 
-pub struct DirectoryEntryIterator(crate::encryptedfs::DirectoryEntryIterator, u64);
+pub struct DirectoryEntryStream(crate::encryptedfs::DirectoryEntryStream);
 
-impl Iterator for DirectoryEntryIterator {
+impl Stream for DirectoryEntryStream {
     type Item = Result<DirectoryEntry>;
 
-    #[instrument(name = "DirectoryEntryIterator::next", skip(self))]
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.0.next() {
-            Some(Ok(entry)) => {
-                let kind = if entry.kind == FileType::Directory {
-                    fuse3::raw::prelude::FileType::Directory
-                } else {
-                    fuse3::raw::prelude::FileType::RegularFile
-                };
-                self.1 += 1;
-                Some(Ok(DirectoryEntry {
-                    inode: entry.ino,
-                    kind,
-                    name: OsString::from(&*entry.name.expose_secret()),
-                    #[allow(clippy::cast_possible_wrap)]
-                    offset: self.1 as i64,
-                }))
-            }
-            Some(Err(FsError::Io { source, .. })) => {
-                error!(err = %source);
-                Some(Err(source.into()))
-            }
-            Some(Err(err)) => {
-                error!(err = %err);
-                Some(Err(EIO.into()))
-            }
-            None => None,
-        }
+    #[instrument(name = "DirectoryEntryStream::poll_next", skip(self, cx))]
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let entry = match ready!(self.0.poll_next_unpin(cx)) {
+            Some(Ok((offset, entry))) => Ok(DirectoryEntry {
+                inode: entry.ino,
+                kind: entry.kind.into(),
+                name: OsString::from(&*entry.name.expose_secret()),
+                #[allow(clippy::cast_possible_wrap)]
+                offset: offset as i64,
+            }),
+            Some(Err(err)) => Err(entry_error(err)),
+            None => return Poll::Ready(None),
+        };
+        Poll::Ready(Some(entry))
     }
 }
 
-pub struct DirectoryEntryPlusIterator(crate::encryptedfs::DirectoryEntryPlusIterator, u64);
+pub struct DirectoryEntryPlusStream(crate::encryptedfs::DirectoryEntryPlusStream);
 
-impl Iterator for DirectoryEntryPlusIterator {
+impl Stream for DirectoryEntryPlusStream {
     type Item = Result<DirectoryEntryPlus>;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.0.next() {
-            Some(Ok(entry)) => {
-                let kind = if entry.kind == FileType::Directory {
-                    fuse3::raw::prelude::FileType::Directory
-                } else {
-                    fuse3::raw::prelude::FileType::RegularFile
-                };
-                self.1 += 1;
-                Some(Ok(DirectoryEntryPlus {
-                    inode: entry.ino,
-                    generation: 0,
-                    kind,
-                    name: OsString::from(&*entry.name.expose_secret()),
-                    #[allow(clippy::cast_possible_wrap)]
-                    offset: self.1 as i64,
-                    attr: entry.attr.into(),
-                    entry_ttl: TTL,
-                    attr_ttl: TTL,
-                }))
-            }
-            Some(Err(FsError::Io { source, .. })) => {
-                error!(err = %source);
-                Some(Err(source.into()))
-            }
-            Some(Err(err)) => {
-                error!(err = %err);
-                Some(Err(EIO.into()))
-            }
-            None => None,
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let entry = match ready!(self.0.poll_next_unpin(cx)) {
+            Some(Ok((offset, entry))) => Ok(DirectoryEntryPlus {
+                inode: entry.ino,
+                generation: 0,
+                kind: entry.kind.into(),
+                name: OsString::from(&*entry.name.expose_secret()),
+                #[allow(clippy::cast_possible_wrap)]
+                offset: offset as i64,
+                attr: entry.attr.into(),
+                entry_ttl: TTL,
+                attr_ttl: TTL,
+            }),
+            Some(Err(err)) => Err(entry_error(err)),
+            None => return Poll::Ready(None),
+        };
+        Poll::Ready(Some(entry))
+    }
+}
+
+fn entry_error(err: FsError) -> Errno {
+    match err {
+        FsError::Io { source, .. } => {
+            error!(err = %source);
+            source.into()
+        }
+        err => {
+            error!(err = %err);
+            EIO.into()
         }
     }
 }
 
 struct EncryptedFsFuse3 {
     fs: Arc<EncryptedFs>,
+    locks: LockManager,
 }
 
 impl EncryptedFsFuse3 {
     pub async fn new(
         data_dir: PathBuf,
-        password_provider: Box<dyn PasswordProvider>,
+        password_provider: Box<dyn KeyMaterialProvider>,
         cipher: Cipher,
         read_only: bool,
     ) -> FsResult<Self> {
+        let fs = EncryptedFs::new(data_dir, password_provider, cipher, read_only).await?;
+        if !read_only && fs.rekey_in_progress() {
+            // continue it in the background, the filesystem can be used meanwhile
+            let fs = fs.clone();
+            tokio::spawn(async move {
+                let res = fs
+                    .rekey(|progress| {
+                        info!(
+                            done = progress.done,
+                            total = progress.total,
+                            "re-encrypting with the new key"
+                        );
+                    })
+                    .await;
+                if let Err(err) = res {
+                    error!(err = %err, "rekey failed, it continues on the next mount");
+                }
+            });
+        }
         Ok(Self {
-            fs: EncryptedFs::new(data_dir, password_provider, cipher, read_only).await?,
+            fs,
+            locks: LockManager::default(),
         })
     }
 
@@ -148,6 +148,48 @@ impl EncryptedFsFuse3 {
         self.fs.clone()
     }
 
+    /// Attributes in the `user.` namespace follow the permissions of the inode, `trusted.` ones
+    /// are only for root and `security.` ones can be changed only by the owner or root, like on
+    /// Linux.
+    async fn check_xattr_access(
+        &self,
+        req: &Request,
+        inode: Inode,
+        name: &str,
+        access_mask: i32,
+    ) -> Result<()> {
+        if name.starts_with("trusted.") {
+            return if req.uid == 0 {
+                Ok(())
+            } else {
+                Err(EPERM.into())
+            };
+        }
+        // anyone can read `security.` attributes, the kernel does it when executing a file
+        let security = name.starts_with("security.") && access_mask == libc::W_OK;
+        if !security && !name.starts_with("user.") {
+            return Ok(());
+        }
+        let attr = self.get_fs().get_attr(inode).await.map_err(|err| {
+            error!(err = %err);
+            Errno::from(ENOENT)
+        })?;
+        if security {
+            return if req.uid == 0 || req.uid == attr.uid {
+                Ok(())
+            } else {
+                Err(EPERM.into())
+            };
+        }
+        if attr.kind == FileType::Symlink {
+            return Err(EPERM.into());
+        }
+        if !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
+            return Err(EACCES.into());
+        }
+        Ok(())
+    }
+
     #[allow(clippy::cast_possible_truncation)]
     const fn creation_mode(&self, mode: u32) -> u16 {
         (mode & !(libc::S_ISUID | libc::S_ISGID)) as u16
@@ -233,6 +275,31 @@ const fn creation_gid(parent: &FileAttr, gid: u32) -> u32 {
     gid
 }
 
+impl From<FileType> for fuse3::raw::prelude::FileType {
+    fn from(from: FileType) -> Self {
+        match from {
+            FileType::Directory => Self::Directory,
+            FileType::RegularFile => Self::RegularFile,
+            FileType::Symlink => Self::Symlink,
+        }
+    }
+}
+
+impl From<StatFs> for ReplyStatFs {
+    fn from(from: StatFs) -> Self {
+        Self {
+            blocks: from.blocks,
+            bfree: from.bfree,
+            bavail: from.bavail,
+            files: from.files,
+            ffree: from.ffree,
+            bsize: from.bsize,
+            namelen: from.namelen,
+            frsize: from.frsize,
+        }
+    }
+}
+
 impl From<FileAttr> for fuse3::raw::prelude::FileAttr {
     fn from(from: FileAttr) -> Self {
         Self {
@@ -242,11 +309,7 @@ impl From<FileAttr> for fuse3::raw::prelude::FileAttr {
             atime: from.atime.into(),
             mtime: from.mtime.into(),
             ctime: from.ctime.into(),
-            kind: if from.kind == FileType::Directory {
-                fuse3::raw::prelude::FileType::Directory
-            } else {
-                fuse3::raw::prelude::FileType::RegularFile
-            },
+            kind: from.kind.into(),
             perm: from.perm,
             nlink: from.nlink,
             uid: from.uid,
@@ -522,6 +585,137 @@ impl Filesystem for EncryptedFsFuse3 {
         })
     }
 
+    #[instrument(skip(self), err(level = Level::WARN))]
+    async fn readlink(&self, req: Request, inode: Inode) -> Result<ReplyData> {
+        trace!("");
+
+        match self.get_fs().read_link(inode).await {
+            Err(FsError::InvalidInodeType) => Err(libc::EINVAL.into()),
+            Err(err) => {
+                error!(err = %err);
+                Err(ENOENT.into())
+            }
+            Ok(target) => Ok(ReplyData {
+                data: Bytes::copy_from_slice(target.expose_secret().as_bytes()),
+            }),
+        }
+    }
+
+    #[instrument(skip(self, name, link), fields(name = %name.to_string_lossy()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn symlink(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        link: &OsStr,
+    ) -> Result<ReplyEntry> {
+        trace!("");
+
+        // we store the names and the targets as strings
+        let name_str = name.to_str().ok_or(libc::EINVAL)?;
+        let link_str = link.to_str().ok_or(libc::EINVAL)?;
+        let parent_attr = match self.get_fs().get_attr(parent).await {
+            Err(err) => {
+                error!(err = %err);
+                return Err(ENOENT.into());
+            }
+            Ok(parent_attr) => parent_attr,
+        };
+
+        if !check_access(
+            parent_attr.uid,
+            parent_attr.gid,
+            parent_attr.perm,
+            req.uid,
+            req.gid,
+            libc::W_OK,
+        ) {
+            return Err(EACCES.into());
+        }
+
+        let mut attr = symlink_attr();
+        attr.uid = req.uid;
+        attr.gid = creation_gid(&parent_attr, req.gid);
+
+        let attr = self
+            .get_fs()
+            .create_symlink(
+                parent,
+                &SecretString::from_str(name_str).unwrap(),
+                &SecretString::from_str(link_str).unwrap(),
+                attr,
+            )
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                match err {
+                    FsError::AlreadyExists => EEXIST,
+                    FsError::InvalidInput(_) => libc::EINVAL,
+                    _ => EIO,
+                }
+            })?;
+        Ok(ReplyEntry {
+            ttl: TTL,
+            attr: attr.into(),
+            generation: 0,
+        })
+    }
+
+    #[instrument(skip(self, new_name), fields(new_name = new_name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn link(
+        &self,
+        req: Request,
+        inode: Inode,
+        new_parent: Inode,
+        new_name: &OsStr,
+    ) -> Result<ReplyEntry> {
+        trace!("");
+
+        let new_parent_attr = match self.get_fs().get_attr(new_parent).await {
+            Err(err) => {
+                error!(err = %err);
+                return Err(ENOENT.into());
+            }
+            Ok(attr) => attr,
+        };
+
+        if !check_access(
+            new_parent_attr.uid,
+            new_parent_attr.gid,
+            new_parent_attr.perm,
+            req.uid,
+            req.gid,
+            libc::W_OK,
+        ) {
+            return Err(EACCES.into());
+        }
+
+        let attr = self
+            .get_fs()
+            .link(
+                inode,
+                new_parent,
+                &SecretString::from_str(new_name.to_str().unwrap()).unwrap(),
+            )
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                match err {
+                    FsError::AlreadyExists => EEXIST,
+                    FsError::InodeNotFound => ENOENT,
+                    FsError::InvalidInodeType => libc::EPERM,
+                    FsError::InvalidInput(_) => libc::EINVAL,
+                    FsError::ReadOnly => libc::EROFS,
+                    _ => EIO,
+                }
+            })?;
+        Ok(ReplyEntry {
+            ttl: TTL,
+            attr: attr.into(),
+            generation: 0,
+        })
+    }
+
     #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn mknod(
         &self,
@@ -982,8 +1176,14 @@ impl Filesystem for EncryptedFsFuse3 {
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn statfs(&self, req: Request, inode: u64) -> Result<ReplyStatFs> {
         trace!("");
-        warn!("implementation is a stub");
-        Ok(STATFS)
+
+        match self.get_fs().statfs() {
+            Err(err) => {
+                error!(err = %err);
+                Err(EIO.into())
+            }
+            Ok(stat) => Ok(stat.into()),
+        }
     }
 
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
@@ -998,6 +1198,7 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<()> {
         trace!("");
 
+        self.locks.unlock_all(inode, lock_owner);
         let fs = self.get_fs();
 
         if flush {
@@ -1037,6 +1238,9 @@ impl Filesystem for EncryptedFsFuse3 {
     async fn flush(&self, req: Request, inode: Inode, fh: u64, lock_owner: u64) -> Result<()> {
         trace!("");
 
+        // on close, POSIX locks of the process are released
+        self.locks.unlock_all(inode, lock_owner);
+
         if let Err(err) = self.get_fs().flush(fh).await {
             error!(err = %err, fh);
             return Err(EIO.into());
@@ -1045,6 +1249,22 @@ impl Filesystem for EncryptedFsFuse3 {
         Ok(())
     }
 
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn fsync(&self, req: Request, inode: Inode, fh: u64, datasync: bool) -> Result<()> {
+        trace!("");
+
+        self.get_fs()
+            .fsync(fh, datasync)
+            .await
+            .map_err(|err| match err {
+                FsError::InvalidFileHandle => EBADF.into(),
+                _ => {
+                    error!(err = %err, fh);
+                    EIO.into()
+                }
+            })
+    }
+
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     #[allow(clippy::cast_possible_wrap)]
     async fn opendir(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
@@ -1074,18 +1294,23 @@ impl Filesystem for EncryptedFsFuse3 {
             Ok(attr) => attr,
         };
 
-        if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
-            Ok(ReplyOpen {
-                fh: 0, // we don't use handles for directories
-                flags: 0,
-            })
-        } else {
+        if !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
             return Err(EACCES.into());
         }
+        // it keeps the listing, so the next batches continue from there
+        let fh = match self.get_fs().open_dir(inode).await {
+            Err(FsError::InvalidInodeType) => return Err(libc::ENOTDIR.into()),
+            Err(err) => {
+                error!(err = %err);
+                return Err(EIO.into());
+            }
+            Ok(fh) => fh,
+        };
+        Ok(ReplyOpen { fh, flags: 0 })
     }
 
     type DirEntryStream<'a>
-        = Iter<Skip<DirectoryEntryIterator>>
+        = DirectoryEntryStream
     where
         Self: 'a;
 
@@ -1099,20 +1324,22 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<ReplyDirectory<Self::DirEntryStream<'_>>> {
         trace!("");
 
+        // the offset is the one of the last entry we returned
         #[allow(clippy::cast_sign_loss)]
-        let iter = match self.get_fs().read_dir(inode).await {
+        let entries = match self
+            .get_fs()
+            .read_dir_stream(inode, offset as u64, fh)
+            .await
+        {
             Err(err) => {
                 error!(err = %err);
                 return Err(EIO.into());
             }
-            Ok(iter) => iter,
+            Ok(entries) => entries,
         };
-        let iter = DirectoryEntryIterator(iter, 0);
 
         Ok(ReplyDirectory {
-            #[allow(clippy::cast_possible_truncation)]
-            #[allow(clippy::cast_sign_loss)]
-            entries: stream::iter(iter.skip(offset as usize)),
+            entries: DirectoryEntryStream(entries),
         })
     }
 
@@ -1120,6 +1347,116 @@ impl Filesystem for EncryptedFsFuse3 {
     async fn releasedir(&self, req: Request, inode: Inode, fh: u64, flags: u32) -> Result<()> {
         trace!("");
 
+        self.get_fs().release_dir(fh).await.map_err(|err| {
+            error!(err = %err);
+            EBADF.into()
+        })
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn fsyncdir(&self, req: Request, inode: Inode, fh: u64, datasync: bool) -> Result<()> {
+        trace!("");
+
+        self.get_fs()
+            .fsyncdir(inode)
+            .await
+            .map_err(|err| match err {
+                FsError::InodeNotFound => ENOENT.into(),
+                FsError::InvalidInodeType => ENOTDIR.into(),
+                _ => {
+                    error!(err = %err);
+                    EIO.into()
+                }
+            })
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    #[allow(clippy::cast_possible_wrap)]
+    async fn getlk(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        pid: u32,
+    ) -> Result<ReplyLock> {
+        trace!("");
+
+        let kind = lock_kind(r#type as i32)?.unwrap_or(LockKind::Read);
+        let lock = Lock {
+            owner: lock_owner,
+            start,
+            end,
+            kind,
+            pid,
+        };
+        Ok(match self.locks.get(inode, &lock) {
+            Some(conflict) => ReplyLock {
+                start: conflict.start,
+                end: conflict.end,
+                r#type: match conflict.kind {
+                    LockKind::Read => F_RDLCK as u32,
+                    LockKind::Write => F_WRLCK as u32,
+                },
+                pid: conflict.pid,
+            },
+            None => ReplyLock {
+                start,
+                end,
+                r#type: F_UNLCK as u32,
+                pid: 0,
+            },
+        })
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    #[allow(clippy::cast_possible_wrap)]
+    async fn setlk(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        pid: u32,
+        block: bool,
+    ) -> Result<()> {
+        trace!("");
+
+        let Some(kind) = lock_kind(r#type as i32)? else {
+            self.locks.unlock(inode, lock_owner, start, end);
+            return Ok(());
+        };
+        let lock = Lock {
+            owner: lock_owner,
+            start,
+            end,
+            kind,
+            pid,
+        };
+        if block {
+            // if the process gets a signal it gives up, we don't grant it after
+            if self.locks.lock_interruptible(inode, lock, req.unique).await {
+                Ok(())
+            } else {
+                Err(EINTR.into())
+            }
+        } else {
+            self.locks.try_lock(inode, lock).map_err(|_| EAGAIN.into())
+        }
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn interrupt(&self, req: Request, unique: u64) -> Result<()> {
+        trace!("");
+
+        // only waiting for a lock can be interrupted, the other requests finish anyway
+        self.locks.interrupt(unique);
         Ok(())
     }
 
@@ -1179,7 +1516,7 @@ impl Filesystem for EncryptedFsFuse3 {
     }
 
     type DirEntryPlusStream<'a>
-        = Iter<Skip<DirectoryEntryPlusIterator>>
+        = DirectoryEntryPlusStream
     where
         Self: 'a;
 
@@ -1194,22 +1531,90 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<ReplyDirectoryPlus<Self::DirEntryPlusStream<'_>>> {
         trace!("");
 
-        #[allow(clippy::cast_sign_loss)]
-        let iter = match self.get_fs().read_dir_plus(parent).await {
+        let entries = match self.get_fs().read_dir_plus_stream(parent, offset, fh).await {
             Err(err) => {
                 error!(err = %err);
                 return Err(EIO.into());
             }
-            Ok(iter) => iter,
+            Ok(entries) => entries,
         };
-        let iter = DirectoryEntryPlusIterator(iter, 0);
 
         Ok(ReplyDirectoryPlus {
-            #[allow(clippy::cast_possible_truncation)]
-            entries: stream::iter(iter.skip(offset as usize)),
+            entries: DirectoryEntryPlusStream(entries),
         })
     }
 
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    #[allow(clippy::cast_possible_wrap)]
+    async fn lseek(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        whence: u32,
+    ) -> Result<ReplyLSeek> {
+        trace!("");
+
+        // the kernel handles the other ones
+        let res = match whence as i32 {
+            SEEK_DATA => self.get_fs().seek_data(inode, offset).await,
+            SEEK_HOLE => self.get_fs().seek_hole(inode, offset).await,
+            _ => return Err(EINVAL.into()),
+        };
+        match res {
+            Ok(Some(offset)) => Ok(ReplyLSeek { offset }),
+            Ok(None) => Err(ENXIO.into()),
+            Err(FsError::InodeNotFound) => Err(ENOENT.into()),
+            Err(FsError::InvalidInodeType) => Err(EINVAL.into()),
+            Err(err) => {
+                error!(err = %err);
+                Err(EIO.into())
+            }
+        }
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn fallocate(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        length: u64,
+        mode: u32,
+    ) -> Result<()> {
+        trace!("");
+
+        let mode = mode as i32;
+        let keep_size = mode & FALLOC_FL_KEEP_SIZE != 0;
+        let mode = match mode & !FALLOC_FL_KEEP_SIZE {
+            0 => FallocateMode::Allocate { keep_size },
+            // punch hole needs keep size
+            FALLOC_FL_PUNCH_HOLE if keep_size => FallocateMode::PunchHole,
+            FALLOC_FL_PUNCH_HOLE => return Err(EINVAL.into()),
+            FALLOC_FL_ZERO_RANGE => FallocateMode::ZeroRange { keep_size },
+            _ => return Err(EOPNOTSUPP.into()),
+        };
+        self.get_fs()
+            .fallocate(inode, offset, length, mode)
+            .await
+            .map_err(|err| match err {
+                FsError::InodeNotFound => ENOENT.into(),
+                FsError::InvalidInodeType => ENODEV.into(),
+                FsError::InvalidInput(_) => EINVAL.into(),
+                FsError::MaxFilesizeExceeded(_) => EFBIG.into(),
+                FsError::ReadOnly => EROFS.into(),
+                FsError::Io { source, .. } if source.raw_os_error() == Some(ENOSPC) => {
+                    ENOSPC.into()
+                }
+                err => {
+                    error!(err = %err);
+                    EIO.into()
+                }
+            })
+    }
+
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn copy_file_range(
         &self,
@@ -1245,6 +1650,147 @@ impl Filesystem for EncryptedFsFuse3 {
             Ok(len) => Ok(ReplyCopyFileRange { copied: len as u64 }),
         }
     }
+
+    #[instrument(skip(self, name, value), fields(name = %name.to_string_lossy(), len = value.len()), err(level = Level::WARN))]
+    async fn setxattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        position: u32,
+    ) -> Result<()> {
+        trace!("");
+
+        let name_str = name.to_str().ok_or(libc::EINVAL)?;
+        if name_str.len() > XATTR_NAME_MAX {
+            return Err(libc::ERANGE.into());
+        }
+        if value.len() > XATTR_VALUE_MAX {
+            return Err(libc::E2BIG.into());
+        }
+        #[allow(clippy::cast_possible_wrap)]
+        let mode = match flags as i32 {
+            0 => SetXattrMode::Upsert,
+            libc::XATTR_CREATE => SetXattrMode::Create,
+            libc::XATTR_REPLACE => SetXattrMode::Replace,
+            _ => return Err(libc::EINVAL.into()),
+        };
+        self.check_xattr_access(&req, inode, name_str, libc::W_OK)
+            .await?;
+
+        self.get_fs()
+            .set_xattr(
+                inode,
+                &SecretString::from_str(name_str).unwrap(),
+                value,
+                mode,
+            )
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                xattr_errno(&err).into()
+            })
+    }
+
+    #[instrument(skip(self, name), fields(name = %name.to_string_lossy()), err(level = Level::DEBUG))]
+    async fn getxattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        name: &OsStr,
+        size: u32,
+    ) -> Result<ReplyXAttr> {
+        trace!("");
+
+        let name_str = name.to_str().ok_or(libc::EINVAL)?;
+        self.check_xattr_access(&req, inode, name_str, libc::R_OK)
+            .await?;
+
+        let value = self
+            .get_fs()
+            .get_xattr(inode, &SecretString::from_str(name_str).unwrap())
+            .await
+            .map_err(|err| {
+                if !matches!(err, FsError::XattrNotFound) {
+                    error!(err = %err);
+                }
+                xattr_errno(&err)
+            })?;
+        xattr_reply(&value.expose_secret(), size)
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN))]
+    async fn listxattr(&self, req: Request, inode: Inode, size: u32) -> Result<ReplyXAttr> {
+        trace!("");
+
+        let names = self.get_fs().list_xattrs(inode).await.map_err(|err| {
+            error!(err = %err);
+            xattr_errno(&err)
+        })?;
+        // names are null-terminated and concatenated
+        let mut data = vec![];
+        // `trusted.` attributes are visible only to root
+        for name in names
+            .into_iter()
+            .filter(|name| req.uid == 0 || !name.expose_secret().starts_with("trusted."))
+        {
+            data.extend_from_slice(name.expose_secret().as_bytes());
+            data.push(0);
+        }
+        xattr_reply(&data, size)
+    }
+
+    #[instrument(skip(self, name), fields(name = %name.to_string_lossy()), err(level = Level::WARN))]
+    async fn removexattr(&self, req: Request, inode: Inode, name: &OsStr) -> Result<()> {
+        trace!("");
+
+        let name_str = name.to_str().ok_or(libc::EINVAL)?;
+        self.check_xattr_access(&req, inode, name_str, libc::W_OK)
+            .await?;
+
+        self.get_fs()
+            .remove_xattr(inode, &SecretString::from_str(name_str).unwrap())
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                xattr_errno(&err).into()
+            })
+    }
+}
+
+/// `None` for `F_UNLCK`.
+fn lock_kind(r#type: i32) -> Result<Option<LockKind>> {
+    match r#type {
+        F_RDLCK => Ok(Some(LockKind::Read)),
+        F_WRLCK => Ok(Some(LockKind::Write)),
+        F_UNLCK => Ok(None),
+        _ => Err(EINVAL.into()),
+    }
+}
+
+fn xattr_errno(err: &FsError) -> c_int {
+    match err {
+        FsError::XattrNotFound => libc::ENODATA,
+        FsError::AlreadyExists => EEXIST,
+        FsError::InodeNotFound => ENOENT,
+        FsError::InvalidInput(_) => libc::EINVAL,
+        FsError::ReadOnly => libc::EROFS,
+        _ => EIO,
+    }
+}
+
+/// If `size` is 0 the caller only asks for the size of the value.
+#[allow(clippy::cast_possible_truncation)]
+fn xattr_reply(data: &[u8], size: u32) -> Result<ReplyXAttr> {
+    if size == 0 {
+        Ok(ReplyXAttr::Size(data.len() as u32))
+    } else if data.len() > size as usize {
+        Err(libc::ERANGE.into())
+    } else {
+        Ok(ReplyXAttr::Data(Bytes::copy_from_slice(data)))
+    }
 }
 
 fn get_groups(pid: u32) -> Vec<u32> {
@@ -1282,8 +1828,8 @@ fn as_file_kind(mut mode: u32) -> FileType {
 
     if mode == libc::S_IFREG {
         FileType::RegularFile
-        // } else if mode == libc::S_IFLNK as u32 {
-        //     return FileType::Symlink;
+    } else if mode == libc::S_IFLNK {
+        FileType::Symlink
     } else if mode == libc::S_IFDIR {
         FileType::Directory
     } else {
@@ -1313,6 +1859,17 @@ const fn file_attr() -> CreateFileAttr {
     }
 }
 
+const fn symlink_attr() -> CreateFileAttr {
+    CreateFileAttr {
+        kind: FileType::Symlink,
+        perm: 0o777,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+    }
+}
+
 fn check_access(
     #[allow(clippy::similar_names)] file_uid: u32,
     #[allow(clippy::similar_names)] file_gid: u32,
@@ -1357,7 +1914,7 @@ fn system_time_from_timestamp(t: Timestamp) -> SystemTime {
 pub struct MountPointImpl {
     mountpoint: PathBuf,
     data_dir: PathBuf,
-    password_provider: Option<Box<dyn PasswordProvider>>,
+    password_provider: Option<Box<dyn KeyMaterialProvider>>,
     cipher: Cipher,
     allow_root: bool,
     allow_other: bool,
@@ -1369,7 +1926,7 @@ impl MountPoint for MountPointImpl {
     fn new(
         mountpoint: PathBuf,
         data_dir: PathBuf,
-        password_provider: Box<dyn PasswordProvider>,
+        password_provider: Box<dyn KeyMaterialProvider>,
         cipher: Cipher,
         allow_root: bool,
         allow_other: bool,
@@ -1426,7 +1983,7 @@ impl MountHandleInner for MountHandleInnerImpl {
 async fn mount_fuse(
     mountpoint: PathBuf,
     data_dir: PathBuf,
-    password_provider: Box<dyn PasswordProvider>,
+    password_provider: Box<dyn KeyMaterialProvider>,
     cipher: Cipher,
     allow_root: bool,
     allow_other: bool,
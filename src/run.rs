@@ -1,7 +1,9 @@
+use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{env, io, panic, process};
 
 use anyhow::Result;
@@ -12,14 +14,17 @@ use shush_rs::{ExposeSecret, SecretString};
 use strum::IntoEnumIterator;
 use thiserror::Error;
 use tokio::sync::Mutex;
-use tokio::{fs, task};
+use tokio::task;
 use tracing::{error, info, warn, Level};
 
 use crate::keyring;
-use rencfs::crypto::Cipher;
-use rencfs::encryptedfs::{EncryptedFs, FsError, PasswordProvider};
+use rencfs::crypto::{Cipher, KdfParams};
+use rencfs::encryptedfs::{
+    EncryptedFs, FsError, InMemoryPasswordProvider, KeyMaterialProvider, KeyfileProvider,
+    PasswordKeyfileProvider, PasswordProvider,
+};
 use rencfs::mount::MountPoint;
-use rencfs::{log, mount};
+use rencfs::{fs_util, log, mount};
 
 static mut PASS: Option<SecretString> = None;
 
@@ -117,7 +122,7 @@ fn get_cli_args() -> ArgMatches {
                 .value_name("cipher")
                 .default_value("ChaCha20Poly1305")
                 .global(true)
-                .help(format!("Cipher used for encryption, possible values: {}",
+                .help(format!("Cipher used for encryption when creating a new data dir, existing ones use the cipher they were created with, possible values: {}",
                               Cipher::iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ")),
                 )
         )
@@ -179,6 +184,32 @@ fn get_cli_args() -> ArgMatches {
                         .requires("data-dir")
                         .help("Set FUSE filesystem read-only mount option, default is disabled.")
                 )
+                .arg(
+                    Arg::new("create")
+                        .long("create")
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Create a new filesystem if data dir doesn't have one, like `init` with default key derivation params")
+                )
+                .arg(
+                    Arg::new("rekey")
+                        .long("rekey")
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .conflicts_with_all(["read-only", "create"])
+                        .help("Replace the master key with a new one and re-encrypt the data with it in the background, like `rekey` but while mounted")
+                )
+                .arg(
+                    Arg::new("drop-other-slots")
+                        .long("drop-other-slots")
+                        .action(ArgAction::SetTrue)
+                        .requires("rekey")
+                        .help("With --rekey, remove the key slots of the other passwords, they cannot unlock the new key")
+                )
+                .args(keyfile_args())
+                .args(password_args())
         ).subcommand(
         Command::new("passwd")
             .about("Change password for the master key used to encrypt the data")
@@ -190,10 +221,171 @@ fn get_cli_args() -> ArgMatches {
                     .value_name("DATA_DIR")
                     .help("Where to store the encrypted data"),
             )
+            .args(kdf_args())
+            .args(keyfile_args())
+            .args(password_args())
+            .args(new_key_args())
+    ).subcommand(
+        Command::new("init")
+            .about("Create a new filesystem in data dir, which needs to be missing or empty")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where to store the encrypted data"),
+            )
+            .args(kdf_args())
+            .args(keyfile_args())
+            .args(password_args())
+    ).subcommand(
+        Command::new("keyslot")
+            .about("Manage the key slots, each of them has a password which can unlock the data")
+            .subcommand_required(true)
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .subcommand(
+                Command::new("add")
+                    .about("Add a key slot with a new password")
+                    .args(kdf_args())
+                    .args(keyfile_args())
+                    .args(password_args())
+                    .args(new_key_args()),
+            )
+            .subcommand(
+                Command::new("remove")
+                    .about("Remove a key slot, its password will not unlock the data anymore")
+                    .arg(
+                        Arg::new("slot")
+                            .long("slot")
+                            .required(true)
+                            .value_name("ID")
+                            .value_parser(clap::value_parser!(u32))
+                            .help("Id of the slot, as shown by `keyslot list`"),
+                    )
+                    .args(keyfile_args())
+                    .args(password_args()),
+            )
+            .subcommand(Command::new("list").about("List the key slots")),
+    ).subcommand(
+        Command::new("fsck")
+            .about("Check the data dir for problems, the filesystem must not be mounted")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .arg(
+                Arg::new("repair")
+                    .long("repair")
+                    .action(ArgAction::SetTrue)
+                    .help("Rebuild the name hashes from the directory listings, fix link counts and sizes and move orphaned files to lost+found"),
+            )
+            .args(keyfile_args())
+            .args(password_args())
+    ).subcommand(
+        Command::new("rekey")
+            .about("Replace the master key with a new one and re-encrypt all the data with it, the filesystem must not be mounted, if interrupted run it again to continue")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .arg(
+                Arg::new("drop-other-slots")
+                    .long("drop-other-slots")
+                    .action(ArgAction::SetTrue)
+                    .help("Remove the key slots of the other passwords, they cannot unlock the new key, add them again after with `keyslot add`"),
+            )
+            .args(keyfile_args())
+            .args(password_args())
+    ).subcommand(
+        Command::new("export")
+            .about("Write the whole tree to an encrypted archive, which can be imported in another data dir, the filesystem must not be mounted")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .arg(
+                Arg::new("out")
+                    .long("out")
+                    .short('o')
+                    .required(true)
+                    .value_name("FILE")
+                    .help("Where to write the archive, it's encrypted with the same password and keyfile"),
+            )
+            .args(kdf_args())
+            .args(keyfile_args())
+            .args(password_args())
+    ).subcommand(
+        Command::new("import")
+            .about("Recreate the tree from an archive made with `export`, existing files are replaced, the filesystem must not be mounted")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored, a new filesystem is created if it doesn't have one"),
+            )
+            .arg(
+                Arg::new("archive")
+                    .long("archive")
+                    .short('a')
+                    .required(true)
+                    .value_name("FILE")
+                    .help("Archive to import, the password and keyfile need to be the ones it was exported with"),
+            )
+            .args(kdf_args())
+            .args(keyfile_args())
+            .args(password_args())
     )
         .get_matches()
 }
 
+fn kdf_args() -> [Arg; 4] {
+    [
+        Arg::new("kdf-time")
+            .long("kdf-time")
+            .value_name("MILLIS")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .conflicts_with_all(["kdf-memory", "kdf-iterations", "kdf-parallelism"])
+            .help("Calibrate the key derivation so unlocking takes about this many milliseconds on this machine"),
+        Arg::new("kdf-memory")
+            .long("kdf-memory")
+            .value_name("KIB")
+            .value_parser(clap::value_parser!(u32))
+            .help("Memory used by the key derivation, in KiB"),
+        Arg::new("kdf-iterations")
+            .long("kdf-iterations")
+            .value_name("ITERATIONS")
+            .value_parser(clap::value_parser!(u32))
+            .help("Number of iterations of the key derivation"),
+        Arg::new("kdf-parallelism")
+            .long("kdf-parallelism")
+            .value_name("THREADS")
+            .value_parser(clap::value_parser!(u32))
+            .help("Degree of parallelism of the key derivation"),
+    ]
+}
+
 async fn async_main() -> Result<()> {
     let matches = get_cli_args();
 
@@ -206,7 +398,13 @@ async fn async_main() -> Result<()> {
     let cipher = cipher.unwrap();
 
     match matches.subcommand() {
-        Some(("change-password", matches)) => run_change_password(cipher, matches).await?,
+        Some(("passwd", matches)) => run_change_password(cipher, matches).await?,
+        Some(("init", matches)) => run_init(cipher, matches).await?,
+        Some(("keyslot", matches)) => run_keyslot(cipher, matches).await?,
+        Some(("fsck", matches)) => run_fsck(cipher, matches).await?,
+        Some(("rekey", matches)) => run_rekey(cipher, matches).await?,
+        Some(("export", matches)) => run_export(cipher, matches).await?,
+        Some(("import", matches)) => run_import(cipher, matches).await?,
         Some(("mount", matches)) => run_mount(cipher, matches).await?,
         None => {
             error!("No subcommand provided");
@@ -224,22 +422,17 @@ async fn async_main() -> Result<()> {
 async fn run_change_password(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
     let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
 
-    // read password from stdin
-    print!("Enter old password: ");
-    io::stdout().flush().unwrap();
-    let password = SecretString::new(Box::new(read_password()?));
-    print!("Enter new password: ");
-    io::stdout().flush().unwrap();
-    let new_password = SecretString::new(Box::new(read_password()?));
-    print!("Confirm new password: ");
-    io::stdout().flush().unwrap();
-    let new_password2 = SecretString::new(Box::new(read_password()?));
-    if new_password.expose_secret() != new_password2.expose_secret() {
-        println!("Passwords do not match");
+    let Some(password) =
+        key_material_from_args(matches, "Enter old password: ", false)?.get_key_material()
+    else {
         return Err(ExitStatusError::Failure(1).into());
-    }
+    };
+    let Some(new_password) = new_key_material_from_args(matches)?.get_key_material() else {
+        return Err(ExitStatusError::Failure(1).into());
+    };
+    let kdf = kdf_params_from_args(matches)?;
     println!("Changing password...");
-    EncryptedFs::passwd(Path::new(&data_dir), password, new_password, cipher)
+    EncryptedFs::passwd_with_kdf(Path::new(&data_dir), password, new_password, cipher, kdf)
         .await
         .map_err(|err| {
             match err {
@@ -260,56 +453,529 @@ async fn run_change_password(cipher: Cipher, matches: &ArgMatches) -> Result<()>
     Ok(())
 }
 
-async fn run_mount(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
-    let mountpoint: String = matches
-        .get_one::<String>("mount-point")
-        .unwrap()
-        .to_string();
-
+async fn run_init(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
     let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
 
-    // when running from IDE we can't read from stdin with rpassword, get it from env var
-    let mut password = SecretString::from_str(
-        env::var("RENCFS_PASSWORD")
-            .unwrap_or_else(|_| String::new())
-            .as_str(),
-    )
-    .unwrap();
-    if password.expose_secret().is_empty() {
-        // read password from stdin
-        print!("Enter password: ");
-        io::stdout().flush().unwrap();
-        password = SecretString::new(Box::new(read_password()?));
+    let Some(password) =
+        key_material_from_args(matches, "Enter password: ", true)?.get_key_material()
+    else {
+        return Err(ExitStatusError::Failure(1).into());
+    };
+    let kdf = kdf_params_from_args(matches)?.unwrap_or_default();
+    println!("Creating filesystem...");
+    EncryptedFs::init(Path::new(&data_dir), password, cipher, kdf)
+        .await
+        .map_err(|err| {
+            match err {
+                FsError::DataDirAlreadyInitialized => {
+                    println!("Data dir already has a filesystem");
+                }
+                FsError::NotEmpty => {
+                    println!("Data dir is not empty");
+                }
+                _ => {
+                    error!(err = %err);
+                }
+            }
+            ExitStatusError::Failure(1)
+        })?;
+    println!("Filesystem created successfully");
+
+    Ok(())
+}
 
-        if !PathBuf::new().join(data_dir.clone()).is_dir()
-            || fs::read_dir(&data_dir)
+async fn run_keyslot(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let data_dir = Path::new(&data_dir);
+
+    let res = match matches.subcommand() {
+        Some(("list", _)) => EncryptedFs::list_key_slots(data_dir).map(|slots| {
+            for slot in slots {
+                println!(
+                    "{}: {} KiB memory, {} iterations, {} parallelism",
+                    slot.id, slot.kdf.m_cost, slot.kdf.t_cost, slot.kdf.p_cost
+                );
+            }
+        }),
+        Some(("add", matches)) => {
+            let Some(password) =
+                key_material_from_args(matches, "Enter existing password: ", false)?
+                    .get_key_material()
+            else {
+                return Err(ExitStatusError::Failure(1).into());
+            };
+            let Some(new_password) = new_key_material_from_args(matches)?.get_key_material() else {
+                return Err(ExitStatusError::Failure(1).into());
+            };
+            let kdf = kdf_params_from_args(matches)?.unwrap_or_default();
+            EncryptedFs::add_key_slot(data_dir, password, new_password, cipher, kdf)
                 .await
-                .unwrap()
-                .next_entry()
+                .map(|id| println!("Added key slot {id}"))
+        }
+        Some(("remove", matches)) => {
+            let id = *matches.get_one::<u32>("slot").unwrap();
+            let Some(password) =
+                key_material_from_args(matches, "Enter password: ", false)?.get_key_material()
+            else {
+                return Err(ExitStatusError::Failure(1).into());
+            };
+            EncryptedFs::remove_key_slot(data_dir, password, id, cipher)
                 .await
-                .unwrap()
-                .is_none()
-        {
-            // first run, ask to confirm password
-            print!("Confirm password: ");
+                .map(|()| println!("Removed key slot {id}"))
+        }
+        _ => {
+            error!("Invalid subcommand");
+            return Err(ExitStatusError::Failure(1).into());
+        }
+    };
+    res.map_err(|err| {
+        match err {
+            FsError::InvalidPassword => {
+                println!("Invalid password");
+            }
+            FsError::InvalidDataDirStructure => {
+                println!("Invalid structure of data directory");
+            }
+            _ => {
+                println!("{err}");
+            }
+        }
+        ExitStatusError::Failure(1)
+    })?;
+
+    Ok(())
+}
+
+async fn run_fsck(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    if !EncryptedFs::is_initialized(Path::new(&data_dir)) {
+        error!("No filesystem in {data_dir}");
+        return Err(ExitStatusError::Failure(1).into());
+    }
+    let repair = matches.get_flag("repair");
+
+    let key_material_provider = key_material_from_args(matches, "Enter password: ", false)?;
+    let report = async {
+        let fs = EncryptedFs::new(
+            PathBuf::from(&data_dir),
+            key_material_provider,
+            cipher,
+            !repair,
+        )
+        .await?;
+        fs.fsck(repair).await
+    }
+    .await
+    .map_err(|err| {
+        match err {
+            FsError::InvalidPassword => {
+                println!("Invalid password");
+            }
+            FsError::InvalidDataDirStructure => {
+                println!("Invalid structure of data directory");
+            }
+            _ => {
+                error!(err = %err);
+            }
+        }
+        ExitStatusError::Failure(1)
+    })?;
+
+    for problem in &report.problems {
+        println!("{problem}");
+    }
+    if report.is_clean() {
+        println!("No problems found");
+    } else if repair {
+        println!(
+            "Found {} problems, repaired {}",
+            report.problems.len(),
+            report.repaired
+        );
+        if report.repaired < report.problems.len() {
+            return Err(ExitStatusError::Failure(1).into());
+        }
+    } else {
+        println!(
+            "Found {} problems, run with --repair to fix them",
+            report.problems.len()
+        );
+        return Err(ExitStatusError::Failure(1).into());
+    }
+
+    Ok(())
+}
+
+async fn run_rekey(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    if !EncryptedFs::is_initialized(Path::new(&data_dir)) {
+        error!("No filesystem in {data_dir}");
+        return Err(ExitStatusError::Failure(1).into());
+    }
+
+    let Some(password) =
+        key_material_from_args(matches, "Enter password: ", false)?.get_key_material()
+    else {
+        return Err(ExitStatusError::Failure(1).into());
+    };
+    let drop_other_slots = matches.get_flag("drop-other-slots");
+    async {
+        EncryptedFs::start_rekey(
+            Path::new(&data_dir),
+            password.clone(),
+            cipher,
+            drop_other_slots,
+        )
+        .await?;
+        let fs = EncryptedFs::new(
+            PathBuf::from(&data_dir),
+            Box::new(InMemoryPasswordProvider::new(password)),
+            cipher,
+            false,
+        )
+        .await?;
+        fs.rekey(|progress| {
+            print!("\rRe-encrypted {}/{} inodes", progress.done, progress.total);
             io::stdout().flush().unwrap();
-            let confirm_password = SecretString::new(Box::new(read_password()?));
-            if password.expose_secret() != confirm_password.expose_secret() {
-                error!("Passwords do not match");
-                return Err(ExitStatusError::Failure(1).into());
+        })
+        .await
+    }
+    .await
+    .map_err(|err| {
+        println!();
+        match err {
+            FsError::InvalidPassword => {
+                println!("Invalid password");
+            }
+            FsError::InvalidDataDirStructure => {
+                println!("Invalid structure of data directory");
+            }
+            _ => {
+                error!(err = %err);
+            }
+        }
+        ExitStatusError::Failure(1)
+    })?;
+    println!();
+    println!("The data is encrypted with the new key");
+
+    Ok(())
+}
+
+async fn run_export(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    if !EncryptedFs::is_initialized(Path::new(&data_dir)) {
+        error!("No filesystem in {data_dir}");
+        return Err(ExitStatusError::Failure(1).into());
+    }
+    let out: String = matches.get_one::<String>("out").unwrap().to_string();
+
+    let Some(password) =
+        key_material_from_args(matches, "Enter password: ", false)?.get_key_material()
+    else {
+        return Err(ExitStatusError::Failure(1).into());
+    };
+    let kdf = kdf_params_from_args(matches)?.unwrap_or_default();
+    println!("Exporting...");
+    async {
+        let fs = EncryptedFs::new(
+            PathBuf::from(&data_dir),
+            Box::new(InMemoryPasswordProvider::new(password.clone())),
+            cipher,
+            true,
+        )
+        .await?;
+        let file = fs_util::open_atomic_write(Path::new(&out))?;
+        let file = fs.export_archive(file, &password, kdf).await?;
+        file.commit()?;
+        Ok(())
+    }
+    .await
+    .map_err(|err| {
+        match err {
+            FsError::InvalidPassword => {
+                println!("Invalid password");
+            }
+            FsError::InvalidDataDirStructure => {
+                println!("Invalid structure of data directory");
+            }
+            _ => {
+                error!(err = %err);
             }
         }
+        ExitStatusError::Failure(1)
+    })?;
+    println!("Exported to {out}");
+
+    Ok(())
+}
+
+async fn run_import(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let archive = File::open(matches.get_one::<String>("archive").unwrap())?;
+    let initialized = EncryptedFs::is_initialized(Path::new(&data_dir));
+
+    // when creating the filesystem it will be its password, so we confirm it
+    let Some(password) =
+        key_material_from_args(matches, "Enter password: ", !initialized)?.get_key_material()
+    else {
+        return Err(ExitStatusError::Failure(1).into());
+    };
+    let kdf = kdf_params_from_args(matches)?.unwrap_or_default();
+    let entries = async {
+        if !initialized {
+            println!("Creating filesystem...");
+            EncryptedFs::init(Path::new(&data_dir), password.clone(), cipher, kdf).await?;
+        }
+        let fs = EncryptedFs::new(
+            PathBuf::from(&data_dir),
+            Box::new(InMemoryPasswordProvider::new(password.clone())),
+            cipher,
+            false,
+        )
+        .await?;
+        println!("Importing...");
+        fs.import_archive(io::BufReader::new(archive), &password)
+            .await
+    }
+    .await
+    .map_err(|err| {
+        match err {
+            FsError::InvalidPassword => {
+                println!("Invalid password");
+            }
+            FsError::InvalidDataDirStructure => {
+                println!("Invalid structure of data directory");
+            }
+            _ => {
+                error!(err = %err);
+            }
+        }
+        ExitStatusError::Failure(1)
+    })?;
+    println!("Imported {entries} entries");
+
+    Ok(())
+}
+
+/// KDF params given in args, `None` if we should keep the current ones.
+fn kdf_params_from_args(matches: &ArgMatches) -> Result<Option<KdfParams>> {
+    if let Some(millis) = matches.get_one::<u64>("kdf-time") {
+        println!("Calibrating key derivation...");
+        let kdf = KdfParams::calibrate(Duration::from_millis(*millis))?;
+        println!(
+            "Using {} KiB memory, {} iterations, {} parallelism",
+            kdf.m_cost, kdf.t_cost, kdf.p_cost
+        );
+        return Ok(Some(kdf));
     }
-    // save password in keyring
-    info!("Save password in keyring");
-    let res = keyring::save(&password, "password").map_err(|err| {
-        warn!(err = %err);
+    let m_cost = matches.get_one::<u32>("kdf-memory");
+    let t_cost = matches.get_one::<u32>("kdf-iterations");
+    let p_cost = matches.get_one::<u32>("kdf-parallelism");
+    if m_cost.is_none() && t_cost.is_none() && p_cost.is_none() {
+        return Ok(None);
+    }
+    let default = KdfParams::default();
+    Ok(Some(KdfParams {
+        m_cost: m_cost.copied().unwrap_or(default.m_cost),
+        t_cost: t_cost.copied().unwrap_or(default.t_cost),
+        p_cost: p_cost.copied().unwrap_or(default.p_cost),
+    }))
+}
+
+/// Key material from the password and keyfile args, the password is prompted for if it's not
+/// given in args, with `confirm` it's asked twice.
+fn key_material_from_args(
+    matches: &ArgMatches,
+    prompt: &str,
+    confirm: bool,
+) -> Result<Box<dyn KeyMaterialProvider>> {
+    let password_provider = password_or_prompt(matches, prompt, confirm)?.map(|password| {
+        Box::new(InMemoryPasswordProvider::new(password)) as Box<dyn PasswordProvider>
     });
-    if res.is_err() {
-        // maybe we don't have a security manager, keep it in mem
-        unsafe {
-            warn!("Cannot save password in keyring, keep it in memory");
-            PASS = Some(password.clone());
+    let keyfile = matches.get_one::<String>("keyfile").map(PathBuf::from);
+    Ok(key_material_provider(password_provider, keyfile))
+}
+
+/// Password from the password args, prompted for if it's not given, `None` with `--no-password`.
+fn password_or_prompt(
+    matches: &ArgMatches,
+    prompt: &str,
+    confirm: bool,
+) -> Result<Option<SecretString>> {
+    if matches.get_flag("no-password") {
+        return Ok(None);
+    }
+    if let Some(password) = password_from_args(matches)? {
+        return Ok(Some(password));
+    }
+    Ok(Some(prompt_password(prompt, confirm)?))
+}
+
+/// Key material for a new password or key slot, from [`new_key_args`].
+fn new_key_material_from_args(matches: &ArgMatches) -> Result<Box<dyn KeyMaterialProvider>> {
+    let password_provider: Option<Box<dyn PasswordProvider>> =
+        if matches.get_flag("new-no-password") {
+            None
+        } else if let Some(path) = matches.get_one::<String>("new-password-file") {
+            Some(Box::new(InMemoryPasswordProvider::read_from(File::open(
+                path,
+            )?)?))
+        } else {
+            Some(Box::new(InMemoryPasswordProvider::new(prompt_password(
+                "Enter new password: ",
+                true,
+            )?)))
+        };
+    let keyfile = matches.get_one::<String>("new-keyfile").map(PathBuf::from);
+    Ok(key_material_provider(password_provider, keyfile))
+}
+
+/// Read the password from the terminal, with `confirm` it's asked twice and they need to match.
+fn prompt_password(prompt: &str, confirm: bool) -> Result<SecretString> {
+    print!("{prompt}");
+    io::stdout().flush().unwrap();
+    let password = SecretString::new(Box::new(read_password()?));
+    if confirm {
+        print!("Confirm password: ");
+        io::stdout().flush().unwrap();
+        let confirm_password = SecretString::new(Box::new(read_password()?));
+        if password.expose_secret() != confirm_password.expose_secret() {
+            println!("Passwords do not match");
+            return Err(ExitStatusError::Failure(1).into());
+        }
+    }
+    Ok(password)
+}
+
+/// Combine the password and the keyfile, at least one of them needs to be given.
+fn key_material_provider(
+    password_provider: Option<Box<dyn PasswordProvider>>,
+    keyfile: Option<PathBuf>,
+) -> Box<dyn KeyMaterialProvider> {
+    match (password_provider, keyfile) {
+        (Some(password_provider), Some(keyfile)) => {
+            Box::new(PasswordKeyfileProvider::new(password_provider, keyfile))
+        }
+        (None, Some(keyfile)) => Box::new(KeyfileProvider::new(keyfile)),
+        (Some(password_provider), None) => Box::new(password_provider),
+        (None, None) => unreachable!("--no-password requires --keyfile"),
+    }
+}
+
+/// Like [`keyfile_args`] and `--password-file`, for the new secret of `passwd` and `keyslot add`.
+fn new_key_args() -> [Arg; 3] {
+    [
+        Arg::new("new-password-file")
+            .long("new-password-file")
+            .value_name("FILE")
+            .help("Read the new password from this file, a trailing newline is removed"),
+        Arg::new("new-keyfile")
+            .long("new-keyfile")
+            .value_name("KEYFILE")
+            .help("Use the content of this file together with the new password to derive the key"),
+        Arg::new("new-no-password")
+            .long("new-no-password")
+            .action(ArgAction::SetTrue)
+            .requires("new-keyfile")
+            .conflicts_with("new-password-file")
+            .help("Derive the new key only from the new keyfile, without a password"),
+    ]
+}
+
+fn password_args() -> [Arg; 3] {
+    [
+        Arg::new("password-file")
+            .long("password-file")
+            .value_name("FILE")
+            .conflicts_with_all(["password-fd", "password-env"])
+            .help("Read the password from this file instead of prompting, a trailing newline is removed"),
+        Arg::new("password-fd")
+            .long("password-fd")
+            .value_name("FD")
+            .value_parser(clap::value_parser!(i32).range(0..))
+            .conflicts_with("password-env")
+            .help("Read the password from this file descriptor until EOF instead of prompting, a trailing newline is removed"),
+        Arg::new("password-env")
+            .long("password-env")
+            .value_name("VAR")
+            .help("Read the password from this environment variable instead of prompting"),
+    ]
+}
+
+/// Password given with `--password-file`, `--password-fd` or `--password-env`, `None` if we
+/// need to prompt for it.
+fn password_from_args(matches: &ArgMatches) -> Result<Option<SecretString>> {
+    let provider = if let Some(path) = matches.get_one::<String>("password-file") {
+        InMemoryPasswordProvider::read_from(File::open(path)?)?
+    } else if let Some(fd) = matches.get_one::<i32>("password-fd") {
+        let fd = *fd;
+        if fd <= 2 {
+            // we close it after reading
+            error!("--password-fd cannot be stdin, stdout or stderr, use --password-file /dev/stdin to read from stdin");
+            return Err(ExitStatusError::Failure(1).into());
+        }
+        // safety: it only checks the fd
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            error!("--password-fd {fd} is not an open file descriptor");
+            return Err(ExitStatusError::Failure(1).into());
+        }
+        // safety: the fd is open, the user gave it to us for this and we don't use it anywhere else
+        unsafe { InMemoryPasswordProvider::from_fd(fd)? }
+    } else if let Some(var) = matches.get_one::<String>("password-env") {
+        let Ok(password) = env::var(var) else {
+            error!("Environment variable {var} is not set");
+            return Err(ExitStatusError::Failure(1).into());
+        };
+        InMemoryPasswordProvider::new(SecretString::new(Box::new(password)))
+    } else {
+        return Ok(None);
+    };
+    Ok(provider.get_password())
+}
+
+fn keyfile_args() -> [Arg; 2] {
+    [
+        Arg::new("keyfile")
+            .long("keyfile")
+            .value_name("KEYFILE")
+            .help("Use the content of this file together with the password to derive the key, it can be any file"),
+        Arg::new("no-password")
+            .long("no-password")
+            .action(ArgAction::SetTrue)
+            .requires("keyfile")
+            .conflicts_with_all(["password-file", "password-fd", "password-env"])
+            .help("Derive the key only from the keyfile, without a password"),
+    ]
+}
+
+async fn run_mount(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let mountpoint: String = matches
+        .get_one::<String>("mount-point")
+        .unwrap()
+        .to_string();
+
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let create = !EncryptedFs::is_initialized(Path::new(&data_dir));
+    if create && !matches.get_flag("create") {
+        error!("No filesystem in {data_dir}, create it with `init` or mount with `--create`");
+        return Err(ExitStatusError::Failure(1).into());
+    }
+
+    let password = password_or_prompt(matches, "Enter password: ", create)?;
+    let no_password = password.is_none();
+    if let Some(password) = password {
+        // save password in keyring
+        info!("Save password in keyring");
+        let res = keyring::save(&password, "password").map_err(|err| {
+            warn!(err = %err);
+        });
+        if res.is_err() {
+            // maybe we don't have a security manager, keep it in mem
+            unsafe {
+                warn!("Cannot save password in keyring, keep it in memory");
+                PASS = Some(password);
+            }
         }
     }
 
@@ -341,10 +1007,46 @@ async fn run_mount(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
             }
         }
     }
+    let password_provider: Option<Box<dyn PasswordProvider>> = if no_password {
+        None
+    } else {
+        Some(Box::new(PasswordProviderImpl {}))
+    };
+    let keyfile = matches.get_one::<String>("keyfile").map(PathBuf::from);
+    let key_material_provider = key_material_provider(password_provider, keyfile);
+    if create {
+        let Some(password) = key_material_provider.get_key_material() else {
+            return Err(ExitStatusError::Failure(1).into());
+        };
+        println!("Creating filesystem...");
+        EncryptedFs::init(Path::new(&data_dir), password, cipher, KdfParams::default())
+            .await
+            .map_err(|err| {
+                error!(err = %err, "cannot create filesystem");
+                ExitStatusError::Failure(1)
+            })?;
+    }
+    if matches.get_flag("rekey") {
+        // the data is re-encrypted in the background after it's mounted
+        let Some(password) = key_material_provider.get_key_material() else {
+            return Err(ExitStatusError::Failure(1).into());
+        };
+        EncryptedFs::start_rekey(
+            Path::new(&data_dir),
+            password,
+            cipher,
+            matches.get_flag("drop-other-slots"),
+        )
+        .await
+        .map_err(|err| {
+            error!(err = %err, "cannot start rekey");
+            ExitStatusError::Failure(1)
+        })?;
+    }
     let mount_point = mount::create_mount_point(
         Path::new(&mountpoint),
         Path::new(&data_dir),
-        Box::new(PasswordProviderImpl {}),
+        key_material_provider,
         cipher,
         matches.get_flag("allow-root"),
         matches.get_flag("allow-other"),
@@ -361,7 +1063,9 @@ async fn run_mount(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
         // can't use tracing methods here as guard cannot be dropper to flush content before we exit
         eprintln!("Received signal to exit");
         let mut status: Option<ExitStatusError> = None;
-        remove_pass();
+        if !no_password {
+            remove_pass();
+        }
         eprintln!("Unmounting {mountpoint}");
         // create new tokio runtime
         let rt = tokio::runtime::Builder::new_current_thread()
@@ -10,7 +10,7 @@ use tempfile::NamedTempFile;
 use thread_local::ThreadLocal;
 use tokio::sync::Mutex;
 
-use crate::crypto::Cipher;
+use crate::crypto::{Cipher, KdfParams};
 use crate::encryptedfs::{
     CopyFileRangeReq, CreateFileAttr, EncryptedFs, FileType, PasswordProvider,
 };
@@ -80,6 +80,7 @@ async fn setup(setup: TestSetup) -> SetupResult {
     let _ = fs::remove_dir_all(data_dir_str);
     let _ = fs::create_dir_all(data_dir_str);
 
+    init_fs(Path::new(data_dir_str)).await;
     let fs = EncryptedFs::new(
         Path::new(data_dir_str).to_path_buf(),
         Box::new(PasswordProviderImpl {}),
@@ -95,6 +96,19 @@ async fn setup(setup: TestSetup) -> SetupResult {
     }
 }
 
+/// Create a filesystem in `data_dir` with the password of [`PasswordProviderImpl`].
+#[allow(dead_code)]
+pub async fn init_fs(data_dir: &Path) {
+    EncryptedFs::init(
+        data_dir,
+        SecretString::from_str("password").unwrap(),
+        Cipher::ChaCha20Poly1305,
+        KdfParams::default(),
+    )
+    .await
+    .unwrap();
+}
+
 #[allow(dead_code)]
 async fn teardown() -> Result<(), io::Error> {
     let s = SETUP_RESULT.get_or(|| Mutex::new(None));
@@ -219,3 +233,12 @@ pub async fn get_fs() -> Arc<EncryptedFs> {
     let mut fs = fs.lock().await;
     fs.as_mut().unwrap().fs.as_ref().unwrap().clone()
 }
+
+/// Like [`get_fs`], but we don't keep it, so the data dir can be opened again in read-write mode
+/// after it's dropped.
+#[allow(dead_code)]
+pub async fn take_fs() -> Arc<EncryptedFs> {
+    let fs = SETUP_RESULT.get_or(|| Mutex::new(None));
+    let mut fs = fs.lock().await;
+    fs.as_mut().unwrap().fs.take().unwrap()
+}
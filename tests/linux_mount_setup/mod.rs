@@ -7,8 +7,8 @@ use std::sync::{Arc, Mutex, Once};
 use std::thread::sleep;
 use std::time::Duration;
 
-use rencfs::crypto::Cipher;
-use rencfs::encryptedfs::PasswordProvider;
+use rencfs::crypto::{Cipher, KdfParams};
+use rencfs::encryptedfs::{EncryptedFs, KeyMaterialProvider, PasswordProvider};
 use rencfs::mount::{create_mount_point, MountHandle, MountPoint};
 use shush_rs::SecretString;
 use tokio::runtime::Runtime;
@@ -38,6 +38,16 @@ impl TestResource {
             .build()
             .unwrap();
         let mh = runtime.block_on(async {
+            if !EncryptedFs::is_initialized(Path::new(&DATA_PATH)) {
+                EncryptedFs::init(
+                    Path::new(&DATA_PATH),
+                    get_password_provider().get_key_material().unwrap(),
+                    Cipher::ChaCha20Poly1305,
+                    KdfParams::default(),
+                )
+                .await
+                .unwrap();
+            }
             let mh = mount_point.mount().await;
             sleep(Duration::from_millis(100));
             mh
@@ -110,7 +120,7 @@ impl PasswordProvider for TestPasswordProvider {
     }
 }
 
-pub fn get_password_provider() -> Box<dyn PasswordProvider> {
+pub fn get_password_provider() -> Box<dyn KeyMaterialProvider> {
     Box::new(TestPasswordProvider {})
 }
 
@@ -147,3 +147,134 @@ fn it_create_empty_dir_check_attr() {
     let res = fs::remove_dir_all(Path::new(&test_folder));
     assert!(res.is_ok(), "failed to delete [{}]", &test_folder);
 }
+
+#[test]
+fn it_fcntl_lock() {
+    use std::os::fd::AsRawFd;
+
+    // open file description locks, so the two opens have different owners in the same process
+    fn set_lock(file: &File, r#type: i32, cmd: i32) -> (i32, libc::flock) {
+        let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+        #[allow(clippy::cast_possible_truncation)]
+        {
+            lock.l_type = r#type as i16;
+            lock.l_whence = libc::SEEK_SET as i16;
+        }
+        lock.l_start = 0;
+        lock.l_len = 10;
+        let res = unsafe { libc::fcntl(file.as_raw_fd(), cmd, &mut lock) };
+        (res, lock)
+    }
+
+    let _guard = TestGuard::setup();
+    let test_file = format!("{}{}", MOUNT_PATH, "/lock.txt");
+    let path = Path::new(&test_file);
+    {
+        let file1 = File::create_new(path).unwrap();
+        let file2 = File::options().read(true).write(true).open(path).unwrap();
+        assert_eq!(set_lock(&file1, libc::F_WRLCK, libc::F_OFD_SETLK).0, 0);
+        assert_eq!(set_lock(&file2, libc::F_RDLCK, libc::F_OFD_SETLK).0, -1);
+        let (res, lock) = set_lock(&file2, libc::F_RDLCK, libc::F_OFD_GETLK);
+        assert_eq!(res, 0);
+        assert_eq!(i32::from(lock.l_type), libc::F_WRLCK);
+
+        assert_eq!(set_lock(&file1, libc::F_UNLCK, libc::F_OFD_SETLK).0, 0);
+        assert_eq!(set_lock(&file2, libc::F_RDLCK, libc::F_OFD_SETLK).0, 0);
+        // shared
+        assert_eq!(set_lock(&file1, libc::F_RDLCK, libc::F_OFD_SETLK).0, 0);
+        drop(file2);
+        // released on close
+        assert_eq!(set_lock(&file1, libc::F_WRLCK, libc::F_OFD_SETLK).0, 0);
+    }
+    let res = fs::remove_file(path);
+    assert!(res.is_ok(), "failed to delete [{}]", res.err().unwrap());
+}
+
+#[test]
+fn it_seek_hole() {
+    use std::os::fd::AsRawFd;
+
+    fn seek(file: &File, offset: i64, whence: i32) -> i64 {
+        unsafe { libc::lseek(file.as_raw_fd(), offset, whence) }
+    }
+
+    let _guard = TestGuard::setup();
+    let test_file = format!("{}{}", MOUNT_PATH, "/sparse.img");
+    let path = Path::new(&test_file);
+    let mb = 1024 * 1024;
+    {
+        let mut file = File::create_new(path).unwrap();
+        file.set_len(10 * mb).unwrap();
+        file.write_all(b"start").unwrap();
+        file.sync_all().unwrap();
+        assert_eq!(seek(&file, 0, libc::SEEK_DATA), 0);
+        let hole = seek(&file, 0, libc::SEEK_HOLE);
+        assert!(hole > 0 && hole < 10 * mb as i64);
+        assert_eq!(seek(&file, hole, libc::SEEK_DATA), -1);
+        assert_eq!(seek(&file, 10 * mb as i64, libc::SEEK_HOLE), -1);
+    }
+    let mut buf = vec![];
+    File::open(path).unwrap().read_to_end(&mut buf).unwrap();
+    assert_eq!(buf.len() as u64, 10 * mb);
+    assert_eq!(&buf[..5], b"start");
+    assert!(buf[5..].iter().all(|b| *b == 0));
+    let res = fs::remove_file(path);
+    assert!(res.is_ok(), "failed to delete [{}]", res.err().unwrap());
+}
+
+#[test]
+fn it_fallocate() {
+    use std::os::fd::AsRawFd;
+
+    let _guard = TestGuard::setup();
+    let test_file = format!("{}{}", MOUNT_PATH, "/fallocate.img");
+    let path = Path::new(&test_file);
+    let mb = 1024 * 1024;
+    {
+        let mut file = File::create_new(path).unwrap();
+        file.write_all(&vec![1; 2 * mb]).unwrap();
+        let fd = file.as_raw_fd();
+        let res = unsafe {
+            libc::fallocate(
+                fd,
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                1,
+                mb as i64,
+            )
+        };
+        assert_eq!(res, 0);
+        // punch hole needs keep size
+        let res = unsafe { libc::fallocate(fd, libc::FALLOC_FL_PUNCH_HOLE, 0, 1) };
+        assert_eq!(res, -1);
+        let res = unsafe { libc::fallocate(fd, 0, 0, 3 * mb as i64) };
+        assert_eq!(res, 0);
+        file.sync_all().unwrap();
+    }
+    let mut buf = vec![];
+    File::open(path).unwrap().read_to_end(&mut buf).unwrap();
+    assert_eq!(buf.len(), 3 * mb);
+    assert_eq!(buf[0], 1);
+    assert!(buf[1..=mb].iter().all(|b| *b == 0));
+    assert!(buf[mb + 1..2 * mb].iter().all(|b| *b == 1));
+    assert!(buf[2 * mb..].iter().all(|b| *b == 0));
+    let res = fs::remove_file(path);
+    assert!(res.is_ok(), "failed to delete [{}]", res.err().unwrap());
+}
+
+#[test]
+fn it_symlink_non_utf8_target() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let _guard = TestGuard::setup();
+    let link = format!("{}{}", MOUNT_PATH, "/non_utf8_link");
+    let path = Path::new(&link);
+    let err = std::os::unix::fs::symlink(OsStr::from_bytes(b"target-\xff"), path).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    assert!(fs::symlink_metadata(path).is_err());
+    // still mounted
+    std::os::unix::fs::symlink("target", path).unwrap();
+    assert_eq!(fs::read_link(path).unwrap(), Path::new("target"));
+    let res = fs::remove_file(path);
+    assert!(res.is_ok(), "failed to delete [{}]", res.err().unwrap());
+}